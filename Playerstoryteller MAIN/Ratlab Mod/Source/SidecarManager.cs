@@ -157,9 +157,10 @@ namespace PlayerStoryteller
                 ProcessStartInfo startInfo = new ProcessStartInfo
                 {
                     FileName = exePath,
-                    Arguments = $"--url {wsUrl} --pid {rimWorldPid} --stream-key {streamKey} --session-id {sessionId} --quality {PlayerStorytellerMod.settings.streamingQuality}",
+                    // The stream key is handed over on stdin so it never shows up in the process list
+                    Arguments = $"--url {wsUrl} --pid {rimWorldPid} --stream-key-stdin --session-id {sessionId} --quality {PlayerStorytellerMod.settings.streamingQuality}",
                     UseShellExecute = false,
-                    RedirectStandardInput = false,  // Window capture mode doesn't need stdin
+                    RedirectStandardInput = true,
                     RedirectStandardOutput = true,
                     RedirectStandardError = true,
                     CreateNoWindow = true,
@@ -177,7 +178,11 @@ namespace PlayerStoryteller
 
                 sidecarProcess.Start();
 
-                // Window capture mode - no stdin needed, but we still want to read status messages
+                // Stream key handoff: one line on stdin, then close it (sidecar reads nothing else)
+                sidecarProcess.StandardInput.WriteLine(streamKey ?? string.Empty);
+                sidecarProcess.StandardInput.Close();
+
+                // Window capture mode - we still want to read status messages
                 cancellationTokenSource = new CancellationTokenSource();
                 outputReaderTask = Task.Run(() => ReadOutputLoop(sidecarProcess.StandardOutput, cancellationTokenSource.Token), cancellationTokenSource.Token);
 
@@ -0,0 +1,328 @@
+//! Stream authentication.
+//!
+//! `bearer` mode sends the stream key as `Authorization: Bearer <key>` on every
+//! connect, which is what `streamer.js` expects. `hmac` mode never puts the key
+//! on the wire:
+//!
+//! 1. The sidecar connects with `Authorization: Ratlab-HMAC` (no credentials) and `Session-Id`.
+//! 2. The server sends `{"type":"challenge","nonce":"<hex>"}`.
+//! 3. The sidecar answers `{"type":"auth","session":..,"timestamp":..,"nonce":..,"signature":..}`
+//!    where `signature = hex(HMAC-SHA256(key, "ratlab-stream-v1\n{session}\n{timestamp}\n{nonce}"))`.
+//! 4. The server replies `{"type":"auth_ok"}` or `{"type":"auth_failed","reason":..}` and closes.
+//!
+//! Nonces are single use and a signed response is only valid for `MAX_TOKEN_AGE`,
+//! so a captured handshake cannot be replayed. `ChallengeVerifier` is the
+//! reference implementation of the server side.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio_tungstenite::tungstenite::{self, protocol::Message};
+
+use crate::secret::StreamKey;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Authorization scheme announced by a streamer that wants a challenge.
+pub const HMAC_SCHEME: &str = "Ratlab-HMAC";
+
+/// How long a challenge (and the token signed for it) stays valid.
+pub const MAX_TOKEN_AGE: Duration = Duration::from_secs(30);
+
+/// How long the sidecar waits for each step of the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SIGNATURE_CONTEXT: &str = "ratlab-stream-v1";
+const NONCE_LEN: usize = 32;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AuthMode {
+    /// Static `Authorization: Bearer <key>` header
+    #[default]
+    Bearer,
+    /// HMAC challenge-response, the key never leaves this machine
+    Hmac,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuthMessage {
+    Challenge { nonce: String },
+    Auth { session: String, timestamp: u64, nonce: String, signature: String },
+    AuthOk,
+    AuthFailed { reason: String },
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("WebSocket error during authentication: {0}")]
+    WebSocket(Box<tungstenite::Error>),
+    #[error("Malformed authentication message: {0}")]
+    Malformed(String),
+    #[error("Server closed the connection during authentication")]
+    Closed,
+    #[error("Timed out waiting for the server")]
+    Timeout,
+    #[error("Server rejected authentication: {0}")]
+    Rejected(String),
+    #[error("Unknown or already used nonce")]
+    UnknownNonce,
+    #[error("Token expired or timestamp out of range")]
+    Expired,
+    #[error("Session mismatch")]
+    SessionMismatch,
+    #[error("Bad signature")]
+    BadSignature,
+}
+
+impl From<tungstenite::Error> for AuthError {
+    fn from(e: tungstenite::Error) -> Self {
+        AuthError::WebSocket(Box::new(e))
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn mac_for(key: &StreamKey, session: &str, timestamp: u64, nonce: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key.expose().as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}\n{}\n{}\n{}", SIGNATURE_CONTEXT, session, timestamp, nonce).as_bytes());
+    mac
+}
+
+/// Sign a server challenge. Returns the hex-encoded HMAC.
+pub fn sign(key: &StreamKey, session: &str, timestamp: u64, nonce: &str) -> String {
+    hex::encode(mac_for(key, session, timestamp, nonce).finalize().into_bytes())
+}
+
+/// Client side of the handshake, run right after the WebSocket upgrade.
+pub async fn respond_to_challenge<S>(ws: &mut S, key: &StreamKey, session: &str) -> Result<(), AuthError>
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    let nonce = match next_message(ws).await? {
+        AuthMessage::Challenge { nonce } => nonce,
+        AuthMessage::AuthFailed { reason } => return Err(AuthError::Rejected(reason)),
+        other => return Err(AuthError::Malformed(format!("expected challenge, got {:?}", other))),
+    };
+
+    let timestamp = unix_now();
+    let reply = AuthMessage::Auth {
+        session: session.to_string(),
+        timestamp,
+        signature: sign(key, session, timestamp, &nonce),
+        nonce,
+    };
+    send_message(ws, &reply).await?;
+
+    match next_message(ws).await? {
+        AuthMessage::AuthOk => Ok(()),
+        AuthMessage::AuthFailed { reason } => Err(AuthError::Rejected(reason)),
+        other => Err(AuthError::Malformed(format!("expected auth result, got {:?}", other))),
+    }
+}
+
+/// Server side of the handshake, for a streamer that connected with `HMAC_SCHEME`.
+/// The streamer is told the outcome either way.
+pub async fn challenge_streamer<S>(ws: &mut S, verifier: &mut ChallengeVerifier, session: &str) -> Result<(), AuthError>
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    let challenge = verifier.issue_challenge();
+    send_message(ws, &challenge).await?;
+
+    let response = next_message(ws).await?;
+    match verifier.verify(&response, session, unix_now()) {
+        Ok(()) => send_message(ws, &AuthMessage::AuthOk).await,
+        Err(e) => {
+            let _ = send_message(ws, &AuthMessage::AuthFailed { reason: e.to_string() }).await;
+            Err(e)
+        }
+    }
+}
+
+async fn send_message<S>(ws: &mut S, message: &AuthMessage) -> Result<(), AuthError>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    let text = serde_json::to_string(message).map_err(|e| AuthError::Malformed(e.to_string()))?;
+    ws.send(Message::Text(text)).await?;
+    Ok(())
+}
+
+async fn next_message<S>(ws: &mut S) -> Result<AuthMessage, AuthError>
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    loop {
+        let msg = match tokio::time::timeout(HANDSHAKE_TIMEOUT, ws.next()).await {
+            Ok(Some(msg)) => msg?,
+            Ok(None) => return Err(AuthError::Closed),
+            Err(_) => return Err(AuthError::Timeout),
+        };
+        match msg {
+            Message::Text(text) => {
+                return serde_json::from_str(&text).map_err(|e| AuthError::Malformed(e.to_string()));
+            }
+            Message::Close(_) => return Err(AuthError::Closed),
+            // Pings are answered by tungstenite itself
+            _ => continue,
+        }
+    }
+}
+
+/// Reference server-side verifier.
+///
+/// Issues nonces, remembers them until they are used or expire, and checks the
+/// signed response. Used by the local relay and in tests of the client side.
+pub struct ChallengeVerifier {
+    key: StreamKey,
+    max_age: Duration,
+    outstanding: HashMap<String, Instant>,
+}
+
+impl ChallengeVerifier {
+    pub fn new(key: StreamKey) -> Self {
+        Self { key, max_age: MAX_TOKEN_AGE, outstanding: HashMap::new() }
+    }
+
+    /// Shorter lifetimes, to test expiry.
+    #[cfg(test)]
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn issue_challenge(&mut self) -> AuthMessage {
+        self.prune();
+        let mut bytes = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut bytes).expect("OS random source unavailable");
+        let nonce = hex::encode(bytes);
+        self.outstanding.insert(nonce.clone(), Instant::now());
+        AuthMessage::Challenge { nonce }
+    }
+
+    /// Check a response against the expected session. `now` is unix seconds.
+    pub fn verify(&mut self, response: &AuthMessage, expected_session: &str, now: u64) -> Result<(), AuthError> {
+        let (session, timestamp, nonce, signature) = match response {
+            AuthMessage::Auth { session, timestamp, nonce, signature } => (session, *timestamp, nonce, signature),
+            other => return Err(AuthError::Malformed(format!("expected auth, got {:?}", other))),
+        };
+
+        // Consume the nonce up front: a failed attempt burns it too
+        let issued = self.outstanding.remove(nonce).ok_or(AuthError::UnknownNonce)?;
+        if issued.elapsed() > self.max_age || now.abs_diff(timestamp) > self.max_age.as_secs() {
+            return Err(AuthError::Expired);
+        }
+        if session != expected_session {
+            return Err(AuthError::SessionMismatch);
+        }
+
+        let signature = hex::decode(signature).map_err(|_| AuthError::BadSignature)?;
+        // verify_slice compares in constant time
+        mac_for(&self.key, session, timestamp, nonce)
+            .verify_slice(&signature)
+            .map_err(|_| AuthError::BadSignature)
+    }
+
+    fn prune(&mut self) {
+        let max_age = self.max_age;
+        self.outstanding.retain(|_, issued| issued.elapsed() <= max_age);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::WebSocketStream;
+
+    fn key() -> StreamKey {
+        StreamKey::new("correct-horse".to_string())
+    }
+
+    /// A response to a fresh challenge, signed with `key`.
+    fn respond(verifier: &mut ChallengeVerifier, key: &StreamKey, session: &str, timestamp: u64) -> AuthMessage {
+        let AuthMessage::Challenge { nonce } = verifier.issue_challenge() else { unreachable!() };
+        AuthMessage::Auth { session: session.to_string(), timestamp, signature: sign(key, session, timestamp, &nonce), nonce }
+    }
+
+    #[test]
+    fn accepts_a_signed_response() {
+        let mut verifier = ChallengeVerifier::new(key());
+        let response = respond(&mut verifier, &key(), "s1", unix_now());
+        assert!(verifier.verify(&response, "s1", unix_now()).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_bad_signature() {
+        let mut verifier = ChallengeVerifier::new(key());
+        let response = respond(&mut verifier, &StreamKey::new("wrong-key".to_string()), "s1", unix_now());
+        assert!(matches!(verifier.verify(&response, "s1", unix_now()), Err(AuthError::BadSignature)));
+
+        let AuthMessage::Auth { session, timestamp, nonce, .. } = respond(&mut verifier, &key(), "s1", unix_now()) else { unreachable!() };
+        let garbled = AuthMessage::Auth { session, timestamp, nonce, signature: "not hex".to_string() };
+        assert!(matches!(verifier.verify(&garbled, "s1", unix_now()), Err(AuthError::BadSignature)));
+    }
+
+    #[test]
+    fn rejects_another_session() {
+        let mut verifier = ChallengeVerifier::new(key());
+        let response = respond(&mut verifier, &key(), "s1", unix_now());
+        assert!(matches!(verifier.verify(&response, "s2", unix_now()), Err(AuthError::SessionMismatch)));
+    }
+
+    #[test]
+    fn rejects_an_expired_challenge() {
+        let mut verifier = ChallengeVerifier::new(key()).max_age(Duration::from_secs(5));
+        let now = unix_now();
+        // Signed too long ago, or too far ahead of the server's clock
+        let response = respond(&mut verifier, &key(), "s1", now - 10);
+        assert!(matches!(verifier.verify(&response, "s1", now), Err(AuthError::Expired)));
+        let response = respond(&mut verifier, &key(), "s1", now + 10);
+        assert!(matches!(verifier.verify(&response, "s1", now), Err(AuthError::Expired)));
+
+        // Answered after the challenge itself has expired
+        let mut verifier = ChallengeVerifier::new(key()).max_age(Duration::ZERO);
+        let response = respond(&mut verifier, &key(), "s1", now);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(matches!(verifier.verify(&response, "s1", now), Err(AuthError::Expired)));
+    }
+
+    #[test]
+    fn rejects_a_replayed_nonce() {
+        let mut verifier = ChallengeVerifier::new(key());
+        let response = respond(&mut verifier, &key(), "s1", unix_now());
+        assert!(verifier.verify(&response, "s1", unix_now()).is_ok());
+        assert!(matches!(verifier.verify(&response, "s1", unix_now()), Err(AuthError::UnknownNonce)));
+        // A failed attempt burns the nonce too
+        let response = respond(&mut verifier, &key(), "s1", unix_now());
+        assert!(verifier.verify(&response, "s2", unix_now()).is_err());
+        assert!(matches!(verifier.verify(&response, "s1", unix_now()), Err(AuthError::UnknownNonce)));
+    }
+
+    #[tokio::test]
+    async fn runs_the_handshake_over_a_websocket() {
+        for (client_key, accepted) in [(key(), true), (StreamKey::new("wrong-key".to_string()), false)] {
+            let (client, server) = tokio::io::duplex(4096);
+            let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+            let mut server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+            let mut verifier = ChallengeVerifier::new(key());
+            let (client, server) = tokio::join!(
+                respond_to_challenge(&mut client, &client_key, "s1"),
+                challenge_streamer(&mut server, &mut verifier, "s1")
+            );
+            assert_eq!(server.is_ok(), accepted);
+            match client {
+                Ok(()) => assert!(accepted),
+                Err(AuthError::Rejected(reason)) => assert!(!accepted && reason == "Bad signature"),
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+}
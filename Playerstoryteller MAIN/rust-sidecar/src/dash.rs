@@ -0,0 +1,125 @@
+//! `--dash-dir`: MPEG-DASH for dash.js players and archival pipelines.
+//!
+//! Segments from the encoder are re-cut into whole groups of pictures and
+//! written as CMAF, next to an MPD using the live profile's SegmentTemplate
+//! with a SegmentTimeline. The MPD is `dynamic` while streaming and is
+//! rewritten as a `static` recording of every segment when the stream ends,
+//! so segments are never deleted.
+
+mod mpd;
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+
+use crate::mp4::{CmafWriter, FragmentTarget, Mp4Segment, Refragmenter};
+
+use self::mpd::Manifest;
+
+/// Name of the manifest
+pub const MANIFEST: &str = "manifest.mpd";
+
+#[derive(Debug, Clone, Copy)]
+pub struct DashSettings {
+    /// Shortest segment; segments end at the first keyframe after it
+    pub segment_target: Duration,
+    /// How far back a live player may seek
+    pub window: Duration,
+}
+
+impl Default for DashSettings {
+    fn default() -> Self {
+        Self { segment_target: Duration::from_secs(2), window: Duration::from_secs(30) }
+    }
+}
+
+pub struct DashOutput {
+    dir: PathBuf,
+    segments: Refragmenter,
+    cmaf: CmafWriter,
+    manifest: Manifest,
+}
+
+impl DashOutput {
+    pub fn new(settings: DashSettings, dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        info!("Writing DASH to {}", dir.join(MANIFEST).display());
+        Ok(Self {
+            dir,
+            segments: Refragmenter::new(FragmentTarget::Gop(settings.segment_target)),
+            cmaf: CmafWriter::new(),
+            manifest: Manifest::new(settings),
+        })
+    }
+
+    /// Take in a segment of the encoder's stream, whatever its fragment size.
+    pub fn publish(&mut self, segment: &Mp4Segment) {
+        let ready: Vec<Mp4Segment> = self.segments.push(segment.clone()).into_iter().filter_map(|s| self.cmaf.push(s)).collect();
+        if !ready.is_empty() {
+            self.write(&ready);
+        }
+    }
+
+    /// The stream has ended: write what is left and make the MPD static.
+    pub fn finish(&mut self) {
+        let last: Vec<Mp4Segment> = self.segments.flush().and_then(|s| self.cmaf.push(s)).into_iter().collect();
+        self.manifest.finish();
+        self.write(&last);
+    }
+
+    fn write(&mut self, segments: &[Mp4Segment]) {
+        if let Err(e) = self.write_files(segments) {
+            warn!("Cannot write DASH files to {}: {}", self.dir.display(), e);
+        }
+    }
+
+    /// Files for `segments`, then the MPD, replaced in one rename so web
+    /// servers never serve half of it.
+    fn write_files(&mut self, segments: &[Mp4Segment]) -> io::Result<()> {
+        let now = SystemTime::now();
+        for segment in segments {
+            if let Some(name) = self.manifest.push(segment, now) {
+                fs::write(self.dir.join(name), &segment.data)?;
+            }
+        }
+        let temp = self.dir.join(format!("{}.tmp", MANIFEST));
+        fs::write(&temp, self.manifest.render(now))?;
+        fs::rename(temp, self.dir.join(MANIFEST))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::h264_stream;
+
+    #[test]
+    fn writes_the_mpd_and_segments_to_a_directory() {
+        let dir = std::env::temp_dir().join(format!("ratlab-dash-{}", std::process::id()));
+        let mut dash = DashOutput::new(DashSettings::default(), dir.clone()).unwrap();
+        let stream = h264_stream(150);
+        for segment in &stream {
+            dash.publish(segment);
+        }
+        let live = fs::read_to_string(dir.join(MANIFEST)).unwrap();
+        assert!(live.contains(r#"type="dynamic""#));
+        dash.finish();
+
+        let mpd = fs::read_to_string(dir.join(MANIFEST)).unwrap();
+        assert!(mpd.contains(r#"type="static" mediaPresentationDuration="PT5.000S""#));
+        assert!(mpd.contains(r#"codecs="avc1.42C028" width="640" height="360""#));
+        // Keyframes every second and two second segments; the last is what is left
+        assert!(mpd.contains(r#"<S t="0" d="180000" r="1"/>"#));
+        assert!(mpd.contains(r#"<S d="90000"/>"#));
+
+        let init = fs::read(dir.join("init-0.mp4")).unwrap();
+        assert_eq!(&init[4..12], b"ftypcmfc");
+        let segment = fs::read(dir.join("seg-0-360000.m4s")).unwrap();
+        assert_eq!(&segment[4..12], b"stypcmfs");
+        assert!(!dir.join(format!("{}.tmp", MANIFEST)).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
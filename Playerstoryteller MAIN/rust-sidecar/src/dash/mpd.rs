@@ -0,0 +1,359 @@
+//! The MPD: one Period per init segment, each with a SegmentTemplate and a
+//! SegmentTimeline built from the `tfdt` and durations of its segments.
+//!
+//! While live the MPD is `dynamic` and lists the segments within the time
+//! shift window. Once the stream ends it becomes `static` and lists every
+//! segment, as a recording.
+
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+
+use super::DashSettings;
+use crate::mp4::{avc_config, sps_dimensions, Mp4Segment, SegmentType};
+
+pub fn init_uri(period: usize) -> String {
+    format!("init-{}.mp4", period)
+}
+
+pub fn segment_uri(period: usize, time: u64) -> String {
+    format!("seg-{}-{}.m4s", period, time)
+}
+
+/// What the init segment says about the video.
+#[derive(Debug, Default, PartialEq)]
+struct Representation {
+    codecs: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    frame_rate: Option<f64>,
+}
+
+impl Representation {
+    fn from_init(init: &[u8]) -> Self {
+        let Some(config) = avc_config(init) else {
+            warn!("DASH: no avcC in the init segment, the MPD will have no codecs");
+            return Self::default();
+        };
+        let frame_rate = config.first_sps().map_err(|e| debug!("Cannot read the SPS: {}", e)).ok().and_then(|sps| sps.frame_rate());
+        let (width, height) = sps_dimensions(init).unzip();
+        Self { codecs: Some(config.codecs()), width, height, frame_rate }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Segment {
+    /// Presentation time, in timescale ticks
+    time: u64,
+    duration: u64,
+}
+
+struct Period {
+    id: usize,
+    /// Seconds after availabilityStartTime
+    start: f64,
+    representation: Representation,
+    timescale: u32,
+    /// presentationTimeOffset: the time of the first segment
+    time_offset: u64,
+    segments: Vec<Segment>,
+    /// Highest bit rate of any segment, bits per second
+    bandwidth: u64,
+}
+
+impl Period {
+    fn end_time(&self) -> u64 {
+        self.segments.last().map_or(self.time_offset, |s| s.time + s.duration)
+    }
+
+    /// Seconds from the start of the period to `time`
+    fn seconds(&self, time: u64) -> f64 {
+        time.saturating_sub(self.time_offset) as f64 / self.timescale as f64
+    }
+
+    fn duration(&self) -> f64 {
+        self.seconds(self.end_time())
+    }
+}
+
+pub struct Manifest {
+    settings: DashSettings,
+    periods: Vec<Period>,
+    /// Wall clock time of the start of the first period
+    availability_start: Option<SystemTime>,
+    ended: bool,
+}
+
+impl Manifest {
+    pub fn new(settings: DashSettings) -> Self {
+        Self { settings, periods: Vec::new(), availability_start: None, ended: false }
+    }
+
+    /// Take in a CMAF segment, made available at `now`. Returns the name to
+    /// store it under, or `None` if it does not belong in the MPD.
+    pub fn push(&mut self, segment: &Mp4Segment, now: SystemTime) -> Option<String> {
+        if segment.kind == SegmentType::Init {
+            let id = self.periods.len();
+            let previous_end = self.periods.last().map_or(0.0, |p| p.start + p.duration());
+            self.periods.push(Period {
+                id,
+                start: previous_end,
+                representation: Representation::from_init(&segment.data),
+                timescale: 0,
+                time_offset: 0,
+                segments: Vec::new(),
+                bandwidth: 0,
+            });
+            return Some(init_uri(id));
+        }
+
+        let info = segment.fragment.filter(|f| f.timescale > 0 && f.duration > 0)?;
+        let period = self.periods.last_mut()?;
+        // The first sample's presentation time; fragments without tfdt follow on
+        let time = info.composition_time.or(info.decode_time).unwrap_or(period.end_time());
+        if period.segments.is_empty() {
+            period.timescale = info.timescale;
+            period.time_offset = time;
+            let length = Duration::from_secs_f64(info.duration as f64 / info.timescale as f64);
+            let start = now.checked_sub(length).unwrap_or(now);
+            let availability_start = *self.availability_start.get_or_insert(start);
+            // A period after a restart starts when its media does, not before the last one ended
+            let since = start.duration_since(availability_start).unwrap_or_default().as_secs_f64();
+            period.start = period.start.max(since);
+        } else if info.timescale != period.timescale {
+            warn!("DASH: timescale changed without a new init segment, dropping the segment");
+            return None;
+        }
+
+        period.segments.push(Segment { time, duration: info.duration });
+        let bits_per_second = segment.data.len() as u64 * 8 * info.timescale as u64 / info.duration;
+        period.bandwidth = period.bandwidth.max(bits_per_second);
+        Some(segment_uri(period.id, time))
+    }
+
+    /// The stream has ended: the MPD becomes a static recording.
+    pub fn finish(&mut self) {
+        self.ended = true;
+    }
+
+    pub fn render(&self, now: SystemTime) -> String {
+        let periods: Vec<&Period> = self.periods.iter().filter(|p| !p.segments.is_empty()).collect();
+        let longest = periods
+            .iter()
+            .flat_map(|p| p.segments.iter().map(|s| s.duration as f64 / p.timescale as f64))
+            .fold(0.0, f64::max);
+        let min_buffer = self.settings.segment_target.as_secs_f64().max(longest);
+
+        let mut out = String::new();
+        let _ = writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        let _ = write!(out, r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011""#);
+        // Static periods are laid end to end; live ones keep their wall clock starts
+        let mut starts: Vec<f64> = periods.iter().map(|p| p.start).collect();
+        let mut live_edge = periods.last().map_or(0.0, |p| p.start + p.duration());
+        if self.ended {
+            let mut start = 0.0;
+            for (period, slot) in periods.iter().zip(&mut starts) {
+                *slot = start;
+                start += period.duration();
+            }
+            live_edge = start;
+            let _ = write!(out, r#" type="static" mediaPresentationDuration="{}""#, duration(live_edge));
+        } else {
+            let _ = write!(out, r#" type="dynamic""#);
+            if let Some(availability_start) = self.availability_start {
+                let _ = write!(out, r#" availabilityStartTime="{}""#, date_time(availability_start));
+            }
+            let _ = write!(
+                out,
+                r#" publishTime="{}" minimumUpdatePeriod="{}" timeShiftBufferDepth="{}""#,
+                date_time(now),
+                duration(self.settings.segment_target.as_secs_f64()),
+                duration(self.settings.window.as_secs_f64())
+            );
+        }
+        let _ = writeln!(out, r#" maxSegmentDuration="{}" minBufferTime="{}">"#, duration(longest), duration(min_buffer));
+
+        let window_start = live_edge - self.settings.window.as_secs_f64();
+        for (period, start) in periods.into_iter().zip(starts) {
+            let segments: Vec<Segment> = period
+                .segments
+                .iter()
+                .filter(|s| self.ended || start + period.seconds(s.time + s.duration) > window_start)
+                .copied()
+                .collect();
+            if segments.is_empty() {
+                continue;
+            }
+            write_period(&mut out, period, start, &segments);
+        }
+        let _ = writeln!(out, "</MPD>");
+        out
+    }
+}
+
+fn write_period(out: &mut String, period: &Period, start: f64, segments: &[Segment]) {
+    let _ = writeln!(out, r#"  <Period id="{}" start="{}">"#, period.id, duration(start));
+    let _ = writeln!(out, r#"    <AdaptationSet contentType="video" mimeType="video/mp4" segmentAlignment="true" startWithSAP="1">"#);
+    let _ = writeln!(
+        out,
+        r#"      <SegmentTemplate timescale="{}" presentationTimeOffset="{}" initialization="{}" media="seg-{}-$Time$.m4s">"#,
+        period.timescale,
+        period.time_offset,
+        init_uri(period.id),
+        period.id
+    );
+    let _ = writeln!(out, "        <SegmentTimeline>");
+    write_timeline(out, segments);
+    let _ = writeln!(out, "        </SegmentTimeline>");
+    let _ = writeln!(out, "      </SegmentTemplate>");
+
+    let video = &period.representation;
+    let _ = write!(out, r#"      <Representation id="video" bandwidth="{}""#, period.bandwidth);
+    if let Some(codecs) = &video.codecs {
+        let _ = write!(out, r#" codecs="{}""#, codecs);
+    }
+    if let (Some(width), Some(height)) = (video.width, video.height) {
+        let _ = write!(out, r#" width="{}" height="{}""#, width, height);
+    }
+    // Only whole rates; others would need the exact fraction
+    if let Some(rate) = video.frame_rate.filter(|r| (r - r.round()).abs() < 0.01) {
+        let _ = write!(out, r#" frameRate="{}""#, rate.round());
+    }
+    let _ = writeln!(out, "/>");
+    let _ = writeln!(out, "    </AdaptationSet>");
+    let _ = writeln!(out, "  </Period>");
+}
+
+/// `S` elements, with a run of equal, back to back segments in one `r` and
+/// `t` only where the time does not follow on.
+fn write_timeline(out: &mut String, segments: &[Segment]) {
+    let mut runs: Vec<(Segment, u64)> = Vec::new();
+    for &segment in segments {
+        match runs.last_mut() {
+            Some((first, repeat)) if first.duration == segment.duration && first.time + (*repeat + 1) * first.duration == segment.time => {
+                *repeat += 1;
+            }
+            _ => runs.push((segment, 0)),
+        }
+    }
+
+    let mut next_time = None;
+    for (segment, repeat) in runs {
+        let _ = write!(out, "          <S");
+        if next_time != Some(segment.time) {
+            let _ = write!(out, r#" t="{}""#, segment.time);
+        }
+        let _ = write!(out, r#" d="{}""#, segment.duration);
+        if repeat > 0 {
+            let _ = write!(out, r#" r="{}""#, repeat);
+        }
+        let _ = writeln!(out, "/>");
+        next_time = Some(segment.time + (repeat + 1) * segment.duration);
+    }
+}
+
+/// An xs:duration of `seconds`.
+fn duration(seconds: f64) -> String {
+    format!("PT{:.3}S", seconds.max(0.0))
+}
+
+/// An xs:dateTime in UTC, to the millisecond.
+fn date_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let time_of_day = seconds % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Year, month and day of a count of days since 1970-01-01, in the proleptic
+/// Gregorian calendar (Howard Hinnant's `civil_from_days`).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::FragmentInfo;
+
+    fn settings() -> DashSettings {
+        DashSettings { segment_target: Duration::from_secs(2), window: Duration::from_secs(5) }
+    }
+
+    fn segment(time: u64, duration: u64) -> Mp4Segment {
+        let fragment = FragmentInfo { is_keyframe: true, decode_time: Some(time), composition_time: None, duration, timescale: 1000 };
+        Mp4Segment { kind: SegmentType::Media, data: vec![0; 500], fragment: Some(fragment) }
+    }
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000 + seconds)
+    }
+
+    #[test]
+    fn writes_a_run_length_timeline() {
+        let mut manifest = Manifest::new(settings());
+        assert_eq!(manifest.push(&Mp4Segment::init(b"init".to_vec()), at(0)), Some("init-0.mp4".into()));
+        for (i, (time, length)) in [(5000, 2000), (7000, 2000), (9000, 2000), (11000, 1000), (20000, 1000)].into_iter().enumerate() {
+            let name = manifest.push(&segment(time, length), at(2 + i as u64));
+            assert_eq!(name, Some(segment_uri(0, time)));
+        }
+        manifest.finish();
+
+        let mpd = manifest.render(at(10));
+        assert!(mpd.contains(r#"type="static" mediaPresentationDuration="PT16.000S""#));
+        assert!(mpd.contains(r#"<SegmentTemplate timescale="1000" presentationTimeOffset="5000" initialization="init-0.mp4" media="seg-0-$Time$.m4s">"#));
+        let timeline: Vec<&str> = mpd.lines().filter(|l| l.trim_start().starts_with("<S ")).map(str::trim).collect();
+        assert_eq!(timeline, [r#"<S t="5000" d="2000" r="2"/>"#, r#"<S d="1000"/>"#, r#"<S t="20000" d="1000"/>"#]);
+        // 500 bytes in one second
+        assert!(mpd.contains(r#"<Representation id="video" bandwidth="4000"/>"#));
+    }
+
+    #[test]
+    fn lists_the_window_while_live() {
+        let mut manifest = Manifest::new(settings());
+        manifest.push(&Mp4Segment::init(b"init".to_vec()), at(0));
+        for i in 0..5 {
+            manifest.push(&segment(i * 2000, 2000), at(2 + 2 * i));
+        }
+        let mpd = manifest.render(at(10));
+        assert!(mpd.contains(r#"type="dynamic" availabilityStartTime="2023-11-14T22:13:20.000Z" publishTime="2023-11-14T22:13:30.000Z""#));
+        assert!(mpd.contains(r#"timeShiftBufferDepth="PT5.000S" maxSegmentDuration="PT2.000S""#));
+        // Only the segments ending in the last five seconds
+        assert!(mpd.contains(r#"<S t="4000" d="2000" r="2"/>"#));
+
+        // A restart after a pause is a new period, starting when its media does
+        manifest.push(&Mp4Segment::init(b"init".to_vec()), at(30));
+        manifest.push(&segment(0, 2000), at(32));
+        let mpd = manifest.render(at(32));
+        assert!(mpd.contains(r#"<Period id="1" start="PT30.000S">"#));
+        assert!(mpd.contains(r#"initialization="init-1.mp4" media="seg-1-$Time$.m4s""#));
+        assert!(!mpd.contains(r#"<Period id="0""#));
+    }
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(date_time(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(date_time(at(0) + Duration::from_millis(500)), "2023-11-14T22:13:20.500Z");
+        assert_eq!(date_time(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00.000Z");
+    }
+}
@@ -0,0 +1,165 @@
+//! Encoders that turn `VideoFrame`s into H.264 access units.
+//!
+//! Media Foundation (`encoder_patched.rs`) writes its own fMP4 through the
+//! SinkWriter. The backends here return Annex-B access units with timing and
+//! keyframe flags, and the sidecar packages them itself.
+
+#[cfg(feature = "openh264")]
+mod openh264;
+mod process;
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use thiserror::Error;
+
+use crate::source::VideoFrame;
+
+#[cfg(feature = "openh264")]
+pub use self::openh264::OpenH264Encoder;
+pub use self::process::ProcessEncoder;
+
+/// Keyframe spacing when the caller doesn't ask for one
+pub const DEFAULT_KEYFRAME_INTERVAL: Duration = Duration::from_secs(2);
+
+/// One encoded picture.
+#[derive(Clone, PartialEq)]
+pub struct EncodedFrame {
+    /// Annex-B NAL units with start codes. Keyframes carry SPS and PPS.
+    pub data: Vec<u8>,
+    /// Presentation time, from the source frame
+    pub pts: Duration,
+    /// Decode time; equal to `pts` without B-frames
+    pub dts: Duration,
+    pub keyframe: bool,
+}
+
+impl fmt::Debug for EncodedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncodedFrame")
+            .field("len", &self.data.len())
+            .field("pts", &self.pts)
+            .field("dts", &self.dts)
+            .field("keyframe", &self.keyframe)
+            .finish()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum EncoderError {
+    #[error("cannot start encoder: {0}")]
+    Init(String),
+    #[error("encoding failed: {0}")]
+    Encode(String),
+    #[error("{0}")]
+    Process(String),
+    #[error("frame is {width}x{height}, the encoder was set up for {expected_width}x{expected_height}")]
+    SizeChanged { width: u32, height: u32, expected_width: u32, expected_height: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct EncoderSettings {
+    pub width: u32,
+    pub height: u32,
+    /// Target bits per second
+    pub bitrate: u32,
+    pub frame_rate: u32,
+    pub keyframe_interval: Duration,
+    /// Command line of the `process` backend
+    pub command: Option<String>,
+}
+
+impl EncoderSettings {
+    pub fn new(width: u32, height: u32, bitrate: u32, frame_rate: u32) -> Self {
+        Self {
+            width,
+            height,
+            bitrate,
+            frame_rate: frame_rate.max(1),
+            keyframe_interval: DEFAULT_KEYFRAME_INTERVAL,
+            command: None,
+        }
+    }
+}
+
+pub trait Encoder: Send {
+    /// Encode one frame. Access units come out in decode order, possibly
+    /// later than the frame that produced them.
+    fn encode(&mut self, frame: &VideoFrame) -> Result<Vec<EncodedFrame>, EncoderError>;
+
+    /// Drain anything still buffered at the end of the stream.
+    fn finish(&mut self) -> Result<Vec<EncodedFrame>, EncoderError> {
+        Ok(Vec::new())
+    }
+
+    /// Make the next encoded frame a keyframe, e.g. for a new viewer.
+    fn request_keyframe(&mut self);
+}
+
+/// A keyframe asked for from another task, and passed on to the encoder
+/// before its next frame.
+#[derive(Debug, Clone, Default)]
+pub struct KeyframeRequest(Arc<AtomicBool>);
+
+impl KeyframeRequest {
+    pub fn request(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether a keyframe was asked for since the last call.
+    pub fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// `--encoder`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EncoderKind {
+    /// Media Foundation H.264 MFT, hardware accelerated where available
+    #[cfg(windows)]
+    #[value(name = "mf")]
+    MediaFoundation,
+    /// Cisco OpenH264, software
+    #[cfg(feature = "openh264")]
+    #[value(name = "openh264")]
+    OpenH264,
+    /// An external encoder process, see --encoder-command
+    Process,
+}
+
+impl EncoderKind {
+    /// Media Foundation on Windows, otherwise the first backend compiled in.
+    pub fn platform_default() -> Option<Self> {
+        #[cfg(windows)]
+        {
+            Some(EncoderKind::MediaFoundation)
+        }
+        #[cfg(all(not(windows), feature = "openh264"))]
+        {
+            Some(EncoderKind::OpenH264)
+        }
+        #[cfg(all(not(windows), not(feature = "openh264")))]
+        {
+            None
+        }
+    }
+
+    /// The backend behind the `Encoder` trait, if this is one.
+    pub fn create(self, settings: EncoderSettings) -> Result<Option<Box<dyn Encoder>>, EncoderError> {
+        match self {
+            #[cfg(windows)]
+            EncoderKind::MediaFoundation => Ok(None),
+            #[cfg(feature = "openh264")]
+            EncoderKind::OpenH264 => Ok(Some(Box::new(OpenH264Encoder::new(settings)?))),
+            EncoderKind::Process => {
+                let command = settings.command.clone().ok_or_else(|| {
+                    EncoderError::Init(format!("--encoder-command is required, e.g. {}", process::FFMPEG_EXAMPLE))
+                })?;
+                Ok(Some(Box::new(ProcessEncoder::spawn(&command, settings)?)))
+            }
+        }
+    }
+}
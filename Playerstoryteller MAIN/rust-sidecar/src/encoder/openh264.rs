@@ -0,0 +1,190 @@
+//! Software H.264 with Cisco's OpenH264, built from source by the `openh264`
+//! crate. Constrained Baseline without B-frames, so decode order is
+//! presentation order and each frame comes out as soon as it goes in.
+
+use std::time::Duration;
+
+use log::info;
+use openh264::encoder::{BitRate, Encoder as H264Encoder, EncoderConfig, FrameRate, FrameType, RateControlMode, UsageType};
+use openh264::formats::YUVSource;
+use openh264::OpenH264API;
+
+use super::{EncodedFrame, Encoder, EncoderError, EncoderSettings};
+use crate::source::VideoFrame;
+
+pub struct OpenH264Encoder {
+    encoder: H264Encoder,
+    settings: EncoderSettings,
+    /// Timestamp of the last keyframe, for the keyframe interval
+    last_keyframe: Option<Duration>,
+    keyframe_requested: bool,
+}
+
+impl OpenH264Encoder {
+    pub fn new(settings: EncoderSettings) -> Result<Self, EncoderError> {
+        let config = EncoderConfig::new()
+            .bitrate(BitRate::from_bps(settings.bitrate))
+            .max_frame_rate(FrameRate::from_hz(settings.frame_rate as f32))
+            .rate_control_mode(RateControlMode::Bitrate)
+            .usage_type(UsageType::ScreenContentRealTime);
+        let encoder = H264Encoder::with_api_config(OpenH264API::from_source(), config)
+            .map_err(|e| EncoderError::Init(e.to_string()))?;
+        info!(
+            "OpenH264 encoder: {}x{} at {} fps, {} bps",
+            settings.width & !1,
+            settings.height & !1,
+            settings.frame_rate,
+            settings.bitrate
+        );
+        Ok(Self { encoder, settings, last_keyframe: None, keyframe_requested: false })
+    }
+}
+
+impl Encoder for OpenH264Encoder {
+    fn encode(&mut self, frame: &VideoFrame) -> Result<Vec<EncodedFrame>, EncoderError> {
+        if (frame.width, frame.height) != (self.settings.width, self.settings.height) {
+            return Err(EncoderError::SizeChanged {
+                width: frame.width,
+                height: frame.height,
+                expected_width: self.settings.width,
+                expected_height: self.settings.height,
+            });
+        }
+
+        let due = self.last_keyframe.is_none_or(|last| frame.timestamp >= last + self.settings.keyframe_interval);
+        if due || self.keyframe_requested {
+            self.encoder.force_intra_frame();
+        }
+
+        let yuv = I420Frame::from_frame(frame);
+        let bitstream = self.encoder.encode(&yuv).map_err(|e| EncoderError::Encode(e.to_string()))?;
+        let keyframe = match bitstream.frame_type() {
+            // Rate control may skip a frame entirely
+            FrameType::Skip | FrameType::Invalid => return Ok(Vec::new()),
+            FrameType::IDR => true,
+            _ => false,
+        };
+        let data = bitstream.to_vec();
+        if keyframe {
+            self.last_keyframe = Some(frame.timestamp);
+            self.keyframe_requested = false;
+        }
+        Ok(vec![EncodedFrame { data, pts: frame.timestamp, dts: frame.timestamp, keyframe }])
+    }
+
+    fn request_keyframe(&mut self) {
+        self.keyframe_requested = true;
+    }
+}
+
+/// Planar 4:2:0 in the layout OpenH264 takes, cropped to even dimensions.
+struct I420Frame {
+    width: usize,
+    height: usize,
+    y: Vec<u8>,
+    u: Vec<u8>,
+    v: Vec<u8>,
+}
+
+impl I420Frame {
+    fn from_frame(frame: &VideoFrame) -> Self {
+        let nv12 = frame.to_nv12();
+        let (src_w, src_h) = (frame.width as usize, frame.height as usize);
+        let (width, height) = (src_w & !1, src_h & !1);
+        let (luma, chroma) = nv12.split_at(src_w * src_h);
+        let src_cw = src_w.div_ceil(2);
+
+        let mut y = Vec::with_capacity(width * height);
+        for row in luma.chunks_exact(src_w).take(height) {
+            y.extend_from_slice(&row[..width]);
+        }
+        let (mut u, mut v) = (Vec::with_capacity(width * height / 4), Vec::with_capacity(width * height / 4));
+        for row in chroma.chunks_exact(src_cw * 2).take(height / 2) {
+            for pair in row[..width].chunks_exact(2) {
+                u.push(pair[0]);
+                v.push(pair[1]);
+            }
+        }
+        Self { width, height, y, u, v }
+    }
+}
+
+impl YUVSource for I420Frame {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn strides(&self) -> (usize, usize, usize) {
+        (self.width, self.width / 2, self.width / 2)
+    }
+
+    fn y(&self) -> &[u8] {
+        &self.y
+    }
+
+    fn u(&self) -> &[u8] {
+        &self.u
+    }
+
+    fn v(&self) -> &[u8] {
+        &self.v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h264::{nal_units, NalKind};
+    use crate::source::{FrameSource, PixelFormat, TestPattern};
+
+    fn nal_kinds(data: &[u8]) -> Vec<NalKind> {
+        nal_units(data).map(NalKind::of).collect()
+    }
+
+    #[test]
+    fn encodes_keyframes_on_interval_and_request() {
+        let mut settings = EncoderSettings::new(320, 180, 500_000, 10);
+        settings.keyframe_interval = Duration::from_millis(500);
+        let mut encoder = OpenH264Encoder::new(settings).unwrap();
+        let mut pattern = TestPattern::new(320, 180, 10).format(PixelFormat::Nv12);
+
+        let mut keyframes = Vec::new();
+        for n in 0..12 {
+            if n == 7 {
+                encoder.request_keyframe();
+            }
+            let frame = pattern.next_frame().unwrap().unwrap();
+            for unit in encoder.encode(&frame).unwrap() {
+                assert_eq!((unit.pts, unit.dts), (frame.timestamp, frame.timestamp));
+                if unit.keyframe {
+                    // SPS and PPS travel with every IDR
+                    let kinds = nal_kinds(&unit.data);
+                    assert!(kinds.starts_with(&[NalKind::Sps, NalKind::Pps]), "{:?}", kinds);
+                    assert!(kinds.contains(&NalKind::Idr));
+                    keyframes.push(n);
+                }
+            }
+        }
+        assert_eq!(keyframes, [0, 5, 7]);
+    }
+
+    #[test]
+    fn crops_odd_sizes_to_even() {
+        let frame = VideoFrame {
+            width: 5,
+            height: 3,
+            format: PixelFormat::Bgra,
+            timestamp: Duration::ZERO,
+            data: vec![128; 5 * 3 * 4],
+        };
+        let yuv = I420Frame::from_frame(&frame);
+        assert_eq!((yuv.dimensions(), yuv.y.len(), yuv.u.len(), yuv.v.len()), ((4, 2), 8, 2, 2));
+    }
+
+    #[test]
+    fn rejects_size_changes() {
+        let mut encoder = OpenH264Encoder::new(EncoderSettings::new(320, 180, 500_000, 10)).unwrap();
+        let frame = TestPattern::new(640, 360, 10).next_frame().unwrap().unwrap();
+        assert!(matches!(encoder.encode(&frame), Err(EncoderError::SizeChanged { .. })));
+    }
+}
@@ -0,0 +1,321 @@
+//! An external encoder such as ffmpeg or x264, run as a child process.
+//!
+//! Frames go to its stdin as raw NV12, cropped to even dimensions. Its stdout
+//! must be an Annex-B H.264 elementary stream, which a reader thread cuts into
+//! access units. The stream carries no timestamps, so access units are given
+//! the timestamps of the frames in order: the encoder must not drop frames or
+//! use B-frames (`-bf 0`, or `-tune zerolatency` with x264).
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{debug, info};
+use parking_lot::Mutex;
+
+use super::{EncodedFrame, Encoder, EncoderError, EncoderSettings};
+use crate::h264::{nal_units, AccessUnitSplitter, NalKind};
+use crate::source::VideoFrame;
+
+/// `--encoder-command` for ffmpeg with libx264, tuned for latency
+pub const FFMPEG_EXAMPLE: &str = "ffmpeg -loglevel error -f rawvideo -pix_fmt nv12 -s {width}x{height} -r {fps} -i - \
+     -c:v libx264 -preset veryfast -tune zerolatency -b:v {bitrate} -g {keyint} -f h264 -";
+
+pub struct ProcessEncoder {
+    child: Child,
+    /// `None` once closed by `finish`
+    stdin: Option<ChildStdin>,
+    units: Receiver<Vec<u8>>,
+    reader: Option<JoinHandle<()>>,
+    /// Timestamps of frames written but not yet returned
+    timestamps: VecDeque<Duration>,
+    settings: EncoderSettings,
+    /// Last line the encoder printed to stderr, for error messages
+    last_error: Arc<Mutex<String>>,
+    stderr_reader: Option<JoinHandle<()>>,
+}
+
+impl ProcessEncoder {
+    /// Start `command`. `{width}`, `{height}`, `{fps}`, `{bitrate}` and
+    /// `{keyint}` (frames between keyframes) are filled in from `settings`.
+    pub fn spawn(command: &str, settings: EncoderSettings) -> Result<Self, EncoderError> {
+        let args = command_line(command, &settings)?;
+        let (program, args) = args.split_first().ok_or_else(|| EncoderError::Init("empty encoder command".into()))?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| EncoderError::Init(format!("cannot run {}: {}", program, e)))?;
+        info!("Encoder process {} started (PID {})", program, child.id());
+
+        let stdin = child.stdin.take();
+        let mut stdout = child.stdout.take().expect("piped stdout");
+        let stderr = child.stderr.take().expect("piped stderr");
+
+        let (sender, units) = mpsc::channel();
+        let reader = thread::spawn(move || {
+            let mut splitter = AccessUnitSplitter::new();
+            let mut buffer = vec![0u8; 64 * 1024];
+            while let Ok(n @ 1..) = stdout.read(&mut buffer) {
+                for unit in splitter.push(&buffer[..n]) {
+                    if sender.send(unit).is_err() {
+                        return;
+                    }
+                }
+            }
+            for unit in splitter.finish() {
+                let _ = sender.send(unit);
+            }
+        });
+
+        let last_error = Arc::new(Mutex::new(String::new()));
+        let stderr_line = last_error.clone();
+        let stderr_reader = thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                debug!("Encoder process: {}", line);
+                if !line.trim().is_empty() {
+                    *stderr_line.lock() = line;
+                }
+            }
+        });
+
+        Ok(Self {
+            child,
+            stdin,
+            units,
+            reader: Some(reader),
+            timestamps: VecDeque::new(),
+            settings,
+            last_error,
+            stderr_reader: Some(stderr_reader),
+        })
+    }
+
+    fn take_units(&mut self, units: impl IntoIterator<Item = Vec<u8>>) -> Vec<EncodedFrame> {
+        let mut frames = Vec::new();
+        for data in units {
+            let Some(timestamp) = self.timestamps.pop_front() else {
+                debug!("Encoder process returned more access units than frames, dropping one");
+                continue;
+            };
+            let keyframe = nal_units(&data).any(|unit| NalKind::of(unit) == NalKind::Idr);
+            frames.push(EncodedFrame { data, pts: timestamp, dts: timestamp, keyframe });
+        }
+        frames
+    }
+
+    /// Why the child went away, once it has.
+    fn failure(&mut self, context: &str) -> EncoderError {
+        let status = self.child.wait().map(|s| s.to_string()).unwrap_or_else(|e| e.to_string());
+        // stderr closes with the process; read it to the end for the last line
+        if let Some(stderr_reader) = self.stderr_reader.take() {
+            let _ = stderr_reader.join();
+        }
+        let last_error = self.last_error.lock().clone();
+        let mut message = format!("{} ({})", context, status);
+        if !last_error.is_empty() {
+            message = format!("{}: {}", message, last_error);
+        }
+        EncoderError::Process(message)
+    }
+}
+
+impl Encoder for ProcessEncoder {
+    fn encode(&mut self, frame: &VideoFrame) -> Result<Vec<EncodedFrame>, EncoderError> {
+        if (frame.width, frame.height) != (self.settings.width, self.settings.height) {
+            return Err(EncoderError::SizeChanged {
+                width: frame.width,
+                height: frame.height,
+                expected_width: self.settings.width,
+                expected_height: self.settings.height,
+            });
+        }
+
+        let raw = even_nv12(frame);
+        let written = match self.stdin.as_mut() {
+            Some(stdin) => stdin.write_all(&raw).and_then(|_| stdin.flush()),
+            None => return Err(EncoderError::Encode("encoder process input is closed".into())),
+        };
+        if written.is_err() {
+            self.stdin = None;
+            return Err(self.failure("encoder process stopped reading frames"));
+        }
+        self.timestamps.push_back(frame.timestamp);
+
+        let ready: Vec<Vec<u8>> = self.units.try_iter().collect();
+        Ok(self.take_units(ready))
+    }
+
+    fn finish(&mut self) -> Result<Vec<EncodedFrame>, EncoderError> {
+        // End of input makes the encoder flush and exit
+        self.stdin = None;
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+        let remaining: Vec<Vec<u8>> = self.units.try_iter().collect();
+        let frames = self.take_units(remaining);
+
+        match self.child.wait() {
+            Ok(status) if status.success() => Ok(frames),
+            _ => Err(self.failure("encoder process failed")),
+        }
+    }
+
+    fn request_keyframe(&mut self) {
+        debug!("Keyframe request ignored: the encoder process sets its own keyframes");
+    }
+}
+
+impl Drop for ProcessEncoder {
+    fn drop(&mut self) {
+        if self.reader.is_some() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Split `command` into arguments, honouring double quotes, and fill in the
+/// placeholders.
+fn command_line(command: &str, settings: &EncoderSettings) -> Result<Vec<String>, EncoderError> {
+    let keyint = (settings.keyframe_interval.as_secs_f64() * settings.frame_rate as f64).round().max(1.0);
+    let values = [
+        ("{width}", (settings.width & !1).to_string()),
+        ("{height}", (settings.height & !1).to_string()),
+        ("{fps}", settings.frame_rate.to_string()),
+        ("{bitrate}", settings.bitrate.to_string()),
+        ("{keyint}", keyint.to_string()),
+    ];
+
+    let mut args = Vec::new();
+    let (mut current, mut in_arg, mut quoted) = (String::new(), false, false);
+    for c in command.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_arg = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                }
+                in_arg = false;
+            }
+            c => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if quoted {
+        return Err(EncoderError::Init("unterminated quote in encoder command".into()));
+    }
+    if in_arg {
+        args.push(current);
+    }
+
+    Ok(args
+        .into_iter()
+        .map(|arg| values.iter().fold(arg, |arg, (placeholder, value)| arg.replace(placeholder, value)))
+        .collect())
+}
+
+/// NV12 cropped to even dimensions, as announced by `{width}` and `{height}`
+fn even_nv12(frame: &VideoFrame) -> Vec<u8> {
+    let nv12 = frame.to_nv12();
+    let (src_w, src_h) = (frame.width as usize, frame.height as usize);
+    let (width, height) = (src_w & !1, src_h & !1);
+    if (width, height) == (src_w, src_h) {
+        return nv12.into_owned();
+    }
+
+    let (luma, chroma) = nv12.split_at(src_w * src_h);
+    let mut out = Vec::with_capacity(width * height * 3 / 2);
+    for row in luma.chunks_exact(src_w).take(height) {
+        out.extend_from_slice(&row[..width]);
+    }
+    for row in chroma.chunks_exact(src_w.div_ceil(2) * 2).take(height / 2) {
+        out.extend_from_slice(&row[..width]);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::PixelFormat;
+
+    fn settings() -> EncoderSettings {
+        EncoderSettings::new(16, 16, 500_000, 30)
+    }
+
+    /// A 16x16 NV12 frame whose bytes are an access unit, for `cat` to echo
+    fn frame(millis: u64, access_unit: &[u8]) -> VideoFrame {
+        let mut data = access_unit.to_vec();
+        data.resize(PixelFormat::Nv12.frame_size(16, 16), 0);
+        VideoFrame { width: 16, height: 16, format: PixelFormat::Nv12, timestamp: Duration::from_millis(millis), data }
+    }
+
+    #[test]
+    fn fills_in_the_command_line() {
+        let mut settings = EncoderSettings::new(1281, 720, 2_500_000, 30);
+        settings.keyframe_interval = Duration::from_secs(2);
+        let args = command_line(r#"x264 "--output file" -  --fps {fps} {width}x{height} -B {bitrate} --keyint {keyint} """#, &settings);
+        assert_eq!(
+            args.unwrap(),
+            ["x264", "--output file", "-", "--fps", "30", "1280x720", "-B", "2500000", "--keyint", "60", ""]
+        );
+        assert!(command_line(r#"ffmpeg "-i"#, &settings).is_err());
+    }
+
+    #[test]
+    fn crops_odd_frames() {
+        let data: Vec<u8> = (0..PixelFormat::Nv12.frame_size(3, 3) as u8).collect();
+        let frame = VideoFrame { width: 3, height: 3, format: PixelFormat::Nv12, timestamp: Duration::ZERO, data };
+        // 2x2 of the 3x3 luma, then the first U/V pair
+        assert_eq!(even_nv12(&frame), [0, 1, 3, 4, 9, 10]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reads_access_units_from_the_process() {
+        let mut encoder = ProcessEncoder::spawn("cat", settings()).unwrap();
+        let mut units = Vec::new();
+        units.extend(encoder.encode(&frame(0, &[0, 0, 0, 1, 0x67, 0x42, 0, 0, 0, 1, 0x68, 0xCE, 0, 0, 1, 0x65, 0x88])).unwrap());
+        units.extend(encoder.encode(&frame(33, &[0, 0, 0, 1, 0x41, 0x9A])).unwrap());
+        units.extend(encoder.encode(&frame(66, &[0, 0, 0, 1, 0x41, 0x9B])).unwrap());
+        units.extend(encoder.finish().unwrap());
+
+        let summary: Vec<(u128, bool)> = units.iter().map(|u| (u.pts.as_millis(), u.keyframe)).collect();
+        assert_eq!(summary, [(0, true), (33, false), (66, false)]);
+        assert_eq!(units[1].data, [0, 0, 0, 1, 0x41, 0x9A]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_process_failures() {
+        assert!(matches!(ProcessEncoder::spawn("no-such-encoder-binary", settings()), Err(EncoderError::Init(_))));
+
+        let mut encoder = ProcessEncoder::spawn(r#"sh -c "cat >/dev/null; echo out of licences >&2; exit 3""#, settings()).unwrap();
+        encoder.encode(&frame(0, &[])).unwrap();
+        let error = encoder.finish().unwrap_err().to_string();
+        assert!(error.contains("exit status: 3"), "{}", error);
+        assert!(error.contains("out of licences"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_a_process_that_exits_early() {
+        let mut encoder = ProcessEncoder::spawn("true", settings()).unwrap();
+        let _ = encoder.child.wait();
+        // The pipe buffer may take the first frames before the write fails
+        let error = (0..100).find_map(|n| encoder.encode(&frame(n, &[])).err());
+        assert!(matches!(error, Some(EncoderError::Process(_))), "{:?}", error);
+    }
+}
@@ -29,6 +29,8 @@ use windows::Win32::Media::MediaFoundation::{
 };
 use windows::Win32::System::Com::IStream;
 
+use crate::source::VideoFrame;
+
 use windows_capture::d3d11::SendDirectX;
 use windows_capture::frame::Frame;
 use windows_capture::settings::ColorFormat;
@@ -168,7 +170,6 @@ impl Default for AudioSettingsBuilder {
 }
 
 pub struct VideoEncoder {
-    first_timestamp: Option<TimeSpan>,
     frame_sender: mpsc::SyncSender<Option<(VideoEncoderSource, TimeSpan)>>,
     audio_sender: mpsc::Sender<Option<(AudioEncoderSource, TimeSpan)>>,
     transcode_thread: Option<JoinHandle<Result<(), VideoEncoderError>>>,
@@ -430,7 +431,6 @@ impl VideoEncoder {
         let audio_block_align = (audio_settings.bit_per_sample / 8) * audio_settings.channel_count;
 
         Ok(Self {
-            first_timestamp: None,
             frame_sender,
             audio_sender,
             transcode_thread: Some(transcode_thread),
@@ -496,22 +496,16 @@ impl VideoEncoder {
         Ok(SendDirectX::new(cache.surface.0.clone()))
     }
 
-    pub fn send_frame(&mut self, frame: &mut Frame) -> Result<(), VideoEncoderError> {
+    pub fn send_frame(&mut self, frame: &VideoFrame) -> Result<(), VideoEncoderError> {
          if self.is_video_disabled { return Err(VideoEncoderError::VideoDisabled); }
-         
-         let timestamp = match self.first_timestamp {
-            Some(t0) => TimeSpan { Duration: frame.timestamp()?.Duration - t0.Duration },
-            None => {
-                let ts = frame.timestamp()?;
-                self.first_timestamp = Some(ts);
-                TimeSpan { Duration: 0 }
-            }
-        };
 
-        let width = frame.width();
-        let height = frame.height();
-        let mut buffer = frame.buffer().map_err(|e| VideoEncoderError::IoError(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())))?;
-        let raw_data = buffer.as_raw_buffer();
+        // TimeSpan ticks are 100 ns; sources start their timestamps at zero
+        let timestamp = TimeSpan { Duration: (frame.timestamp.as_nanos() / 100) as i64 };
+
+        let width = frame.width;
+        let height = frame.height;
+        let bgra = frame.to_bgra();
+        let raw_data: &[u8] = &bgra;
 
         // Calculate strides
         let input_stride = (width * 4) as usize;
@@ -523,7 +517,7 @@ impl VideoEncoder {
         let mut new_buffer = vec![0u8; output_stride * self.target_height as usize];
         
         // Copy rows in REVERSE order to flip the image vertically
-        // This fixes the upside-down issue caused by Windows Capture providing top-down data
+        // This fixes the upside-down issue caused by frames arriving top-down
         // while the H.264 encoder expects bottom-up
         for i in 0..copy_rows {
             let src_row = i;
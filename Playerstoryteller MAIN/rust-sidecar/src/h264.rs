@@ -0,0 +1,211 @@
+//! H.264 bitstreams: NAL units and access unit boundaries of Annex-B
+//! streams, and the parameter sets that describe them.
+
+mod avcc;
+mod bits;
+mod sps;
+
+use thiserror::Error;
+
+pub use self::avcc::AvcConfig;
+pub use self::sps::{Pps, Sps};
+
+#[derive(Debug, Error, PartialEq)]
+pub enum H264Error {
+    #[error("bitstream ends early")]
+    Truncated,
+    #[error("invalid bitstream: {0}")]
+    Invalid(&'static str),
+}
+
+const NAL_SLICE: u8 = 1;
+const NAL_IDR_SLICE: u8 = 5;
+const NAL_SEI: u8 = 6;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_AUD: u8 = 9;
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// NAL units of an Annex-B buffer, without start codes.
+pub fn nal_units(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    let ends: Vec<usize> = starts.iter().skip(1).map(|&s| s - 3).chain([data.len()]).collect();
+    starts.into_iter().zip(ends).map(move |(start, end)| trim_trailing_zeros(&data[start..end]))
+}
+
+/// nal_unit_type, the low five bits of the header byte
+pub fn nal_type(unit: &[u8]) -> u8 {
+    unit.first().map_or(0, |b| b & 0x1F)
+}
+
+/// What a NAL unit carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NalKind {
+    /// Slice of a picture that refers to earlier ones
+    NonIdr,
+    /// Slice of an instantaneous decoder refresh picture: a keyframe
+    Idr,
+    Sei,
+    Sps,
+    Pps,
+    /// Access unit delimiter
+    Aud,
+    Other(u8),
+}
+
+impl NalKind {
+    pub fn of(unit: &[u8]) -> Self {
+        match nal_type(unit) {
+            NAL_SLICE => NalKind::NonIdr,
+            NAL_IDR_SLICE => NalKind::Idr,
+            NAL_SEI => NalKind::Sei,
+            NAL_SPS => NalKind::Sps,
+            NAL_PPS => NalKind::Pps,
+            NAL_AUD => NalKind::Aud,
+            other => NalKind::Other(other),
+        }
+    }
+
+    pub fn is_slice(self) -> bool {
+        matches!(self, NalKind::NonIdr | NalKind::Idr)
+    }
+}
+
+/// A four byte start code, or trailing_zero_8bits, leaves zeros behind on
+/// the unit before it.
+fn trim_trailing_zeros(mut unit: &[u8]) -> &[u8] {
+    while let [rest @ .., 0] = unit {
+        unit = rest;
+    }
+    unit
+}
+
+/// Cuts an Annex-B stream that arrives in arbitrary chunks, like an encoder's
+/// stdout, into access units.
+///
+/// A new access unit starts at a delimiter, SEI or parameter set after a
+/// slice, or at a slice whose first_mb_in_slice is 0. That is enough for
+/// encoder output, which sends one picture per access unit.
+#[derive(Default)]
+pub struct AccessUnitSplitter {
+    /// Bytes after the last start code: the NAL unit still arriving
+    pending: Vec<u8>,
+    /// A start code has been seen, so `pending` is a NAL unit
+    started: bool,
+    /// Where to resume the start code search in `pending`
+    scan_from: usize,
+    /// Annex-B access unit being collected
+    current: Vec<u8>,
+    has_slice: bool,
+}
+
+impl AccessUnitSplitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Access units completed by `data`, with four byte start codes.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(data);
+        let mut units = Vec::new();
+        while let Some(pos) = self.pending[self.scan_from..].windows(3).position(|w| w == [0, 0, 1]) {
+            let pos = self.scan_from + pos;
+            if self.started {
+                let nal = trim_trailing_zeros(&self.pending[..pos]).to_vec();
+                units.extend(self.add_nal(&nal));
+            }
+            self.pending.drain(..pos + 3);
+            self.started = true;
+            self.scan_from = 0;
+        }
+        // A start code may straddle the next chunk
+        self.scan_from = self.pending.len().saturating_sub(2);
+        units
+    }
+
+    /// The access units still held, once the stream has ended.
+    pub fn finish(&mut self) -> Vec<Vec<u8>> {
+        let mut units = Vec::new();
+        if self.started {
+            let nal = trim_trailing_zeros(&self.pending).to_vec();
+            units.extend(self.add_nal(&nal));
+        }
+        units.push(std::mem::take(&mut self.current));
+        units.retain(|unit| !unit.is_empty());
+        *self = Self::default();
+        units
+    }
+
+    fn add_nal(&mut self, nal: &[u8]) -> Option<Vec<u8>> {
+        if nal.is_empty() {
+            return None;
+        }
+        let kind = NalKind::of(nal);
+        let starts_unit = match kind {
+            NalKind::Sei | NalKind::Sps | NalKind::Pps | NalKind::Aud => self.has_slice,
+            // first_mb_in_slice is ue(v), so 0 is a single 1 bit
+            NalKind::NonIdr | NalKind::Idr => self.has_slice && nal.get(1).is_some_and(|b| b & 0x80 != 0),
+            NalKind::Other(_) => false,
+        };
+        let complete = if starts_unit {
+            self.has_slice = false;
+            Some(std::mem::take(&mut self.current))
+        } else {
+            None
+        };
+        if kind.is_slice() {
+            self.has_slice = true;
+        }
+        self.current.extend_from_slice(&START_CODE);
+        self.current.extend_from_slice(nal);
+        complete
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_annex_b() {
+        let data = [0, 0, 0, 1, 0x67, 1, 0, 0, 1, 0x68, 2, 0, 0, 0, 1, 0x65, 3, 0];
+        let units: Vec<&[u8]> = nal_units(&data).collect();
+        assert_eq!(units, [&[0x67, 1][..], &[0x68, 2], &[0x65, 3]]);
+    }
+
+    #[test]
+    fn finds_access_units_across_chunks() {
+        // SPS, PPS and an IDR in two slices, then three single-slice pictures
+        let stream = [
+            &[0, 0, 0, 1, 0x67, 0x42][..],
+            &[0, 0, 0, 1, 0x68, 0xCE],
+            &[0, 0, 1, 0x65, 0x88, 1],
+            &[0, 0, 1, 0x65, 0x40, 2],
+            &[0, 0, 1, 0x41, 0x9A, 3],
+            &[0, 0, 0, 1, 0x09, 0xF0, 0, 0, 1, 0x41, 0x9A, 4],
+            &[0, 0, 1, 0x41, 0x9A, 5],
+        ]
+        .concat();
+
+        // Byte at a time, so every start code straddles a chunk
+        let mut splitter = AccessUnitSplitter::new();
+        let mut units: Vec<Vec<u8>> = stream.iter().flat_map(|b| splitter.push(&[*b])).collect();
+        units.extend(splitter.finish());
+        assert!(splitter.finish().is_empty());
+
+        use NalKind::*;
+        let kinds: Vec<Vec<NalKind>> = units.iter().map(|u| nal_units(u).map(NalKind::of).collect()).collect();
+        assert_eq!(kinds, [vec![Sps, Pps, Idr, Idr], vec![NonIdr], vec![Aud, NonIdr], vec![NonIdr]]);
+        assert_eq!(units[1], [0, 0, 0, 1, 0x41, 0x9A, 3]);
+    }
+}
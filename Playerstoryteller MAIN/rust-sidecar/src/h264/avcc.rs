@@ -0,0 +1,169 @@
+//! `avcC`, the AVCDecoderConfigurationRecord of ISO/IEC 14496-15 5.3.3.
+
+use super::{H264Error, Sps};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvcConfig {
+    pub profile_idc: u8,
+    pub profile_compatibility: u8,
+    pub level_idc: u8,
+    /// Bytes in each NAL unit length prefix of the samples
+    pub nal_length_size: u8,
+    /// SPS and PPS NAL units, header byte included
+    pub sps: Vec<Vec<u8>>,
+    pub pps: Vec<Vec<u8>>,
+    /// Chroma format and bit depths, present for High profiles
+    pub high: Option<HighProfileFields>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HighProfileFields {
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+}
+
+impl AvcConfig {
+    /// The record for one SPS and PPS, with 4-byte NAL lengths.
+    pub fn from_parameter_sets(sps: &[u8], pps: &[u8]) -> Result<Self, H264Error> {
+        let parsed = Sps::parse(sps)?;
+        let high = matches!(parsed.profile_idc, 100 | 110 | 122 | 144).then_some(HighProfileFields {
+            chroma_format_idc: parsed.chroma_format_idc as u8,
+            bit_depth_luma: parsed.bit_depth_luma,
+            bit_depth_chroma: parsed.bit_depth_chroma,
+        });
+        Ok(Self {
+            profile_idc: parsed.profile_idc,
+            profile_compatibility: parsed.constraint_flags,
+            level_idc: parsed.level_idc,
+            nal_length_size: 4,
+            sps: vec![sps.to_vec()],
+            pps: vec![pps.to_vec()],
+            high,
+        })
+    }
+
+    /// Parse the payload of an `avcC` box.
+    pub fn parse(data: &[u8]) -> Result<Self, H264Error> {
+        let mut reader = ByteReader { data, position: 0 };
+        if reader.u8()? != 1 {
+            return Err(H264Error::Invalid("unknown avcC version"));
+        }
+        let profile_idc = reader.u8()?;
+        let profile_compatibility = reader.u8()?;
+        let level_idc = reader.u8()?;
+        let nal_length_size = (reader.u8()? & 0x03) + 1;
+        if nal_length_size == 3 {
+            return Err(H264Error::Invalid("3-byte NAL lengths are not allowed"));
+        }
+
+        let sps_count = reader.u8()? & 0x1F;
+        let sps = (0..sps_count).map(|_| reader.nal()).collect::<Result<Vec<_>, _>>()?;
+        let pps_count = reader.u8()?;
+        let pps = (0..pps_count).map(|_| reader.nal()).collect::<Result<Vec<_>, _>>()?;
+
+        // Older writers leave the High profile fields out, so they are optional
+        let high = match (matches!(profile_idc, 100 | 110 | 122 | 144), reader.remaining()) {
+            (true, 4..) => Some(HighProfileFields {
+                chroma_format_idc: reader.u8()? & 0x03,
+                bit_depth_luma: (reader.u8()? & 0x07) + 8,
+                bit_depth_chroma: (reader.u8()? & 0x07) + 8,
+            }),
+            _ => None,
+        };
+        Ok(Self { profile_idc, profile_compatibility, level_idc, nal_length_size, sps, pps, high })
+    }
+
+    /// The first SPS, parsed.
+    pub fn first_sps(&self) -> Result<Sps, H264Error> {
+        Sps::parse(self.sps.first().ok_or(H264Error::Invalid("avcC has no SPS"))?)
+    }
+
+    /// RFC 6381 codecs string, as MSE, HLS and DASH want it: `avc1.PPCCLL`.
+    pub fn codecs(&self) -> String {
+        format!("avc1.{:02X}{:02X}{:02X}", self.profile_idc, self.profile_compatibility, self.level_idc)
+    }
+
+    /// The `avcC` payload.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[1, self.profile_idc, self.profile_compatibility, self.level_idc]);
+        // Reserved bits are all ones
+        out.push(0xFC | (self.nal_length_size - 1));
+        out.push(0xE0 | self.sps.len() as u8);
+        for sps in &self.sps {
+            out.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+            out.extend_from_slice(sps);
+        }
+        out.push(self.pps.len() as u8);
+        for pps in &self.pps {
+            out.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+            out.extend_from_slice(pps);
+        }
+        if let Some(high) = self.high {
+            out.push(0xFC | high.chroma_format_idc);
+            out.push(0xF8 | (high.bit_depth_luma - 8));
+            out.push(0xF8 | (high.bit_depth_chroma - 8));
+            // numOfSequenceParameterSetExt
+            out.push(0);
+        }
+    }
+}
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl ByteReader<'_> {
+    fn u8(&mut self) -> Result<u8, H264Error> {
+        let byte = *self.data.get(self.position).ok_or(H264Error::Truncated)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    /// A parameter set with its 16-bit length
+    fn nal(&mut self) -> Result<Vec<u8>, H264Error> {
+        let length = (self.u8()? as usize) << 8 | self.u8()? as usize;
+        let nal = self.data.get(self.position..self.position + length).ok_or(H264Error::Truncated)?;
+        self.position += length;
+        Ok(nal.to_vec())
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{h264_pps, h264_sps};
+
+    #[test]
+    fn round_trips_a_record() {
+        let config = AvcConfig::from_parameter_sets(&h264_sps(640, 360), &h264_pps()).unwrap();
+        assert_eq!((config.profile_idc, config.profile_compatibility, config.level_idc), (66, 0xC0, 40));
+        assert_eq!(config.high, None);
+
+        let mut payload = Vec::new();
+        config.write(&mut payload);
+        assert_eq!(payload[..6], [1, 66, 0xC0, 40, 0xFF, 0xE1]);
+        assert_eq!(AvcConfig::parse(&payload), Ok(config.clone()));
+        assert_eq!(config.first_sps().unwrap().height(), 360);
+        assert_eq!(config.codecs(), "avc1.42C028");
+
+        let high = AvcConfig { profile_idc: 100, high: Some(HighProfileFields { chroma_format_idc: 1, bit_depth_luma: 8, bit_depth_chroma: 10 }), ..config };
+        let mut payload = Vec::new();
+        high.write(&mut payload);
+        assert_eq!(payload[payload.len() - 4..], [0xFD, 0xF8, 0xFA, 0]);
+        assert_eq!(AvcConfig::parse(&payload), Ok(high));
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        let mut payload = Vec::new();
+        AvcConfig::from_parameter_sets(&h264_sps(640, 360), &h264_pps()).unwrap().write(&mut payload);
+        assert_eq!(AvcConfig::parse(&payload[..payload.len() - 1]), Err(H264Error::Truncated));
+        assert!(matches!(AvcConfig::parse(&[0]), Err(H264Error::Invalid(_))));
+    }
+}
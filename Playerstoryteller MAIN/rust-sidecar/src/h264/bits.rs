@@ -0,0 +1,97 @@
+//! Bit-level reading of RBSP payloads, with the exp-Golomb codes of the spec.
+
+use super::H264Error;
+
+/// The RBSP of a NAL unit payload: `00 00 03` emulation prevention bytes
+/// removed.
+pub fn rbsp(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len());
+    let mut zeros = 0;
+    for &byte in payload {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        out.push(byte);
+    }
+    out
+}
+
+/// Most significant bit first, as H.264 syntax is written.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn bit(&mut self) -> Result<u32, H264Error> {
+        let byte = self.data.get(self.position / 8).ok_or(H264Error::Truncated)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Ok(bit as u32)
+    }
+
+    pub fn flag(&mut self) -> Result<bool, H264Error> {
+        Ok(self.bit()? == 1)
+    }
+
+    /// u(n), up to 32 bits
+    pub fn bits(&mut self, count: u32) -> Result<u32, H264Error> {
+        (0..count).try_fold(0u32, |value, _| Ok(value << 1 | self.bit()?))
+    }
+
+    pub fn skip(&mut self, count: usize) -> Result<(), H264Error> {
+        if self.position + count > self.data.len() * 8 {
+            return Err(H264Error::Truncated);
+        }
+        self.position += count;
+        Ok(())
+    }
+
+    /// ue(v): leading zeros, a one, then as many bits again
+    pub fn ue(&mut self) -> Result<u32, H264Error> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return Err(H264Error::Invalid("exp-Golomb code longer than 32 bits"));
+            }
+        }
+        let suffix = self.bits(zeros)? as u64;
+        Ok(((1u64 << zeros) - 1 + suffix) as u32)
+    }
+
+    /// se(v): ue(v) mapped to 0, 1, -1, 2, -2, ...
+    pub fn se(&mut self) -> Result<i32, H264Error> {
+        let code = self.ue()? as i64;
+        Ok(if code % 2 == 1 { (code + 1) / 2 } else { -(code / 2) } as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_exp_golomb() {
+        // ue 0, 1, 2, 3 then se 1, -1, 2: 1 010 011 00100 010 011 00100
+        let data = [0b1010_0110, 0b0100_0100, 0b1100_1000];
+        let mut reader = BitReader::new(&data);
+        let ue: Vec<u32> = (0..4).map(|_| reader.ue().unwrap()).collect();
+        let se: Vec<i32> = (0..3).map(|_| reader.se().unwrap()).collect();
+        assert_eq!((ue, se), (vec![0, 1, 2, 3], vec![1, -1, 2]));
+        assert_eq!(reader.bits(1), Ok(0));
+        assert_eq!(reader.bit(), Err(H264Error::Truncated));
+    }
+
+    #[test]
+    fn removes_emulation_prevention() {
+        assert_eq!(rbsp(&[0x42, 0, 0, 3, 1, 0, 0, 3, 0, 3]), [0x42, 0, 0, 1, 0, 0, 0, 3]);
+    }
+}
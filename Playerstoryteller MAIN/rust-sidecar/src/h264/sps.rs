@@ -0,0 +1,414 @@
+//! Sequence and picture parameter sets (H.264 7.3.2.1 and 7.3.2.2, VUI in E.1.1).
+
+use super::bits::{rbsp, BitReader};
+use super::{nal_type, H264Error, NAL_PPS, NAL_SPS};
+
+/// Profiles whose SPS carries chroma format, bit depth and scaling lists
+const HIGH_PROFILES: [u8; 12] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134];
+
+/// Largest frame in macroblocks, MaxFS of level 6.2 (Table A-1)
+const MAX_FRAME_MBS: u64 = 139_264;
+
+/// Sample aspect ratios of aspect_ratio_idc 1 to 16 (Table E-1)
+const ASPECT_RATIOS: [(u16, u16); 16] = [
+    (1, 1), (12, 11), (10, 11), (16, 11), (40, 33), (24, 11), (20, 11), (32, 11),
+    (80, 33), (18, 11), (15, 11), (64, 33), (160, 99), (4, 3), (3, 2), (2, 1),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sps {
+    pub profile_idc: u8,
+    /// constraint_set0_flag to constraint_set5_flag and the reserved bits
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub id: u32,
+    /// 0 monochrome, 1 4:2:0, 2 4:2:2, 3 4:4:4
+    pub chroma_format_idc: u32,
+    pub separate_colour_plane: bool,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    pub max_num_ref_frames: u32,
+    /// Progressive only, no field coding
+    pub frame_mbs_only: bool,
+    /// Size of the decoded picture, whole macroblocks
+    pub coded_width: u32,
+    pub coded_height: u32,
+    pub crop: Crop,
+    pub vui: Option<Vui>,
+}
+
+/// Frame cropping in luma samples.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Crop {
+    pub left: u32,
+    pub right: u32,
+    pub top: u32,
+    pub bottom: u32,
+}
+
+/// The parts of the VUI worth knowing about.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Vui {
+    /// Sample aspect ratio, width:height
+    pub sample_aspect_ratio: Option<(u16, u16)>,
+    /// 0 component, 1 PAL, 2 NTSC, ... 5 unspecified
+    pub video_format: Option<u8>,
+    pub full_range: bool,
+    pub colour: Option<ColourDescription>,
+    pub timing: Option<Timing>,
+}
+
+/// ISO/IEC 23091-2 code points, as in the `colr` box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColourDescription {
+    pub primaries: u8,
+    pub transfer: u8,
+    pub matrix: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing {
+    pub num_units_in_tick: u32,
+    pub time_scale: u32,
+    pub fixed_frame_rate: bool,
+}
+
+impl Sps {
+    /// Parse an SPS NAL unit, header byte included.
+    pub fn parse(nal: &[u8]) -> Result<Self, H264Error> {
+        if nal_type(nal) != NAL_SPS {
+            return Err(H264Error::Invalid("not an SPS"));
+        }
+        let data = rbsp(&nal[1..]);
+        let mut r = BitReader::new(&data);
+
+        let profile_idc = r.bits(8)? as u8;
+        let constraint_flags = r.bits(8)? as u8;
+        let level_idc = r.bits(8)? as u8;
+        let id = r.ue()?;
+
+        let (mut chroma_format_idc, mut separate_colour_plane, mut bit_depth_luma, mut bit_depth_chroma) = (1, false, 8, 8);
+        if HIGH_PROFILES.contains(&profile_idc) {
+            chroma_format_idc = r.ue()?;
+            if chroma_format_idc == 3 {
+                separate_colour_plane = r.flag()?;
+            }
+            bit_depth_luma = bit_depth(&mut r)?;
+            bit_depth_chroma = bit_depth(&mut r)?;
+            // qpprime_y_zero_transform_bypass_flag
+            r.skip(1)?;
+            if r.flag()? {
+                let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+                for i in 0..lists {
+                    if r.flag()? {
+                        skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+        if chroma_format_idc > 3 {
+            return Err(H264Error::Invalid("chroma_format_idc out of range"));
+        }
+
+        // log2_max_frame_num_minus4
+        r.ue()?;
+        match r.ue()? {
+            // log2_max_pic_order_cnt_lsb_minus4
+            0 => {
+                r.ue()?;
+            }
+            1 => {
+                // delta_pic_order_always_zero_flag, offset_for_non_ref_pic, offset_for_top_to_bottom_field
+                r.skip(1)?;
+                r.se()?;
+                r.se()?;
+                for _ in 0..r.ue()? {
+                    r.se()?;
+                }
+            }
+            2 => {}
+            _ => return Err(H264Error::Invalid("pic_order_cnt_type out of range")),
+        }
+        let max_num_ref_frames = r.ue()?;
+        // gaps_in_frame_num_value_allowed_flag
+        r.skip(1)?;
+        let width_in_mbs = r.ue()? as u64 + 1;
+        let height_in_map_units = r.ue()? as u64 + 1;
+        let frame_mbs_only = r.flag()?;
+        if !frame_mbs_only {
+            // mb_adaptive_frame_field_flag
+            r.skip(1)?;
+        }
+        // direct_8x8_inference_flag
+        r.skip(1)?;
+
+        let height_in_mbs = height_in_map_units * if frame_mbs_only { 1 } else { 2 };
+        if width_in_mbs * height_in_mbs > MAX_FRAME_MBS {
+            return Err(H264Error::Invalid("picture is larger than any level allows"));
+        }
+        // Both fit easily in u32 after the check above
+        let coded_width = width_in_mbs as u32 * 16;
+        let coded_height = height_in_mbs as u32 * 16;
+
+        let mut crop = Crop::default();
+        if r.flag()? {
+            // Offsets count in chroma samples, and in field pairs for field coding
+            let chroma_array_type = if separate_colour_plane { 0 } else { chroma_format_idc };
+            let (sub_width, sub_height) = match chroma_array_type {
+                1 => (2, 2),
+                2 => (2, 1),
+                _ => (1, 1),
+            };
+            let unit_x = sub_width;
+            let unit_y = sub_height * if frame_mbs_only { 1 } else { 2 };
+            let too_large = || H264Error::Invalid("cropping is larger than the picture");
+            let mut offset = |unit: u32| r.ue()?.checked_mul(unit).ok_or_else(too_large);
+            crop = Crop { left: offset(unit_x)?, right: offset(unit_x)?, top: offset(unit_y)?, bottom: offset(unit_y)? };
+            let fits = |a: u32, b: u32, size: u32| a.checked_add(b).is_some_and(|sum| sum < size);
+            if !fits(crop.left, crop.right, coded_width) || !fits(crop.top, crop.bottom, coded_height) {
+                return Err(too_large());
+            }
+        }
+
+        let vui = if r.flag()? { Some(parse_vui(&mut r)?) } else { None };
+
+        Ok(Self {
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            id,
+            chroma_format_idc,
+            separate_colour_plane,
+            bit_depth_luma,
+            bit_depth_chroma,
+            max_num_ref_frames,
+            frame_mbs_only,
+            coded_width,
+            coded_height,
+            crop,
+            vui,
+        })
+    }
+
+    /// Displayed width, after cropping
+    pub fn width(&self) -> u32 {
+        self.coded_width - self.crop.left - self.crop.right
+    }
+
+    /// Displayed height, after cropping
+    pub fn height(&self) -> u32 {
+        self.coded_height - self.crop.top - self.crop.bottom
+    }
+
+    /// Frames per second from the VUI timing, if the stream states it.
+    pub fn frame_rate(&self) -> Option<f64> {
+        let timing = self.vui.as_ref()?.timing?;
+        // A frame is two ticks: time_scale counts fields
+        (timing.num_units_in_tick > 0).then(|| timing.time_scale as f64 / (2.0 * timing.num_units_in_tick as f64))
+    }
+
+    pub fn profile_name(&self) -> &'static str {
+        match self.profile_idc {
+            // constraint_set1_flag makes Baseline constrained
+            66 if self.constraint_flags & 0x40 != 0 => "Constrained Baseline",
+            66 => "Baseline",
+            77 => "Main",
+            88 => "Extended",
+            100 => "High",
+            110 => "High 10",
+            122 => "High 4:2:2",
+            244 => "High 4:4:4 Predictive",
+            44 => "CAVLC 4:4:4 Intra",
+            _ => "Unknown",
+        }
+    }
+
+    /// Level as written, e.g. "3.1". Level 1b is signalled with
+    /// constraint_set3_flag at level_idc 11 in Baseline and Main.
+    pub fn level_name(&self) -> String {
+        if self.level_idc == 11 && self.constraint_flags & 0x10 != 0 && matches!(self.profile_idc, 66 | 77 | 88) {
+            return "1b".into();
+        }
+        format!("{}.{}", self.level_idc / 10, self.level_idc % 10)
+    }
+}
+
+/// bit_depth_*_minus8, which is at most 6 (14-bit samples).
+fn bit_depth(r: &mut BitReader) -> Result<u8, H264Error> {
+    match r.ue()? {
+        minus8 @ 0..=6 => Ok(minus8 as u8 + 8),
+        _ => Err(H264Error::Invalid("bit depth out of range")),
+    }
+}
+
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Result<(), H264Error> {
+    let (mut last, mut next) = (8i32, 8i32);
+    for _ in 0..size {
+        if next != 0 {
+            next = (last + r.se()? + 256) % 256;
+        }
+        if next != 0 {
+            last = next;
+        }
+    }
+    Ok(())
+}
+
+fn parse_vui(r: &mut BitReader) -> Result<Vui, H264Error> {
+    let mut vui = Vui::default();
+    if r.flag()? {
+        vui.sample_aspect_ratio = match r.bits(8)? {
+            // Extended_SAR
+            255 => Some((r.bits(16)? as u16, r.bits(16)? as u16)),
+            idc @ 1..=16 => Some(ASPECT_RATIOS[idc as usize - 1]),
+            _ => None,
+        };
+    }
+    if r.flag()? {
+        // overscan_appropriate_flag
+        r.skip(1)?;
+    }
+    if r.flag()? {
+        vui.video_format = Some(r.bits(3)? as u8);
+        vui.full_range = r.flag()?;
+        if r.flag()? {
+            vui.colour = Some(ColourDescription {
+                primaries: r.bits(8)? as u8,
+                transfer: r.bits(8)? as u8,
+                matrix: r.bits(8)? as u8,
+            });
+        }
+    }
+    if r.flag()? {
+        // chroma_sample_loc_type_top_field and _bottom_field
+        r.ue()?;
+        r.ue()?;
+    }
+    if r.flag()? {
+        vui.timing = Some(Timing { num_units_in_tick: r.bits(32)?, time_scale: r.bits(32)?, fixed_frame_rate: r.flag()? });
+    }
+    // HRD parameters and bitstream restrictions follow; nothing here needs them
+    Ok(vui)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pps {
+    pub id: u32,
+    pub sps_id: u32,
+    /// CABAC rather than CAVLC
+    pub entropy_coding_mode: bool,
+}
+
+impl Pps {
+    /// Parse a PPS NAL unit, header byte included.
+    pub fn parse(nal: &[u8]) -> Result<Self, H264Error> {
+        if nal_type(nal) != NAL_PPS {
+            return Err(H264Error::Invalid("not a PPS"));
+        }
+        let data = rbsp(&nal[1..]);
+        let mut r = BitReader::new(&data);
+        Ok(Self { id: r.ue()?, sps_id: r.ue()?, entropy_coding_mode: r.flag()? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{h264_pps, h264_sps, BitWriter};
+
+    #[test]
+    fn parses_a_cropped_baseline_sps() {
+        // 1920x1080 is coded as 1920x1088 with 8 lines cropped
+        let sps = Sps::parse(&h264_sps(1920, 1080)).unwrap();
+        assert_eq!((sps.coded_width, sps.coded_height), (1920, 1088));
+        assert_eq!(sps.crop, Crop { left: 0, right: 0, top: 0, bottom: 8 });
+        assert_eq!((sps.width(), sps.height()), (1920, 1080));
+        assert_eq!((sps.profile_name(), sps.level_name().as_str()), ("Constrained Baseline", "4.0"));
+        assert_eq!(sps.vui, None);
+
+        let pps = Pps::parse(&h264_pps()).unwrap();
+        assert_eq!((pps.id, pps.sps_id, pps.entropy_coding_mode), (0, 0, false));
+    }
+
+    #[test]
+    fn parses_a_high_profile_sps_with_vui() {
+        let mut w = BitWriter::default();
+        // High 3.1, chroma 4:2:0, 8-bit, no transform bypass, one scaling list
+        w.bits(8, 100).bits(8, 0).bits(8, 31).ue(0);
+        w.ue(1).ue(0).ue(0).flag(false).flag(true).flag(true);
+        w.se(-8);
+        (1..8).for_each(|_| {
+            w.flag(false);
+        });
+        // frame_num, POC type 0 with its lsb length, references, 1280x720
+        w.ue(0).ue(0).ue(2).ue(4).flag(false).ue(79).ue(44).flag(true).flag(true).flag(false);
+        // VUI: square pixels, no overscan info, BT.709 limited range, no chroma location
+        w.flag(true).flag(true).bits(8, 1).flag(false);
+        w.flag(true).bits(3, 5).flag(false).flag(true).bits(8, 1).bits(8, 1).bits(8, 1).flag(false);
+        // 30 fps as 60 ticks a second; the run of zero bytes needs emulation prevention
+        w.flag(true).bits(32, 1).bits(32, 60).flag(true);
+        let nal = w.nal(0x67);
+        assert!(nal.windows(3).any(|w| w == [0, 0, 3]));
+
+        let sps = Sps::parse(&nal).unwrap();
+        assert_eq!((sps.profile_name(), sps.level_name().as_str()), ("High", "3.1"));
+        assert_eq!((sps.chroma_format_idc, sps.bit_depth_luma, sps.max_num_ref_frames), (1, 8, 4));
+        assert_eq!((sps.width(), sps.height()), (1280, 720));
+
+        let vui = sps.vui.clone().unwrap();
+        assert_eq!((vui.sample_aspect_ratio, vui.video_format, vui.full_range), (Some((1, 1)), Some(5), false));
+        assert_eq!(vui.colour, Some(ColourDescription { primaries: 1, transfer: 1, matrix: 1 }));
+        assert_eq!(vui.timing.map(|t| t.fixed_frame_rate), Some(true));
+        assert_eq!(sps.frame_rate(), Some(30.0));
+    }
+
+    #[test]
+    fn rejects_bad_parameter_sets() {
+        assert!(matches!(Sps::parse(&h264_pps()), Err(H264Error::Invalid(_))));
+        assert_eq!(Sps::parse(&[0x67, 0x42, 0xC0]), Err(H264Error::Truncated));
+    }
+
+    /// Baseline up to the picture size, with POC type 2 and one reference
+    fn baseline(width_in_mbs: u32, height_in_map_units: u32, frame_mbs_only: bool) -> BitWriter {
+        let mut w = BitWriter::default();
+        w.bits(8, 66).bits(8, 0).bits(8, 40).ue(0).ue(0).ue(2).ue(1).flag(false);
+        w.ue(width_in_mbs).ue(height_in_map_units).flag(frame_mbs_only);
+        if !frame_mbs_only {
+            w.flag(false);
+        }
+        w.flag(true);
+        w
+    }
+
+    #[test]
+    fn rejects_huge_exp_golomb_values() {
+        let huge = u32::MAX - 1;
+        for (width, height, frame_mbs_only) in [(huge, 0, true), (0, huge, true), (0, huge / 2, false), (1055, 1055, true), (372, 372, false)] {
+            let nal = baseline(width, height, frame_mbs_only).flag(false).flag(false).nal(0x67);
+            assert!(matches!(Sps::parse(&nal), Err(H264Error::Invalid(_))), "{}x{}", width, height);
+        }
+
+        // Cropping offsets that overflow once scaled, or sum past u32
+        for offsets in [[huge, 0, 0, 0], [0, 0, 0, huge], [u32::MAX / 2, u32::MAX / 2, 0, 0]] {
+            let mut w = baseline(119, 67, true);
+            w.flag(true);
+            offsets.iter().for_each(|&o| {
+                w.ue(o);
+            });
+            let nal = w.flag(false).nal(0x67);
+            assert!(matches!(Sps::parse(&nal), Err(H264Error::Invalid(_))), "{:?}", offsets);
+        }
+
+        // The largest level 6.2 frame still parses
+        let sps = Sps::parse(&baseline(511, 271, true).flag(false).flag(false).nal(0x67)).unwrap();
+        assert_eq!((sps.width(), sps.height()), (8192, 4352));
+
+        // bit_depth_luma_minus8 past 6, in a High profile SPS
+        let mut w = BitWriter::default();
+        w.bits(8, 100).bits(8, 0).bits(8, 40).ue(0).ue(1).ue(huge);
+        assert!(matches!(Sps::parse(&w.nal(0x67)), Err(H264Error::Invalid(_))));
+        let mut w = BitWriter::default();
+        w.bits(8, 100).bits(8, 0).bits(8, 40).ue(0).ue(1).ue(0).ue(7);
+        assert!(matches!(Sps::parse(&w.nal(0x67)), Err(H264Error::Invalid(_))));
+    }
+}
@@ -0,0 +1,191 @@
+//! `--hls-dir` / `--hls-listen`: Low-Latency HLS for players that can't use
+//! the MSE-over-WebSocket stream, like iOS Safari or OBS browser sources.
+//!
+//! Segments from the encoder are cut into single frames and written as CMAF,
+//! then collected into parts and segments by `Playlist`. They are written to
+//! a directory for any web server to serve, and/or served from memory by the
+//! built-in HTTP server, which also supports blocking playlist reload.
+
+mod playlist;
+mod server;
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{info, warn};
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::mp4::{CmafWriter, FragmentTarget, Mp4Segment, Refragmenter};
+
+use self::playlist::{init_uri, part_uri, segment_uri, Change, Playlist};
+
+/// Name of the media playlist
+pub const PLAYLIST: &str = "live.m3u8";
+
+#[derive(Debug, Clone, Copy)]
+pub struct HlsSettings {
+    /// Longest partial segment
+    pub part_target: Duration,
+    /// Shortest segment; segments end at the first keyframe after it
+    pub segment_target: Duration,
+    /// Complete segments kept in the playlist
+    pub window: usize,
+}
+
+impl Default for HlsSettings {
+    fn default() -> Self {
+        Self { part_target: Duration::from_millis(333), segment_target: Duration::from_secs(2), window: 6 }
+    }
+}
+
+struct Pipeline {
+    /// Single frames, so parts can be cut to any length
+    frames: Refragmenter,
+    cmaf: CmafWriter,
+    playlist: Playlist,
+    /// Files of segments that left the playlist, and when to delete them
+    removed: VecDeque<(Instant, PathBuf)>,
+}
+
+pub struct HlsOutput {
+    pipeline: Mutex<Pipeline>,
+    /// Where to write the playlist and segments, if anywhere
+    dir: Option<PathBuf>,
+    /// Woken on every playlist change, for blocking requests
+    updated: Notify,
+}
+
+impl HlsOutput {
+    pub fn new(settings: HlsSettings, dir: Option<PathBuf>) -> io::Result<Self> {
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir)?;
+            info!("Writing HLS to {}", dir.join(PLAYLIST).display());
+        }
+        Ok(Self {
+            pipeline: Mutex::new(Pipeline {
+                frames: Refragmenter::new(FragmentTarget::Frame),
+                cmaf: CmafWriter::new(),
+                playlist: Playlist::new(settings),
+                removed: VecDeque::new(),
+            }),
+            dir,
+            updated: Notify::new(),
+        })
+    }
+
+    /// Take in a segment of the encoder's stream, whatever its fragment size.
+    pub fn publish(&self, segment: &Mp4Segment) {
+        let mut pipeline = self.pipeline.lock();
+        let Pipeline { frames, cmaf, playlist, removed } = &mut *pipeline;
+        let mut changes = Vec::new();
+        for frame in frames.push(segment.clone()).into_iter().filter_map(|s| cmaf.push(s)) {
+            changes.extend(playlist.push(&frame));
+        }
+        self.apply(playlist, removed, &changes);
+    }
+
+    /// The stream has ended: send what is left and end the playlist.
+    pub fn finish(&self) {
+        let mut pipeline = self.pipeline.lock();
+        let Pipeline { frames, cmaf, playlist, removed } = &mut *pipeline;
+        let mut changes = Vec::new();
+        if let Some(frame) = frames.flush().and_then(|s| cmaf.push(s)) {
+            changes.extend(playlist.push(&frame));
+        }
+        changes.extend(playlist.finish());
+        self.apply(playlist, removed, &changes);
+    }
+
+    fn apply(&self, playlist: &Playlist, removed: &mut VecDeque<(Instant, PathBuf)>, changes: &[Change]) {
+        if changes.is_empty() {
+            return;
+        }
+        if let Some(dir) = &self.dir {
+            if let Err(e) = write_changes(dir, playlist, removed, changes) {
+                warn!("Cannot write HLS files to {}: {}", dir.display(), e);
+            }
+        }
+        self.updated.notify_waiters();
+    }
+}
+
+/// Files for `changes`, then the playlist, replaced in one rename so web
+/// servers never serve half of it.
+///
+/// Removed segments and parts stay for a target duration, for players still
+/// working from an older playlist, and are deleted on a later write. Those
+/// removed when the stream ends stay.
+fn write_changes(dir: &Path, playlist: &Playlist, removed: &mut VecDeque<(Instant, PathBuf)>, changes: &[Change]) -> io::Result<()> {
+    let now = Instant::now();
+    let expires = now + Duration::from_secs(playlist.target_duration());
+    for change in changes {
+        match *change {
+            Change::Init(id) => fs::write(dir.join(init_uri(id)), playlist.init_data(id).unwrap_or_default())?,
+            Change::Part { msn, index } => fs::write(dir.join(part_uri(msn, index)), playlist.part_data(msn, index).unwrap_or_default())?,
+            Change::Segment(msn) => fs::write(dir.join(segment_uri(msn)), playlist.segment_data(msn).unwrap_or_default())?,
+            Change::Removed { msn, parts } => {
+                removed.push_back((expires, dir.join(segment_uri(msn))));
+                removed.extend((0..parts).map(|index| (expires, dir.join(part_uri(msn, index)))));
+            }
+        }
+    }
+    let temp = dir.join(format!("{}.tmp", PLAYLIST));
+    // Plain files can't hold requests, so no blocking reload
+    fs::write(&temp, playlist.render(false))?;
+    fs::rename(temp, dir.join(PLAYLIST))?;
+
+    while removed.front().is_some_and(|(expires, _)| *expires <= now) {
+        if let Some((_, path)) = removed.pop_front() {
+            // Already gone is fine
+            let _ = fs::remove_file(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::h264_stream;
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_the_playlist_and_segments_to_a_directory() {
+        let dir = std::env::temp_dir().join(format!("ratlab-hls-{}", std::process::id()));
+        let settings = HlsSettings { window: 2, ..HlsSettings::default() };
+        let hls = HlsOutput::new(settings, Some(dir.clone())).unwrap();
+        // 2 s segments: seg0 leaves the playlist at 6 s, then after a pause
+        // seg1 leaves at 8 s and seg2 at the end
+        let stream = h264_stream(300);
+        for segment in &stream[..101] {
+            hls.publish(segment);
+        }
+        tokio::time::advance(Duration::from_secs(3)).await;
+        for segment in &stream[101..] {
+            hls.publish(segment);
+        }
+        hls.finish();
+
+        let playlist = fs::read_to_string(dir.join(PLAYLIST)).unwrap();
+        assert!(playlist.contains("#EXT-X-MAP:URI=\"init0.mp4\"\n"));
+        assert!(playlist.contains("#EXT-X-SERVER-CONTROL:PART-HOLD-BACK="));
+        assert!(playlist.ends_with("seg4.m4s\n#EXT-X-ENDLIST\n"));
+
+        let init = fs::read(dir.join("init0.mp4")).unwrap();
+        assert_eq!(&init[4..12], b"ftypcmfc");
+        let segment = fs::read(dir.join("seg4.m4s")).unwrap();
+        assert_eq!(&segment[4..12], b"stypcmfs");
+        assert!(segment.starts_with(&fs::read(dir.join("seg4.0.m4s")).unwrap()));
+        // Segments out of the window are deleted a target duration later
+        assert!(!playlist.contains("seg1.m4s"));
+        assert!(!dir.join("seg0.m4s").exists());
+        assert!(!dir.join("seg0.0.m4s").exists());
+        assert!(dir.join("seg1.m4s").exists());
+        assert!(dir.join("seg1.0.m4s").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,418 @@
+//! The LL-HLS media playlist, and the segments and parts it lists.
+//!
+//! Frames arrive as single-frame CMAF fragments. They are collected into
+//! parts of at most the part target, and parts into segments that end at the
+//! first keyframe after the segment target, or earlier if the keyframe is too
+//! late for the target duration. A segment is the concatenation of its parts,
+//! so only the parts are kept.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+
+use log::{debug, warn};
+
+use super::HlsSettings;
+use crate::mp4::{Mp4Segment, SegmentType};
+
+/// Segments and parts listed as live, with their full parts
+const SEGMENTS_WITH_PARTS: usize = 3;
+
+/// What `Playlist::push` changed, for the directory writer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Init(u32),
+    Part { msn: u64, index: usize },
+    Segment(u64),
+    /// A segment and its parts slid out of the playlist
+    Removed { msn: u64, parts: usize },
+}
+
+pub fn init_uri(id: u32) -> String {
+    format!("init{}.mp4", id)
+}
+
+pub fn segment_uri(msn: u64) -> String {
+    format!("seg{}.m4s", msn)
+}
+
+pub fn part_uri(msn: u64, index: usize) -> String {
+    format!("seg{}.{}.m4s", msn, index)
+}
+
+struct Part {
+    data: Vec<u8>,
+    /// Seconds
+    duration: f64,
+    /// Starts with a keyframe
+    independent: bool,
+}
+
+struct Segment {
+    /// Media sequence number
+    msn: u64,
+    init: u32,
+    discontinuity: bool,
+    parts: Vec<Part>,
+    complete: bool,
+}
+
+impl Segment {
+    fn duration(&self) -> f64 {
+        self.parts.iter().map(|p| p.duration).sum()
+    }
+}
+
+pub struct Playlist {
+    settings: HlsSettings,
+    inits: HashMap<u32, Vec<u8>>,
+    /// Init segment of new segments
+    init: Option<u32>,
+    next_init: u32,
+    /// The next segment follows a new init segment
+    discontinuity: bool,
+    /// In order; only the last may be incomplete
+    segments: VecDeque<Segment>,
+    next_msn: u64,
+    /// Discontinuities that slid out of the playlist
+    discontinuity_sequence: u64,
+    /// Frames of the part being collected
+    part: Option<Part>,
+    /// A frame longer than the part target was reported
+    warned_long_frame: bool,
+    /// Seconds; players rely on it never changing
+    target_duration: u64,
+    ended: bool,
+}
+
+impl Playlist {
+    pub fn new(settings: HlsSettings) -> Self {
+        let target_duration = settings.segment_target.as_secs_f64().ceil().max(1.0) as u64;
+        Self {
+            settings,
+            inits: HashMap::new(),
+            init: None,
+            next_init: 0,
+            discontinuity: false,
+            segments: VecDeque::new(),
+            next_msn: 0,
+            discontinuity_sequence: 0,
+            part: None,
+            warned_long_frame: false,
+            target_duration,
+            ended: false,
+        }
+    }
+
+    /// Take in an init segment or a single-frame fragment.
+    pub fn push(&mut self, segment: &Mp4Segment) -> Vec<Change> {
+        let mut changes = Vec::new();
+        if segment.kind == SegmentType::Init {
+            self.close_segment(&mut changes);
+            let id = self.next_init;
+            self.next_init += 1;
+            self.inits.insert(id, segment.data.clone());
+            // Old init segments stay until no listed segment uses them
+            let listed: Vec<u32> = self.segments.iter().map(|s| s.init).collect();
+            self.inits.retain(|i, _| *i == id || listed.contains(i));
+            self.discontinuity = self.init.is_some();
+            self.init = Some(id);
+            changes.push(Change::Init(id));
+            return changes;
+        }
+
+        let Some(info) = segment.fragment.filter(|f| f.timescale > 0) else {
+            debug!("HLS: skipping a media segment without timing");
+            return changes;
+        };
+        let Some(init) = self.init else { return changes };
+        let duration = info.duration as f64 / info.timescale as f64;
+        let part_target = self.settings.part_target.as_secs_f64();
+        if duration > part_target && !std::mem::replace(&mut self.warned_long_frame, true) {
+            // A frame can't be split, so its part overruns PART-TARGET
+            warn!(
+                "HLS: {:.0} ms frames are longer than the {:.0} ms part target; raise --hls-part-ms",
+                duration * 1000.0,
+                part_target * 1000.0
+            );
+        }
+
+        let open = self.segments.back().filter(|s| !s.complete);
+        let open_duration = open.map(|s| s.duration()).unwrap_or(0.0) + self.part.as_ref().map_or(0.0, |p| p.duration);
+        // A segment longer than the target duration (rounded) is cut short
+        let full = open.is_some() && (open_duration + duration).round() as u64 > self.target_duration;
+        if full || info.is_keyframe && (open.is_none() || open_duration >= self.settings.segment_target.as_secs_f64()) {
+            self.close_segment(&mut changes);
+            self.segments.push_back(Segment {
+                msn: self.next_msn,
+                init,
+                discontinuity: std::mem::take(&mut self.discontinuity),
+                parts: Vec::new(),
+                complete: false,
+            });
+            self.next_msn += 1;
+        } else if open.is_none() {
+            // Segments start with a keyframe
+            return changes;
+        } else if info.is_keyframe || self.part.as_ref().is_some_and(|p| p.duration + duration > part_target) {
+            self.close_part(&mut changes);
+        }
+
+        let part = self.part.get_or_insert_with(|| Part { data: Vec::new(), duration: 0.0, independent: info.is_keyframe });
+        part.data.extend_from_slice(&segment.data);
+        part.duration += duration;
+        // Close as soon as another frame of the same length would not fit
+        if part.duration + duration > part_target {
+            self.close_part(&mut changes);
+        }
+        changes
+    }
+
+    /// The stream has ended: close the last segment and end the playlist.
+    pub fn finish(&mut self) -> Vec<Change> {
+        let mut changes = Vec::new();
+        self.close_segment(&mut changes);
+        self.ended = true;
+        changes
+    }
+
+    fn close_part(&mut self, changes: &mut Vec<Change>) {
+        let Some(part) = self.part.take() else { return };
+        let Some(segment) = self.segments.back_mut().filter(|s| !s.complete) else { return };
+        segment.parts.push(part);
+        changes.push(Change::Part { msn: segment.msn, index: segment.parts.len() - 1 });
+    }
+
+    fn close_segment(&mut self, changes: &mut Vec<Change>) {
+        self.close_part(changes);
+        let Some(segment) = self.segments.back_mut().filter(|s| !s.complete) else { return };
+        segment.complete = true;
+        changes.push(Change::Segment(segment.msn));
+
+        while self.segments.len() > self.settings.window {
+            let Some(old) = self.segments.pop_front() else { break };
+            if old.discontinuity {
+                self.discontinuity_sequence += 1;
+            }
+            changes.push(Change::Removed { msn: old.msn, parts: old.parts.len() });
+        }
+    }
+
+    pub fn init_data(&self, id: u32) -> Option<&[u8]> {
+        self.inits.get(&id).map(Vec::as_slice)
+    }
+
+    pub fn part_data(&self, msn: u64, index: usize) -> Option<&[u8]> {
+        let segment = self.segments.iter().find(|s| s.msn == msn)?;
+        segment.parts.get(index).map(|p| p.data.as_slice())
+    }
+
+    /// A complete segment: its parts back to back.
+    pub fn segment_data(&self, msn: u64) -> Option<Vec<u8>> {
+        let segment = self.segments.iter().find(|s| s.msn == msn && s.complete)?;
+        Some(segment.parts.iter().flat_map(|p| p.data.iter().copied()).collect())
+    }
+
+    /// Whether a playlist would list segment `msn`, complete or up to part
+    /// `part`. Always true once the stream has ended.
+    pub fn has(&self, msn: u64, part: Option<usize>) -> bool {
+        self.ended
+            || self.segments.iter().any(|s| {
+                s.msn > msn || (s.msn == msn && part.map_or(s.complete, |p| s.complete || s.parts.len() > p))
+            })
+    }
+
+    /// Media sequence number of the segment being written.
+    pub fn last_msn(&self) -> Option<u64> {
+        self.segments.back().map(|s| s.msn)
+    }
+
+    pub fn target_duration(&self) -> u64 {
+        self.target_duration
+    }
+
+    /// Seconds; fixed like the target duration, and no part is longer
+    /// unless a single frame is.
+    pub fn part_target(&self) -> f64 {
+        self.settings.part_target.as_secs_f64()
+    }
+
+    /// The media playlist. `can_block` when served by a server that holds
+    /// `_HLS_msn` requests until the segment is there.
+    pub fn render(&self, can_block: bool) -> String {
+        let part_target = self.part_target();
+        let mut out = String::new();
+        let _ = writeln!(out, "#EXTM3U");
+        let _ = writeln!(out, "#EXT-X-VERSION:9");
+        let _ = writeln!(out, "#EXT-X-TARGETDURATION:{}", self.target_duration());
+        let _ = writeln!(out, "#EXT-X-PART-INF:PART-TARGET={:.5}", part_target);
+        let block = if can_block { "CAN-BLOCK-RELOAD=YES," } else { "" };
+        let _ = writeln!(out, "#EXT-X-SERVER-CONTROL:{}PART-HOLD-BACK={:.5}", block, 3.0 * part_target);
+        let _ = writeln!(out, "#EXT-X-MEDIA-SEQUENCE:{}", self.segments.front().map_or(self.next_msn, |s| s.msn));
+        let _ = writeln!(out, "#EXT-X-DISCONTINUITY-SEQUENCE:{}", self.discontinuity_sequence);
+
+        let with_parts = self.segments.len().saturating_sub(SEGMENTS_WITH_PARTS);
+        let mut init = None;
+        for (i, segment) in self.segments.iter().enumerate() {
+            if segment.discontinuity {
+                let _ = writeln!(out, "#EXT-X-DISCONTINUITY");
+            }
+            if init != Some(segment.init) {
+                let _ = writeln!(out, "#EXT-X-MAP:URI=\"{}\"", init_uri(segment.init));
+                init = Some(segment.init);
+            }
+            if i >= with_parts {
+                for (index, part) in segment.parts.iter().enumerate() {
+                    let independent = if part.independent { ",INDEPENDENT=YES" } else { "" };
+                    let _ = writeln!(out, "#EXT-X-PART:DURATION={:.5},URI=\"{}\"{}", part.duration, part_uri(segment.msn, index), independent);
+                }
+            }
+            if segment.complete {
+                let _ = writeln!(out, "#EXTINF:{:.5},", segment.duration());
+                let _ = writeln!(out, "{}", segment_uri(segment.msn));
+            } else if !self.ended {
+                let _ = writeln!(out, "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}\"", part_uri(segment.msn, segment.parts.len()));
+            }
+        }
+        if self.ended {
+            let _ = writeln!(out, "#EXT-X-ENDLIST");
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::mp4::FragmentInfo;
+
+    fn settings() -> HlsSettings {
+        HlsSettings { part_target: Duration::from_millis(100), segment_target: Duration::from_millis(300), window: 2 }
+    }
+
+    fn init() -> Mp4Segment {
+        Mp4Segment::init(b"init".to_vec())
+    }
+
+    /// A 40 ms frame, identifiable by `tag`
+    fn frame(tag: u8, keyframe: bool) -> Mp4Segment {
+        let fragment = FragmentInfo { is_keyframe: keyframe, decode_time: None, composition_time: None, duration: 40, timescale: 1000 };
+        Mp4Segment { kind: SegmentType::Media, data: vec![tag], fragment: Some(fragment) }
+    }
+
+    /// Frames with a keyframe every `gop`
+    fn push_frames(playlist: &mut Playlist, tags: std::ops::Range<u8>, gop: u8) -> Vec<Change> {
+        tags.flat_map(|tag| playlist.push(&frame(tag, tag % gop == 0))).collect()
+    }
+
+    #[test]
+    fn collects_frames_into_parts_and_segments() {
+        let mut playlist = Playlist::new(settings());
+        assert_eq!(playlist.push(&init()), [Change::Init(0)]);
+        // Nothing to start a segment with before the first keyframe
+        assert!(playlist.push(&frame(99, false)).is_empty());
+
+        let changes = push_frames(&mut playlist, 0..11, 5);
+        // Two 40 ms frames per 100 ms part; the keyframe at 200 ms is too early
+        // for a new segment, but still starts a part
+        assert_eq!(changes[..3], [
+            Change::Part { msn: 0, index: 0 },
+            Change::Part { msn: 0, index: 1 },
+            Change::Part { msn: 0, index: 2 },
+        ]);
+        assert_eq!(playlist.part_data(0, 0), Some(&[0, 1][..]));
+        assert_eq!(playlist.part_data(0, 2), Some(&[4][..]));
+        assert_eq!(playlist.part_data(0, 3), Some(&[5, 6][..]));
+        // The keyframe at 400 ms starts segment 1
+        assert!(changes.contains(&Change::Segment(0)));
+        assert_eq!(playlist.segment_data(0), Some((0..10).collect()));
+        assert!(playlist.has(0, None));
+        assert!(!playlist.has(1, Some(0)));
+
+        let text = playlist.render(true);
+        assert!(text.contains("#EXT-X-PART-INF:PART-TARGET=0.10000\n"));
+        assert!(text.contains("#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=0.30000\n"));
+        assert!(text.contains("#EXT-X-PART:DURATION=0.08000,URI=\"seg0.0.m4s\",INDEPENDENT=YES\n"));
+        assert!(text.contains("#EXT-X-PART:DURATION=0.08000,URI=\"seg0.1.m4s\"\n"));
+        assert!(text.contains("#EXTINF:0.40000,\nseg0.m4s\n"));
+        assert!(text.ends_with("#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"seg1.0.m4s\"\n"));
+        assert!(!playlist.render(false).contains("CAN-BLOCK-RELOAD"));
+    }
+
+    #[test]
+    fn slides_the_window_and_ends() {
+        let mut playlist = Playlist::new(settings());
+        playlist.push(&init());
+        let changes = push_frames(&mut playlist, 0..40, 10);
+        assert!(changes.contains(&Change::Removed { msn: 0, parts: 5 }));
+        assert_eq!(playlist.part_data(0, 0), None);
+
+        let changes = playlist.finish();
+        assert_eq!(changes, [Change::Segment(3), Change::Removed { msn: 1, parts: 5 }]);
+        let text = playlist.render(true);
+        assert!(text.contains("#EXT-X-MEDIA-SEQUENCE:2\n"));
+        assert!(text.ends_with("seg3.m4s\n#EXT-X-ENDLIST\n"));
+        assert!(!text.contains("PRELOAD-HINT"));
+        assert!(playlist.has(10, None));
+    }
+
+    #[test]
+    fn marks_a_new_init_as_a_discontinuity() {
+        let mut playlist = Playlist::new(settings());
+        playlist.push(&init());
+        push_frames(&mut playlist, 0..5, 5);
+        assert_eq!(playlist.push(&init()), [Change::Part { msn: 0, index: 2 }, Change::Segment(0), Change::Init(1)]);
+        push_frames(&mut playlist, 0..5, 5);
+
+        let text = playlist.render(true);
+        assert!(text.contains("#EXT-X-MAP:URI=\"init0.mp4\"\n"));
+        assert!(text.contains("seg0.m4s\n#EXT-X-DISCONTINUITY\n#EXT-X-MAP:URI=\"init1.mp4\"\n"));
+        assert_eq!(playlist.init_data(0), Some(&b"init"[..]));
+
+        // Still marked while first in the playlist
+        push_frames(&mut playlist, 0..20, 5);
+        let text = playlist.render(true);
+        assert!(text.contains("#EXT-X-MEDIA-SEQUENCE:1\n#EXT-X-DISCONTINUITY-SEQUENCE:0\n#EXT-X-DISCONTINUITY\n#EXT-X-MAP:URI=\"init1.mp4\"\n"));
+
+        // Once the old segments are gone, so is their init and the discontinuity
+        push_frames(&mut playlist, 0..40, 10);
+        let text = playlist.render(true);
+        assert!(text.contains("#EXT-X-DISCONTINUITY-SEQUENCE:1\n"));
+        assert!(!text.contains("#EXT-X-DISCONTINUITY\n"));
+    }
+
+    #[test]
+    fn cuts_long_groups_of_pictures_at_the_target_duration() {
+        let mut playlist = Playlist::new(settings());
+        playlist.push(&init());
+        // One keyframe in 1.6 s, against a target duration of 1 s
+        let changes = push_frames(&mut playlist, 0..40, 50);
+        assert!(changes.contains(&Change::Segment(0)));
+        assert_eq!(playlist.segment_data(0), Some((0..37).collect()));
+
+        let text = playlist.render(true);
+        assert!(text.contains("#EXT-X-TARGETDURATION:1\n"));
+        assert!(text.contains("#EXTINF:1.48000,\nseg0.m4s\n"));
+        // The rest continues without a keyframe
+        assert!(text.contains("#EXT-X-PART:DURATION=0.08000,URI=\"seg1.0.m4s\"\n"));
+    }
+
+    #[test]
+    fn keeps_the_part_target_when_a_frame_is_longer() {
+        let mut playlist = Playlist::new(settings());
+        playlist.push(&init());
+        push_frames(&mut playlist, 0..3, 5);
+        let mut slow = frame(3, false);
+        slow.fragment.as_mut().unwrap().duration = 150;
+        playlist.push(&slow);
+        push_frames(&mut playlist, 4..6, 5);
+
+        let text = playlist.render(true);
+        assert!(text.contains("#EXT-X-PART-INF:PART-TARGET=0.10000\n"));
+        assert!(text.contains("#EXT-X-PART:DURATION=0.15000,URI=\"seg0.2.m4s\"\n"));
+        // Frames either side still fit the target
+        assert_eq!(playlist.part_data(0, 1), Some(&[2][..]));
+        assert_eq!(playlist.part_data(0, 3), Some(&[4][..]));
+    }
+}
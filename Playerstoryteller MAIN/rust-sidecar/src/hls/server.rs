@@ -0,0 +1,218 @@
+//! The built-in LL-HLS server: the playlist, init segments, segments and
+//! parts from memory, with blocking playlist reload (`_HLS_msn`, `_HLS_part`)
+//! and blocking requests for the part in the preload hint.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout_at, Instant};
+
+use super::playlist::Playlist;
+use super::{HlsOutput, PLAYLIST};
+use crate::http;
+
+const PLAYLIST_TYPE: &str = "application/vnd.apple.mpegurl";
+const MEDIA_TYPE: &str = "video/mp4";
+
+/// What a request path names.
+#[derive(Debug, PartialEq)]
+enum Resource {
+    Playlist,
+    Init(u32),
+    Segment(u64),
+    Part(u64, usize),
+}
+
+impl Resource {
+    fn parse(path: &str) -> Option<Self> {
+        let name = path.strip_prefix('/')?;
+        if name == PLAYLIST {
+            return Some(Resource::Playlist);
+        }
+        if let Some(id) = name.strip_prefix("init").and_then(|n| n.strip_suffix(".mp4")) {
+            return id.parse().ok().map(Resource::Init);
+        }
+        let name = name.strip_prefix("seg")?.strip_suffix(".m4s")?;
+        match name.split_once('.') {
+            Some((msn, part)) => Some(Resource::Part(msn.parse().ok()?, part.parse().ok()?)),
+            None => name.parse().ok().map(Resource::Segment),
+        }
+    }
+}
+
+impl HlsOutput {
+    /// Serve on `listen`. Unlike the preview this may face the LAN, so
+    /// phones and other machines can play.
+    pub async fn run(self: Arc<Self>, listen: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(listen).await?;
+        info!("LL-HLS available at http://{}/{}", listener.local_addr()?, PLAYLIST);
+        self.serve(listener).await
+    }
+
+    async fn serve(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        http::serve(listener, "HLS", move |stream| {
+            let hls = self.clone();
+            async move { hls.handle_connection(stream).await }
+        })
+        .await
+    }
+
+    async fn handle_connection(&self, mut stream: TcpStream) -> io::Result<()> {
+        let Some(head) = http::read_head(&mut stream).await? else { return Ok(()) };
+        if head.method != "GET" {
+            return respond(&mut stream, "405 Method Not Allowed", "text/plain", b"").await;
+        }
+        let param = |name: &str| {
+            head.query.split('&').find_map(|pair| pair.strip_prefix(name)?.strip_prefix('=')?.parse::<u64>().ok())
+        };
+
+        let Some(resource) = Resource::parse(&head.path) else {
+            return respond(&mut stream, "404 Not Found", "text/plain", b"Not Found").await;
+        };
+        match resource {
+            Resource::Playlist => match (param("_HLS_msn"), param("_HLS_part")) {
+                (None, None) => {}
+                (Some(msn), part) => {
+                    let last = self.pipeline.lock().playlist.last_msn().unwrap_or(0);
+                    // A segment this far ahead is not coming soon
+                    if msn > last + 2 {
+                        return respond(&mut stream, "400 Bad Request", "text/plain", b"_HLS_msn is too far ahead").await;
+                    }
+                    if !self.wait_for(|p| p.has(msn, part.map(|p| p as usize))).await {
+                        return respond(&mut stream, "503 Service Unavailable", "text/plain", b"").await;
+                    }
+                }
+                (None, Some(_)) => return respond(&mut stream, "400 Bad Request", "text/plain", b"_HLS_part needs _HLS_msn").await,
+            },
+            Resource::Part(msn, index) => {
+                // The part in the preload hint, or one just after it, is worth waiting for
+                let next = self.pipeline.lock().playlist.last_msn().is_some_and(|last| msn == last || msn == last + 1);
+                if next {
+                    self.wait_for(|p| p.has(msn, Some(index))).await;
+                }
+            }
+            Resource::Init(_) | Resource::Segment(_) => {}
+        }
+
+        let (content_type, body) = {
+            let pipeline = self.pipeline.lock();
+            let playlist = &pipeline.playlist;
+            match resource {
+                Resource::Playlist => (PLAYLIST_TYPE, Some(playlist.render(true).into_bytes())),
+                Resource::Init(id) => (MEDIA_TYPE, playlist.init_data(id).map(<[u8]>::to_vec)),
+                Resource::Segment(msn) => (MEDIA_TYPE, playlist.segment_data(msn)),
+                Resource::Part(msn, index) => (MEDIA_TYPE, playlist.part_data(msn, index).map(<[u8]>::to_vec)),
+            }
+        };
+        match body {
+            Some(body) => respond(&mut stream, "200 OK", content_type, &body).await,
+            None => respond(&mut stream, "404 Not Found", "text/plain", b"Not Found").await,
+        }
+    }
+
+    /// Wait until `ready`, for at most three target durations as the spec
+    /// asks of blocking requests.
+    async fn wait_for(&self, ready: impl Fn(&Playlist) -> bool) -> bool {
+        let target = self.pipeline.lock().playlist.target_duration();
+        let deadline = Instant::now() + Duration::from_secs(3 * target);
+        loop {
+            // Registered before the check, so a change in between still wakes us
+            let updated = self.updated.notified();
+            if ready(&self.pipeline.lock().playlist) {
+                return true;
+            }
+            if timeout_at(deadline, updated).await.is_err() {
+                return false;
+            }
+        }
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    // Players on other origins (OBS browser sources, hls.js pages) need CORS
+    let headers = [("Cache-Control", "no-cache"), ("Access-Control-Allow-Origin", "*")];
+    http::respond(stream, status, content_type, &headers, body).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::hls::HlsSettings;
+    use crate::testing::h264_stream;
+
+    async fn start() -> (Arc<HlsOutput>, SocketAddr) {
+        let hls = Arc::new(HlsOutput::new(HlsSettings::default(), None).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(hls.clone().serve(listener));
+        (hls, addr)
+    }
+
+    /// Status line and body
+    async fn get(addr: SocketAddr, target: &str) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", target).as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let status = String::from_utf8_lossy(&response[..split]).lines().next().unwrap().to_string();
+        (status, response[split + 4..].to_vec())
+    }
+
+    #[test]
+    fn parses_resources() {
+        assert_eq!(Resource::parse("/live.m3u8"), Some(Resource::Playlist));
+        assert_eq!(Resource::parse("/init2.mp4"), Some(Resource::Init(2)));
+        assert_eq!(Resource::parse("/seg41.m4s"), Some(Resource::Segment(41)));
+        assert_eq!(Resource::parse("/seg41.3.m4s"), Some(Resource::Part(41, 3)));
+        assert_eq!(Resource::parse("/seg.m4s"), None);
+        assert_eq!(Resource::parse("/../etc/passwd"), None);
+    }
+
+    #[tokio::test]
+    async fn holds_playlist_and_part_requests_until_they_exist() {
+        let (hls, addr) = start().await;
+        let segments = h264_stream(90);
+        hls.publish(&segments[0]);
+        hls.publish(&segments[1]);
+
+        let playlist = tokio::spawn(get(addr, "/live.m3u8?_HLS_msn=0&_HLS_part=1"));
+        let part = tokio::spawn(get(addr, "/seg0.1.m4s"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!playlist.is_finished() && !part.is_finished());
+
+        for segment in &segments[2..] {
+            hls.publish(segment);
+        }
+        let (status, body) = playlist.await.unwrap();
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let text = String::from_utf8(body).unwrap();
+        assert!(text.contains("CAN-BLOCK-RELOAD=YES"));
+        assert!(text.contains("URI=\"seg0.1.m4s\""));
+
+        let (status, body) = part.await.unwrap();
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(Some(body.as_slice()), hls.pipeline.lock().playlist.part_data(0, 1));
+
+        let (status, body) = get(addr, "/init0.mp4").await;
+        assert_eq!((status.as_str(), &body[4..8]), ("HTTP/1.1 200 OK", &b"ftyp"[..]));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_requests() {
+        let (hls, addr) = start().await;
+        for segment in h264_stream(10) {
+            hls.publish(&segment);
+        }
+        assert_eq!(get(addr, "/live.m3u8?_HLS_msn=5").await.0, "HTTP/1.1 400 Bad Request");
+        assert_eq!(get(addr, "/live.m3u8?_HLS_part=1").await.0, "HTTP/1.1 400 Bad Request");
+        assert_eq!(get(addr, "/seg9.m4s").await.0, "HTTP/1.1 404 Not Found");
+        assert_eq!(get(addr, "/index.html").await.0, "HTTP/1.1 404 Not Found");
+    }
+}
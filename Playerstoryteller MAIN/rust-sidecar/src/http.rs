@@ -0,0 +1,131 @@
+//! Just enough HTTP/1.1 for the built-in servers (the preview and LL-HLS):
+//! one request per connection, answered from memory and then closed.
+
+use std::future::Future;
+use std::io;
+
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Largest request head we are willing to read.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Request line and headers of one request.
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    /// Without the `?`, empty if there is none
+    pub query: String,
+    headers: Vec<(String, String)>,
+    /// The head as received
+    raw: Vec<u8>,
+}
+
+impl RequestHead {
+    fn parse(raw: Vec<u8>) -> Self {
+        let text = String::from_utf8_lossy(&raw).into_owned();
+        let mut lines = text.lines();
+        let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+        let method = request_line.next().unwrap_or_default().to_string();
+        let target = request_line.next().unwrap_or("/");
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Self { method, path: path.to_string(), query: query.to_string(), headers, raw }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// The head as received, for a WebSocket handshake that reads the request itself.
+    pub fn into_raw(self) -> Vec<u8> {
+        self.raw
+    }
+}
+
+/// Accept connections until the listener fails, each handled on its own task.
+pub async fn serve<F, Fut>(listener: TcpListener, name: &'static str, handle: F) -> io::Result<()>
+where
+    F: Fn(TcpStream) -> Fut,
+    Fut: Future<Output = io::Result<()>> + Send + 'static,
+{
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let _ = stream.set_nodelay(true);
+        let connection = handle(stream);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("{} connection error: {}", name, e);
+            }
+        });
+    }
+}
+
+/// Read a request head. An oversized one is answered with 431 and gives `None`.
+pub async fn read_head(stream: &mut TcpStream) -> io::Result<Option<RequestHead>> {
+    // Byte by byte so nothing after the head is swallowed
+    let mut head = Vec::with_capacity(512);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_REQUEST_HEAD {
+            respond(stream, "431 Request Header Fields Too Large", "text/plain", &[], b"").await?;
+            return Ok(None);
+        }
+        head.push(stream.read_u8().await?);
+    }
+    Ok(Some(RequestHead::parse(head)))
+}
+
+/// Answer with `body`, with `headers` after the content type and length, and close.
+pub async fn respond(stream: &mut TcpStream, status: &str, content_type: &str, headers: &[(&str, &str)], body: &[u8]) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n", status, content_type, body.len());
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_request_heads() {
+        let head = RequestHead::parse(b"GET /live.m3u8?_HLS_msn=3 HTTP/1.1\r\nHost: a\r\nSec-WebSocket-Key: k: v\r\n\r\n".to_vec());
+        assert_eq!((head.method.as_str(), head.path.as_str(), head.query.as_str()), ("GET", "/live.m3u8", "_HLS_msn=3"));
+        assert_eq!(head.header("sec-websocket-key"), Some("k: v"));
+        assert_eq!(head.header("origin"), None);
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_heads() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, "Test", |mut stream| async move {
+            if let Some(head) = read_head(&mut stream).await? {
+                respond(&mut stream, "200 OK", "text/plain", &[("Cache-Control", "no-store")], head.path.as_bytes()).await?;
+            }
+            Ok(())
+        }));
+
+        let get = |request: Vec<u8>| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(&request).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = get(b"GET /x HTTP/1.1\r\n\r\n".to_vec()).await;
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n/x");
+        let mut oversized = b"GET / HTTP/1.1\r\nX: ".to_vec();
+        oversized.resize(MAX_REQUEST_HEAD, b'a');
+        let response = get(oversized).await;
+        assert!(response.starts_with("HTTP/1.1 431 "));
+    }
+}
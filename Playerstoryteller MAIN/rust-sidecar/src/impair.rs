@@ -0,0 +1,511 @@
+//! Network impairment for transport testing.
+//!
+//! `ImpairedStream` sits between the TCP socket and TLS/WebSocket and makes a
+//! good connection behave like a bad one:
+//!
+//! - `latency` and `jitter` delay outgoing bytes (order is kept, like TCP)
+//! - `bandwidth` caps the upload rate
+//! - `stall=<every>/<for>` freezes both directions for `for` out of every `every`
+//! - `disconnect=<after>` kills the connection `after` it was opened
+//!
+//! Configured with a spec string such as
+//! `latency=80ms,jitter=40ms,bandwidth=2mbit,stall=10s/1500ms,disconnect=60s`.
+//! Only the upload direction is delayed and shaped, since that is the one the
+//! sidecar's queue reacts to.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use log::info;
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
+use tokio::sync::Notify;
+use tokio::time::{sleep_until, Duration, Instant, Sleep};
+
+/// Bytes allowed "on the wire" before writes start to block, roughly a socket send buffer.
+const PIPE_CAPACITY: usize = 256 * 1024;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ImpairmentError {
+    #[error("Unknown impairment '{0}' (expected latency, jitter, bandwidth, stall or disconnect)")]
+    UnknownKey(String),
+    #[error("Invalid value for {key}: '{value}'")]
+    InvalidValue { key: String, value: String },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Impairment {
+    pub latency: Duration,
+    /// Extra delay per write, uniformly distributed in `0..=jitter`
+    pub jitter: Duration,
+    /// Upload cap in bytes per second
+    pub bandwidth: Option<u64>,
+    /// `(every, for)`: stall both directions for `for` at the end of every `every`
+    pub stall: Option<(Duration, Duration)>,
+    pub disconnect_after: Option<Duration>,
+}
+
+impl Impairment {
+    pub fn is_none(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Wrap `stream`. Without any impairment this is a plain pass-through.
+    pub fn wrap<S>(&self, stream: S) -> ImpairedStream<S>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        if self.is_none() {
+            return ImpairedStream::Direct(stream);
+        }
+        info!("Impairing connection: {}", self);
+
+        let start = Instant::now();
+        let (read, write) = tokio::io::split(stream);
+        let pipe = Arc::new(Pipe::default());
+        tokio::spawn(deliver(write, pipe.clone(), self.clone(), start));
+
+        ImpairedStream::Impaired(Box::new(Impaired {
+            read,
+            pipe,
+            config: self.clone(),
+            start,
+            last_release: start,
+            rng: Rng::new(),
+            timer: Box::pin(sleep_until(start)),
+        }))
+    }
+
+    /// End of the stall window `now` falls into, if any.
+    fn stalled_until(&self, start: Instant, now: Instant) -> Option<Instant> {
+        let (every, length) = self.stall?;
+        if every.is_zero() {
+            return None;
+        }
+        let elapsed = now.duration_since(start);
+        let phase = Duration::from_nanos((elapsed.as_nanos() % every.as_nanos()) as u64);
+        (phase >= every.saturating_sub(length)).then(|| now + (every - phase))
+    }
+
+    fn disconnect_at(&self, start: Instant) -> Option<Instant> {
+        self.disconnect_after.map(|after| start + after)
+    }
+
+    /// When the next stall begins or the disconnect is due, whichever comes first.
+    fn next_event(&self, start: Instant, now: Instant) -> Option<Instant> {
+        let next_stall = self.stall.filter(|(every, _)| !every.is_zero()).map(|(every, length)| {
+            let phase = Duration::from_nanos((now.duration_since(start).as_nanos() % every.as_nanos()) as u64);
+            let stall_start = every - length;
+            if phase < stall_start {
+                now + (stall_start - phase)
+            } else {
+                now + (every - phase) + stall_start
+            }
+        });
+        match (next_stall, self.disconnect_at(start)) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+impl FromStr for Impairment {
+    type Err = ImpairmentError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut impairment = Impairment::default();
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = part.split_once('=').unwrap_or((part, ""));
+            let invalid = || ImpairmentError::InvalidValue { key: key.to_string(), value: value.to_string() };
+            match key {
+                "latency" => impairment.latency = parse_duration(value).ok_or_else(invalid)?,
+                "jitter" => impairment.jitter = parse_duration(value).ok_or_else(invalid)?,
+                "bandwidth" => impairment.bandwidth = Some(parse_rate(value).ok_or_else(invalid)?),
+                "stall" => {
+                    let (every, length) = value.split_once('/').ok_or_else(invalid)?;
+                    let every = parse_duration(every).ok_or_else(invalid)?;
+                    let length = parse_duration(length).ok_or_else(invalid)?;
+                    // A stall as long as the period would never end
+                    if every.is_zero() || length >= every {
+                        return Err(invalid());
+                    }
+                    impairment.stall = Some((every, length));
+                }
+                "disconnect" => impairment.disconnect_after = Some(parse_duration(value).ok_or_else(invalid)?),
+                _ => return Err(ImpairmentError::UnknownKey(key.to_string())),
+            }
+        }
+        Ok(impairment)
+    }
+}
+
+impl fmt::Display for Impairment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "latency={:?} jitter={:?}", self.latency, self.jitter)?;
+        if let Some(bandwidth) = self.bandwidth {
+            write!(f, " bandwidth={}kbit", bandwidth * 8 / 1000)?;
+        }
+        if let Some((every, length)) = self.stall {
+            write!(f, " stall={:?}/{:?}", every, length)?;
+        }
+        if let Some(after) = self.disconnect_after {
+            write!(f, " disconnect={:?}", after)?;
+        }
+        Ok(())
+    }
+}
+
+/// `250ms`, `2s`, `1.5s`
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (number, scale) = if let Some(ms) = value.strip_suffix("ms") {
+        (ms, 0.001)
+    } else {
+        (value.strip_suffix('s')?, 1.0)
+    };
+    let number: f64 = number.parse().ok()?;
+    (number >= 0.0 && number.is_finite()).then(|| Duration::from_secs_f64(number * scale))
+}
+
+/// Bits per second with `kbit`/`mbit`/`gbit`, or bytes with `kB`/`MB`. Returns bytes per second.
+fn parse_rate(value: &str) -> Option<u64> {
+    const UNITS: &[(&str, f64)] =
+        &[("gbit", 1e9 / 8.0), ("mbit", 1e6 / 8.0), ("kbit", 1e3 / 8.0), ("bit", 1.0 / 8.0), ("MB", 1e6), ("kB", 1e3), ("B", 1.0)];
+    let (number, scale) = UNITS.iter().find_map(|(unit, scale)| Some((value.strip_suffix(unit)?, *scale)))?;
+    let rate = number.parse::<f64>().ok()? * scale;
+    (rate >= 1.0 && rate.is_finite()).then_some(rate as u64)
+}
+
+/// Small xorshift generator for jitter. Not for anything security related.
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Self {
+        let mut seed = [0u8; 8];
+        let _ = getrandom::getrandom(&mut seed);
+        Self(u64::from_le_bytes(seed) | 1)
+    }
+
+    fn below(&mut self, max: Duration) -> Duration {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        if max.is_zero() {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos(self.0 % (max.as_nanos() as u64 + 1))
+    }
+}
+
+/// Bytes written but not yet delivered, each with the time it may leave.
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    data_ready: Notify,
+}
+
+#[derive(Default)]
+struct PipeState {
+    chunks: VecDeque<(Instant, Vec<u8>)>,
+    buffered: usize,
+    closing: bool,
+    broken: bool,
+    writer: Option<Waker>,
+}
+
+impl Pipe {
+    fn wake_writer(&self) {
+        if let Some(waker) = self.state.lock().writer.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Moves bytes from the pipe to the socket once they are due.
+async fn deliver<S>(mut write: WriteHalf<S>, pipe: Arc<Pipe>, config: Impairment, start: Instant)
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let disconnect_at = config.disconnect_at(start);
+    let mut link_free_at = start;
+
+    loop {
+        let front = {
+            let state = pipe.state.lock();
+            match state.chunks.front() {
+                Some((release_at, data)) => Some((*release_at, data.len())),
+                None if state.closing => break,
+                None => None,
+            }
+        };
+        let Some((release_at, len)) = front else {
+            match disconnect_at {
+                Some(at) => tokio::select! {
+                    _ = pipe.data_ready.notified() => {}
+                    _ = sleep_until(at) => {}
+                },
+                None => pipe.data_ready.notified().await,
+            }
+            if disconnect_at.is_some_and(|at| Instant::now() >= at) {
+                break;
+            }
+            continue;
+        };
+
+        // Serialization delay on a capped link
+        let mut send_at = release_at;
+        if let Some(bandwidth) = config.bandwidth {
+            send_at = send_at.max(link_free_at);
+            link_free_at = send_at + Duration::from_secs_f64(len as f64 / bandwidth as f64);
+            send_at = link_free_at;
+        }
+        sleep_until(send_at).await;
+        while let Some(until) = config.stalled_until(start, Instant::now()) {
+            sleep_until(until).await;
+        }
+        if disconnect_at.is_some_and(|at| Instant::now() >= at) {
+            break;
+        }
+
+        let Some((_, data)) = pipe.state.lock().chunks.pop_front() else { continue };
+        let result = write.write_all(&data).await;
+        {
+            let mut state = pipe.state.lock();
+            state.buffered -= data.len();
+        }
+        pipe.wake_writer();
+        if result.is_err() {
+            break;
+        }
+    }
+
+    // Whatever ended delivery, nothing more can be written
+    pipe.state.lock().broken = true;
+    pipe.wake_writer();
+    let _ = write.shutdown().await;
+}
+
+pub struct Impaired<S> {
+    read: ReadHalf<S>,
+    pipe: Arc<Pipe>,
+    config: Impairment,
+    start: Instant,
+    last_release: Instant,
+    rng: Rng,
+    /// Ends read stalls and wakes a pending read for the next scheduled event
+    timer: Pin<Box<Sleep>>,
+}
+
+impl<S> Impaired<S> {
+    fn disconnected(&self) -> bool {
+        self.pipe.state.lock().broken || self.config.disconnect_at(self.start).is_some_and(|at| Instant::now() >= at)
+    }
+}
+
+impl<S> Drop for Impaired<S> {
+    fn drop(&mut self) {
+        self.pipe.state.lock().closing = true;
+        self.pipe.data_ready.notify_one();
+    }
+}
+
+/// A stream with optional impairment, see the module docs.
+pub enum ImpairedStream<S> {
+    Direct(S),
+    Impaired(Box<Impaired<S>>),
+}
+
+fn reset() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "impairment: forced disconnect")
+}
+
+impl<S> AsyncRead for ImpairedStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = match self.get_mut() {
+            ImpairedStream::Direct(stream) => return Pin::new(stream).poll_read(cx, buf),
+            ImpairedStream::Impaired(this) => this,
+        };
+
+        loop {
+            if this.disconnected() {
+                return Poll::Ready(Err(reset()));
+            }
+            let now = Instant::now();
+            if let Some(until) = this.config.stalled_until(this.start, now) {
+                this.timer.as_mut().reset(until);
+                if this.timer.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                continue;
+            }
+
+            if let Poll::Ready(result) = Pin::new(&mut this.read).poll_read(cx, buf) {
+                return Poll::Ready(result);
+            }
+            // A silent peer must not hide the next stall or the disconnect
+            if let Some(at) = this.config.next_event(this.start, now) {
+                this.timer.as_mut().reset(at);
+                if this.timer.as_mut().poll(cx).is_ready() {
+                    continue;
+                }
+            }
+            return Poll::Pending;
+        }
+    }
+}
+
+impl<S> AsyncWrite for ImpairedStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = match self.get_mut() {
+            ImpairedStream::Direct(stream) => return Pin::new(stream).poll_write(cx, buf),
+            ImpairedStream::Impaired(this) => this,
+        };
+        if this.disconnected() {
+            return Poll::Ready(Err(reset()));
+        }
+
+        let mut state = this.pipe.state.lock();
+        let space = PIPE_CAPACITY.saturating_sub(state.buffered);
+        if space == 0 {
+            state.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = space.min(buf.len());
+
+        // Later bytes never overtake earlier ones
+        let delay = this.config.latency + this.rng.below(this.config.jitter);
+        let release_at = (Instant::now() + delay).max(this.last_release);
+        this.last_release = release_at;
+
+        state.chunks.push_back((release_at, buf[..n].to_vec()));
+        state.buffered += n;
+        drop(state);
+        this.pipe.data_ready.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    /// Bytes in the pipe count as sent, like bytes handed to the kernel.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ImpairedStream::Direct(stream) => Pin::new(stream).poll_flush(cx),
+            ImpairedStream::Impaired(this) if this.disconnected() => Poll::Ready(Err(reset())),
+            ImpairedStream::Impaired(_) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ImpairedStream::Direct(stream) => Pin::new(stream).poll_shutdown(cx),
+            ImpairedStream::Impaired(this) => {
+                // The delivery task shuts the socket down once the pipe is drained
+                this.pipe.state.lock().closing = true;
+                this.pipe.data_ready.notify_one();
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt};
+
+    fn impairment(spec: &str) -> Impairment {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_spec() {
+        let parsed = impairment("latency=80ms, jitter=0.5s,bandwidth=2mbit,stall=10s/1500ms,disconnect=60s");
+        assert_eq!(parsed.latency, Duration::from_millis(80));
+        assert_eq!(parsed.jitter, Duration::from_millis(500));
+        assert_eq!(parsed.bandwidth, Some(250_000));
+        assert_eq!(parsed.stall, Some((Duration::from_secs(10), Duration::from_millis(1500))));
+        assert_eq!(parsed.disconnect_after, Some(Duration::from_secs(60)));
+        assert!(impairment("").is_none());
+
+        assert_eq!("loss=5".parse::<Impairment>(), Err(ImpairmentError::UnknownKey("loss".to_string())));
+        assert!("latency=fast".parse::<Impairment>().is_err());
+        assert!("bandwidth=100".parse::<Impairment>().is_err());
+        assert!("stall=1s/2s".parse::<Impairment>().is_err());
+        assert!("stall=2s/2s".parse::<Impairment>().is_err());
+        assert!("stall=0s/0s".parse::<Impairment>().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn latency_delays_delivery_in_order() {
+        let (local, mut remote) = duplex(64 * 1024);
+        let mut stream = impairment("latency=100ms,jitter=50ms").wrap(local);
+        let start = Instant::now();
+
+        for i in 0..20u8 {
+            stream.write_all(&[i]).await.unwrap();
+        }
+        let mut received = [0u8; 20];
+        remote.read_exact(&mut received).await.unwrap();
+
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100) && elapsed <= Duration::from_millis(150), "{:?}", elapsed);
+        assert_eq!(received.to_vec(), (0..20).collect::<Vec<u8>>());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bandwidth_caps_upload_rate() {
+        let (local, mut remote) = duplex(64 * 1024);
+        let mut stream = impairment("bandwidth=1kB").wrap(local);
+        let start = Instant::now();
+
+        stream.write_all(&[7u8; 2000]).await.unwrap();
+        let mut received = [0u8; 2000];
+        remote.read_exact(&mut received).await.unwrap();
+
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stall_holds_both_directions() {
+        let (local, mut remote) = duplex(64 * 1024);
+        let mut stream = impairment("stall=1s/500ms").wrap(local);
+        let start = Instant::now();
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        stream.write_all(b"up").await.unwrap();
+        remote.write_all(b"down").await.unwrap();
+
+        let mut up = [0u8; 2];
+        remote.read_exact(&mut up).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        let mut down = [0u8; 4];
+        stream.read_exact(&mut down).await.unwrap();
+        assert_eq!(&down, b"down");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forced_disconnect_breaks_both_sides() {
+        let (local, mut remote) = duplex(64 * 1024);
+        let mut stream = impairment("disconnect=1s").wrap(local);
+        let start = Instant::now();
+
+        // A pending read notices the disconnect even though the peer is silent
+        let mut buf = [0u8; 16];
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        assert!(stream.write_all(b"late").await.is_err());
+        assert_eq!(remote.read(&mut buf).await.unwrap(), 0);
+    }
+}
@@ -1,113 +1,290 @@
+mod auth;
+mod dash;
+mod encoder;
+#[cfg(windows)]
 mod encoder_patched;
+mod h264;
+mod hls;
+mod http;
+mod impair;
 mod websocket;
 mod monitor;
 mod mp4;
+mod net;
+mod outputs;
+mod packager;
+mod policy;
+mod preview;
+mod proxy;
+mod queue;
+mod reconnect;
+mod relay;
+mod replay;
+mod rtmp;
+mod secret;
+mod source;
+mod srt;
+mod status;
+#[cfg(windows)]
 mod stream;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod ts;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use log::{info, error, LevelFilter};
 use simplelog::{CombinedLogger, TermLogger, WriteLogger, Config, TerminalMode, ColorChoice};
 use std::fs::File;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::Duration;
 use tokio::sync::mpsc;
 
-use windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED, COINIT_APARTMENTTHREADED};
+#[cfg(windows)]
+use windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
+#[cfg(windows)]
 use windows::Win32::System::Com::IStream;
 
-use windows_capture::capture::{Context, GraphicsCaptureApiHandler};
-use windows_capture::frame::Frame;
-use windows_capture::graphics_capture_api::InternalCaptureControl;
-use windows_capture::settings::{
-    ColorFormat, CursorCaptureSettings, DirtyRegionSettings, DrawBorderSettings,
-    MinimumUpdateIntervalSettings, SecondaryWindowSettings, Settings,
-};
-use windows_capture::window::Window;
-
-use encoder_patched::{VideoEncoder, VideoSettingsBuilder, AudioSettingsBuilder};
+use auth::AuthMode;
+use dash::{DashOutput, DashSettings};
+use encoder::{Encoder, EncoderKind, EncoderSettings, KeyframeRequest};
+use hls::{HlsOutput, HlsSettings};
+use rtmp::{RtmpOutput, RtmpUrl};
+use srt::{SrtOutput, SrtUrl};
+use ts::{TsFileOutput, UdpOutput, UdpUrl};
+#[cfg(windows)]
+use encoder_patched::{VideoEncoder, VideoEncoderError, VideoSettingsBuilder, AudioSettingsBuilder};
+use impair::Impairment;
+use mp4::{CmafWriter, FragmentTarget, Mp4Segment, Refragmenter};
+use outputs::{OutputMode, Outputs};
+use packager::Packager;
+use policy::TransportPolicy;
+use preview::PreviewServer;
+use proxy::ProxySettings;
+use relay::Relay;
+use replay::FileReplay;
+use source::{FrameSource, SourceError, SourceSpec};
+use status::StatusLevel;
+use tls::{TlsConnector, TlsOptions};
+use secret::{StreamKey, StreamKeySources};
+#[cfg(windows)]
 use stream::WebSocketStream;
 use websocket::WebSocketManager;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about)]
+#[command(author, version, about, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long, default_value = "ws://localhost:3000")]
-    url: String,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    #[arg(short, long)]
-    pid: u32,
+    /// Streaming server URL. Repeat for backup servers or extra destinations,
+    /// see --output-mode.
+    #[arg(short, long = "url", default_value = "ws://localhost:3000")]
+    urls: Vec<String>,
+
+    /// What to do with more than one --url
+    #[arg(long, value_enum, default_value_t = OutputMode::Failover)]
+    output_mode: OutputMode,
+
+    #[arg(short, long, required_unless_present_any = ["source_file", "source"])]
+    pid: Option<u32>,
 
     #[arg(short, long)]
     gpu: Option<u32>,
 
-    #[arg(long, default_value = "")]
-    stream_key: String,
+    /// Deprecated: visible in the process list. Prefer --stream-key-file,
+    /// RATLAB_STREAM_KEY or --stream-key-stdin.
+    #[arg(long, hide = true)]
+    stream_key: Option<StreamKey>,
+
+    /// Read the stream key from this file
+    #[arg(long, conflicts_with = "stream_key_stdin")]
+    stream_key_file: Option<PathBuf>,
+
+    /// Read the stream key from the first line of stdin
+    #[arg(long)]
+    stream_key_stdin: bool,
+
+    /// Allow plain ws:// to public addresses (sends the stream unencrypted)
+    #[arg(long)]
+    allow_insecure_transport: bool,
+
+    /// Proxy for the streaming connection: http://[user:pass@]host:port or
+    /// socks5://[user:pass@]host:port. Defaults to HTTPS_PROXY/HTTP_PROXY.
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Extra PEM root certificate to trust for wss:// (repeatable)
+    #[arg(long = "tls-ca", value_name = "PEM")]
+    tls_ca: Vec<PathBuf>,
+
+    /// PEM client certificate chain for servers that require mutual TLS
+    #[arg(long, value_name = "PEM", requires = "tls_client_key")]
+    tls_client_cert: Option<PathBuf>,
+
+    /// PEM (PKCS#8) private key for --tls-client-cert
+    #[arg(long, value_name = "PEM", requires = "tls_client_cert")]
+    tls_client_key: Option<PathBuf>,
+
+    /// SHA-256 fingerprint of an accepted server certificate (repeatable)
+    #[arg(long = "tls-pin", value_name = "SHA256")]
+    tls_pin: Vec<String>,
+
+    /// DEVELOPMENT ONLY: accept any server certificate
+    #[arg(long)]
+    tls_insecure_dev: bool,
+
+    /// How to authenticate the stream against the server
+    #[arg(long, value_enum, default_value_t = AuthMode::Bearer)]
+    auth: AuthMode,
 
     #[arg(long, default_value = "current-session")]
     session_id: String,
 
+    /// TESTING: simulate a bad network, e.g.
+    /// latency=80ms,jitter=40ms,bandwidth=2mbit,stall=10s/1500ms,disconnect=60s
+    #[arg(long, value_name = "SPEC")]
+    impair: Option<Impairment>,
+
+    /// Serve a local preview player on http://127.0.0.1:<PORT>/
+    #[arg(long, value_name = "PORT")]
+    preview: Option<u16>,
+
     #[arg(long, default_value = "medium")]
     quality: String,
+
+    /// What to encode: capture (the --pid window, Windows only), pattern[:WxH],
+    /// y4m:<file> or png:<dir>
+    #[arg(long, value_name = "SOURCE")]
+    source: Option<SourceSpec>,
+
+    /// Frames per second for --source pattern and png:
+    #[arg(long, default_value_t = 30)]
+    frame_rate: u32,
+
+    /// H.264 encoder. Defaults to mf on Windows, openh264 elsewhere, or
+    /// process with --encoder-command.
+    #[arg(long, value_enum)]
+    encoder: Option<EncoderKind>,
+
+    /// Encoder to run for --encoder process. Reads NV12 frames on stdin and
+    /// writes Annex-B H.264 to stdout. {width}, {height}, {fps}, {bitrate}
+    /// and {keyint} are filled in.
+    #[arg(long, value_name = "COMMAND")]
+    encoder_command: Option<String>,
+
+    /// Re-cut media segments: frame for one frame per fragment (lowest
+    /// latency), or milliseconds to merge frames into. Keyframes always
+    /// start a new fragment.
+    #[arg(long, value_name = "frame|MS")]
+    fragment: Option<FragmentTarget>,
+
+    /// Send conforming CMAF: CMAF brands, one track per fragment and a
+    /// `styp` before each
+    #[arg(long)]
+    cmaf: bool,
+
+    /// Write a Low-Latency HLS playlist (live.m3u8) with its segments and
+    /// parts to this directory
+    #[arg(long, value_name = "DIR")]
+    hls_dir: Option<PathBuf>,
+
+    /// Serve Low-Latency HLS on this address, e.g. 0.0.0.0:8080, with
+    /// blocking playlist reload
+    #[arg(long, value_name = "ADDR")]
+    hls_listen: Option<SocketAddr>,
+
+    /// Longest HLS partial segment, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 333)]
+    hls_part_ms: u64,
+
+    /// Shortest HLS segment, in milliseconds. Segments end at the next keyframe.
+    #[arg(long, value_name = "MS", default_value_t = 2000)]
+    hls_segment_ms: u64,
+
+    /// Write an MPEG-DASH manifest (manifest.mpd) with its segments to this
+    /// directory. It becomes a static recording when the stream ends.
+    #[arg(long, value_name = "DIR")]
+    dash_dir: Option<PathBuf>,
+
+    /// Shortest DASH segment, in milliseconds. Segments end at the next keyframe.
+    #[arg(long, value_name = "MS", default_value_t = 2000)]
+    dash_segment_ms: u64,
+
+    /// Also push the stream to an RTMP ingest, e.g.
+    /// rtmps://live.twitch.tv/app/<stream key> (repeatable). Plain rtmp://
+    /// follows the same rules as ws://, see --allow-insecure-transport.
+    #[arg(long = "rtmp", value_name = "URL")]
+    rtmp_urls: Vec<RtmpUrl>,
+
+    /// Also send the stream as MPEG-TS to an SRT listener, e.g.
+    /// srt://relay.example.com:9000?latency=200&streamid=live (repeatable).
+    /// Takes latency, passphrase, pbkeylen and streamid options.
+    #[arg(long = "srt", value_name = "URL")]
+    srt_urls: Vec<SrtUrl>,
+
+    /// Read the SRT passphrase from this file, for --srt URLs without one
+    #[arg(long, value_name = "PATH")]
+    srt_passphrase_file: Option<PathBuf>,
+
+    /// Write the stream as MPEG-TS to this file, for tools that can't read
+    /// fragmented MP4
+    #[arg(long, value_name = "PATH")]
+    ts_file: Option<PathBuf>,
+
+    /// Also send the stream as MPEG-TS over UDP, e.g. udp://239.0.0.1:1234
+    /// to multicast it on the LAN (repeatable). Takes ttl and localaddr options.
+    #[arg(long = "udp", value_name = "URL")]
+    udp_urls: Vec<UdpUrl>,
+
+    /// TESTING: stream this fragmented MP4 recording in real time instead of
+    /// capturing a window
+    #[arg(long, value_name = "FMP4")]
+    source_file: Option<PathBuf>,
+
+    /// Start --source-file over at the end (implies --rewrite-timeline)
+    #[arg(long = "loop", requires = "source_file")]
+    loop_source: bool,
+
+    /// Rebase --source-file decode times to zero and renumber fragments
+    #[arg(long, requires = "source_file")]
+    rewrite_timeline: bool,
 }
 
-struct StreamApp {
-    encoder: Option<VideoEncoder>,
-    start: Instant,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a local stand-in for the server's stream relay (streamer.js)
+    Relay(RelayArgs),
 }
 
-impl GraphicsCaptureApiHandler for StreamApp {
-    // Flags: Sender, Width, Height, Bitrate
-    type Flags = (mpsc::UnboundedSender<Vec<u8>>, u32, u32, u32);
-    type Error = Box<dyn std::error::Error + Send + Sync>;
-
-    fn new(ctx: Context<Self::Flags>) -> Result<Self, Self::Error> {
-        let (sender, width, height, bitrate) = ctx.flags;
-        let ws_stream = WebSocketStream::new(sender);
-        let stream: IStream = ws_stream.into();
-
-        let encoder = VideoEncoder::new(
-            VideoSettingsBuilder::new(width, height).bitrate(bitrate),
-            AudioSettingsBuilder::default().disabled(true), 
-            &stream,
-        ).map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-
-        Ok(Self {
-            encoder: Some(encoder),
-            start: Instant::now(),
-        })
-    }
+#[derive(clap::Args, Debug)]
+struct RelayArgs {
+    /// Address to accept streamers and viewers on
+    #[arg(long, default_value = "127.0.0.1:3000")]
+    listen: SocketAddr,
 
-    fn on_frame_arrived(
-        &mut self,
-        frame: &mut Frame,
-        _capture_control: InternalCaptureControl,
-    ) -> Result<(), Self::Error> {
-        if let Some(encoder) = self.encoder.as_mut() {
-            // Ignore FrameDropped errors (normal when encoder can't keep up)
-            // But propagate other errors
-            if let Err(e) = encoder.send_frame(frame) {
-                match e {
-                    encoder_patched::VideoEncoderError::FrameDropped => {
-                        // Frame dropped is expected, continue
-                    }
-                    other => return Err(Box::new(other) as Box<dyn std::error::Error + Send + Sync>),
-                }
-            }
-        }
-        Ok(())
-    }
+    /// WebSocket path, as served by the Node server
+    #[arg(long, default_value = "/stream")]
+    path: String,
 
-    fn on_closed(&mut self) -> Result<(), Self::Error> {
-        info!("Capture session ended");
-        if let Some(encoder) = self.encoder.take() {
-            encoder.finish().map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-        }
-        Ok(())
-    }
+    /// Only accept streamers with the key in this file (or RATLAB_STREAM_KEY)
+    #[arg(long)]
+    stream_key_file: Option<PathBuf>,
+
+    /// Skip media for viewers with more than this many bytes unsent
+    #[arg(long, default_value_t = relay::MAX_BUFFERED_AMOUNT)]
+    max_buffered: usize,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Scrubbing the key from the environment is only sound before the runtime starts its threads
+    let env_key = StreamKey::from_env();
+    tokio::runtime::Runtime::new()?.block_on(run(env_key))
+}
+
+async fn run(env_key: Option<StreamKey>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let log_file = File::create("sidecar.log").unwrap_or_else(|_| File::create("sidecar_fallback.log").unwrap());
     CombinedLogger::init(
         vec![
@@ -116,6 +293,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         ]
     ).unwrap();
 
+    #[cfg(windows)]
     unsafe {
         let hr = CoInitializeEx(None, COINIT_MULTITHREADED);
         if hr.is_ok() {
@@ -127,7 +305,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     info!("=== Ratlab Rust Sidecar (Windows Capture + SinkWriter) Started ===");
 
-    let args = match Args::try_parse() {
+    let mut args = match Args::try_parse() {
         Ok(a) => a,
         Err(e) => {
             error!("Argument parsing failed: {}", e);
@@ -136,104 +314,481 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         }
     };
     
-    info!("Arguments parsed. PID: {}, URL: {}", args.pid, args.url);
+    if let Some(Command::Relay(relay_args)) = args.command.take() {
+        return run_relay(relay_args, env_key).await;
+    }
+
+    let pid = args.pid.unwrap_or_default();
+    let urls: Vec<String> = args.urls.iter().map(|u| secret::redact_url(u)).collect();
+    info!("Arguments parsed. PID: {}, URL: {}", pid, urls.join(", "));
+
+    let stream_key = match secret::resolve_stream_key(StreamKeySources {
+        file: args.stream_key_file.as_deref(),
+        stdin: args.stream_key_stdin,
+        env: env_key,
+        argv: args.stream_key.take(),
+    }) {
+        Ok(k) => k,
+        Err(e) => {
+            error!("Failed to read stream key: {}", e);
+            return Ok(());
+        }
+    };
+    if stream_key.is_empty() {
+        info!("No stream key provided. Connecting unauthenticated.");
+    }
+
+    let tls_options = TlsOptions {
+        ca_certs: args.tls_ca.clone(),
+        client_cert: args.tls_client_cert.clone(),
+        client_key: args.tls_client_key.clone(),
+        pins: args.tls_pin.clone(),
+        insecure_dev: args.tls_insecure_dev,
+    };
+    let tls_connector = match TlsConnector::new(&tls_options) {
+        Ok(c) => Arc::new(c),
+        Err(e) => {
+            error!("Invalid TLS configuration: {}", e);
+            status::emit("tls_config", StatusLevel::Error, &e.to_string());
+            return Ok(());
+        }
+    };
+    if args.tls_insecure_dev {
+        status::emit("tls_insecure", StatusLevel::Warning, "TLS certificate checks are disabled (development mode)");
+    }
+
+    let proxy_settings = match ProxySettings::from_env(args.proxy.as_deref()) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Invalid proxy configuration: {}", e);
+            status::emit("proxy_config", StatusLevel::Error, &e.to_string());
+            return Ok(());
+        }
+    };
+
+    let replay = match &args.source_file {
+        Some(path) => match FileReplay::open(path).await {
+            Ok(replay) => {
+                info!(
+                    "Source file {}: {} fragments, {:.1}s",
+                    path.display(),
+                    replay.fragment_count(),
+                    replay.duration().as_secs_f64()
+                );
+                Some(replay.looping(args.loop_source).rewrite_timeline(args.rewrite_timeline))
+            }
+            Err(e) => {
+                error!("Cannot replay {}: {}", path.display(), e);
+                status::emit("source_file", StatusLevel::Error, &e.to_string());
+                return Ok(());
+            }
+        },
+        None => None,
+    };
 
-    tokio::spawn(monitor::monitor_parent(args.pid));
+    tokio::spawn(monitor::monitor_parent(pid));
 
-    let ws_manager = Arc::new(WebSocketManager::new(
-        args.url.clone(),
-        args.stream_key.clone(),
-        args.session_id.clone(),
-    ));
+    let outputs = Arc::new(Outputs::new(&args.urls, args.output_mode, |url| {
+        WebSocketManager::new(url, stream_key.clone(), args.session_id.clone())
+            .auth_mode(args.auth)
+            .transport_policy(TransportPolicy { allow_insecure: args.allow_insecure_transport })
+            .tls_connector(tls_connector.clone())
+            .proxy(proxy_settings.clone())
+            .impairment(args.impair.clone().unwrap_or_default())
+    }));
     
-    let ws_clone = ws_manager.clone();
+    let outputs_run = outputs.clone();
     tokio::spawn(async move {
-        ws_clone.connect_loop().await;
+        match outputs_run.run().await {
+            Ok(()) => {
+                info!("Streaming connections closed.");
+                std::process::exit(0);
+            }
+            Err(e) => {
+                error!("Streaming connection stopped: {}", e);
+                std::process::exit(1);
+            }
+        }
     });
 
-    info!("Waiting for WebSocket connection...");
-    ws_manager.wait_for_connection().await;
-    info!("WebSocket connected. Starting capture...");
-
-    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
-    let ws_send = ws_manager.clone();
+    let outputs_shutdown = outputs.clone();
     tokio::spawn(async move {
-        while let Some(data) = rx.recv().await {
-            let _ = ws_send.send_data(data).await;
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("Ctrl+C received, closing streaming connections...");
+            outputs_shutdown.shutdown();
         }
     });
 
-    let (window, w, h) = if args.pid != 0 {
-        info!("Searching for window with PID: {}", args.pid);
-        let hwnd = unsafe { find_main_window(args.pid) };
-        if hwnd.0 == std::ptr::null_mut() {
-            error!("Game window not found (PID {})", args.pid);
-            return Ok(());
+    // Viewers joining mid-stream ask for a keyframe instead of waiting for the next one
+    let keyframes = KeyframeRequest::default();
+    let preview = args.preview.map(|port| {
+        let preview = Arc::new(PreviewServer::new().keyframe_request(keyframes.clone()));
+        let preview_run = preview.clone();
+        tokio::spawn(async move {
+            if let Err(e) = preview_run.run(port).await {
+                error!("Preview server stopped: {}", e);
+                status::emit("preview", StatusLevel::Warning, &e.to_string());
+            }
+        });
+        preview
+    });
+
+    let hls = if args.hls_dir.is_some() || args.hls_listen.is_some() {
+        let settings = HlsSettings {
+            part_target: Duration::from_millis(args.hls_part_ms),
+            segment_target: Duration::from_millis(args.hls_segment_ms),
+            ..HlsSettings::default()
+        };
+        match HlsOutput::new(settings, args.hls_dir.clone()) {
+            Ok(hls) => Some(Arc::new(hls)),
+            Err(e) => {
+                error!("Cannot write HLS output: {}", e);
+                status::emit("hls", StatusLevel::Error, &e.to_string());
+                return Ok(());
+            }
         }
-        
-        let mut rect = windows::Win32::Foundation::RECT::default();
-        unsafe { windows::Win32::UI::WindowsAndMessaging::GetClientRect(hwnd, &mut rect)? };
-        let w = (rect.right - rect.left) as u32;
-        let h = (rect.bottom - rect.top) as u32;
-        
-        (Window::from_raw_hwnd(hwnd.0), w, h)
     } else {
-        error!("PID required");
-        return Ok(());
+        None
     };
-
-    if !window.is_valid() {
-        error!("Invalid window handle");
-        return Ok(());
+    if let (Some(hls), Some(listen)) = (&hls, args.hls_listen) {
+        let hls_run = hls.clone();
+        tokio::spawn(async move {
+            if let Err(e) = hls_run.run(listen).await {
+                error!("HLS server stopped: {}", e);
+                status::emit("hls", StatusLevel::Warning, &e.to_string());
+            }
+        });
     }
 
-    let bitrate = match args.quality.to_lowercase().as_str() {
-        "low" => 1_000_000,
-        "high" => 4_500_000,
-        _ => 2_500_000, // Medium default
+    let mut dash = match &args.dash_dir {
+        Some(dir) => {
+            let settings = DashSettings { segment_target: Duration::from_millis(args.dash_segment_ms), ..DashSettings::default() };
+            match DashOutput::new(settings, dir.clone()) {
+                Ok(dash) => Some(dash),
+                Err(e) => {
+                    error!("Cannot write DASH output: {}", e);
+                    status::emit("dash", StatusLevel::Error, &e.to_string());
+                    return Ok(());
+                }
+            }
+        }
+        None => None,
+    };
+
+    let rtmp: Vec<Arc<RtmpOutput>> = args
+        .rtmp_urls
+        .iter()
+        .map(|url| {
+            let output = RtmpOutput::new(url.clone())
+                .transport_policy(TransportPolicy { allow_insecure: args.allow_insecure_transport })
+                .tls_connector(tls_connector.clone());
+            Arc::new(output)
+        })
+        .collect();
+    let rtmp_runs: Vec<_> = rtmp
+        .iter()
+        .map(|output| {
+            let output = output.clone();
+            tokio::spawn(async move { output.run().await })
+        })
+        .collect();
+
+    let srt_passphrase = match &args.srt_passphrase_file {
+        Some(path) => match StreamKey::from_file(path).map_err(|e| e.to_string()).and_then(|key| srt::check_passphrase(key.expose()).map(|_| key)) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                error!("Cannot use the SRT passphrase in {}: {}", path.display(), e);
+                return Ok(());
+            }
+        },
+        None => None,
     };
-    info!("Selected Quality: {} (Bitrate: {})", args.quality, bitrate);
-
-    let settings = Settings::new(
-        window,
-        CursorCaptureSettings::Default,
-        DrawBorderSettings::Default,
-        SecondaryWindowSettings::Default,
-        MinimumUpdateIntervalSettings::Default,
-        DirtyRegionSettings::Default,
-        ColorFormat::Bgra8,
-        (tx, w, h, bitrate), // Pass tuple as flags
-    );
-
-    info!("Starting Capture Loop...");
-    StreamApp::start(settings).map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+    let srt: Vec<Arc<SrtOutput>> = args
+        .srt_urls
+        .iter()
+        .map(|url| {
+            let output = SrtOutput::new(url.clone());
+            Arc::new(match &srt_passphrase {
+                Some(passphrase) => output.passphrase(passphrase.clone()),
+                None => output,
+            })
+        })
+        .collect();
+    let srt_runs: Vec<_> = srt
+        .iter()
+        .map(|output| {
+            let output = output.clone();
+            tokio::spawn(async move { output.run().await })
+        })
+        .collect();
+
+    let mut ts_file = match &args.ts_file {
+        Some(path) => match TsFileOutput::new(path) {
+            Ok(output) => Some(output),
+            Err(e) => {
+                error!("Cannot write MPEG-TS to {}: {}", path.display(), e);
+                status::emit("ts", StatusLevel::Error, &e.to_string());
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+
+    let mut udp = Vec::with_capacity(args.udp_urls.len());
+    for url in &args.udp_urls {
+        match UdpOutput::new(url.clone()).await {
+            Ok(output) => udp.push(output),
+            Err(e) => {
+                error!("Cannot send MPEG-TS to {}: {}", url, e);
+                status::emit("udp", StatusLevel::Error, &format!("{}: {}", url, e));
+                return Ok(());
+            }
+        }
+    }
+
+    let local_output = preview.is_some() || hls.is_some() || dash.is_some() || ts_file.is_some();
+    if local_output || !rtmp.is_empty() || !srt.is_empty() || !udp.is_empty() {
+        // These outputs are useful without the server, so don't hold capture back for one
+        info!("Local output enabled. Starting capture without waiting for the server...");
+    } else {
+        info!("Waiting for WebSocket connection...");
+        outputs.wait_for_connection().await;
+        info!("WebSocket connected. Starting capture...");
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<Mp4Segment>();
+    let outputs_send = outputs.clone();
+    let mut refragmenter = args.fragment.map(Refragmenter::new);
+    let mut cmaf = args.cmaf.then(CmafWriter::new);
+    let rtmp_forward = rtmp.clone();
+    let srt_forward = srt.clone();
+    let forward = tokio::spawn(async move {
+        let mut send = |segment: Mp4Segment| {
+            let Some(segment) = (match &mut cmaf {
+                Some(cmaf) => cmaf.push(segment),
+                None => Some(segment),
+            }) else {
+                return;
+            };
+            if let Some(preview) = &preview {
+                preview.publish(&segment);
+            }
+            outputs_send.send(segment);
+        };
+        while let Some(segment) = rx.recv().await {
+            if let Some(hls) = &hls {
+                hls.publish(&segment);
+            }
+            if let Some(dash) = &mut dash {
+                dash.publish(&segment);
+            }
+            for output in &rtmp_forward {
+                output.publish(&segment);
+            }
+            for output in &srt_forward {
+                output.publish(&segment);
+            }
+            if let Some(ts_file) = &mut ts_file {
+                ts_file.publish(&segment);
+            }
+            for output in &mut udp {
+                output.publish(&segment);
+            }
+            match &mut refragmenter {
+                Some(refragmenter) => refragmenter.push(segment).into_iter().for_each(&mut send),
+                None => send(segment),
+            }
+        }
+        if let Some(segment) = refragmenter.as_mut().and_then(Refragmenter::flush) {
+            send(segment);
+        }
+        if let Some(hls) = &hls {
+            hls.finish();
+        }
+        if let Some(dash) = &mut dash {
+            dash.finish();
+        }
+        if let Some(ts_file) = &mut ts_file {
+            ts_file.finish();
+        }
+    });
+
+    if let Some(replay) = replay {
+        info!("Replaying source file...");
+        replay.run(&tx).await;
+        drop(tx);
+    } else {
+        let spec = args.source.clone().unwrap_or(SourceSpec::Capture);
+        let source = match open_source(&spec, pid, args.frame_rate) {
+            Ok(source) => source,
+            Err(e) => {
+                error!("Cannot open video source: {}", e);
+                status::emit("source", StatusLevel::Error, &e.to_string());
+                return Ok(());
+            }
+        };
+        info!("Video source: {:?} ({}x{})", spec, source.width(), source.height());
+
+        let bitrate = match args.quality.to_lowercase().as_str() {
+            "low" => 1_000_000,
+            "high" => 4_500_000,
+            _ => 2_500_000, // Medium default
+        };
+        info!("Selected Quality: {} (Bitrate: {})", args.quality, bitrate);
+
+        let implied = args.encoder_command.as_ref().map(|_| EncoderKind::Process);
+        let Some(encoder_kind) = args.encoder.or(implied).or_else(EncoderKind::platform_default) else {
+            error!("No H.264 encoder was compiled in. Rebuild with --features openh264.");
+            return Ok(());
+        };
+        // Capture has no nominal rate; 60 matches the Media Foundation default
+        let frame_rate = source.frame_rate().unwrap_or(60);
+        let mut settings = EncoderSettings::new(source.width(), source.height(), bitrate, frame_rate);
+        settings.command = args.encoder_command.clone();
+        match encoder_kind.create(settings.clone()) {
+            Ok(None) => {
+                info!("Starting Capture Loop...");
+                tokio::task::spawn_blocking(move || encode_media_foundation(source, tx, bitrate)).await??;
+            }
+            Ok(Some(encoder)) => {
+                info!("Starting Capture Loop...");
+                if let Err(e) = tokio::task::spawn_blocking(move || encode_access_units(source, encoder, settings, keyframes, tx)).await? {
+                    error!("Encoding stopped: {}", e);
+                    status::emit("encoder", StatusLevel::Error, &e.to_string());
+                }
+            }
+            Err(e) => {
+                error!("Cannot start the {:?} encoder: {}", encoder_kind, e);
+                status::emit("encoder", StatusLevel::Error, &e.to_string());
+                return Ok(());
+            }
+        }
+    }
+
+    // Let the last segments reach the outputs, then close cleanly
+    let _ = forward.await;
+    for output in &rtmp {
+        output.finish();
+    }
+    for output in &srt {
+        output.finish();
+    }
+    // RTMP ingests and SRT listeners are told the stream ended, but not waited on forever
+    let runs = futures_util::future::join(futures_util::future::join_all(rtmp_runs), futures_util::future::join_all(srt_runs));
+    let _ = tokio::time::timeout(Duration::from_secs(5), runs).await;
+    info!("Source finished, closing streaming connections...");
+    outputs.shutdown();
+    // The outputs task exits the process once the connections have closed
+    std::future::pending::<()>().await;
+    Ok(())
+}
+
+/// The portable sources, or the game window of `pid`.
+fn open_source(spec: &SourceSpec, pid: u32, frame_rate: u32) -> Result<Box<dyn FrameSource>, SourceError> {
+    if let Some(source) = spec.open(frame_rate)? {
+        return Ok(source);
+    }
+    #[cfg(windows)]
+    {
+        match pid {
+            0 => Err(SourceError::Capture("PID required".into())),
+            pid => Ok(Box::new(source::capture::WindowCapture::start(pid)?)),
+        }
+    }
+    #[cfg(not(windows))]
+    {
+        let _ = pid;
+        Err(SourceError::CaptureUnsupported)
+    }
+}
+
+/// Encode frames with the SinkWriter until the source ends. Runs on a blocking thread.
+#[cfg(windows)]
+fn encode_media_foundation(
+    mut source: Box<dyn FrameSource>,
+    sender: mpsc::UnboundedSender<Mp4Segment>,
+    bitrate: u32,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let ws_stream = WebSocketStream::new(sender);
+    let stream: IStream = ws_stream.into();
+
+    let mut video_settings = VideoSettingsBuilder::new(source.width(), source.height()).bitrate(bitrate);
+    if let Some(frame_rate) = source.frame_rate() {
+        video_settings = video_settings.frame_rate(frame_rate);
+    }
+    let mut encoder = VideoEncoder::new(video_settings, AudioSettingsBuilder::default().disabled(true), &stream)?;
 
+    while let Some(frame) = source.next_frame()? {
+        // Frame dropped is expected when the encoder can't keep up
+        match encoder.send_frame(&frame) {
+            Ok(()) | Err(VideoEncoderError::FrameDropped) => {}
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+    encoder.finish()?;
     Ok(())
 }
 
-use windows::Win32::Foundation::{HWND, LPARAM};
-use windows::core::BOOL;
-use windows::Win32::UI::WindowsAndMessaging::{EnumWindows, GetWindowThreadProcessId, IsWindowVisible, GetWindowTextLengthW};
-
-unsafe fn find_main_window(pid: u32) -> HWND {
-    let _found_hwnd = HWND(std::ptr::null_mut());
-    unsafe extern "system" fn enum_window_callback(hwnd: HWND, lparam: LPARAM) -> BOOL {
-        let context = &mut *(lparam.0 as *mut FindWindowContext);
-        let mut window_pid = 0;
-        GetWindowThreadProcessId(hwnd, Some(&mut window_pid));
-        if window_pid == context.target_pid {
-            if IsWindowVisible(hwnd).as_bool() {
-                let len = GetWindowTextLengthW(hwnd);
-                if len > 0 {
-                    context.found_hwnd = hwnd;
-                    return BOOL(0);
+/// Encode frames with an `Encoder` backend and package them as fMP4 until
+/// the source ends. Runs on a blocking thread.
+fn encode_access_units(
+    mut source: Box<dyn FrameSource>,
+    mut encoder: Box<dyn Encoder>,
+    settings: EncoderSettings,
+    keyframes: KeyframeRequest,
+    sender: mpsc::UnboundedSender<Mp4Segment>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut packager = Packager::new(settings.frame_rate);
+    while let Some(frame) = source.next_frame()? {
+        if keyframes.take() {
+            encoder.request_keyframe();
+        }
+        for unit in encoder.encode(&frame)? {
+            for segment in packager.push(unit) {
+                if sender.send(segment).is_err() {
+                    // Nobody is forwarding segments any more
+                    return Ok(());
                 }
             }
         }
-        BOOL(1)
     }
-    struct FindWindowContext { target_pid: u32, found_hwnd: HWND }
-    let mut context = FindWindowContext { target_pid: pid, found_hwnd: HWND(std::ptr::null_mut()) };
-    let _ = EnumWindows(Some(enum_window_callback), LPARAM(&mut context as *mut _ as isize));
-    context.found_hwnd
-}
\ No newline at end of file
+    for unit in encoder.finish()? {
+        packager.push(unit).into_iter().for_each(|segment| drop(sender.send(segment)));
+    }
+    packager.finish().into_iter().for_each(|segment| drop(sender.send(segment)));
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn encode_media_foundation(
+    _source: Box<dyn FrameSource>,
+    _sender: mpsc::UnboundedSender<Mp4Segment>,
+    _bitrate: u32,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Err("Media Foundation is only available on Windows".into())
+}
+
+async fn run_relay(args: RelayArgs, env_key: Option<StreamKey>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let stream_key = match secret::resolve_stream_key(StreamKeySources {
+        file: args.stream_key_file.as_deref(),
+        stdin: false,
+        env: env_key,
+        argv: None,
+    }) {
+        Ok(k) => k,
+        Err(e) => {
+            error!("Failed to read stream key: {}", e);
+            return Ok(());
+        }
+    };
+
+    let relay = Relay::new(args.listen)
+        .path(args.path)
+        .stream_key(stream_key)
+        .max_buffered(args.max_buffered);
+    if let Err(e) = relay.run().await {
+        error!("Relay stopped: {}", e);
+        status::emit("relay", StatusLevel::Error, &e.to_string());
+    }
+    Ok(())
+}
@@ -1,8 +1,11 @@
 use log::{info, warn};
 use std::process;
+#[cfg(windows)]
 use windows::Win32::Foundation::{CloseHandle, WAIT_OBJECT_0};
+#[cfg(windows)]
 use windows::Win32::System::Threading::{OpenProcess, WaitForSingleObject, PROCESS_SYNCHRONIZE, INFINITE};
 
+#[cfg(windows)]
 pub async fn monitor_parent(pid_u32: u32) {
     if pid_u32 == 0 {
         info!("No parent PID provided. Monitoring disabled.");
@@ -34,3 +37,30 @@ pub async fn monitor_parent(pid_u32: u32) {
         }
     });
 }
+
+/// Without a process handle to wait on, poll `/proc` for the parent.
+#[cfg(not(windows))]
+pub async fn monitor_parent(pid_u32: u32) {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    if pid_u32 == 0 {
+        info!("No parent PID provided. Monitoring disabled.");
+        return;
+    }
+    let proc_entry = PathBuf::from(format!("/proc/{}", pid_u32));
+    if !std::path::Path::new("/proc/self").exists() {
+        info!("No /proc on this platform. Parent monitoring disabled.");
+        return;
+    }
+
+    info!("Monitoring parent process PID: {} (polling /proc)", pid_u32);
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        if !proc_entry.exists() {
+            warn!("Parent process {} exited. Shutting down.", pid_u32);
+            process::exit(0);
+        }
+    }
+}
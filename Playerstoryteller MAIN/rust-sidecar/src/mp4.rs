@@ -1,16 +1,85 @@
-use std::io::{Cursor, Write, Read};
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+mod cmaf;
+mod mux;
+mod refragment;
+
+use std::collections::HashMap;
 use log::{debug, error};
 
-#[derive(Debug, PartialEq)]
+use crate::h264::AvcConfig;
+
+pub use self::cmaf::CmafWriter;
+pub use self::mux::{Fmp4Muxer, Sample, VideoTrack};
+pub use self::refragment::{FragmentTarget, Refragmenter};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SegmentType {
     Init,
     Media,
 }
 
+impl SegmentType {
+    /// Classify a complete segment by its first box, the way the server does:
+    /// `ftyp` starts an init segment, `moof` (or a CMAF `styp`) a media segment.
+    pub fn identify(data: &[u8]) -> Option<Self> {
+        match data.get(4..8)? {
+            b"ftyp" => Some(SegmentType::Init),
+            b"moof" | b"styp" => Some(SegmentType::Media),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Mp4Segment {
     pub kind: SegmentType,
     pub data: Vec<u8>,
+    /// Keyframe and timing of a media segment with a `moof`
+    pub fragment: Option<FragmentInfo>,
+}
+
+impl Mp4Segment {
+    pub fn init(data: Vec<u8>) -> Self {
+        Self { kind: SegmentType::Init, data, fragment: None }
+    }
+
+    /// A media segment, with its fragment info read from the `moof` using
+    /// the track defaults of the init segment.
+    pub fn media(data: Vec<u8>, tracks: &HashMap<u32, TrackInfo>) -> Self {
+        let fragment = fragment_timing(&data, tracks).first().map(|timing| FragmentInfo::new(timing, tracks));
+        Self { kind: SegmentType::Media, data, fragment }
+    }
+
+    /// A media segment that starts with a sync sample, where a decoder can join.
+    pub fn is_keyframe(&self) -> bool {
+        self.fragment.is_some_and(|f| f.is_keyframe)
+    }
+}
+
+/// What a media segment starts with and how long it lasts, for the first
+/// track of its `moof`. Times are in track ticks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FragmentInfo {
+    /// The first sample is a sync sample
+    pub is_keyframe: bool,
+    /// Decode time of the first sample, if the fragment has a `tfdt`
+    pub decode_time: Option<u64>,
+    /// Composition (presentation) time of the first sample
+    pub composition_time: Option<u64>,
+    pub duration: u64,
+    /// Ticks per second, 0 when the init segment is unknown
+    pub timescale: u32,
+}
+
+impl FragmentInfo {
+    fn new(timing: &TrackFragmentTiming, tracks: &HashMap<u32, TrackInfo>) -> Self {
+        Self {
+            is_keyframe: timing.is_keyframe,
+            decode_time: timing.decode_time,
+            composition_time: timing.decode_time.map(|t| t.saturating_add_signed(timing.composition_offset)),
+            duration: timing.duration,
+            timescale: tracks.get(&timing.track_id).map_or(0, |t| t.timescale),
+        }
+    }
 }
 
 pub struct Mp4Parser {
@@ -19,6 +88,8 @@ pub struct Mp4Parser {
     init_segment: Vec<u8>,
     pending_moof: Vec<u8>,
     cumulative_decode_time: u64, // Tracks baseMediaDecodeTime for tfdt
+    /// Track defaults of the init segment, for reading fragment sample flags
+    tracks: HashMap<u32, TrackInfo>,
 }
 
 impl Mp4Parser {
@@ -29,240 +100,100 @@ impl Mp4Parser {
             init_segment: Vec::new(),
             pending_moof: Vec::new(),
             cumulative_decode_time: 0,
+            tracks: HashMap::new(),
         }
     }
 
-    /// Find avc1 box and extract video dimensions (width, height)
+    /// Video dimensions (width, height) in the `avc1` sample entry
     fn find_avc1_dimensions(data: &[u8]) -> Option<(u16, u16)> {
-        // Search for "avc1" pattern
-        for i in 0..data.len().saturating_sub(40) {
-            if &data[i..i+4] == b"avc1" {
-                // avc1 sample entry structure:
-                // +0-3: size (already passed)
-                // +4-7: "avc1"
-                // +8-13: reserved (6 bytes)
-                // +14-15: data_reference_index (2 bytes)
-                // +16-31: pre_defined/reserved (16 bytes)
-                // +32-33: width (2 bytes)
-                // +34-35: height (2 bytes)
-                let width_offset = i + 28; // +4 (type already at i) + 24 = 28 from 'a' of avc1
-                let height_offset = i + 30;
-
-                if height_offset + 2 <= data.len() {
-                    let width = u16::from_be_bytes([data[width_offset], data[width_offset + 1]]);
-                    let height = u16::from_be_bytes([data[height_offset], data[height_offset + 1]]);
-                    if width > 0 && height > 0 {
-                        debug!("Found avc1 dimensions: {}x{}", width, height);
-                        return Some((width, height));
-                    }
-                }
-            }
+        let (_, avc1) = avc1_entry(data)?;
+        // avc1 sample entry: reserved (6), data_reference_index (2),
+        // pre_defined/reserved (16), then width and height (2 each)
+        let width = read_u16(data, avc1.body() + 24)?;
+        let height = read_u16(data, avc1.body() + 26)?;
+        if width == 0 || height == 0 {
+            return None;
         }
-        None
+        debug!("Found avc1 dimensions: {}x{}", width, height);
+        Some((width, height))
     }
 
-    /// Patch tkhd box to set correct track dimensions
+    /// Patch the tkhd of the video track to set correct track dimensions
     fn patch_tkhd(data: &mut [u8], width: u16, height: u16) -> bool {
-        // Search for "tkhd" pattern
-        for i in 0..data.len().saturating_sub(92) {
-            if &data[i..i+4] == b"tkhd" {
-                let version = data[i + 4];
-
-                // Calculate offset for width/height based on version
-                // Version 0: width at +80, height at +84 from 'tkhd' start
-                // Version 1: width at +92, height at +96 from 'tkhd' start
-                let (width_offset, height_offset) = if version == 0 {
-                    (i + 80, i + 84)  // Relative to 'tkhd' type field
-                } else {
-                    (i + 92, i + 96)
-                };
-
-                if height_offset + 4 <= data.len() {
-                    // Width and height are stored as 16.16 fixed-point
-                    let width_fixed = (width as u32) << 16;
-                    let height_fixed = (height as u32) << 16;
+        let Some(tkhd) = avc1_entry(data).and_then(|(trak, _)| find_child(data, &trak, b"tkhd")) else {
+            return false;
+        };
+        let Some((version, _)) = full_box_header(data, &tkhd) else { return false };
+        // Width and height come after the times, ids, duration, layer, volume and
+        // matrix: 76 bytes into a version 0 body, 88 into a version 1 one
+        let offset = tkhd.body() + if version == 1 { 88 } else { 76 };
+        if offset + 8 > tkhd.end {
+            return false;
+        }
 
-                    data[width_offset..width_offset+4].copy_from_slice(&width_fixed.to_be_bytes());
-                    data[height_offset..height_offset+4].copy_from_slice(&height_fixed.to_be_bytes());
+        // Width and height are stored as 16.16 fixed-point
+        let width_fixed = (width as u32) << 16;
+        let height_fixed = (height as u32) << 16;
+        data[offset..offset + 4].copy_from_slice(&width_fixed.to_be_bytes());
+        data[offset + 4..offset + 8].copy_from_slice(&height_fixed.to_be_bytes());
 
-                    debug!("Patched tkhd dimensions to {}x{} (fixed: 0x{:08X}, 0x{:08X})",
-                           width, height, width_fixed, height_fixed);
-                    return true;
-                }
-            }
-        }
-        false
+        debug!("Patched tkhd dimensions to {}x{} (fixed: 0x{:08X}, 0x{:08X})",
+               width, height, width_fixed, height_fixed);
+        true
     }
 
     /// Patch moof for MSE streaming compatibility.
     /// Windows SinkWriter uses absolute file offsets in tfhd/trun which breaks MSE.
     /// Also injects tfdt if missing (required by Chrome MSE).
     fn patch_moof(&mut self, mut data: Vec<u8>) -> Vec<u8> {
-        if data.len() < 8 { return data; }
-
-        let moof_size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
-        if moof_size > data.len() { return data; }
-
-        // Find tfhd, tfdt, trun, and traf within moof
-        let mut i = 8; // Skip moof header
-        let mut tfhd_offset = None;
-        let mut tfdt_offset = None;
-        let mut trun_offset = None;
-        let mut traf_offset = None;
-
-        while i + 8 <= moof_size {
-            let box_size = u32::from_be_bytes([data[i], data[i+1], data[i+2], data[i+3]]) as usize;
-            if box_size < 8 || i + box_size > moof_size { break; }
-
-            let box_type = &data[i+4..i+8];
-
-            match box_type {
-                b"mfhd" => {
-                    // Movie fragment header - skip
-                }
-                b"traf" => {
-                    traf_offset = Some(i);
-
-                    // Parse traf children
-                    let mut j = i + 8;
-                    let traf_end = i + box_size;
-
-                    while j + 8 <= traf_end {
-                        let child_size = u32::from_be_bytes([data[j], data[j+1], data[j+2], data[j+3]]) as usize;
-                        if child_size < 8 || j + child_size > traf_end { break; }
-
-                        let child_type = &data[j+4..j+8];
-                        match child_type {
-                            b"tfhd" => tfhd_offset = Some(j),
-                            b"tfdt" => tfdt_offset = Some(j),
-                            b"trun" => trun_offset = Some(j),
-                            _ => {}
-                        }
-                        j += child_size;
-                    }
-                }
-                _ => {}
-            }
-            i += box_size;
-        }
+        // The moof and its first traf, found again after every edit
+        let first_traf = |data: &[u8]| {
+            let moof = child_boxes(data, 0, data.len()).into_iter().next().filter(|b| &b.kind == b"moof")?;
+            Some((moof, find_child(data, &moof, b"traf")?))
+        };
 
         // Patch tfhd: remove base-data-offset and set default-base-is-moof flag
         // This is required for MSE streaming where each segment is self-contained
-        let mut size_reduction = 0i32;
-        if let Some(off) = tfhd_offset {
-            if off + 16 <= data.len() {
-                let flags = u32::from_be_bytes([0, data[off+9], data[off+10], data[off+11]]);
-
-                if flags & 0x000001 != 0 {
-                    // base-data-offset-present is set - we need to remove it
-                    // and set default-base-is-moof (0x020000) instead
-
-                    // New flags: remove 0x000001, add 0x020000
+        if let Some((moof, traf)) = first_traf(&data) {
+            if let Some(tfhd) = find_child(&data, &traf, b"tfhd") {
+                let flags = full_box_header(&data, &tfhd).map_or(0, |(_, flags)| flags);
+                if flags & 0x000001 != 0 && tfhd.body() + 16 <= tfhd.end {
                     let new_flags = (flags & !0x000001) | 0x020000;
-                    data[off+9] = ((new_flags >> 16) & 0xFF) as u8;
-                    data[off+10] = ((new_flags >> 8) & 0xFF) as u8;
-                    data[off+11] = (new_flags & 0xFF) as u8;
-
-                    // Remove the 8-byte base_data_offset field at offset +16
-                    let remove_start = off + 16;
-                    let remove_end = off + 24;
-                    if remove_end <= data.len() {
-                        data.drain(remove_start..remove_end);
-                        size_reduction = 8;
-
-                        // Update tfhd size (subtract 8)
-                        let old_tfhd_size = u32::from_be_bytes([data[off], data[off+1], data[off+2], data[off+3]]);
-                        let new_tfhd_size = old_tfhd_size - 8;
-                        data[off..off+4].copy_from_slice(&new_tfhd_size.to_be_bytes());
-
-                        debug!("Patched tfhd: removed base_data_offset, set default-base-is-moof flag");
-                    }
+                    data[tfhd.body() + 1..tfhd.body() + 4].copy_from_slice(&new_flags.to_be_bytes()[1..]);
+                    // The 8-byte base_data_offset follows the track_ID
+                    data.drain(tfhd.body() + 8..tfhd.body() + 16);
+                    resize_boxes(&mut data, &[tfhd, traf, moof], -8);
+                    debug!("Patched tfhd: removed base_data_offset, set default-base-is-moof flag");
                 }
             }
         }
 
-        // Update traf size if we removed bytes
-        if size_reduction > 0 {
-            if let Some(off) = traf_offset {
-                let old_size = u32::from_be_bytes([data[off], data[off+1], data[off+2], data[off+3]]);
-                let new_size = (old_size as i32 - size_reduction) as u32;
-                data[off..off+4].copy_from_slice(&new_size.to_be_bytes());
-            }
-
-            // Update moof size
-            let old_moof_size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
-            let new_moof_size = (old_moof_size as i32 - size_reduction) as u32;
-            data[0..4].copy_from_slice(&new_moof_size.to_be_bytes());
-
-            // Adjust trun_offset since we removed bytes before it
-            if let Some(old_off) = trun_offset {
-                trun_offset = Some((old_off as i32 - size_reduction) as usize);
-            }
-        }
-
-        // If tfdt is missing, we need to inject it (required by Chrome MSE)
-        // tfdt v0: 16 bytes (size=4, type=4, version+flags=4, baseMediaDecodeTime=4)
-        let tfdt_box_size = 16u32;
-        let needs_tfdt = tfdt_offset.is_none();
-
-        if needs_tfdt {
-            if let (Some(tfhd_off), Some(traf_off)) = (tfhd_offset, traf_offset) {
-                // Read CURRENT tfhd size from data (after any drain modifications)
-                let current_tfhd_size = u32::from_be_bytes([data[tfhd_off], data[tfhd_off+1], data[tfhd_off+2], data[tfhd_off+3]]) as usize;
-                let insert_point = tfhd_off + current_tfhd_size;
-
-                // Create tfdt box: version 0, baseMediaDecodeTime = cumulative time so far
-                let mut tfdt_box = Vec::with_capacity(16);
-                tfdt_box.extend_from_slice(&tfdt_box_size.to_be_bytes()); // size
-                tfdt_box.extend_from_slice(b"tfdt"); // type
-                tfdt_box.extend_from_slice(&0u32.to_be_bytes()); // version 0 + flags 0
-                tfdt_box.extend_from_slice(&(self.cumulative_decode_time as u32).to_be_bytes()); // baseMediaDecodeTime
-
-                // Insert tfdt into data
-                data.splice(insert_point..insert_point, tfdt_box.iter().cloned());
-
-                // Update traf size: read CURRENT size from data and add 16
-                let current_traf_size = u32::from_be_bytes([data[traf_off], data[traf_off+1], data[traf_off+2], data[traf_off+3]]);
-                let new_traf_size = current_traf_size + tfdt_box_size;
-                data[traf_off..traf_off+4].copy_from_slice(&new_traf_size.to_be_bytes());
-
-                // Update moof size: read CURRENT size from data and add 16
-                let current_moof_size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
-                let new_moof_size = current_moof_size + tfdt_box_size;
-                data[0..4].copy_from_slice(&new_moof_size.to_be_bytes());
-
-                debug!("Injected tfdt box (16 bytes) at offset {} (tfhd_size={})", insert_point, current_tfhd_size);
-
-                // Recalculate trun_offset since we inserted bytes
-                if let Some(old_trun_off) = trun_offset {
-                    trun_offset = Some(old_trun_off + tfdt_box_size as usize);
-                }
+        // If tfdt is missing, we need to inject it after tfhd (required by Chrome MSE)
+        if let Some((moof, traf)) = first_traf(&data) {
+            if let (None, Some(tfhd)) = (find_child(&data, &traf, b"tfdt"), find_child(&data, &traf, b"tfhd")) {
+                // Version 0, baseMediaDecodeTime = cumulative time so far
+                let mut tfdt = Vec::with_capacity(16);
+                write_full_box(&mut tfdt, b"tfdt", 0, 0, |out| out.extend_from_slice(&(self.cumulative_decode_time as u32).to_be_bytes()));
+                let tfdt_size = tfdt.len() as isize;
+                data.splice(tfhd.end..tfhd.end, tfdt);
+                resize_boxes(&mut data, &[traf, moof], tfdt_size);
+                debug!("Injected tfdt box ({} bytes) at offset {}", tfdt_size, tfhd.end);
             }
         }
 
         // Patch trun: set data_offset to point to start of mdat payload
         // Must be done AFTER tfdt injection since moof size changed
-        if let Some(off) = trun_offset {
-            if off + 16 <= data.len() {
-                let flags = u32::from_be_bytes([0, data[off+9], data[off+10], data[off+11]]);
-
-                if flags & 0x000001 != 0 {
-                    // data-offset-present - update it
-                    if off + 20 <= data.len() {
-                        // data_offset = new_moof_size + 8 (mdat header)
-                        let current_moof_size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
-                        let data_offset = current_moof_size + 8;
-                        data[off+16..off+20].copy_from_slice(&data_offset.to_be_bytes());
-                        debug!("Patched trun: set data_offset to {}", data_offset);
-                    }
-                }
+        if let Some((moof, trun)) = first_traf(&data).and_then(|(moof, traf)| Some((moof, find_child(&data, &traf, b"trun")?))) {
+            let data_offset_present = full_box_header(&data, &trun).is_some_and(|(_, flags)| flags & 0x000001 != 0);
+            if data_offset_present && trun.body() + 12 <= trun.end {
+                // data_offset = moof size + 8 (mdat header)
+                let data_offset = (moof.end - moof.start) as u32 + 8;
+                data[trun.body() + 8..trun.body() + 12].copy_from_slice(&data_offset.to_be_bytes());
+                debug!("Patched trun: set data_offset to {}", data_offset);
             }
-        }
-        // Extract sample_count from trun and advance cumulative_decode_time
-        // At 60fps with timescale 60000, each sample is 1000 ticks
-        if let Some(off) = trun_offset {
-            if off + 16 <= data.len() {
-                let sample_count = u32::from_be_bytes([data[off+12], data[off+13], data[off+14], data[off+15]]);
+            // Extract sample_count from trun and advance cumulative_decode_time
+            // At 60fps with timescale 60000, each sample is 1000 ticks
+            if let Some(sample_count) = read_u32(&data, trun.body() + 4) {
                 self.cumulative_decode_time += (sample_count as u64) * 1000; // 1000 ticks per frame at 60fps/60000 timescale
             }
         }
@@ -271,65 +202,34 @@ impl Mp4Parser {
     }
 
     fn patch_moov(data: Vec<u8>) -> Vec<u8> {
-        if data.len() < 8 { return data; }
-
-        let mut cursor = Cursor::new(&data);
-        let total_size = match cursor.read_u32::<BigEndian>() {
-            Ok(s) => s as usize,
-            Err(_) => return data,
+        let Some(moov) = child_boxes(&data, 0, data.len()).into_iter().next().filter(|b| &b.kind == b"moov" && b.end == data.len()) else {
+            return data;
         };
 
-        if total_size != data.len() { return data; }
-
-        cursor.set_position(8); // Skip type (moov)
-
-        let mut new_payload = Vec::new();
-        let mut found_iods = false;
-
-        loop {
-            let start_pos = cursor.position() as usize;
-            if start_pos >= data.len() { break; }
-
-            let child_size = match cursor.read_u32::<BigEndian>() {
-                Ok(s) => s as usize,
-                Err(_) => break,
-            };
-
-            if child_size < 8 || start_pos + child_size > data.len() { break; }
-
-            let mut type_buf = [0u8; 4];
-            if cursor.read_exact(&mut type_buf).is_err() { break; }
-
-            let type_str = String::from_utf8_lossy(&type_buf);
-
-            if type_str == "iods" {
-                found_iods = true;
-                cursor.set_position((start_pos + child_size) as u64);
-            } else {
-                new_payload.extend_from_slice(&data[start_pos..start_pos + child_size]);
-                cursor.set_position((start_pos + child_size) as u64);
-            }
-        }
-
-        let mut result = if found_iods {
-            let new_size = 8 + new_payload.len();
-            let mut new_moov = Vec::with_capacity(new_size);
-            let _ = new_moov.write_u32::<BigEndian>(new_size as u32);
-            let _ = new_moov.write(&b"moov"[..]);
-            let _ = new_moov.write(&new_payload);
+        let children = child_boxes(&data, moov.body(), moov.end);
+        let mut result = if children.iter().any(|b| &b.kind == b"iods") {
+            let mut new_moov = Vec::with_capacity(data.len());
+            write_box(&mut new_moov, b"moov", |out| {
+                for child in children.iter().filter(|b| &b.kind != b"iods") {
+                    out.extend_from_slice(&data[child.start..child.end]);
+                }
+            });
             new_moov
         } else {
             data
         };
 
-        // CRITICAL: Patch tkhd dimensions using avc1 dimensions
-        // Windows Media Foundation SinkWriter often leaves tkhd width/height as 0
-        if let Some((width, height)) = Self::find_avc1_dimensions(&result) {
+        // CRITICAL: Patch tkhd dimensions, which the Windows Media Foundation
+        // SinkWriter often leaves as 0. The SPS has the real size; avc1 is the fallback.
+        let dimensions = sps_dimensions(&result)
+            .and_then(|(w, h)| Some((u16::try_from(w).ok()?, u16::try_from(h).ok()?)))
+            .or_else(|| Self::find_avc1_dimensions(&result));
+        if let Some((width, height)) = dimensions {
             if !Self::patch_tkhd(&mut result, width, height) {
                 error!("Failed to patch tkhd dimensions!");
             }
         } else {
-            error!("Could not find avc1 dimensions to patch tkhd!");
+            error!("Could not find SPS or avc1 dimensions to patch tkhd!");
         }
 
         result
@@ -342,11 +242,7 @@ impl Mp4Parser {
         loop {
             if self.buffer.len() < 8 { break; }
 
-            let mut cursor = Cursor::new(&self.buffer);
-            let atom_size = match cursor.read_u32::<BigEndian>() {
-                Ok(s) => s as usize,
-                Err(_) => break,
-            };
+            let Some(atom_size) = read_u32(&self.buffer, 0).map(|s| s as usize) else { break };
 
             if atom_size < 8 { 
                 // Recovery: skip 1 byte if invalid
@@ -373,16 +269,15 @@ impl Mp4Parser {
                     self.init_segment.extend_from_slice(&data_to_add);
                     
                     if atom_type_str == "moov" {
-                        let has_mvex = self.init_segment.windows(4).any(|w| w == b"mvex");
+                        let moov = child_boxes(&self.init_segment, 0, self.init_segment.len()).into_iter().find(|b| &b.kind == b"moov");
+                        let has_mvex = moov.is_some_and(|moov| find_child(&self.init_segment, &moov, b"mvex").is_some());
                         if !has_mvex {
                             error!("MP4Parser: 'moov' atom missing 'mvex' box! MSE playback will likely fail.");
                         }
 
                         self.init_complete = true;
-                        segments.push(Mp4Segment {
-                            kind: SegmentType::Init,
-                            data: std::mem::take(&mut self.init_segment),
-                        });
+                        self.tracks = track_info(&self.init_segment);
+                        segments.push(Mp4Segment::init(std::mem::take(&mut self.init_segment)));
                     }
                 },
                 "moof" => {
@@ -397,29 +292,551 @@ impl Mp4Parser {
                             let mut combined = Vec::new();
                             combined.extend_from_slice(&self.pending_moof);
                             combined.extend_from_slice(&atom_data);
-                            segments.push(Mp4Segment {
-                                kind: SegmentType::Media,
-                                data: combined,
-                            });
+                            segments.push(Mp4Segment::media(combined, &self.tracks));
                             self.pending_moof.clear();
                         } else {
-                            segments.push(Mp4Segment {
-                                kind: SegmentType::Media,
-                                data: atom_data,
-                            });
+                            segments.push(Mp4Segment::media(atom_data, &self.tracks));
                         }
                     }
                 },
                 _ => {
                     if self.init_complete {
-                        segments.push(Mp4Segment {
-                            kind: SegmentType::Media,
-                            data: atom_data,
-                        });
+                        segments.push(Mp4Segment::media(atom_data, &self.tracks));
                     }
                 }
             }
         }
         segments
     }
-}
\ No newline at end of file
+}
+
+/// Timing defaults of one track, from the `moov` of an init segment.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TrackInfo {
+    /// Ticks per second (`mdhd`)
+    pub timescale: u32,
+    /// Sample duration when a fragment doesn't say (`trex`)
+    pub default_sample_duration: u32,
+    /// Sample size when a fragment doesn't say (`trex`)
+    pub default_sample_size: u32,
+    /// Sample flags when a fragment doesn't say (`trex`)
+    pub default_sample_flags: u32,
+}
+
+/// sample_is_non_sync_sample in the sample flags of `trex`, `tfhd` and `trun`
+const SAMPLE_IS_NON_SYNC: u32 = 0x0001_0000;
+
+/// Decode time and duration of one track in a media segment, in track ticks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackFragmentTiming {
+    pub track_id: u32,
+    /// `tfdt` baseMediaDecodeTime, if the fragment has one
+    pub decode_time: Option<u64>,
+    pub duration: u64,
+    /// The first sample is a sync sample
+    pub is_keyframe: bool,
+    /// Composition time offset of the first sample
+    pub composition_offset: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BoxRef {
+    kind: [u8; 4],
+    start: usize,
+    end: usize,
+}
+
+impl BoxRef {
+    /// Offset of the payload after the header
+    fn body(&self) -> usize {
+        self.start + 8
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some((read_u32(data, offset)? as u64) << 32 | read_u32(data, offset + 4)? as u64)
+}
+
+/// Boxes laid out back to back in `data[start..end]`. Stops at the first malformed one.
+fn child_boxes(data: &[u8], start: usize, end: usize) -> Vec<BoxRef> {
+    let mut boxes = Vec::new();
+    let mut i = start;
+    while i + 8 <= end {
+        let size = read_u32(data, i).unwrap_or(0) as usize;
+        if size < 8 || i + size > end { break; }
+        boxes.push(BoxRef { kind: [data[i + 4], data[i + 5], data[i + 6], data[i + 7]], start: i, end: i + size });
+        i += size;
+    }
+    boxes
+}
+
+fn find_child(data: &[u8], parent: &BoxRef, kind: &[u8; 4]) -> Option<BoxRef> {
+    child_boxes(data, parent.body(), parent.end).into_iter().find(|b| &b.kind == kind)
+}
+
+/// The box at `path` below `parent`, one child type per level.
+fn find_path(data: &[u8], parent: &BoxRef, path: &[&[u8; 4]]) -> Option<BoxRef> {
+    path.iter().try_fold(*parent, |parent, kind| find_child(data, &parent, kind))
+}
+
+/// The first `trak` with an `avc1` sample entry, and that entry.
+fn avc1_entry(init: &[u8]) -> Option<(BoxRef, BoxRef)> {
+    let moov = child_boxes(init, 0, init.len()).into_iter().find(|b| &b.kind == b"moov")?;
+    child_boxes(init, moov.body(), moov.end).into_iter().filter(|b| &b.kind == b"trak").find_map(|trak| {
+        let stsd = find_path(init, &trak, &[b"mdia", b"minf", b"stbl", b"stsd"])?;
+        // Sample entries follow the version, flags and entry_count
+        let avc1 = child_boxes(init, stsd.body() + 8, stsd.end).into_iter().find(|b| &b.kind == b"avc1")?;
+        Some((trak, avc1))
+    })
+}
+
+/// Change the size fields of `boxes`, as they were before an edit inside
+/// all of them, by `delta` bytes.
+fn resize_boxes(data: &mut [u8], boxes: &[BoxRef], delta: isize) {
+    for b in boxes {
+        let size = (b.end - b.start).saturating_add_signed(delta) as u32;
+        data[b.start..b.start + 4].copy_from_slice(&size.to_be_bytes());
+    }
+}
+
+/// Version and flags of a full box
+fn full_box_header(data: &[u8], b: &BoxRef) -> Option<(u8, u32)> {
+    let word = read_u32(data, b.body())?;
+    Some(((word >> 24) as u8, word & 0x00FF_FFFF))
+}
+
+/// Append a box of `kind`, with the body written by `body`, and fill in its size.
+fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(kind);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+/// `write_box` for a full box, with version and flags before the body
+fn write_full_box(out: &mut Vec<u8>, kind: &[u8; 4], version: u8, flags: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    write_box(out, kind, |out| {
+        out.extend_from_slice(&((version as u32) << 24 | flags & 0x00FF_FFFF).to_be_bytes());
+        body(out);
+    });
+}
+
+/// Track ids with their timescale and default sample duration, from an init segment.
+pub fn track_info(init: &[u8]) -> HashMap<u32, TrackInfo> {
+    let mut tracks = HashMap::new();
+    let Some(moov) = child_boxes(init, 0, init.len()).into_iter().find(|b| &b.kind == b"moov") else {
+        return tracks;
+    };
+
+    for child in child_boxes(init, moov.body(), moov.end) {
+        match &child.kind {
+            b"trak" => {
+                let track_id = tkhd_track_id(init, &child);
+                let timescale = find_child(init, &child, b"mdia")
+                    .and_then(|mdia| find_child(init, &mdia, b"mdhd"))
+                    .and_then(|mdhd| {
+                        let (version, _) = full_box_header(init, &mdhd)?;
+                        read_u32(init, mdhd.body() + if version == 1 { 20 } else { 12 })
+                    });
+                if let (Some(track_id), Some(timescale)) = (track_id, timescale) {
+                    tracks.entry(track_id).or_insert_with(TrackInfo::default).timescale = timescale;
+                }
+            }
+            b"mvex" => {
+                for trex in child_boxes(init, child.body(), child.end).iter().filter(|b| &b.kind == b"trex") {
+                    // track_ID, default_sample_description_index, default_sample_duration,
+                    // default_sample_size, default_sample_flags
+                    let Some(track_id) = read_u32(init, trex.body() + 4) else { continue };
+                    let track = tracks.entry(track_id).or_insert_with(TrackInfo::default);
+                    track.default_sample_duration = read_u32(init, trex.body() + 12).unwrap_or(0);
+                    track.default_sample_size = read_u32(init, trex.body() + 16).unwrap_or(0);
+                    track.default_sample_flags = read_u32(init, trex.body() + 20).unwrap_or(0);
+                }
+            }
+            _ => {}
+        }
+    }
+    tracks
+}
+
+/// Displayed size of the first video track in an init segment, from the SPS
+/// in its `avcC`.
+pub fn sps_dimensions(init: &[u8]) -> Option<(u32, u32)> {
+    match avc_config(init)?.first_sps() {
+        Ok(sps) => Some((sps.width(), sps.height())),
+        Err(e) => {
+            debug!("Cannot read the SPS in avcC: {}", e);
+            None
+        }
+    }
+}
+
+/// The `avcC` of the first video track in an init segment.
+pub fn avc_config(init: &[u8]) -> Option<AvcConfig> {
+    let (_, avc1) = avc1_entry(init)?;
+    // Child boxes follow the 78 bytes of visual sample entry fields
+    let avcc = child_boxes(init, avc1.body() + 78, avc1.end).into_iter().find(|b| &b.kind == b"avcC")?;
+    AvcConfig::parse(&init[avcc.body()..avcc.end]).map_err(|e| debug!("Cannot read avcC: {}", e)).ok()
+}
+
+/// Track id in the `tkhd` of a `trak`.
+fn tkhd_track_id(init: &[u8], trak: &BoxRef) -> Option<u32> {
+    let tkhd = find_child(init, trak, b"tkhd")?;
+    let (version, _) = full_box_header(init, &tkhd)?;
+    // creation and modification times come first: 32 or 64 bits each
+    read_u32(init, tkhd.body() + if version == 1 { 20 } else { 12 })
+}
+
+/// Per-track timing of a media segment (`moof` followed by `mdat`), with
+/// the sync flag and composition offset of each track's first sample.
+pub fn fragment_timing(segment: &[u8], tracks: &HashMap<u32, TrackInfo>) -> Vec<TrackFragmentTiming> {
+    track_fragments(segment, tracks)
+        .into_iter()
+        .map(|fragment| {
+            let first = fragment.samples.first();
+            TrackFragmentTiming {
+                track_id: fragment.track_id,
+                decode_time: fragment.decode_time,
+                duration: fragment.samples.iter().map(|s| s.duration as u64).sum(),
+                is_keyframe: first.is_some_and(SampleRecord::is_sync),
+                composition_offset: first.map_or(0, |s| s.composition_offset),
+            }
+        })
+        .collect()
+}
+
+/// One sample of a track fragment, with the `tfhd` and `trex` defaults applied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SampleRecord {
+    duration: u32,
+    size: u32,
+    flags: u32,
+    composition_offset: i64,
+    /// Where the sample data starts in the segment
+    offset: usize,
+}
+
+impl SampleRecord {
+    fn is_sync(&self) -> bool {
+        self.flags & SAMPLE_IS_NON_SYNC == 0
+    }
+}
+
+/// The samples of one `traf`.
+struct TrackFragment {
+    track_id: u32,
+    /// `tfdt` baseMediaDecodeTime, if the fragment has one
+    decode_time: Option<u64>,
+    samples: Vec<SampleRecord>,
+}
+
+/// The `traf`s of a media segment, sample by sample.
+fn track_fragments(segment: &[u8], tracks: &HashMap<u32, TrackInfo>) -> Vec<TrackFragment> {
+    let top = child_boxes(segment, 0, segment.len());
+    let Some(moof) = top.iter().find(|b| &b.kind == b"moof") else {
+        return Vec::new();
+    };
+    let mdat_body = top.iter().find(|b| &b.kind == b"mdat").map_or(segment.len(), |b| b.body());
+
+    let mut fragments = Vec::new();
+    for traf in child_boxes(segment, moof.body(), moof.end).iter().filter(|b| &b.kind == b"traf") {
+        let Some(tfhd) = find_child(segment, traf, b"tfhd") else { continue };
+        let Some((_, tfhd_flags)) = full_box_header(segment, &tfhd) else { continue };
+        let Some(track_id) = read_u32(segment, tfhd.body() + 4) else { continue };
+        let track = tracks.get(&track_id).copied().unwrap_or_default();
+
+        // Optional tfhd fields, in order: base_data_offset (8), sample_description_index,
+        // default_sample_duration, default_sample_size, default_sample_flags (4 each)
+        let mut offset = tfhd.body() + 8;
+        let mut next_field = |present: bool, size: usize| {
+            offset += if present { size } else { 0 };
+            present.then_some(offset - size)
+        };
+        // Offsets count from the moof unless the segment says otherwise
+        let base = next_field(tfhd_flags & 0x000001 != 0, 8).and_then(|at| read_u64(segment, at));
+        next_field(tfhd_flags & 0x000002 != 0, 4);
+        let default_duration = next_field(tfhd_flags & 0x000008 != 0, 4).and_then(|at| read_u32(segment, at));
+        let default_size = next_field(tfhd_flags & 0x000010 != 0, 4).and_then(|at| read_u32(segment, at));
+        let default_flags = next_field(tfhd_flags & 0x000020 != 0, 4).and_then(|at| read_u32(segment, at));
+        let default_duration = default_duration.unwrap_or(track.default_sample_duration);
+        let default_size = default_size.unwrap_or(track.default_sample_size);
+        let default_flags = default_flags.unwrap_or(track.default_sample_flags);
+        let base = base.map_or(moof.start, |b| b as usize);
+
+        let decode_time = find_child(segment, traf, b"tfdt").and_then(|tfdt| {
+            let (version, _) = full_box_header(segment, &tfdt)?;
+            if version == 1 {
+                read_u64(segment, tfdt.body() + 4)
+            } else {
+                read_u32(segment, tfdt.body() + 4).map(u64::from)
+            }
+        });
+
+        let mut samples = Vec::new();
+        // A trun without data_offset continues where the previous one ended
+        let mut data = mdat_body;
+        for trun in child_boxes(segment, traf.body(), traf.end).iter().filter(|b| &b.kind == b"trun") {
+            let Some((version, flags)) = full_box_header(segment, trun) else { continue };
+            let sample_count = read_u32(segment, trun.body() + 4).unwrap_or(0);
+
+            // data_offset and first_sample_flags, then one record per sample
+            let mut offset = trun.body() + 8;
+            if flags & 0x000001 != 0 {
+                let data_offset = read_u32(segment, offset).unwrap_or(0) as i32;
+                data = base.saturating_add_signed(data_offset as isize);
+                offset += 4;
+            }
+            let first_sample_flags = if flags & 0x000004 != 0 {
+                offset += 4;
+                read_u32(segment, offset - 4)
+            } else {
+                None
+            };
+            let record_size = 4 * [0x000100, 0x000200, 0x000400, 0x000800].iter().filter(|f| flags & **f != 0).count();
+            // Without records nothing bounds the count, but every sample takes a byte of the segment
+            let sample_count = if record_size == 0 { sample_count.min(segment.len() as u32) } else { sample_count };
+
+            for index in 0..sample_count {
+                let Some(record) = segment.get(offset..offset + record_size) else { break };
+                let mut fields = record.chunks_exact(4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+                let duration = if flags & 0x000100 != 0 { fields.next().unwrap_or(0) } else { default_duration };
+                let size = if flags & 0x000200 != 0 { fields.next().unwrap_or(0) } else { default_size };
+                // Sample flags override first_sample_flags, which override the defaults
+                let sample_flags = if flags & 0x000400 != 0 { fields.next() } else { None };
+                let sample_flags = sample_flags.or(first_sample_flags.filter(|_| index == 0)).unwrap_or(default_flags);
+                // Signed in version 1 only
+                let composition_offset = match fields.next() {
+                    Some(value) if version == 1 => value as i32 as i64,
+                    Some(value) => value as i64,
+                    None => 0,
+                };
+
+                samples.push(SampleRecord { duration, size, flags: sample_flags, composition_offset, offset: data });
+                data = data.saturating_add(size as usize);
+                offset += record_size;
+            }
+        }
+
+        fragments.push(TrackFragment { track_id, decode_time, samples });
+    }
+    fragments
+}
+
+/// Track id, tfdt and samples of a media segment with one `traf`, for
+/// writing its samples again. `None` for other segments.
+pub fn single_track_samples(data: &[u8], tracks: &HashMap<u32, TrackInfo>) -> Option<(u32, Option<u64>, Vec<Sample>)> {
+    let mut fragments = track_fragments(data, tracks);
+    if fragments.len() != 1 {
+        debug!("Media segment with {} track fragments cannot be rewritten", fragments.len());
+        return None;
+    }
+    let fragment = fragments.pop()?;
+    let samples = fragment
+        .samples
+        .iter()
+        .map(|record| {
+            Some(Sample {
+                data: data.get(record.offset..record.offset.checked_add(record.size as usize)?)?.to_vec(),
+                duration: record.duration,
+                keyframe: record.is_sync(),
+                composition_offset: i32::try_from(record.composition_offset).ok()?,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some((fragment.track_id, fragment.decode_time, samples))
+}
+
+/// Overwrite the `mfhd` sequence number of a media segment.
+pub fn set_sequence_number(segment: &mut [u8], sequence: u32) -> bool {
+    let Some(moof) = child_boxes(segment, 0, segment.len()).into_iter().find(|b| &b.kind == b"moof") else {
+        return false;
+    };
+    match find_child(segment, &moof, b"mfhd") {
+        Some(mfhd) if mfhd.body() + 8 <= mfhd.end => {
+            segment[mfhd.body() + 4..mfhd.body() + 8].copy_from_slice(&sequence.to_be_bytes());
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Overwrite the `tfdt` of `track_id` in a media segment. A version 0 `tfdt`
+/// is widened to version 1 once the time no longer fits in 32 bits, which
+/// moves the `mdat` and so the trun data offsets.
+pub fn set_decode_time(segment: &mut Vec<u8>, track_id: u32, decode_time: u64) -> bool {
+    let Some(moof) = child_boxes(segment, 0, segment.len()).into_iter().find(|b| &b.kind == b"moof") else {
+        return false;
+    };
+    let trafs: Vec<BoxRef> = child_boxes(segment, moof.body(), moof.end).into_iter().filter(|b| &b.kind == b"traf").collect();
+    let Some((traf, tfdt)) = trafs.iter().find_map(|traf| {
+        let tfhd = find_child(segment, traf, b"tfhd")?;
+        if read_u32(segment, tfhd.body() + 4)? != track_id { return None; }
+        Some((*traf, find_child(segment, traf, b"tfdt")?))
+    }) else {
+        return false;
+    };
+    let Some((version, _)) = full_box_header(segment, &tfdt) else { return false };
+
+    if version == 1 {
+        segment[tfdt.body() + 4..tfdt.body() + 12].copy_from_slice(&decode_time.to_be_bytes());
+        return true;
+    }
+    if let Ok(time) = u32::try_from(decode_time) {
+        segment[tfdt.body() + 4..tfdt.body() + 8].copy_from_slice(&time.to_be_bytes());
+        return true;
+    }
+
+    // Widen: version 1 with a 64-bit time, four bytes longer
+    segment[tfdt.body()] = 1;
+    segment.splice(tfdt.body() + 4..tfdt.body() + 8, decode_time.to_be_bytes());
+    resize_boxes(segment, &[tfdt, traf, moof], 4);
+    let moof = BoxRef { end: moof.end + 4, ..moof };
+    for traf in child_boxes(segment, moof.body(), moof.end).iter().filter(|b| &b.kind == b"traf") {
+        for trun in child_boxes(segment, traf.body(), traf.end).iter().filter(|b| &b.kind == b"trun") {
+            if full_box_header(segment, trun).is_some_and(|(_, flags)| flags & 0x000001 != 0) {
+                let offset = trun.body() + 8;
+                if let Some(data_offset) = read_u32(segment, offset) {
+                    segment[offset..offset + 4].copy_from_slice(&data_offset.wrapping_add(4).to_be_bytes());
+                }
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NON_SYNC: u32 = 0x0101_0000;
+
+    /// A `moof` with one traf of track 1 and one trun, with the given optional fields.
+    fn moof(tfhd_flags: u32, tfhd_fields: &[u32], trun_flags: u32, trun_fields: &[u32]) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_box(&mut data, b"moof", |out| {
+            write_box(out, b"traf", |out| {
+                write_full_box(out, b"tfhd", 0, tfhd_flags, |out| {
+                    for value in [1].iter().chain(tfhd_fields) {
+                        out.extend_from_slice(&value.to_be_bytes());
+                    }
+                });
+                write_full_box(out, b"tfdt", 0, 0, |out| out.extend_from_slice(&100u32.to_be_bytes()));
+                write_full_box(out, b"trun", 0, trun_flags, |out| {
+                    for value in trun_fields {
+                        out.extend_from_slice(&value.to_be_bytes());
+                    }
+                });
+            });
+        });
+        data
+    }
+
+    #[test]
+    fn sample_flags_follow_the_defaults_chain() {
+        let tracks = HashMap::from([(1, TrackInfo { timescale: 1000, default_sample_duration: 40, default_sample_size: 1, default_sample_flags: NON_SYNC })]);
+        let first = |data: &[u8]| fragment_timing(data, &tracks)[0];
+
+        // trex defaults only
+        let timing = first(&moof(0, &[], 0, &[3]));
+        assert_eq!((timing.is_keyframe, timing.duration, timing.decode_time), (false, 120, Some(100)));
+        // tfhd default_sample_flags, after its default_sample_duration
+        let timing = first(&moof(0x28, &[20, 0], 0, &[3]));
+        assert_eq!((timing.is_keyframe, timing.duration), (true, 60));
+        // first_sample_flags
+        assert!(first(&moof(0x20, &[NON_SYNC], 0x004, &[3, 0])).is_keyframe);
+        // Per-sample flags win, with their composition offsets
+        let timing = first(&moof(0, &[], 0x005 | 0xD00, &[2, 0, 0, 40, NON_SYNC, 80, 40, 0, 0]));
+        assert_eq!((timing.is_keyframe, timing.duration, timing.composition_offset), (false, 80, 80));
+        // No samples, nothing to join at
+        assert!(!first(&moof(0x20, &[0], 0, &[0])).is_keyframe);
+
+        let segment = Mp4Segment::media(moof(0x20, &[0], 0x800, &[1, 40]), &tracks);
+        let info = segment.fragment.unwrap();
+        assert!(segment.is_keyframe());
+        assert_eq!((info.decode_time, info.composition_time, info.duration, info.timescale), (Some(100), Some(140), 40, 1000));
+    }
+
+    #[test]
+    fn makes_sinkwriter_fragments_self_contained() {
+        let mut moof = Vec::new();
+        write_box(&mut moof, b"moof", |out| {
+            write_full_box(out, b"mfhd", 0, 0, |out| out.extend_from_slice(&1u32.to_be_bytes()));
+            write_box(out, b"traf", |out| {
+                // An absolute base_data_offset and no tfdt
+                write_full_box(out, b"tfhd", 0, 0x000001, |out| {
+                    out.extend_from_slice(&1u32.to_be_bytes());
+                    out.extend_from_slice(&5000u64.to_be_bytes());
+                });
+                write_full_box(out, b"trun", 0, 0x000301, |out| {
+                    for value in [2u32, 5000, 1000, 3, 1000, 3] {
+                        out.extend_from_slice(&value.to_be_bytes());
+                    }
+                });
+            });
+        });
+        let mut parser = Mp4Parser::new();
+        parser.cumulative_decode_time = 7000;
+        let mut segment = parser.patch_moof(moof);
+        write_box(&mut segment, b"mdat", |out| out.extend_from_slice(&[0x65, 1, 2, 0x41, 3, 4]));
+        assert_eq!(parser.cumulative_decode_time, 9000);
+
+        let top = child_boxes(&segment, 0, segment.len());
+        assert_eq!((top.len(), top[1].end), (2, segment.len()));
+        let traf = find_child(&segment, &top[0], b"traf").unwrap();
+        let tfhd = find_child(&segment, &traf, b"tfhd").unwrap();
+        assert_eq!((full_box_header(&segment, &tfhd), tfhd.end - tfhd.start), (Some((0, 0x020000)), 16));
+        let tracks = HashMap::from([(1, TrackInfo::default())]);
+        let fragment = track_fragments(&segment, &tracks).pop().unwrap();
+        assert_eq!(fragment.decode_time, Some(7000));
+        assert_eq!(fragment.samples[0].offset, top[1].body());
+    }
+
+    #[test]
+    fn fixes_sinkwriter_init_segments() {
+        let track = VideoTrack::new(crate::testing::h264_sps(640, 360), crate::testing::h264_pps(), 90_000).unwrap();
+        let init = Fmp4Muxer::new(track).init_segment().data;
+        let moov = child_boxes(&init, 0, init.len())[1];
+        // An iods, and a tkhd without dimensions
+        let mut data = Vec::new();
+        write_box(&mut data, b"moov", |out| {
+            write_full_box(out, b"iods", 0, 0, |out| out.extend_from_slice(&[0x10, 0x80, 0x80, 0x80, 0x07, 0, 0x4F, 0xFF, 0xFF, 0x29, 0x15, 0xFF]));
+            out.extend_from_slice(&init[moov.body()..moov.end]);
+        });
+        let (trak, _) = avc1_entry(&data).unwrap();
+        let tkhd = find_child(&data, &trak, b"tkhd").unwrap();
+        data[tkhd.body() + 76..tkhd.body() + 84].fill(0);
+
+        let patched = Mp4Parser::patch_moov(data);
+        assert_eq!(patched, init[moov.start..moov.end]);
+    }
+
+    #[test]
+    fn reads_avcc_from_the_sample_entry() {
+        let track = VideoTrack::new(crate::testing::h264_sps(640, 360), crate::testing::h264_pps(), 90_000).unwrap();
+        let init = Fmp4Muxer::new(track).init_segment().data;
+        let moov = child_boxes(&init, 0, init.len())[1];
+        // Something that merely looks like an avcC, ahead of the track
+        let mut data = init[..moov.start].to_vec();
+        write_box(&mut data, b"moov", |out| {
+            write_box(out, b"free", |out| {
+                out.extend_from_slice(&16u32.to_be_bytes());
+                out.extend_from_slice(b"avcC\x01\x42\x00\x1E\xFF\xE1\x00\x00");
+            });
+            out.extend_from_slice(&init[moov.body()..moov.end]);
+        });
+
+        assert!(avc_config(&init).is_some());
+        assert_eq!(avc_config(&data), avc_config(&init));
+        assert_eq!(sps_dimensions(&data), Some((640, 360)));
+    }
+}
@@ -0,0 +1,176 @@
+//! `--cmaf`: conforming CMAF rather than fMP4 that browsers tolerate.
+//!
+//! The init segment gets CMAF brands in its `ftyp` and a fresh `mvex` with one
+//! `trex` per track. Each media segment is written again as a single `traf`
+//! with a version 1 `tfdt`, behind a `styp`: `cmfs` when it starts with a
+//! keyframe and so can begin a CMAF segment, `cmfl` for the chunks after it.
+
+use std::collections::HashMap;
+
+use log::warn;
+
+use super::mux::write_fragment;
+use super::{
+    avc_config, child_boxes, single_track_samples, sps_dimensions, tkhd_track_id, track_info, write_box, write_full_box,
+    Mp4Segment, SegmentType, TrackInfo,
+};
+
+pub struct CmafWriter {
+    /// Track defaults of the current init segment
+    tracks: HashMap<u32, TrackInfo>,
+    /// mfhd sequence number of the next fragment
+    sequence: u32,
+    /// Decode time after the last fragment, for fragments without tfdt
+    next_decode_time: u64,
+}
+
+impl CmafWriter {
+    pub fn new() -> Self {
+        Self { tracks: HashMap::new(), sequence: 1, next_decode_time: 0 }
+    }
+
+    /// `segment` as CMAF, or `None` for media with no CMAF form, like
+    /// fragments of several tracks.
+    pub fn push(&mut self, segment: Mp4Segment) -> Option<Mp4Segment> {
+        if segment.kind == SegmentType::Init {
+            self.tracks = track_info(&segment.data);
+            self.sequence = 1;
+            self.next_decode_time = 0;
+            if self.tracks.len() != 1 {
+                warn!("CMAF: init segment has {} tracks, a CMAF track file carries one", self.tracks.len());
+            }
+            return Some(Mp4Segment::init(init_segment(&segment.data)));
+        }
+
+        let Some((track_id, decode_time, samples)) = single_track_samples(&segment.data, &self.tracks) else {
+            warn!("CMAF: dropping a media segment that is not one track fragment");
+            return None;
+        };
+        let decode_time = decode_time.unwrap_or(self.next_decode_time);
+        let timescale = self.tracks.get(&track_id).map_or(0, |t| t.timescale);
+        let mut fragment = write_fragment(self.sequence, track_id, decode_time, timescale, &samples)?;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.next_decode_time = decode_time + fragment.fragment.map_or(0, |f| f.duration);
+
+        let brand = if fragment.is_keyframe() { b"cmfs" } else { b"cmfl" };
+        let mut data = Vec::with_capacity(fragment.data.len() + 24);
+        write_box(&mut data, b"styp", |out| {
+            out.extend_from_slice(brand);
+            out.extend_from_slice(&0u32.to_be_bytes());
+            out.extend_from_slice(brand);
+            out.extend_from_slice(b"msdh");
+        });
+        data.extend_from_slice(&fragment.data);
+        fragment.data = data;
+        Some(fragment)
+    }
+}
+
+/// `init` with a CMAF `ftyp` and `mvex`. Other boxes are kept as they are.
+fn init_segment(init: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(init.len() + 32);
+    write_box(&mut out, b"ftyp", |out| {
+        out.extend_from_slice(b"cmfc");
+        out.extend_from_slice(&0u32.to_be_bytes());
+        for brand in [b"cmfc", b"iso6", b"isom"] {
+            out.extend_from_slice(brand);
+        }
+        if let Some(brand) = media_profile(init) {
+            out.extend_from_slice(brand);
+        }
+    });
+
+    let tracks = track_info(init);
+    for top in child_boxes(init, 0, init.len()) {
+        match &top.kind {
+            b"ftyp" => {}
+            b"moov" => write_box(&mut out, b"moov", |out| {
+                let children = child_boxes(init, top.body(), top.end);
+                for child in children.iter().filter(|b| &b.kind != b"mvex") {
+                    out.extend_from_slice(&init[child.start..child.end]);
+                }
+                write_box(out, b"mvex", |out| {
+                    for track_id in children.iter().filter(|b| &b.kind == b"trak").filter_map(|trak| tkhd_track_id(init, trak)) {
+                        let track = tracks.get(&track_id).copied().unwrap_or_default();
+                        // Samples use the one sample entry of their track
+                        let fields = [track_id, 1, track.default_sample_duration, track.default_sample_size, track.default_sample_flags];
+                        write_full_box(out, b"trex", 0, 0, |out| fields.iter().for_each(|f| out.extend_from_slice(&f.to_be_bytes())));
+                    }
+                });
+            }),
+            _ => out.extend_from_slice(&init[top.start..top.end]),
+        }
+    }
+    out
+}
+
+/// The CMAF media profile brand of the video track: HD or SD AVC, when the
+/// stream stays within its profile, level and size limits.
+fn media_profile(init: &[u8]) -> Option<&'static [u8; 4]> {
+    let config = avc_config(init)?;
+    let (width, height) = sps_dimensions(init)?;
+    if !matches!(config.profile_idc, 66 | 77 | 100) {
+        return None;
+    }
+    match (width, height, config.level_idc) {
+        (..=864, ..=576, ..=31) => Some(b"cfsd"),
+        (..=1920, ..=1080, ..=40) => Some(b"cfhd"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::{find_child, fragment_timing, read_u32, Fmp4Muxer, Sample, VideoTrack};
+    use crate::testing::{h264_pps, h264_sps};
+
+    fn sample(keyframe: bool) -> Sample {
+        Sample { data: vec![0, 0, 0, 1, 0x41], duration: 3000, keyframe, composition_offset: 0 }
+    }
+
+    #[test]
+    fn brands_the_init_segment_and_fixes_trex() {
+        let mut init = Fmp4Muxer::new(VideoTrack::new(h264_sps(1280, 720), h264_pps(), 90_000).unwrap()).init_segment();
+        // A trex pointing at sample entry 0, as some writers leave it
+        let trex = init.data.windows(4).position(|w| w == b"trex").unwrap() + 4;
+        init.data[trex + 8..trex + 12].copy_from_slice(&0u32.to_be_bytes());
+
+        let cmaf = CmafWriter::new().push(init.clone()).unwrap();
+        let data = &cmaf.data;
+        let top = child_boxes(data, 0, data.len());
+        let ftyp = &data[top[0].body()..top[0].end];
+        assert_eq!(ftyp, b"cmfc\0\0\0\0cmfciso6isomcfhd");
+        assert_eq!(SegmentType::identify(data), Some(SegmentType::Init));
+
+        let moov = top[1];
+        let mvex = find_child(data, &moov, b"mvex").unwrap();
+        let trex = find_child(data, &mvex, b"trex").unwrap();
+        assert_eq!(read_u32(data, trex.body() + 8), Some(1));
+        // Everything else in the moov is untouched
+        assert_eq!(track_info(data), track_info(&init.data));
+        assert_eq!(sps_dimensions(data), Some((1280, 720)));
+    }
+
+    #[test]
+    fn prefixes_fragments_with_styp() {
+        let mut muxer = Fmp4Muxer::new(VideoTrack::new(h264_sps(640, 360), h264_pps(), 90_000).unwrap());
+        let mut cmaf = CmafWriter::new();
+        let init = cmaf.push(muxer.init_segment()).unwrap();
+        let tracks = track_info(&init.data);
+
+        let first = cmaf.push(muxer.fragment(&[sample(true), sample(false)]).unwrap()).unwrap();
+        let second = cmaf.push(muxer.fragment(&[sample(false)]).unwrap()).unwrap();
+        for (segment, brand, decode_time) in [(&first, b"cmfs", 0), (&second, b"cmfl", 6000)] {
+            assert_eq!(&segment.data[4..12], b"styp".iter().chain(brand).copied().collect::<Vec<_>>());
+            assert_eq!(SegmentType::identify(&segment.data), Some(SegmentType::Media));
+            assert_eq!(fragment_timing(&segment.data, &tracks)[0].decode_time, Some(decode_time));
+            assert_eq!(Mp4Segment::media(segment.data.clone(), &tracks).fragment, segment.fragment);
+        }
+
+        // A lone mdat has no CMAF form
+        let mut mdat = Vec::new();
+        write_box(&mut mdat, b"mdat", |_| {});
+        assert!(cmaf.push(Mp4Segment::media(mdat, &tracks)).is_none());
+    }
+}
@@ -0,0 +1,378 @@
+//! Fragmented MP4 writer for one H.264 video track.
+//!
+//! Produces what the server and MSE expect from the SinkWriter after
+//! patching: an init segment (`ftyp` then `moov` with `mvex`/`trex`), and
+//! media segments of one `moof` and one `mdat`, with a `tfdt` and trun data
+//! offsets relative to the `moof`.
+
+use super::{write_box, write_full_box, FragmentInfo, Mp4Segment, SegmentType};
+use crate::h264::{AvcConfig, H264Error, Pps, Sps};
+
+/// Track id of the video track
+pub const TRACK_ID: u32 = 1;
+
+/// trun sample flags: sync sample, depends on nothing
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+/// trun sample flags: depends on others, sample_is_non_sync_sample
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+/// The 16.16 identity matrix of mvhd and tkhd
+const UNITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// What the init segment describes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoTrack {
+    /// Displayed size, from the SPS
+    pub width: u32,
+    pub height: u32,
+    /// Ticks per second of sample durations and decode times
+    pub timescale: u32,
+    pub sps: Sps,
+    pub config: AvcConfig,
+}
+
+impl VideoTrack {
+    /// A track for the stream described by these parameter sets, NAL units
+    /// without start codes.
+    pub fn new(sps: Vec<u8>, pps: Vec<u8>, timescale: u32) -> Result<Self, H264Error> {
+        let config = AvcConfig::from_parameter_sets(&sps, &pps)?;
+        let sps = Sps::parse(&sps)?;
+        if Pps::parse(&pps)?.sps_id != sps.id {
+            return Err(H264Error::Invalid("PPS refers to another SPS"));
+        }
+        Ok(Self { width: sps.width(), height: sps.height(), timescale, sps, config })
+    }
+}
+
+/// One access unit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// NAL units, each prefixed with its length as four bytes
+    pub data: Vec<u8>,
+    /// In track ticks
+    pub duration: u32,
+    pub keyframe: bool,
+    /// Presentation time minus decode time, in track ticks
+    pub composition_offset: i32,
+}
+
+pub struct Fmp4Muxer {
+    track: VideoTrack,
+    /// mfhd sequence number of the next fragment
+    sequence: u32,
+    /// tfdt of the next fragment
+    decode_time: u64,
+}
+
+impl Fmp4Muxer {
+    pub fn new(track: VideoTrack) -> Self {
+        Self { track, sequence: 1, decode_time: 0 }
+    }
+
+    pub fn init_segment(&self) -> Mp4Segment {
+        let mut data = Vec::new();
+        write_box(&mut data, b"ftyp", |out| {
+            out.extend_from_slice(b"iso5");
+            out.extend_from_slice(&512u32.to_be_bytes());
+            for brand in [b"iso5", b"iso6", b"avc1", b"mp41"] {
+                out.extend_from_slice(brand);
+            }
+        });
+        write_box(&mut data, b"moov", |out| {
+            self.write_mvhd(out);
+            write_box(out, b"trak", |out| {
+                self.write_tkhd(out);
+                write_box(out, b"mdia", |out| {
+                    self.write_mdhd(out);
+                    write_full_box(out, b"hdlr", 0, 0, |out| {
+                        out.extend_from_slice(&[0; 4]);
+                        out.extend_from_slice(b"vide");
+                        out.extend_from_slice(&[0; 12]);
+                        out.extend_from_slice(b"VideoHandler\0");
+                    });
+                    write_box(out, b"minf", |out| {
+                        // graphicsmode and opcolor
+                        write_full_box(out, b"vmhd", 0, 1, |out| out.extend_from_slice(&[0; 8]));
+                        write_box(out, b"dinf", |out| {
+                            write_full_box(out, b"dref", 0, 0, |out| {
+                                out.extend_from_slice(&1u32.to_be_bytes());
+                                // Flag 1: media data is in this file
+                                write_full_box(out, b"url ", 0, 1, |_| {});
+                            });
+                        });
+                        self.write_stbl(out);
+                    });
+                });
+            });
+            write_box(out, b"mvex", |out| {
+                write_full_box(out, b"trex", 0, 0, |out| {
+                    // track_ID, sample description 1, no duration, size or flag defaults
+                    for value in [TRACK_ID, 1, 0, 0, 0] {
+                        out.extend_from_slice(&value.to_be_bytes());
+                    }
+                });
+            });
+        });
+        Mp4Segment::init(data)
+    }
+
+    /// One `moof` and `mdat` holding `samples`, or `None` without samples.
+    pub fn fragment(&mut self, samples: &[Sample]) -> Option<Mp4Segment> {
+        let segment = write_fragment(self.sequence, TRACK_ID, self.decode_time, self.track.timescale, samples)?;
+        self.sequence += 1;
+        self.decode_time += segment.fragment.map_or(0, |f| f.duration);
+        Some(segment)
+    }
+
+    fn write_mvhd(&self, out: &mut Vec<u8>) {
+        write_full_box(out, b"mvhd", 0, 0, |out| {
+            // creation and modification time, timescale, duration unknown
+            for value in [0, 0, 1000, 0] {
+                out.extend_from_slice(&u32::to_be_bytes(value));
+            }
+            // rate 1.0, volume 1.0, reserved
+            out.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+            out.extend_from_slice(&0x0100u16.to_be_bytes());
+            out.extend_from_slice(&[0; 10]);
+            UNITY_MATRIX.iter().for_each(|v| out.extend_from_slice(&v.to_be_bytes()));
+            out.extend_from_slice(&[0; 24]);
+            // next_track_ID
+            out.extend_from_slice(&(TRACK_ID + 1).to_be_bytes());
+        });
+    }
+
+    fn write_tkhd(&self, out: &mut Vec<u8>) {
+        // Flags: track enabled, in movie
+        write_full_box(out, b"tkhd", 0, 0x000003, |out| {
+            // creation and modification time, track_ID, reserved, duration
+            for value in [0, 0, TRACK_ID, 0, 0] {
+                out.extend_from_slice(&u32::to_be_bytes(value));
+            }
+            // reserved, layer, alternate_group, volume, reserved
+            out.extend_from_slice(&[0; 16]);
+            UNITY_MATRIX.iter().for_each(|v| out.extend_from_slice(&v.to_be_bytes()));
+            out.extend_from_slice(&(self.track.width << 16).to_be_bytes());
+            out.extend_from_slice(&(self.track.height << 16).to_be_bytes());
+        });
+    }
+
+    fn write_mdhd(&self, out: &mut Vec<u8>) {
+        write_full_box(out, b"mdhd", 0, 0, |out| {
+            for value in [0, 0, self.track.timescale, 0] {
+                out.extend_from_slice(&u32::to_be_bytes(value));
+            }
+            // Language "und", packed as three 5-bit letters
+            out.extend_from_slice(&0x55C4u16.to_be_bytes());
+            out.extend_from_slice(&[0; 2]);
+        });
+    }
+
+    /// Sample tables are empty: every sample lives in a fragment.
+    fn write_stbl(&self, out: &mut Vec<u8>) {
+        write_box(out, b"stbl", |out| {
+            write_full_box(out, b"stsd", 0, 0, |out| {
+                out.extend_from_slice(&1u32.to_be_bytes());
+                self.write_avc1(out);
+            });
+            for kind in [b"stts", b"stsc", b"stco"] {
+                write_full_box(out, kind, 0, 0, |out| out.extend_from_slice(&[0; 4]));
+            }
+            // sample_size and sample_count
+            write_full_box(out, b"stsz", 0, 0, |out| out.extend_from_slice(&[0; 8]));
+        });
+    }
+
+    fn write_avc1(&self, out: &mut Vec<u8>) {
+        let track = &self.track;
+        write_box(out, b"avc1", |out| {
+            // reserved, data_reference_index 1, pre_defined and reserved
+            out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+            out.extend_from_slice(&[0; 16]);
+            out.extend_from_slice(&(track.width as u16).to_be_bytes());
+            out.extend_from_slice(&(track.height as u16).to_be_bytes());
+            // 72 dpi both ways, reserved, frame_count 1
+            out.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+            out.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+            out.extend_from_slice(&[0, 0, 0, 0, 0, 1]);
+            // compressorname, depth 24, pre_defined -1
+            out.extend_from_slice(&[0; 32]);
+            out.extend_from_slice(&[0x00, 0x18, 0xFF, 0xFF]);
+            write_box(out, b"avcC", |out| track.config.write(out));
+            if let Some(vui) = &track.sps.vui {
+                if let Some(colour) = vui.colour {
+                    write_box(out, b"colr", |out| {
+                        out.extend_from_slice(b"nclx");
+                        for code in [colour.primaries, colour.transfer, colour.matrix] {
+                            out.extend_from_slice(&(code as u16).to_be_bytes());
+                        }
+                        out.push(if vui.full_range { 0x80 } else { 0 });
+                    });
+                }
+            }
+        });
+    }
+}
+
+/// A media segment of one `moof` and `mdat` holding `samples` of `track_id`,
+/// starting at `decode_time`. `None` without samples.
+pub(super) fn write_fragment(sequence: u32, track_id: u32, decode_time: u64, timescale: u32, samples: &[Sample]) -> Option<Mp4Segment> {
+    let first = samples.first()?;
+    let reordered = samples.iter().any(|s| s.composition_offset != 0);
+    // Signed composition offsets need trun version 1
+    let (version, flags) = if reordered { (1, 0x000F01) } else { (0, 0x000701) };
+
+    let mut data = Vec::new();
+    let mut data_offset_at = 0;
+    write_box(&mut data, b"moof", |out| {
+        write_full_box(out, b"mfhd", 0, 0, |out| out.extend_from_slice(&sequence.to_be_bytes()));
+        write_box(out, b"traf", |out| {
+            // default-base-is-moof: offsets count from the moof, not the file
+            write_full_box(out, b"tfhd", 0, 0x020000, |out| out.extend_from_slice(&track_id.to_be_bytes()));
+            write_full_box(out, b"tfdt", 1, 0, |out| out.extend_from_slice(&decode_time.to_be_bytes()));
+            write_full_box(out, b"trun", version, flags, |out| {
+                out.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+                data_offset_at = out.len();
+                out.extend_from_slice(&[0; 4]);
+                for sample in samples {
+                    let sample_flags = if sample.keyframe { SYNC_SAMPLE_FLAGS } else { NON_SYNC_SAMPLE_FLAGS };
+                    out.extend_from_slice(&sample.duration.to_be_bytes());
+                    out.extend_from_slice(&(sample.data.len() as u32).to_be_bytes());
+                    out.extend_from_slice(&sample_flags.to_be_bytes());
+                    if reordered {
+                        out.extend_from_slice(&sample.composition_offset.to_be_bytes());
+                    }
+                }
+            });
+        });
+    });
+    // The first sample starts right after the mdat header
+    let data_offset = data.len() as u32 + 8;
+    data[data_offset_at..data_offset_at + 4].copy_from_slice(&data_offset.to_be_bytes());
+    write_box(&mut data, b"mdat", |out| {
+        for sample in samples {
+            out.extend_from_slice(&sample.data);
+        }
+    });
+
+    let fragment = FragmentInfo {
+        is_keyframe: first.keyframe,
+        decode_time: Some(decode_time),
+        composition_time: Some(decode_time.saturating_add_signed(first.composition_offset as i64)),
+        duration: samples.iter().map(|s| s.duration as u64).sum(),
+        timescale,
+    };
+    Some(Mp4Segment { kind: SegmentType::Media, data, fragment: Some(fragment) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::{child_boxes, find_child, fragment_timing, read_u32, sps_dimensions, track_info, Mp4Parser};
+    use crate::testing::{h264_pps, h264_sps};
+
+    fn track() -> VideoTrack {
+        VideoTrack::new(h264_sps(640, 360), h264_pps(), 90_000).unwrap()
+    }
+
+    fn sample(payload: &[u8], duration: u32, keyframe: bool) -> Sample {
+        let mut data = (payload.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(payload);
+        Sample { data, duration, keyframe, composition_offset: 0 }
+    }
+
+    #[test]
+    fn init_segment_describes_the_track() {
+        let init = Fmp4Muxer::new(track()).init_segment();
+        assert_eq!(SegmentType::identify(&init.data), Some(SegmentType::Init));
+
+        let top: Vec<[u8; 4]> = child_boxes(&init.data, 0, init.data.len()).iter().map(|b| b.kind).collect();
+        assert_eq!(top, [*b"ftyp", *b"moov"]);
+        let moov = child_boxes(&init.data, 0, init.data.len())[1];
+        let children: Vec<[u8; 4]> = child_boxes(&init.data, moov.body(), moov.end).iter().map(|b| b.kind).collect();
+        assert_eq!(children, [*b"mvhd", *b"trak", *b"mvex"]);
+
+        let tracks = track_info(&init.data);
+        assert_eq!(tracks[&TRACK_ID].timescale, 90_000);
+
+        // The SinkWriter patches look for the same layout
+        assert_eq!(Mp4Parser::find_avc1_dimensions(&init.data), Some((640, 360)));
+        assert_eq!(sps_dimensions(&init.data), Some((640, 360)));
+    }
+
+    #[test]
+    fn fragments_point_at_their_samples() {
+        let mut muxer = Fmp4Muxer::new(track());
+        let init = muxer.init_segment();
+        let tracks = track_info(&init.data);
+
+        let first = muxer.fragment(&[sample(&[0x65, 1, 2], 3000, true), sample(&[0x41, 3], 3000, false)]).unwrap();
+        let second = muxer.fragment(&[sample(&[0x41, 4], 1500, false)]).unwrap();
+        assert!(muxer.fragment(&[]).is_none());
+
+        for (segment, sequence, decode_time, duration) in [(&first, 1, 0, 6000), (&second, 2, 6000, 1500)] {
+            assert_eq!(SegmentType::identify(&segment.data), Some(SegmentType::Media));
+            let timing = fragment_timing(&segment.data, &tracks);
+            assert_eq!((timing[0].track_id, timing[0].decode_time, timing[0].duration), (TRACK_ID, Some(decode_time), duration));
+
+            let data = &segment.data;
+            let moof = child_boxes(data, 0, data.len())[0];
+            let mfhd = find_child(data, &moof, b"mfhd").unwrap();
+            assert_eq!(read_u32(data, mfhd.body() + 4), Some(sequence));
+            let traf = find_child(data, &moof, b"traf").unwrap();
+            let trun = find_child(data, &traf, b"trun").unwrap();
+            let data_offset = read_u32(data, trun.body() + 8).unwrap() as usize;
+            let mdat = child_boxes(data, 0, data.len())[1];
+            assert_eq!(&mdat.kind, b"mdat");
+            assert_eq!(data_offset, mdat.body());
+        }
+
+        // Sizes and flags of the first fragment: sync, then non-sync
+        let data = &first.data;
+        let moof = child_boxes(data, 0, data.len())[0];
+        let trun = find_child(data, &find_child(data, &moof, b"traf").unwrap(), b"trun").unwrap();
+        let records: Vec<u32> = (0..6).map(|i| read_u32(data, trun.body() + 12 + i * 4).unwrap()).collect();
+        assert_eq!(records, [3000, 7, SYNC_SAMPLE_FLAGS, 3000, 6, NON_SYNC_SAMPLE_FLAGS]);
+        assert_eq!(&data[data.len() - 13..], [0, 0, 0, 3, 0x65, 1, 2, 0, 0, 0, 2, 0x41, 3]);
+    }
+
+    #[test]
+    fn writes_signed_composition_offsets() {
+        let mut muxer = Fmp4Muxer::new(track());
+        let mut reordered = sample(&[0x41], 3000, false);
+        reordered.composition_offset = -3000;
+        let segment = muxer.fragment(&[sample(&[0x65], 3000, true), reordered]).unwrap();
+
+        let data = &segment.data;
+        let moof = child_boxes(data, 0, data.len())[0];
+        let trun = find_child(data, &find_child(data, &moof, b"traf").unwrap(), b"trun").unwrap();
+        assert_eq!(read_u32(data, trun.body()), Some(0x0100_0F01));
+        assert_eq!(read_u32(data, trun.body() + 12 + 7 * 4), Some(-3000i32 as u32));
+    }
+
+    #[test]
+    fn parser_reads_keyframes_and_first_sample_times() {
+        let mut muxer = Fmp4Muxer::new(track());
+        let mut file = muxer.init_segment().data;
+        let mut delayed = sample(&[0x65], 3000, true);
+        delayed.composition_offset = 6000;
+        let fragments = [
+            muxer.fragment(&[delayed, sample(&[0x41], 3000, false)]).unwrap(),
+            muxer.fragment(&[sample(&[0x41], 1500, false)]).unwrap(),
+        ];
+        for fragment in &fragments {
+            file.extend_from_slice(&fragment.data);
+        }
+
+        let parsed = Mp4Parser::new().parse(&file);
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed[0].fragment, None);
+        assert_eq!(
+            parsed[1].fragment,
+            Some(FragmentInfo { is_keyframe: true, decode_time: Some(0), composition_time: Some(6000), duration: 6000, timescale: 90_000 })
+        );
+        assert!(!parsed[2].is_keyframe());
+        assert_eq!(parsed[2].fragment.unwrap().decode_time, Some(6000));
+        // The muxer knows the same without parsing
+        assert_eq!(fragments.map(|f| f.fragment), [parsed[1].fragment, parsed[2].fragment]);
+    }
+}
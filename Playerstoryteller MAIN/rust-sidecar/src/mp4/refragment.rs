@@ -0,0 +1,229 @@
+//! Re-cutting media segments to a chosen size, whatever the encoder emitted.
+//!
+//! Samples are taken out of each `moof`/`mdat` and written again as new
+//! fragments with fresh `mfhd` sequence numbers, `tfdt` decode times and
+//! `trun` records. A keyframe always starts a new fragment, so every
+//! fragment that carries one can be joined at.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::mux::write_fragment;
+use super::{single_track_samples, track_info, Mp4Segment, Sample, SegmentType, TrackInfo};
+
+/// How long re-cut fragments should be.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FragmentTarget {
+    /// One frame per fragment, for the lowest latency
+    Frame,
+    /// At least this long, or up to the next keyframe
+    Duration(Duration),
+    /// Whole groups of pictures: at least this long, and cut only at
+    /// keyframes, so every fragment can be played on its own
+    Gop(Duration),
+}
+
+impl FromStr for FragmentTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "frame" => Ok(FragmentTarget::Frame),
+            ms => match ms.trim_end_matches("ms").parse::<u64>() {
+                Ok(ms) if ms > 0 => Ok(FragmentTarget::Duration(Duration::from_millis(ms))),
+                _ => Err(format!("invalid fragment size {}, expected frame or milliseconds", s)),
+            },
+        }
+    }
+}
+
+pub struct Refragmenter {
+    target: FragmentTarget,
+    /// Track defaults of the current init segment
+    tracks: HashMap<u32, TrackInfo>,
+    /// Track of the pending samples
+    track_id: u32,
+    pending: Vec<Sample>,
+    /// Decode time of the first pending sample
+    pending_start: u64,
+    /// Decode time after the last sample taken in
+    next_decode_time: u64,
+    /// mfhd sequence number of the next fragment
+    sequence: u32,
+}
+
+impl Refragmenter {
+    pub fn new(target: FragmentTarget) -> Self {
+        Self {
+            target,
+            tracks: HashMap::new(),
+            track_id: 0,
+            pending: Vec::new(),
+            pending_start: 0,
+            next_decode_time: 0,
+            sequence: 1,
+        }
+    }
+
+    /// Segments ready to send after `segment`. Media that can't be re-cut,
+    /// like fragments of several tracks, is passed on as it is.
+    pub fn push(&mut self, segment: Mp4Segment) -> Vec<Mp4Segment> {
+        let mut out = Vec::new();
+        if segment.kind == SegmentType::Init {
+            out.extend(self.flush());
+            self.tracks = track_info(&segment.data);
+            self.sequence = 1;
+            self.next_decode_time = 0;
+            out.push(segment);
+            return out;
+        }
+
+        let Some((track_id, decode_time, samples)) = single_track_samples(&segment.data, &self.tracks) else {
+            out.extend(self.flush());
+            out.push(segment);
+            return out;
+        };
+        // A fragment without tfdt follows on from the previous one
+        let mut decode_time = decode_time.unwrap_or(self.next_decode_time);
+        if track_id != self.track_id || decode_time != self.next_decode_time {
+            out.extend(self.flush());
+            self.track_id = track_id;
+        }
+
+        let target_ticks = self.target_ticks();
+        let whole_gops = matches!(self.target, FragmentTarget::Gop(_));
+        for sample in samples {
+            if sample.keyframe && (!whole_gops || decode_time.saturating_sub(self.pending_start) >= target_ticks) {
+                out.extend(self.flush());
+            }
+            if self.pending.is_empty() {
+                self.pending_start = decode_time;
+            }
+            decode_time += sample.duration as u64;
+            self.pending.push(sample);
+            if !whole_gops && decode_time - self.pending_start >= target_ticks {
+                out.extend(self.flush());
+            }
+        }
+        self.next_decode_time = decode_time;
+        out
+    }
+
+    /// The samples still held back, as one fragment.
+    pub fn flush(&mut self) -> Option<Mp4Segment> {
+        let timescale = self.tracks.get(&self.track_id).map_or(0, |t| t.timescale);
+        let segment = write_fragment(self.sequence, self.track_id, self.pending_start, timescale, &self.pending)?;
+        self.pending.clear();
+        self.sequence = self.sequence.wrapping_add(1);
+        Some(segment)
+    }
+
+    /// Target length in track ticks. Without a timescale every frame is a fragment.
+    fn target_ticks(&self) -> u64 {
+        let timescale = self.tracks.get(&self.track_id).map_or(0, |t| t.timescale) as u128;
+        match self.target {
+            FragmentTarget::Frame => 0,
+            FragmentTarget::Duration(duration) | FragmentTarget::Gop(duration) => (duration.as_nanos() * timescale / 1_000_000_000) as u64,
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::{fragment_timing, read_u32, track_fragments, Fmp4Muxer, VideoTrack};
+    use crate::testing::{h264_pps, h264_sps};
+
+    fn sample(payload: u8, keyframe: bool) -> Sample {
+        Sample { data: vec![0, 0, 0, 1, payload], duration: 3000, keyframe, composition_offset: 0 }
+    }
+
+    /// An init segment, then fragments of the given keyframe patterns.
+    fn encoded(fragments: &[&[bool]]) -> Vec<Mp4Segment> {
+        let mut muxer = Fmp4Muxer::new(VideoTrack::new(h264_sps(320, 240), h264_pps(), 90_000).unwrap());
+        let mut payload = 0;
+        let mut segments = vec![muxer.init_segment()];
+        for keyframes in fragments {
+            let samples: Vec<Sample> = keyframes.iter().map(|&k| {
+                payload += 1;
+                sample(payload, k)
+            }).collect();
+            segments.extend(muxer.fragment(&samples));
+        }
+        segments
+    }
+
+    fn recut(target: FragmentTarget, segments: Vec<Mp4Segment>) -> Vec<Mp4Segment> {
+        let mut refragmenter = Refragmenter::new(target);
+        let mut out: Vec<Mp4Segment> = segments.into_iter().flat_map(|s| refragmenter.push(s)).collect();
+        out.extend(refragmenter.flush());
+        out
+    }
+
+    /// (sequence, decode time, sample count, keyframe) of each media segment
+    fn layout(segments: &[Mp4Segment]) -> Vec<(u32, u64, usize, bool)> {
+        let tracks = track_info(&segments[0].data);
+        segments[1..]
+            .iter()
+            .map(|s| {
+                let fragment = &track_fragments(&s.data, &tracks)[0];
+                let timing = fragment_timing(&s.data, &tracks)[0];
+                // moof and mfhd headers, then the mfhd version and flags
+                let sequence = read_u32(&s.data, 20).unwrap();
+                (sequence, timing.decode_time.unwrap(), fragment.samples.len(), s.is_keyframe())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_into_single_frames() {
+        let out = recut(FragmentTarget::Frame, encoded(&[&[true, false, false], &[false]]));
+        assert_eq!(out[0].kind, SegmentType::Init);
+        assert_eq!(layout(&out), [(1, 0, 1, true), (2, 3000, 1, false), (3, 6000, 1, false), (4, 9000, 1, false)]);
+
+        // Sample data survives the trip
+        let tracks = track_info(&out[0].data);
+        let record = track_fragments(&out[2].data, &tracks)[0].samples[0];
+        assert_eq!(out[2].data[record.offset..record.offset + record.size as usize], [0, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn merges_up_to_the_target_and_cuts_at_keyframes() {
+        // 3000 ticks is 33 ms, so 100 ms takes three frames
+        let target = FragmentTarget::Duration(Duration::from_millis(100));
+        let frames: [&[bool]; 6] = [&[true], &[false], &[false], &[false], &[true], &[false]];
+        let out = recut(target, encoded(&frames));
+        assert_eq!(layout(&out), [(1, 0, 3, true), (2, 9000, 1, false), (3, 12000, 2, true)]);
+        assert_eq!(out[3].fragment.unwrap().duration, 6000);
+    }
+
+    #[test]
+    fn keeps_groups_of_pictures_whole() {
+        // Keyframes every three frames; 150 ms needs two groups
+        let target = FragmentTarget::Gop(Duration::from_millis(150));
+        let frames: [&[bool]; 9] = [&[true], &[false], &[false], &[true], &[false], &[false], &[true], &[false], &[false]];
+        let out = recut(target, encoded(&frames));
+        assert_eq!(layout(&out), [(1, 0, 6, true), (2, 18000, 3, true)]);
+    }
+
+    #[test]
+    fn starts_over_at_a_new_init() {
+        let mut segments = encoded(&[&[true, false]]);
+        segments.extend(encoded(&[&[true]]));
+        let out = recut(FragmentTarget::Duration(Duration::from_secs(1)), segments);
+        let kinds: Vec<SegmentType> = out.iter().map(|s| s.kind).collect();
+        assert_eq!(kinds, [SegmentType::Init, SegmentType::Media, SegmentType::Init, SegmentType::Media]);
+        assert_eq!(layout(&out[2..]), [(1, 0, 1, true)]);
+    }
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!("frame".parse(), Ok(FragmentTarget::Frame));
+        assert_eq!("250".parse(), Ok(FragmentTarget::Duration(Duration::from_millis(250))));
+        assert_eq!("500ms".parse(), Ok(FragmentTarget::Duration(Duration::from_millis(500))));
+        assert!("0".parse::<FragmentTarget>().is_err());
+        assert!("fast".parse::<FragmentTarget>().is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+
+use log::debug;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Duration};
+use url::{Host, Url};
+
+/// Head start each connection attempt gets before the next address is tried (RFC 8305 §5).
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Give up on a single address after this long.
+pub const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The host of `url` in the form DNS, TLS and proxies expect: IPv6 literals without brackets.
+pub fn connect_host(url: &Url) -> Option<String> {
+    match url.host()? {
+        Host::Domain(d) => Some(d.to_string()),
+        Host::Ipv4(ip) => Some(ip.to_string()),
+        Host::Ipv6(ip) => Some(ip.to_string()),
+    }
+}
+
+/// Set query parameter `name` to `value`, replacing any the URL already has
+/// and keeping the other parameters in order.
+pub fn set_query_pair(url: &mut Url, name: &str, value: &str) {
+    let others: Vec<(String, String)> = url.query_pairs().filter(|(k, _)| k != name).map(|(k, v)| (k.into_owned(), v.into_owned())).collect();
+    url.query_pairs_mut().clear().extend_pairs(others).append_pair(name, value);
+}
+
+/// Resolve every A and AAAA record for `host`. IP literals are returned as-is.
+pub async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", host)));
+    }
+    Ok(addrs)
+}
+
+/// Order addresses for connecting: alternate between address families, starting
+/// with whichever family the resolver listed first (RFC 8305 §4).
+pub fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else { return Vec::new() };
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) =
+        addrs.iter().copied().partition(|a| a.is_ipv6() == first.is_ipv6());
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        ordered.extend(preferred.pop_front());
+        ordered.extend(other.pop_front());
+    }
+    ordered
+}
+
+/// Happy-eyeballs connect: start with the first address, then start the next one
+/// every `ATTEMPT_DELAY` (or immediately when an attempt fails) and keep whichever
+/// connects first.
+pub async fn connect_any(addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut queue: VecDeque<SocketAddr> = interleave(addrs).into();
+    let mut attempts = JoinSet::new();
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to");
+
+    loop {
+        if let Some(addr) = queue.pop_front() {
+            debug!("Connecting to {}", addr);
+            attempts.spawn(async move {
+                match timeout(ATTEMPT_TIMEOUT, TcpStream::connect(addr)).await {
+                    Ok(result) => result.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e))),
+                    Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("{}: timed out", addr))),
+                }
+            });
+        }
+        if attempts.is_empty() {
+            return Err(last_error);
+        }
+
+        tokio::select! {
+            Some(joined) = attempts.join_next() => match joined {
+                Ok(Ok(stream)) => {
+                    // Dropping the set aborts the slower attempts
+                    return Ok(stream);
+                }
+                Ok(Err(e)) => {
+                    debug!("Connection attempt failed: {}", e);
+                    last_error = e;
+                }
+                Err(e) => last_error = io::Error::other(e),
+            },
+            _ = sleep(ATTEMPT_DELAY), if !queue.is_empty() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn strips_brackets_from_ipv6_hosts() {
+        let host = |url: &str| connect_host(&Url::parse(url).unwrap());
+        assert_eq!(host("wss://[2001:db8::1]:8443/stream").as_deref(), Some("2001:db8::1"));
+        assert_eq!(host("ws://127.0.0.1:3000").as_deref(), Some("127.0.0.1"));
+        assert_eq!(host("wss://Stream.Example.com/").as_deref(), Some("stream.example.com"));
+        assert_eq!(host("data:text/plain,hi"), None);
+    }
+
+    #[test]
+    fn replaces_query_parameters() {
+        let mut url = Url::parse("wss://example.com/stream?session=old&room=a%20b&session=older").unwrap();
+        set_query_pair(&mut url, "session", "s 1");
+        assert_eq!(url.as_str(), "wss://example.com/stream?room=a+b&session=s+1");
+
+        let mut url = Url::parse("ws://localhost:3000").unwrap();
+        set_query_pair(&mut url, "session", "s1");
+        assert_eq!(url.as_str(), "ws://localhost:3000/?session=s1");
+    }
+
+    #[test]
+    fn interleaves_address_families() {
+        let mixed = addrs(&["[2001:db8::1]:443", "[2001:db8::2]:443", "[2001:db8::3]:443", "192.0.2.1:443", "192.0.2.2:443"]);
+        assert_eq!(
+            interleave(&mixed),
+            addrs(&["[2001:db8::1]:443", "192.0.2.1:443", "[2001:db8::2]:443", "192.0.2.2:443", "[2001:db8::3]:443"])
+        );
+        // The family listed first goes first
+        let v4_first = addrs(&["192.0.2.1:443", "[2001:db8::1]:443"]);
+        assert_eq!(interleave(&v4_first), v4_first);
+        let single_family = addrs(&["192.0.2.1:443", "192.0.2.2:443"]);
+        assert_eq!(interleave(&single_family), single_family);
+        assert!(interleave(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn connects_to_whichever_address_answers() {
+        assert_eq!(resolve("::1", 80).await.unwrap(), addrs(&["[::1]:80"]));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        // A port that was just free, so the first attempt is refused
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let stream = connect_any(&[dead, live]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), live);
+        assert!(connect_any(&[dead]).await.is_err());
+        assert_eq!(connect_any(&[]).await.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
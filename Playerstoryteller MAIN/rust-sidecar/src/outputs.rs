@@ -0,0 +1,110 @@
+use std::sync::Arc;
+
+use futures_util::future::{join_all, select_all};
+use log::info;
+
+use crate::mp4::Mp4Segment;
+use crate::secret;
+use crate::websocket::{ConnectError, WebSocketManager};
+
+/// How several `--url`s are used.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Stream to the first URL, switching to the next one while it is unreachable
+    #[default]
+    Failover,
+    /// Stream to every URL at once
+    Fanout,
+}
+
+/// The set of destinations segments are delivered to. Each destination has its
+/// own connection and queue, so a slow or dead one never holds up the others.
+pub struct Outputs {
+    destinations: Vec<Arc<WebSocketManager>>,
+}
+
+impl Outputs {
+    /// `build` turns a primary URL into a configured destination.
+    pub fn new<F>(urls: &[String], mode: OutputMode, build: F) -> Self
+    where
+        F: Fn(String) -> WebSocketManager,
+    {
+        let destinations = match mode {
+            OutputMode::Failover => {
+                let (primary, backups) = urls.split_first().expect("at least one URL");
+                vec![build(primary.clone()).backup_urls(backups.to_vec())]
+            }
+            OutputMode::Fanout => urls.iter().map(|url| build(url.clone())).collect(),
+        };
+        for url in urls {
+            info!("Stream destination ({:?}): {}", mode, secret::redact_url(url));
+        }
+        Self { destinations: destinations.into_iter().map(Arc::new).collect() }
+    }
+
+    pub fn send(&self, segment: Mp4Segment) {
+        if let Some((last, rest)) = self.destinations.split_last() {
+            for destination in rest {
+                destination.enqueue(segment.clone());
+            }
+            last.enqueue(segment);
+        }
+    }
+
+    /// Resolves as soon as any destination is connected.
+    pub async fn wait_for_connection(&self) {
+        select_all(self.destinations.iter().map(|d| Box::pin(d.wait_for_connection()))).await;
+    }
+
+    /// Flush and close every destination. `run` then returns `Ok(())`.
+    pub fn shutdown(&self) {
+        for destination in &self.destinations {
+            destination.shutdown();
+        }
+    }
+
+    /// Drive every destination's connect loop. Only returns once all of them
+    /// have given up, with the last fatal error.
+    pub async fn run(&self) -> Result<(), ConnectError> {
+        let results = join_all(self.destinations.iter().map(|d| d.connect_loop())).await;
+        results.into_iter().rev().find(|r| r.is_err()).unwrap_or(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::StreamKey;
+    use crate::testing::{init_segment, media_segment, tag, Behavior, TestServer};
+    use tokio::time::{Duration, Instant};
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_destination_does_not_hold_up_fanout() {
+        let mut fast = TestServer::start().await;
+        let mut slow = TestServer::start().await;
+        slow.push_behavior(Behavior::SlowRead(Duration::from_secs(1)));
+
+        let urls = [fast.url("/stream"), slow.url("/stream")];
+        let outputs = Arc::new(Outputs::new(&urls, OutputMode::Fanout, |url| {
+            WebSocketManager::new(url, StreamKey::new("key".to_string()), "fanout".to_string())
+        }));
+        let run = outputs.clone();
+        tokio::spawn(async move { run.run().await });
+        fast.wait_for_connections(1).await;
+        slow.wait_for_connections(1).await;
+
+        let start = Instant::now();
+        outputs.send(init_segment(1));
+        for i in 0..3 {
+            outputs.send(media_segment(10 + i, 100));
+        }
+        for expected in [1, 10, 11, 12] {
+            assert_eq!(tag(&fast.recv().await.data), expected);
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        for expected in [1, 10, 11, 12] {
+            assert_eq!(tag(&slow.recv().await.data), expected);
+        }
+    }
+}
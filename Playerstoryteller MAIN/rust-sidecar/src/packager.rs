@@ -0,0 +1,176 @@
+//! Packages encoder access units as fMP4 segments for the outputs.
+//!
+//! Parameter sets are taken from the first keyframe for the init segment and
+//! stripped from samples. Each access unit becomes its own fragment as soon as
+//! the next one tells us its duration.
+
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use crate::encoder::EncodedFrame;
+use crate::h264::{nal_units, NalKind};
+use crate::mp4::{Fmp4Muxer, Mp4Segment, Sample, VideoTrack};
+
+/// Track ticks per second, the usual one for video
+pub const TIMESCALE: u32 = 90_000;
+
+pub struct Packager {
+    /// Duration of the last access unit, which has no successor to measure by
+    frame_duration: u64,
+    muxer: Option<Fmp4Muxer>,
+    /// Waiting for the next access unit's decode time
+    pending: Option<EncodedFrame>,
+    /// Access units dropped while waiting for the first keyframe
+    skipped: u64,
+}
+
+impl Packager {
+    /// `frame_rate` is the nominal rate the encoder was set up with.
+    pub fn new(frame_rate: u32) -> Self {
+        Self { frame_duration: TIMESCALE as u64 / frame_rate.max(1) as u64, muxer: None, pending: None, skipped: 0 }
+    }
+
+    /// Segments completed by this access unit: the init segment once, then
+    /// the fragment of the previous access unit.
+    pub fn push(&mut self, frame: EncodedFrame) -> Vec<Mp4Segment> {
+        let mut segments = Vec::new();
+        if self.muxer.is_none() {
+            let track = parameter_sets(&frame).map(|(sps, pps)| VideoTrack::new(sps, pps, TIMESCALE));
+            match track {
+                Some(Ok(track)) => {
+                    if self.skipped > 0 {
+                        debug!("Skipped {} access units before the first keyframe", self.skipped);
+                    }
+                    info!(
+                        "Packaging H.264 as fMP4: {}x{}, {} {}",
+                        track.width,
+                        track.height,
+                        track.sps.profile_name(),
+                        track.sps.level_name()
+                    );
+                    if let Some(frame_rate) = track.sps.frame_rate() {
+                        self.frame_duration = (TIMESCALE as f64 / frame_rate).round() as u64;
+                    }
+                    let muxer = Fmp4Muxer::new(track);
+                    segments.push(muxer.init_segment());
+                    self.muxer = Some(muxer);
+                }
+                Some(Err(e)) => {
+                    warn!("Unusable parameter sets, waiting for the next keyframe: {}", e);
+                    self.skipped += 1;
+                    return segments;
+                }
+                None => {
+                    // Nothing can be decoded until there is a keyframe
+                    self.skipped += 1;
+                    return segments;
+                }
+            }
+        }
+
+        let next_dts = frame.dts;
+        if let Some(previous) = self.pending.replace(frame) {
+            let duration = ticks(next_dts).saturating_sub(ticks(previous.dts));
+            segments.extend(self.package(previous, duration));
+        }
+        segments
+    }
+
+    /// Package the last access unit at the nominal frame duration.
+    pub fn finish(&mut self) -> Vec<Mp4Segment> {
+        match self.pending.take() {
+            Some(last) => self.package(last, self.frame_duration).into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn package(&mut self, frame: EncodedFrame, duration: u64) -> Option<Mp4Segment> {
+        let muxer = self.muxer.as_mut()?;
+        let sample = Sample {
+            data: length_prefixed(&frame.data),
+            // At least one tick, so decode times keep increasing
+            duration: duration.clamp(1, u32::MAX as u64) as u32,
+            keyframe: frame.keyframe,
+            composition_offset: (ticks(frame.pts) as i64 - ticks(frame.dts) as i64) as i32,
+        };
+        muxer.fragment(&[sample])
+    }
+}
+
+fn ticks(time: Duration) -> u64 {
+    (time.as_nanos() * TIMESCALE as u128 / 1_000_000_000) as u64
+}
+
+/// SPS and PPS of a keyframe
+fn parameter_sets(frame: &EncodedFrame) -> Option<(Vec<u8>, Vec<u8>)> {
+    if !frame.keyframe {
+        return None;
+    }
+    let sps = nal_units(&frame.data).find(|u| NalKind::of(u) == NalKind::Sps)?;
+    let pps = nal_units(&frame.data).find(|u| NalKind::of(u) == NalKind::Pps)?;
+    Some((sps.to_vec(), pps.to_vec()))
+}
+
+/// Annex-B to four-byte length prefixes. Parameter sets live in the init
+/// segment and delimiters mean nothing in MP4, so both are dropped.
+fn length_prefixed(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let in_band = |u: &[u8]| matches!(NalKind::of(u), NalKind::Sps | NalKind::Pps | NalKind::Aud);
+    for unit in nal_units(data).filter(|u| !u.is_empty() && !in_band(u)) {
+        out.extend_from_slice(&(unit.len() as u32).to_be_bytes());
+        out.extend_from_slice(unit);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::{fragment_timing, sps_dimensions, track_info, SegmentType};
+    use crate::testing::{h264_pps, h264_sps};
+
+    fn unit(millis: u64, parameter_sets: Option<&[Vec<u8>]>) -> EncodedFrame {
+        let mut data = Vec::new();
+        for nal in parameter_sets.unwrap_or_default() {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend_from_slice(nal);
+        }
+        let keyframe = parameter_sets.is_some();
+        data.extend_from_slice(&[0, 0, 1, if keyframe { 0x65 } else { 0x41 }, 0xAB, 0]);
+        let time = Duration::from_millis(millis);
+        EncodedFrame { data, pts: time, dts: time, keyframe }
+    }
+
+    #[test]
+    fn packages_from_the_first_usable_keyframe() {
+        let parameter_sets = [h264_sps(642, 360), h264_pps()];
+        let mut packager = Packager::new(30);
+        assert!(packager.push(unit(0, None)).is_empty());
+        assert!(packager.push(unit(16, Some(&[vec![0x67, 0x42], h264_pps()]))).is_empty());
+
+        let first = packager.push(unit(33, Some(&parameter_sets)));
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].kind, SegmentType::Init);
+        let tracks = track_info(&first[0].data);
+        assert_eq!(tracks.values().next().unwrap().timescale, TIMESCALE);
+        // The size comes from the SPS, cropping included
+        assert_eq!(sps_dimensions(&first[0].data), Some((642, 360)));
+
+        let mut media = packager.push(unit(83, None));
+        media.extend(packager.finish());
+        assert!(packager.finish().is_empty());
+
+        let timings: Vec<(Option<u64>, u64)> = media
+            .iter()
+            .map(|s| {
+                assert_eq!(s.kind, SegmentType::Media);
+                let timing = fragment_timing(&s.data, &tracks)[0];
+                (timing.decode_time, timing.duration)
+            })
+            .collect();
+        assert_eq!(timings, [(Some(0), 4500), (Some(4500), 3000)]);
+        // Parameter sets were stripped from the keyframe sample
+        assert!(media[0].data.ends_with(&[b'm', b'd', b'a', b't', 0, 0, 0, 2, 0x65, 0xAB]));
+    }
+}
@@ -0,0 +1,142 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Plain `ws://` and `rtmp://` carry the stream key (or at least the stream) in
+/// clear text, so they are only allowed towards loopback and private networks
+/// unless overridden.
+#[derive(thiserror::Error, Debug)]
+pub enum PolicyError {
+    #[error("Refusing to stream over unencrypted {scheme}:// to {host} ({addr} is a public address). Use {scheme}s:// or pass --allow-insecure-transport.")]
+    InsecureRemote { scheme: String, host: String, addr: IpAddr },
+    #[error("Refusing to stream over unencrypted {scheme}:// to {host}: it could not be resolved to check that it is local.")]
+    Unresolved { scheme: String, host: String },
+    #[error("Refusing to stream over unencrypted {scheme}:// to {host} through a proxy, which resolves it out of reach of the local address check. Use {scheme}s:// or pass --allow-insecure-transport.")]
+    Proxied { scheme: String, host: String },
+    #[error("Unsupported URL scheme '{0}' (expected ws or wss)")]
+    UnsupportedScheme(String),
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TransportPolicy {
+    pub allow_insecure: bool,
+}
+
+impl TransportPolicy {
+    /// Check a connection target. `addrs` are the resolved addresses of `host`;
+    /// every one of them must be local for plain ws:// or rtmp:// to be allowed.
+    pub fn check(&self, scheme: &str, host: &str, addrs: &[SocketAddr]) -> Result<(), PolicyError> {
+        match scheme {
+            "wss" | "rtmps" => Ok(()),
+            "ws" | "rtmp" if self.allow_insecure => Ok(()),
+            "ws" | "rtmp" => {
+                if addrs.is_empty() {
+                    return Err(PolicyError::Unresolved { scheme: scheme.to_string(), host: host.to_string() });
+                }
+                match addrs.iter().find(|a| !is_local_address(a.ip())) {
+                    Some(public) => Err(PolicyError::InsecureRemote { scheme: scheme.to_string(), host: host.to_string(), addr: public.ip() }),
+                    None => Ok(()),
+                }
+            }
+            other => Err(PolicyError::UnsupportedScheme(other.to_string())),
+        }
+    }
+
+    /// Check a target reached through a proxy, which resolves `host` itself.
+    /// Only an IP literal can then be known to be local.
+    pub fn check_proxied(&self, scheme: &str, host: &str) -> Result<(), PolicyError> {
+        match host.parse::<IpAddr>() {
+            Ok(ip) => self.check(scheme, host, &[SocketAddr::new(ip, 0)]),
+            Err(_) => self.check(scheme, host, &[]).map_err(|e| match e {
+                PolicyError::Unresolved { scheme, host } => PolicyError::Proxied { scheme, host },
+                other => other,
+            }),
+        }
+    }
+}
+
+/// Loopback, RFC 1918 / unique-local, link-local and CGNAT (Tailscale et al.) ranges.
+pub fn is_local_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_local_v4(v4),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_local_v4(v4),
+            None => is_local_v6(v6),
+        },
+    }
+}
+
+fn is_local_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        // 100.64.0.0/10 shared address space
+        || (a == 100 && (64..128).contains(&b))
+}
+
+fn is_local_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        // fc00::/7 unique local
+        || (first & 0xfe00) == 0xfc00
+        // fe80::/10 link local
+        || (first & 0xffc0) == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(ips: &[&str]) -> Vec<SocketAddr> {
+        ips.iter().map(|ip| SocketAddr::new(ip.parse().unwrap(), 3000)).collect()
+    }
+
+    #[test]
+    fn recognises_local_addresses() {
+        let local = [
+            "127.0.0.1", "::1", "10.1.2.3", "172.16.0.1", "172.31.255.255", "192.168.1.20", "169.254.10.1", "100.64.0.1",
+            "100.127.255.255", "fd12:3456::1", "fc00::1", "fe80::1", "::ffff:192.168.1.20",
+        ];
+        for ip in local {
+            assert!(is_local_address(ip.parse().unwrap()), "{} is local", ip);
+        }
+        let public = ["8.8.8.8", "172.32.0.1", "100.128.0.1", "2606:4700::1111", "fec0::1", "::ffff:8.8.8.8"];
+        for ip in public {
+            assert!(!is_local_address(ip.parse().unwrap()), "{} is public", ip);
+        }
+    }
+
+    #[test]
+    fn allows_plaintext_only_to_local_hosts() {
+        let policy = TransportPolicy::default();
+        assert!(policy.check("ws", "localhost", &addrs(&["127.0.0.1", "::1"])).is_ok());
+        assert!(policy.check("rtmp", "nas.lan", &addrs(&["192.168.1.20"])).is_ok());
+        assert!(policy.check("wss", "example.com", &addrs(&["93.184.216.34"])).is_ok());
+        assert!(policy.check("rtmps", "example.com", &[]).is_ok());
+
+        // One public address among private ones is enough to refuse
+        match policy.check("ws", "split.example", &addrs(&["10.0.0.5", "93.184.216.34"])) {
+            Err(PolicyError::InsecureRemote { addr, .. }) => assert_eq!(addr, "93.184.216.34".parse::<IpAddr>().unwrap()),
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(policy.check("ws", "nowhere.invalid", &[]), Err(PolicyError::Unresolved { .. })));
+        assert!(matches!(policy.check("http", "localhost", &addrs(&["127.0.0.1"])), Err(PolicyError::UnsupportedScheme(_))));
+    }
+
+    #[test]
+    fn checks_proxied_targets_without_resolving_them() {
+        let policy = TransportPolicy::default();
+        assert!(policy.check_proxied("wss", "example.com").is_ok());
+        assert!(policy.check_proxied("ws", "192.168.1.20").is_ok());
+        assert!(matches!(policy.check_proxied("ws", "93.184.216.34"), Err(PolicyError::InsecureRemote { .. })));
+        assert!(matches!(policy.check_proxied("ws", "nas.lan"), Err(PolicyError::Proxied { .. })));
+        assert!(TransportPolicy { allow_insecure: true }.check_proxied("ws", "example.com").is_ok());
+    }
+
+    #[test]
+    fn allow_insecure_overrides_the_check() {
+        let policy = TransportPolicy { allow_insecure: true };
+        assert!(policy.check("ws", "example.com", &addrs(&["93.184.216.34"])).is_ok());
+        assert!(policy.check("rtmp", "nowhere.invalid", &[]).is_ok());
+        assert!(matches!(policy.check("ftp", "example.com", &[]), Err(PolicyError::UnsupportedScheme(_))));
+    }
+}
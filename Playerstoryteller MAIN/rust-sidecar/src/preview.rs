@@ -0,0 +1,226 @@
+//! `--preview <port>`: a minimal MSE player on `http://127.0.0.1:<port>/`, fed
+//! from the same segments that go to the streaming server.
+//!
+//! One port serves both the page (`GET /`) and the WebSocket (`GET /stream`).
+//! Late joiners get the cached init segment first, like viewers of the relay.
+
+use std::io::{self, Cursor};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use log::{info, warn};
+use parking_lot::Mutex;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::accept_hdr_async;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+
+use crate::encoder::KeyframeRequest;
+use crate::http;
+use crate::mp4::Mp4Segment;
+use crate::relay::{self, Audience};
+
+const PLAYER_PAGE: &str = include_str!("preview.html");
+
+pub struct PreviewServer {
+    audience: Mutex<Audience>,
+    keyframes: Option<KeyframeRequest>,
+}
+
+impl PreviewServer {
+    pub fn new() -> Self {
+        Self { audience: Mutex::new(Audience::new(relay::MAX_BUFFERED_AMOUNT)), keyframes: None }
+    }
+
+    /// Ask for a keyframe whenever a viewer joins, so its player can start
+    /// without waiting for the next one.
+    pub fn keyframe_request(mut self, keyframes: KeyframeRequest) -> Self {
+        self.keyframes = Some(keyframes);
+        self
+    }
+
+    pub fn publish(&self, segment: &Mp4Segment) {
+        self.audience.lock().publish(segment.data.clone());
+    }
+
+    /// Serve on localhost only: the preview has no authentication.
+    pub async fn run(self: Arc<Self>, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port))).await?;
+        info!("Preview available at http://{}/", listener.local_addr()?);
+        self.serve(listener).await
+    }
+
+    async fn serve(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        http::serve(listener, "Preview", move |stream| {
+            let preview = self.clone();
+            async move { preview.handle_connection(stream).await }
+        })
+        .await
+    }
+
+    async fn handle_connection(&self, mut stream: TcpStream) -> io::Result<()> {
+        let Some(head) = http::read_head(&mut stream).await? else { return Ok(()) };
+        if !head.header("host").is_some_and(|host| local_host(host, stream.local_addr().map_or(0, |a| a.port()))) {
+            warn!("Preview: refused a request for another host");
+            return respond(&mut stream, "403 Forbidden", "text/plain", "Forbidden").await;
+        }
+        let websocket = head.header("sec-websocket-key").is_some();
+        match (head.path.as_str(), websocket) {
+            ("/stream", true) => self.serve_websocket(stream, head.into_raw()).await,
+            ("/", _) | ("/index.html", _) => respond(&mut stream, "200 OK", "text/html; charset=utf-8", PLAYER_PAGE).await,
+            _ => respond(&mut stream, "404 Not Found", "text/plain", "Not Found").await,
+        }
+    }
+
+    async fn serve_websocket(&self, stream: TcpStream, head: Vec<u8>) -> io::Result<()> {
+        // The handshake reads the request itself, so it gets the head back first
+        let (reader, writer) = stream.into_split();
+        let stream = tokio::io::join(Cursor::new(head).chain(reader), writer);
+        // The error type is fixed by tungstenite
+        #[allow(clippy::result_large_err)]
+        let callback = |request: &Request, response: Response| {
+            if same_origin(request) {
+                Ok(response)
+            } else {
+                warn!("Preview: refused a WebSocket from another origin");
+                Err(relay::reject(StatusCode::FORBIDDEN))
+            }
+        };
+        let ws = accept_hdr_async(stream, callback).await.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut handle = self.audience.lock().join();
+        if let Some(keyframes) = &self.keyframes {
+            keyframes.request();
+        }
+        info!("Preview viewer connected ({} watching)", self.audience.lock().len());
+        relay::serve_viewer(ws, &mut handle).await;
+        self.audience.lock().leave(handle.id);
+        info!("Preview viewer disconnected");
+        Ok(())
+    }
+}
+
+/// Whether `host` names this machine on `port`. A page on another site can
+/// point its own name at 127.0.0.1 (DNS rebinding), and its requests then
+/// carry that name: only the loopback names are served.
+fn local_host(host: &str, port: u16) -> bool {
+    // Browsers leave out the default port
+    let (name, host_port) = match host.rsplit_once(':') {
+        Some((name, host_port)) if !host_port.ends_with(']') => (name, host_port.parse().ok()),
+        _ => (host, Some(80)),
+    };
+    host_port == Some(port) && (name.eq_ignore_ascii_case("localhost") || name == "127.0.0.1" || name == "[::1]")
+}
+
+/// Browsers send an `Origin` with every WebSocket, and any page they have open
+/// may try this port: only the player page itself is let in. Clients without
+/// an `Origin` are not browsers and are trusted like any local process.
+fn same_origin(request: &Request) -> bool {
+    let header = |name: &str| request.headers().get(name).and_then(|v| v.to_str().ok());
+    match (header("origin"), header("host")) {
+        (None, _) => true,
+        (Some(origin), Some(host)) => origin.strip_prefix("http://").is_some_and(|origin| origin.eq_ignore_ascii_case(host)),
+        (Some(_), None) => false,
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> io::Result<()> {
+    http::respond(stream, status, content_type, &[("Cache-Control", "no-store")], body.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use futures_util::StreamExt;
+    use tokio_tungstenite::connect_async;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::protocol::Message;
+    use tokio_tungstenite::tungstenite::Error as WsError;
+
+    use super::*;
+    use crate::testing::h264_stream;
+
+    async fn start() -> (Arc<PreviewServer>, SocketAddr) {
+        let preview = Arc::new(PreviewServer::new().keyframe_request(KeyframeRequest::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(preview.clone().serve(listener));
+        (preview, addr)
+    }
+
+    fn request(addr: SocketAddr, origin: &str) -> Request {
+        let mut request = format!("ws://{}/stream", addr).into_client_request().unwrap();
+        request.headers_mut().insert("origin", origin.parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn serves_the_page_and_streams_to_it() {
+        let (preview, addr) = start().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", addr).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(PLAYER_PAGE));
+
+        let segments = h264_stream(2);
+        preview.publish(&segments[0]);
+        let (mut ws, _) = connect_async(request(addr, &format!("http://{}", addr))).await.unwrap();
+        // Joining after the init went out: it is replayed before the media.
+        // Joining also asks the encoder for a keyframe, so wait for that.
+        let joined = async {
+            while !preview.keyframes.as_ref().unwrap().take() {
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), joined).await.unwrap();
+        preview.publish(&segments[1]);
+        for segment in &segments {
+            match ws.next().await {
+                Some(Ok(Message::Binary(data))) => assert_eq!(data, segment.data),
+                other => panic!("expected a segment, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_websockets_from_other_origins() {
+        let (_preview, addr) = start().await;
+        match connect_async(request(addr, "https://example.com")).await {
+            Err(WsError::Http(response)) => assert_eq!(response.status(), StatusCode::FORBIDDEN),
+            other => panic!("expected a refusal, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_rebound_host_names() {
+        let (_preview, addr) = start().await;
+        // evil.example resolving to 127.0.0.1: Origin and Host agree, but name another host
+        let rebound = format!("evil.example:{}", addr.port());
+        let mut request = request(addr, &format!("http://{}", rebound));
+        request.headers_mut().insert("host", rebound.parse().unwrap());
+        match connect_async(request).await {
+            Err(WsError::Http(response)) => assert_eq!(response.status(), StatusCode::FORBIDDEN),
+            other => panic!("expected a refusal, got {:?}", other.map(|_| ())),
+        }
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", rebound).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    }
+
+    #[test]
+    fn accepts_loopback_host_names() {
+        assert!(local_host("localhost:8090", 8090));
+        assert!(local_host("127.0.0.1:8090", 8090));
+        assert!(local_host("[::1]:8090", 8090));
+        assert!(local_host("LocalHost", 80));
+        assert!(!local_host("localhost:8091", 8090));
+        assert!(!local_host("[::1]", 8090));
+        assert!(!local_host("evil.example:8090", 8090));
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::VecDeque;
+
+use crate::mp4::{Mp4Segment, SegmentType};
+
+/// Default cap on media bytes waiting for one destination (a few seconds of video).
+pub const DEFAULT_QUEUE_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Something queued for a destination whose decoder can only start at a keyframe.
+pub trait Frame {
+    fn size(&self) -> usize;
+
+    /// A decoder can start here
+    fn joinable(&self) -> bool;
+}
+
+impl Frame for Mp4Segment {
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Without fragment info there is no telling, so nothing is held back.
+    fn joinable(&self) -> bool {
+        self.is_keyframe() || self.fragment.is_none()
+    }
+}
+
+/// Frames waiting for a connection, dropped whole groups of pictures at a
+/// time so that what does get sent still decodes.
+///
+/// Once `limit` bytes are waiting the oldest frames go first, up to the next
+/// keyframe. A (re)connect starts at a keyframe too: frames before the next
+/// one are skipped.
+pub struct KeyframeQueue<T> {
+    frames: VecDeque<T>,
+    bytes: usize,
+    limit: usize,
+    /// Frames are skipped until the next keyframe
+    waiting_for_keyframe: bool,
+    /// Frames dropped because the connection couldn't keep up
+    dropped: u64,
+}
+
+impl<T: Frame> KeyframeQueue<T> {
+    pub fn new(limit: usize) -> Self {
+        Self { frames: VecDeque::new(), bytes: 0, limit, waiting_for_keyframe: false, dropped: 0 }
+    }
+
+    pub fn push(&mut self, frame: T) {
+        if self.waiting_for_keyframe && !frame.joinable() {
+            return;
+        }
+        self.waiting_for_keyframe = false;
+        self.bytes += frame.size();
+        self.frames.push_back(frame);
+        // The oldest group of pictures goes first, or what is left of it
+        while self.bytes > self.limit {
+            self.drop_front();
+            self.skip_to_keyframe(true);
+            // Nothing left to continue from
+            self.waiting_for_keyframe = self.frames.is_empty();
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        let frame = self.frames.pop_front()?;
+        self.bytes -= frame.size();
+        Some(frame)
+    }
+
+    /// A new connection begins at a keyframe: what is queued before the next
+    /// one is skipped, and if nothing is queued, so are new frames until one.
+    pub fn restart(&mut self) {
+        self.skip_to_keyframe(false);
+        self.waiting_for_keyframe = self.frames.is_empty();
+    }
+
+    /// Drop everything queued, without counting it as dropped.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.bytes = 0;
+    }
+
+    /// For a new connection that starts live: drop everything queued, and
+    /// new frames up to the next keyframe.
+    pub fn reset(&mut self) {
+        self.clear();
+        self.waiting_for_keyframe = true;
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    #[cfg(test)]
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Frames dropped so far because the connection couldn't keep up.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn drop_front(&mut self) {
+        if self.pop().is_some() {
+            self.dropped += 1;
+        }
+    }
+
+    fn skip_to_keyframe(&mut self, count: bool) {
+        while self.frames.front().is_some_and(|frame| !frame.joinable()) {
+            self.pop();
+            self.dropped += u64::from(count);
+        }
+    }
+}
+
+/// Outgoing segments for one destination.
+///
+/// The init segment is kept aside rather than queued: it is replayed first on
+/// every (re)connect, and a new init invalidates any media still queued for the
+/// old one. Media goes through a `KeyframeQueue`, so after a reconnect or an
+/// overflow the destination still gets whole groups of pictures.
+pub struct SendQueue {
+    init: Option<Vec<u8>>,
+    init_pending: bool,
+    media: KeyframeQueue<Mp4Segment>,
+    /// Media dropped because a new init made it undecodable
+    invalidated: u64,
+}
+
+impl SendQueue {
+    pub fn new(limit: usize) -> Self {
+        Self { init: None, init_pending: false, media: KeyframeQueue::new(limit), invalidated: 0 }
+    }
+
+    pub fn push(&mut self, segment: Mp4Segment) {
+        match segment.kind {
+            SegmentType::Init => {
+                // Media queued for the previous init can't be decoded with the new one
+                self.invalidated += self.media.len() as u64;
+                self.media.clear();
+                self.init = Some(segment.data);
+                self.init_pending = true;
+            }
+            SegmentType::Media => self.media.push(segment),
+        }
+    }
+
+    /// Next payload to send, init first if one is due.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        if self.init_pending {
+            self.init_pending = false;
+            if let Some(init) = &self.init {
+                return Some(init.clone());
+            }
+        }
+        self.media.pop().map(|segment| segment.data)
+    }
+
+    /// A fresh connection knows nothing yet, so the cached init goes out
+    /// first, followed by media from a keyframe on.
+    pub fn on_connected(&mut self) {
+        self.init_pending = self.init.is_some();
+        self.media.restart();
+    }
+
+    #[cfg(test)]
+    pub fn has_init(&self) -> bool {
+        self.init.is_some()
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.media.len() + usize::from(self.init_pending)
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[cfg(test)]
+    pub fn queued_bytes(&self) -> usize {
+        self.media.bytes()
+    }
+
+    /// Media segments dropped so far because of overflow or a new init.
+    pub fn dropped(&self) -> u64 {
+        self.media.dropped() + self.invalidated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{h264_stream, init_segment, media_segment, tag};
+
+    /// Tag, keyframe, size
+    struct TestFrame(u8, bool, usize);
+
+    impl Frame for TestFrame {
+        fn size(&self) -> usize {
+            self.2
+        }
+
+        fn joinable(&self) -> bool {
+            self.1
+        }
+    }
+
+    fn push_all(queue: &mut KeyframeQueue<TestFrame>, frames: &[(u8, bool)]) {
+        for &(tag, keyframe) in frames {
+            queue.push(TestFrame(tag, keyframe, 2));
+        }
+    }
+
+    fn tags(queue: &mut KeyframeQueue<TestFrame>) -> Vec<u8> {
+        std::iter::from_fn(|| queue.pop()).map(|frame| frame.0).collect()
+    }
+
+    #[test]
+    fn overflow_drops_whole_groups_of_pictures() {
+        let mut queue = KeyframeQueue::new(10);
+        push_all(&mut queue, &[(1, true), (2, false), (3, false), (4, true), (5, false), (6, false), (7, true)]);
+        assert_eq!((queue.dropped(), queue.bytes()), (3, 8));
+        assert_eq!(tags(&mut queue), [4, 5, 6, 7]);
+
+        // A frame that cannot fit on its own leaves nothing to continue from
+        queue.push(TestFrame(8, true, 12));
+        push_all(&mut queue, &[(9, false), (10, true), (11, false)]);
+        assert_eq!(tags(&mut queue), [10, 11]);
+    }
+
+    #[test]
+    fn a_restart_or_reset_begins_at_a_keyframe() {
+        let mut queue = KeyframeQueue::new(100);
+        push_all(&mut queue, &[(1, true), (2, false), (3, false), (4, true), (5, false)]);
+        queue.pop();
+        queue.restart();
+        assert_eq!(tags(&mut queue), [4, 5]);
+
+        queue.restart();
+        push_all(&mut queue, &[(6, false), (7, true), (8, false)]);
+        assert_eq!(tags(&mut queue), [7, 8]);
+
+        // A reset starts live, even with a keyframe still queued
+        push_all(&mut queue, &[(9, true), (10, false)]);
+        queue.reset();
+        push_all(&mut queue, &[(11, false), (12, true)]);
+        assert_eq!(tags(&mut queue), [12]);
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn a_reconnect_resumes_media_at_a_keyframe() {
+        let stream = h264_stream(60);
+        let mut queue = SendQueue::new(DEFAULT_QUEUE_LIMIT);
+        stream.iter().take(20).for_each(|segment| queue.push(segment.clone()));
+        // The init and a few fragments went out before the connection dropped
+        for _ in 0..4 {
+            queue.pop();
+        }
+
+        queue.on_connected();
+        assert_eq!(queue.pop(), Some(stream[0].data.clone()));
+        // Frame 30, two frames to a fragment
+        assert!(stream[16].is_keyframe());
+        assert_eq!(queue.pop(), Some(stream[16].data.clone()));
+        assert_eq!(queue.len(), 3);
+    }
+
+    fn drain(queue: &mut SendQueue) -> Vec<u8> {
+        std::iter::from_fn(|| queue.pop()).map(|data| tag(&data)).collect()
+    }
+
+    #[test]
+    fn replays_the_init_on_every_connect() {
+        let mut queue = SendQueue::new(DEFAULT_QUEUE_LIMIT);
+        assert!(queue.is_empty() && !queue.has_init());
+        queue.push(init_segment(1));
+        queue.push(media_segment(2, 100));
+        assert_eq!((queue.len(), queue.queued_bytes()), (2, 100));
+        assert_eq!(drain(&mut queue), [1, 2]);
+
+        queue.on_connected();
+        queue.push(media_segment(3, 100));
+        assert_eq!(drain(&mut queue), [1, 3]);
+        assert!(queue.is_empty() && queue.has_init());
+    }
+
+    #[test]
+    fn drops_the_oldest_media_over_the_limit() {
+        let mut queue = SendQueue::new(250);
+        queue.push(init_segment(1));
+        for t in 2..6 {
+            queue.push(media_segment(t, 100));
+        }
+        assert_eq!((queue.dropped(), queue.queued_bytes()), (2, 200));
+        // The init is kept aside, never dropped
+        assert_eq!(drain(&mut queue), [1, 4, 5]);
+    }
+
+    #[test]
+    fn a_new_init_discards_media_for_the_old_one() {
+        let mut queue = SendQueue::new(DEFAULT_QUEUE_LIMIT);
+        queue.push(init_segment(1));
+        queue.push(media_segment(2, 100));
+        queue.push(media_segment(3, 100));
+        queue.push(init_segment(4));
+        queue.push(media_segment(5, 100));
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&mut queue), [4, 5]);
+    }
+}
@@ -0,0 +1,165 @@
+//! Keeping an outgoing connection up until the stream ends: the stop signal
+//! the outputs share, and the connect, send and retry loop of the RTMP and
+//! SRT outputs.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::error;
+use tokio::sync::Notify;
+use tokio::time::{sleep, Duration};
+
+use crate::status::{self, StatusLevel};
+
+/// Wait between connection attempts.
+pub const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Set once when the stream ends, and seen by every wait on it, before or after.
+#[derive(Default)]
+pub struct StopSignal {
+    stopping: AtomicBool,
+    notify: Notify,
+}
+
+impl StopSignal {
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    pub async fn stopped(&self) {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        // Register before checking so a stop in between isn't missed
+        notified.as_mut().enable();
+        if self.is_stopped() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// A failed connection attempt or session.
+pub trait ConnectionError: fmt::Display {
+    /// Retrying cannot help (a transport policy violation, say)
+    fn is_fatal(&self) -> bool {
+        false
+    }
+}
+
+/// Connect, run a session on the connection and, when either fails, try again
+/// after `RECONNECT_INTERVAL`, until `stop` is signalled. Only a fatal error
+/// ends it early.
+///
+/// Failures are logged as `{destination}: {error}` and reported under
+/// `component`, once per kind of failure rather than on every retry.
+pub async fn supervise<T, E, C, S>(
+    stop: &StopSignal,
+    component: &'static str,
+    destination: &str,
+    mut connect: impl FnMut() -> C,
+    mut session: impl FnMut(T) -> S,
+) -> Result<(), E>
+where
+    E: ConnectionError,
+    C: Future<Output = Result<T, E>>,
+    S: Future<Output = Result<(), E>>,
+{
+    let mut last_error = String::new();
+    loop {
+        let attempt = tokio::select! {
+            attempt = connect() => attempt,
+            _ = stop.stopped() => return Ok(()),
+        };
+        let result = match attempt {
+            Ok(connection) => {
+                last_error.clear();
+                session(connection).await
+            }
+            Err(e) if e.is_fatal() => {
+                error!("{}", e);
+                status::emit(component, StatusLevel::Error, &e.to_string());
+                return Err(e);
+            }
+            Err(e) => Err(e),
+        };
+        if stop.is_stopped() {
+            return Ok(());
+        }
+        if let Err(e) = result {
+            let message = format!("{}: {}", destination, e);
+            error!("{}", message);
+            if message != last_error {
+                status::emit(component, StatusLevel::Warning, &message);
+                last_error = message;
+            }
+        }
+
+        tokio::select! {
+            _ = sleep(RECONNECT_INTERVAL) => {}
+            _ = stop.stopped() => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum TestError {
+        Refused,
+        Forbidden,
+    }
+
+    impl fmt::Display for TestError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{:?}", self)
+        }
+    }
+
+    impl ConnectionError for TestError {
+        fn is_fatal(&self) -> bool {
+            *self == TestError::Forbidden
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_until_stopped_or_fatal() {
+        let stop = StopSignal::default();
+        let attempts = Cell::new(0);
+        let connect = || {
+            attempts.set(attempts.get() + 1);
+            async { if attempts.get() < 3 { Err(TestError::Refused) } else { Ok(()) } }
+        };
+        // The third attempt connects, and the stream ends during its session
+        let session = |()| async {
+            stop.stop();
+            Ok(())
+        };
+        let started = tokio::time::Instant::now();
+        assert_eq!(supervise(&stop, "test", "test", connect, session).await, Ok(()));
+        assert_eq!(attempts.get(), 3);
+        assert_eq!(started.elapsed(), 2 * RECONNECT_INTERVAL);
+
+        let stop = StopSignal::default();
+        let connect = || async { Err::<(), _>(TestError::Forbidden) };
+        let result = supervise(&stop, "test", "test", connect, |()| async { Ok(()) }).await;
+        assert_eq!(result, Err(TestError::Forbidden));
+    }
+
+    #[tokio::test]
+    async fn a_stop_is_seen_by_later_waits() {
+        let stop = StopSignal::default();
+        stop.stop();
+        stop.stopped().await;
+        assert!(stop.is_stopped());
+    }
+}
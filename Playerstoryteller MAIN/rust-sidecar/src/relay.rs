@@ -0,0 +1,560 @@
+//! `ratlab-sidecar relay`: a stand-in for the Node server's stream service
+//! (`Ratlab Server/src/services/streamer.js`).
+//!
+//! Same protocol and behaviour:
+//! - A connection that presents a key (`Authorization: Bearer <key>` or `?key=`)
+//!   is the streamer for its session, anything else is a viewer.
+//! - The session comes from the `Session-Id` header, then `?session=`, then "default".
+//! - A new streamer replaces (and closes) the previous one and clears the cached init.
+//! - The last init segment is sent to every viewer as soon as it joins.
+//! - Media is skipped for a viewer with more than `MAX_BUFFERED_AMOUNT` bytes
+//!   still unsent, init segments never are.
+//!
+//! On top of that the relay can require a specific key, in which case streamers
+//! may also use the HMAC challenge from `auth`.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, error, info, warn};
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::{accept_hdr_async, WebSocketStream};
+use url::Url;
+
+use crate::auth::{self, ChallengeVerifier};
+use crate::mp4::SegmentType;
+use crate::secret::StreamKey;
+
+/// Same threshold as `streamer.js`.
+pub const MAX_BUFFERED_AMOUNT: usize = 64 * 1024;
+
+const DEFAULT_SESSION: &str = "default";
+
+enum Role {
+    Streamer { hmac: bool },
+    Viewer,
+}
+
+struct Streamer {
+    id: u64,
+    kick: Arc<Notify>,
+}
+
+/// Writer side of one viewer. `buffered` counts bytes handed to the viewer's
+/// task but not yet written to its socket, like `ws.bufferedAmount`.
+struct Viewer {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    buffered: Arc<AtomicUsize>,
+}
+
+impl Viewer {
+    fn send(&self, data: Vec<u8>) {
+        self.buffered.fetch_add(data.len(), Ordering::SeqCst);
+        let _ = self.tx.send(data);
+    }
+}
+
+/// Socket side of one viewer, returned by `Audience::join`.
+pub struct ViewerHandle {
+    pub id: u64,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    buffered: Arc<AtomicUsize>,
+}
+
+/// The viewers of one stream plus its cached init segment.
+pub struct Audience {
+    viewers: HashMap<u64, Viewer>,
+    init: Option<Vec<u8>>,
+    max_buffered: usize,
+    next_id: u64,
+}
+
+impl Audience {
+    pub fn new(max_buffered: usize) -> Self {
+        Self { viewers: HashMap::new(), init: None, max_buffered, next_id: 1 }
+    }
+
+    /// Send a segment to every viewer, caching it if it is an init segment.
+    pub fn publish(&mut self, data: Vec<u8>) {
+        let kind = SegmentType::identify(&data);
+        if kind == Some(SegmentType::Init) {
+            self.init = Some(data.clone());
+        }
+
+        for viewer in self.viewers.values() {
+            // Backpressure: drop media for slow viewers, never the init
+            if kind == Some(SegmentType::Media) && viewer.buffered.load(Ordering::SeqCst) > self.max_buffered {
+                continue;
+            }
+            viewer.send(data.clone());
+        }
+    }
+
+    /// Add a viewer. The cached init, if any, is queued before anything else.
+    pub fn join(&mut self) -> ViewerHandle {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let buffered = Arc::new(AtomicUsize::new(0));
+        let viewer = Viewer { tx, buffered: buffered.clone() };
+        if let Some(init) = &self.init {
+            viewer.send(init.clone());
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.viewers.insert(id, viewer);
+        ViewerHandle { id, rx, buffered }
+    }
+
+    pub fn leave(&mut self, id: u64) {
+        self.viewers.remove(&id);
+    }
+
+    pub fn clear_init(&mut self) {
+        self.init = None;
+    }
+
+    pub fn init_len(&self) -> Option<usize> {
+        self.init.as_ref().map(Vec::len)
+    }
+
+    pub fn len(&self) -> usize {
+        self.viewers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.viewers.is_empty()
+    }
+}
+
+/// Pump a viewer's queue into its socket until either side closes.
+pub async fn serve_viewer<S>(ws: WebSocketStream<S>, handle: &mut ViewerHandle)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut sink, mut incoming) = ws.split();
+    loop {
+        tokio::select! {
+            data = handle.rx.recv() => match data {
+                Some(data) => {
+                    let len = data.len();
+                    if let Err(e) = sink.send(Message::Binary(data)).await {
+                        debug!("Viewer send error: {}", e);
+                        break;
+                    }
+                    handle.buffered.fetch_sub(len, Ordering::SeqCst);
+                }
+                None => break,
+            },
+            msg = incoming.next() => match msg {
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+struct Session {
+    streamer: Option<Streamer>,
+    audience: Audience,
+}
+
+impl Session {
+    fn new(max_buffered: usize) -> Self {
+        Self { streamer: None, audience: Audience::new(max_buffered) }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.streamer.is_none() && self.audience.is_empty()
+    }
+}
+
+pub struct Relay {
+    listen: SocketAddr,
+    path: String,
+    stream_key: Option<StreamKey>,
+    max_buffered: usize,
+    sessions: Mutex<HashMap<String, Session>>,
+    next_id: AtomicU64,
+}
+
+impl Relay {
+    pub fn new(listen: SocketAddr) -> Self {
+        Self {
+            listen,
+            path: "/stream".to_string(),
+            stream_key: None,
+            max_buffered: MAX_BUFFERED_AMOUNT,
+            sessions: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// WebSocket path streamers and viewers connect to.
+    pub fn path(mut self, path: String) -> Self {
+        self.path = path;
+        self
+    }
+
+    /// Only accept streamers that present this key. Without one, any non-empty
+    /// key makes a streamer, exactly like `streamer.js`.
+    pub fn stream_key(mut self, key: StreamKey) -> Self {
+        self.stream_key = Some(key).filter(|k| !k.is_empty());
+        self
+    }
+
+    pub fn max_buffered(mut self, bytes: usize) -> Self {
+        self.max_buffered = bytes;
+        self
+    }
+
+    pub async fn run(self) -> io::Result<()> {
+        let listener = TcpListener::bind(self.listen).await?;
+        Arc::new(self).serve(listener).await
+    }
+
+    /// Accept connections on an already bound listener until it fails.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        info!(
+            "Relay listening on ws://{}{} ({})",
+            listener.local_addr()?,
+            self.path,
+            if self.stream_key.is_some() { "stream key required" } else { "open" }
+        );
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let _ = stream.set_nodelay(true);
+            let relay = self.clone();
+            tokio::spawn(async move { relay.handle_connection(stream, peer).await });
+        }
+    }
+
+    async fn handle_connection(self: Arc<Self>, stream: TcpStream, peer: SocketAddr) {
+        let mut accepted = None;
+        // The error type is fixed by tungstenite
+        #[allow(clippy::result_large_err)]
+        let callback = |request: &Request, response: Response| match self.classify(request) {
+            Ok(role) => {
+                accepted = Some(role);
+                Ok(response)
+            }
+            Err(status) => Err(reject(status)),
+        };
+
+        let ws = match accept_hdr_async(stream, callback).await {
+            Ok(ws) => ws,
+            Err(e) => {
+                debug!("[Relay] Handshake with {} failed: {}", peer, e);
+                return;
+            }
+        };
+        let Some((role, session_id)) = accepted else { return };
+
+        match role {
+            Role::Streamer { hmac } => self.run_streamer(ws, session_id, hmac).await,
+            Role::Viewer => self.run_viewer(ws, session_id).await,
+        }
+    }
+
+    /// Decide what a connection is from its upgrade request, or why it is refused.
+    fn classify(&self, request: &Request) -> Result<(Role, String), StatusCode> {
+        if request.uri().path() != self.path {
+            return Err(StatusCode::NOT_FOUND);
+        }
+
+        let query: HashMap<String, String> = Url::parse(&format!("ws://relay{}", request.uri()))
+            .map(|u| u.query_pairs().into_owned().collect())
+            .unwrap_or_default();
+        let header = |name: &str| request.headers().get(name).and_then(|v| v.to_str().ok());
+
+        let session_id = header("session-id")
+            .or(query.get("session").map(String::as_str))
+            .filter(|s| !s.is_empty())
+            .unwrap_or(DEFAULT_SESSION)
+            .to_string();
+
+        let authorization = header("authorization").unwrap_or_default();
+        if authorization == auth::HMAC_SCHEME {
+            // Nothing to verify the signature against without a configured key
+            return match self.stream_key {
+                Some(_) => Ok((Role::Streamer { hmac: true }, session_id)),
+                None => Err(StatusCode::UNAUTHORIZED),
+            };
+        }
+
+        let key = authorization
+            .strip_prefix("Bearer ")
+            .or(query.get("key").map(String::as_str))
+            .unwrap_or_default();
+        if key.is_empty() {
+            return Ok((Role::Viewer, session_id));
+        }
+        match &self.stream_key {
+            Some(expected) if !expected.matches(key) => Err(StatusCode::UNAUTHORIZED),
+            _ => Ok((Role::Streamer { hmac: false }, session_id)),
+        }
+    }
+
+    async fn run_streamer(&self, mut ws: WebSocketStream<TcpStream>, session_id: String, hmac: bool) {
+        if hmac {
+            let key = self.stream_key.clone().unwrap_or_default();
+            if let Err(e) = auth::challenge_streamer(&mut ws, &mut ChallengeVerifier::new(key), &session_id).await {
+                warn!("[Relay] Streamer for session {} failed authentication: {}", session_id, e);
+                let _ = ws.close(None).await;
+                return;
+            }
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let kick = Arc::new(Notify::new());
+        {
+            let mut sessions = self.sessions.lock();
+            let session = sessions.entry(session_id.clone()).or_insert_with(|| Session::new(self.max_buffered));
+            if let Some(old) = session.streamer.replace(Streamer { id, kick: kick.clone() }) {
+                warn!("[Relay] Replacing existing streamer for session: {}", session_id);
+                old.kick.notify_one();
+            }
+            session.audience.clear_init();
+        }
+        info!("[Relay] Streamer connected to session: {}", session_id);
+
+        loop {
+            tokio::select! {
+                _ = kick.notified() => {
+                    let _ = ws.close(None).await;
+                    break;
+                }
+                msg = ws.next() => match msg {
+                    Some(Ok(Message::Binary(data))) => self.broadcast(&session_id, data),
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        error!("[Relay] Streamer read error: {}", e);
+                        break;
+                    }
+                },
+            }
+        }
+
+        info!("[Relay] Streamer disconnected from session: {}", session_id);
+        let mut sessions = self.sessions.lock();
+        if let Some(session) = sessions.get_mut(&session_id) {
+            // A replacement may already have taken over. The init stays cached for reconnects.
+            if session.streamer.as_ref().is_some_and(|s| s.id == id) {
+                session.streamer = None;
+            }
+            if session.is_idle() {
+                sessions.remove(&session_id);
+            }
+        }
+    }
+
+    fn broadcast(&self, session_id: &str, data: Vec<u8>) {
+        let mut sessions = self.sessions.lock();
+        let Some(session) = sessions.get_mut(session_id) else { return };
+
+        if SegmentType::identify(&data) == Some(SegmentType::Init) {
+            info!("[Relay] Received init segment ({} bytes) for session: {}", data.len(), session_id);
+        }
+        session.audience.publish(data);
+    }
+
+    async fn run_viewer(&self, ws: WebSocketStream<TcpStream>, session_id: String) {
+        let mut handle = {
+            let mut sessions = self.sessions.lock();
+            let session = sessions.entry(session_id.clone()).or_insert_with(|| Session::new(self.max_buffered));
+            match session.audience.init_len() {
+                Some(len) => info!("[Relay] Sending cached init segment ({} bytes) to new viewer", len),
+                None => warn!("[Relay] No init segment cached for session: {} - viewer must wait for next init", session_id),
+            }
+            // Joined under the lock so no media can overtake the init
+            let handle = session.audience.join();
+            info!("[Relay] Viewer joined session: {} ({} viewers)", session_id, session.audience.len());
+            handle
+        };
+
+        serve_viewer(ws, &mut handle).await;
+
+        info!("[Relay] Viewer left session: {}", session_id);
+        let mut sessions = self.sessions.lock();
+        if let Some(session) = sessions.get_mut(&session_id) {
+            session.audience.leave(handle.id);
+            if session.is_idle() {
+                sessions.remove(&session_id);
+            }
+        }
+    }
+}
+
+/// An upgrade refused with `status`.
+pub fn reject(status: StatusCode) -> ErrorResponse {
+    let mut response = ErrorResponse::new(status.canonical_reason().map(str::to_string));
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::Error as WsError;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream};
+
+    use super::*;
+    use crate::testing::{init_segment, media_segment, tag};
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Serve `relay` on a loopback port, returning its stream URL.
+    async fn start(relay: Relay) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/stream", listener.local_addr().unwrap());
+        tokio::spawn(Arc::new(relay).serve(listener));
+        url
+    }
+
+    fn relay() -> Relay {
+        Relay::new(([127, 0, 0, 1], 0).into())
+    }
+
+    async fn connect(url: &str, headers: &[(&'static str, &str)]) -> Result<Client, WsError> {
+        let mut request = url.into_client_request().unwrap();
+        for (name, value) in headers {
+            request.headers_mut().insert(*name, value.parse().unwrap());
+        }
+        connect_async(request).await.map(|(ws, _)| ws)
+    }
+
+    /// The tag of the next binary message, or None once the connection closes.
+    async fn next_tag(ws: &mut Client) -> Option<u8> {
+        loop {
+            match tokio::time::timeout(Duration::from_secs(5), ws.next()).await.expect("no message from the relay") {
+                Some(Ok(Message::Binary(data))) => return Some(tag(&data)),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return None,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    async fn send(ws: &mut Client, data: Vec<u8>) {
+        ws.send(Message::Binary(data)).await.unwrap();
+    }
+
+    fn status(result: Result<Client, WsError>) -> StatusCode {
+        match result {
+            Err(WsError::Http(response)) => response.status(),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("connection was accepted"),
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_the_cached_init_to_late_viewers() {
+        let url = start(relay()).await;
+        let mut streamer = connect(&format!("{}?key=anything", url), &[]).await.unwrap();
+        send(&mut streamer, init_segment(1).data).await;
+        send(&mut streamer, media_segment(2, 100).data).await;
+        let mut early = connect(&url, &[]).await.unwrap();
+        assert_eq!(next_tag(&mut early).await, Some(1));
+        assert_eq!(next_tag(&mut early).await, Some(2));
+
+        // Media already sent is not replayed, only the init
+        let mut late = connect(&url, &[]).await.unwrap();
+        assert_eq!(next_tag(&mut late).await, Some(1));
+        send(&mut streamer, media_segment(3, 100).data).await;
+        assert_eq!(next_tag(&mut late).await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn a_replacement_streamer_clears_the_cached_init() {
+        let url = start(relay()).await;
+        let mut first = connect(&url, &[("authorization", "Bearer one")]).await.unwrap();
+        send(&mut first, init_segment(1).data).await;
+        let mut viewer = connect(&url, &[]).await.unwrap();
+        assert_eq!(next_tag(&mut viewer).await, Some(1));
+
+        let mut second = connect(&url, &[("authorization", "Bearer two")]).await.unwrap();
+        // The old streamer is closed once the new one has taken over
+        assert_eq!(next_tag(&mut first).await, None);
+        let mut late = connect(&url, &[]).await.unwrap();
+        send(&mut second, init_segment(2).data).await;
+        assert_eq!(next_tag(&mut late).await, Some(2));
+        assert_eq!(next_tag(&mut viewer).await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn routes_by_session_header_then_query_then_default() {
+        let url = start(relay()).await;
+        // The header wins over the query
+        let mut a = connect(&format!("{}?session=b", url), &[("session-id", "a"), ("authorization", "Bearer k")]).await.unwrap();
+        send(&mut a, init_segment(1).data).await;
+        let mut b = connect(&format!("{}?session=b&key=k", url), &[]).await.unwrap();
+        send(&mut b, init_segment(2).data).await;
+        let mut default = connect(&format!("{}?key=k", url), &[]).await.unwrap();
+        send(&mut default, init_segment(3).data).await;
+
+        let mut viewer = connect(&format!("{}?session=a", url), &[]).await.unwrap();
+        assert_eq!(next_tag(&mut viewer).await, Some(1));
+        let mut viewer = connect(&url, &[("session-id", "b")]).await.unwrap();
+        assert_eq!(next_tag(&mut viewer).await, Some(2));
+        let mut viewer = connect(&format!("{}?session=", url), &[]).await.unwrap();
+        assert_eq!(next_tag(&mut viewer).await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn a_configured_key_is_checked_from_the_query_or_the_header() {
+        let url = start(relay().stream_key(StreamKey::new("secret".to_string()))).await;
+        let mut by_query = connect(&format!("{}?key=secret&session=q", url), &[]).await.unwrap();
+        send(&mut by_query, init_segment(1).data).await;
+        let mut by_header = connect(&format!("{}?session=h", url), &[("authorization", "Bearer secret")]).await.unwrap();
+        send(&mut by_header, init_segment(2).data).await;
+
+        assert_eq!(status(connect(&format!("{}?key=wrong", url), &[]).await), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(connect(&url, &[("authorization", "Bearer wrong")]).await), StatusCode::UNAUTHORIZED);
+        // A challenge streamer is upgraded and then answers the challenge
+        assert!(connect(&url, &[("authorization", auth::HMAC_SCHEME)]).await.is_ok());
+        assert_eq!(status(connect(&format!("{}/other", url), &[]).await), StatusCode::NOT_FOUND);
+
+        let mut viewer = connect(&format!("{}?session=q", url), &[]).await.unwrap();
+        assert_eq!(next_tag(&mut viewer).await, Some(1));
+        let mut viewer = connect(&format!("{}?session=h", url), &[]).await.unwrap();
+        assert_eq!(next_tag(&mut viewer).await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn a_challenge_needs_a_configured_key() {
+        let url = start(relay()).await;
+        assert_eq!(status(connect(&url, &[("authorization", auth::HMAC_SCHEME)]).await), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn drops_media_but_never_the_init_for_slow_viewers() {
+        let mut audience = Audience::new(MAX_BUFFERED_AMOUNT);
+        let mut handle = audience.join();
+        audience.publish(init_segment(1).data);
+        // Nothing is written out, so the viewer falls behind
+        for t in 2..6 {
+            audience.publish(media_segment(t, 30 * 1024).data);
+        }
+        audience.publish(init_segment(6).data);
+        audience.publish(media_segment(7, 100).data);
+
+        let mut tags = Vec::new();
+        while let Ok(data) = handle.rx.try_recv() {
+            tags.push(tag(&data));
+        }
+        // Media 2 to 4 fill the buffer past 64 KB, so 5 and 7 are dropped
+        assert_eq!(tags, [1, 2, 3, 4, 6]);
+        assert_eq!(audience.init_len(), Some(32));
+    }
+}
@@ -0,0 +1,266 @@
+//! `--source-file <fmp4>`: replay a recording instead of capturing a window.
+//!
+//! The file is split with `Mp4Parser` like live SinkWriter output, then sent
+//! through the normal output path, paced by the `tfdt`/`trun` timing of the
+//! first track. With `--loop` or `--rewrite-timeline`, decode times and
+//! sequence numbers are rewritten to start at zero and keep increasing across
+//! loops, so players see one continuous stream.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use log::{info, warn};
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::mp4::{self, Mp4Parser, Mp4Segment, SegmentType, TrackInfo};
+
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("cannot read source file: {0}")]
+    Io(#[from] io::Error),
+    #[error("source file has no init segment (ftyp + moov)")]
+    NoInitSegment,
+    #[error("source file has no fragments (moof + mdat)")]
+    NoFragments,
+    #[error("no timescale for track {0} in the init segment")]
+    MissingTimescale(u32),
+    #[error("decode times of track {0} go backwards in the source file")]
+    NonMonotonicTimeline(u32),
+}
+
+struct Fragment {
+    data: Vec<u8>,
+    /// (track id, decode time relative to the track's first fragment, duration)
+    tracks: Vec<(u32, u64, u64)>,
+}
+
+pub struct FileReplay {
+    init: Vec<u8>,
+    /// Track defaults of the init segment
+    track_info: HashMap<u32, TrackInfo>,
+    fragments: Vec<Fragment>,
+    /// Track the pacing follows, with its timescale
+    clock: (u32, u32),
+    /// Length of one pass per track, in track ticks
+    spans: HashMap<u32, u64>,
+    looping: bool,
+    rewrite_timeline: bool,
+}
+
+impl FileReplay {
+    pub async fn open(path: &Path) -> Result<Self, ReplayError> {
+        Self::from_bytes(&tokio::fs::read(path).await?)
+    }
+
+    pub fn from_bytes(file: &[u8]) -> Result<Self, ReplayError> {
+        let mut init = None;
+        let mut media = Vec::new();
+        for segment in Mp4Parser::new().parse(file) {
+            match segment.kind {
+                SegmentType::Init => init = Some(segment.data),
+                // Top-level boxes after the init (sidx, mfra, ...) are not fragments
+                SegmentType::Media if SegmentType::identify(&segment.data) == Some(SegmentType::Media) => media.push(segment.data),
+                SegmentType::Media => {}
+            }
+        }
+        let init = init.ok_or(ReplayError::NoInitSegment)?;
+        let track_info = mp4::track_info(&init);
+
+        // Relative decode times per track. A fragment without tfdt follows on from the previous one.
+        let mut first: HashMap<u32, u64> = HashMap::new();
+        let mut last: HashMap<u32, u64> = HashMap::new();
+        let mut next: HashMap<u32, u64> = HashMap::new();
+        let mut fragments = Vec::with_capacity(media.len());
+        for data in media {
+            let mut tracks = Vec::new();
+            for timing in mp4::fragment_timing(&data, &track_info) {
+                let track = timing.track_id;
+                let decode_time = timing.decode_time.or_else(|| next.get(&track).copied()).unwrap_or(0);
+                if last.get(&track).is_some_and(|last| decode_time < *last) {
+                    return Err(ReplayError::NonMonotonicTimeline(track));
+                }
+                let base = *first.entry(track).or_insert(decode_time);
+                let end = decode_time.checked_add(timing.duration).ok_or(ReplayError::NonMonotonicTimeline(track))?;
+                last.insert(track, decode_time);
+                next.insert(track, end);
+                tracks.push((track, decode_time - base, timing.duration));
+            }
+            fragments.push(Fragment { data, tracks });
+        }
+
+        let clock_track = fragments
+            .iter()
+            .find_map(|f| f.tracks.first().map(|t| t.0))
+            .ok_or(ReplayError::NoFragments)?;
+        let timescale = track_info
+            .get(&clock_track)
+            .map(|t: &TrackInfo| t.timescale)
+            .filter(|t| *t > 0)
+            .ok_or(ReplayError::MissingTimescale(clock_track))?;
+        let spans = next
+            .iter()
+            .map(|(track, end)| end.checked_sub(first[track]).map(|span| (*track, span)).ok_or(ReplayError::NonMonotonicTimeline(*track)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { init, track_info, fragments, clock: (clock_track, timescale), spans, looping: false, rewrite_timeline: false })
+    }
+
+    /// Start over at the end of the file, continuing the timeline.
+    pub fn looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    /// Rebase decode times to zero and renumber fragments. Always on when looping.
+    pub fn rewrite_timeline(mut self, rewrite: bool) -> Self {
+        self.rewrite_timeline = rewrite;
+        self
+    }
+
+    /// Length of one pass through the file.
+    pub fn duration(&self) -> Duration {
+        let (track, timescale) = self.clock;
+        ticks_to_duration(self.spans.get(&track).copied().unwrap_or(0), timescale)
+    }
+
+    pub fn fragment_count(&self) -> usize {
+        self.fragments.len()
+    }
+
+    /// Send the init segment, then each fragment at its decode time. Returns at
+    /// the end of the file, or when the receiving side has gone away.
+    pub async fn run(&self, tx: &UnboundedSender<Mp4Segment>) {
+        if tx.send(Mp4Segment::init(self.init.clone())).is_err() {
+            return;
+        }
+
+        let (clock_track, timescale) = self.clock;
+        let rewrite = self.rewrite_timeline || self.looping;
+        let started = Instant::now();
+        let mut sequence = 1u32;
+        let mut pass = 0u64;
+        loop {
+            for fragment in &self.fragments {
+                let mut data = fragment.data.clone();
+                if rewrite {
+                    for &(track, decode_time, _) in &fragment.tracks {
+                        let offset = pass * self.spans.get(&track).copied().unwrap_or(0);
+                        if !mp4::set_decode_time(&mut data, track, offset + decode_time) {
+                            warn!("Replay: track {} fragment has no tfdt to rewrite", track);
+                        }
+                    }
+                    mp4::set_sequence_number(&mut data, sequence);
+                    sequence = sequence.wrapping_add(1);
+                }
+
+                if let Some(&(_, decode_time, _)) = fragment.tracks.iter().find(|t| t.0 == clock_track) {
+                    let ticks = pass * self.spans[&clock_track] + decode_time;
+                    sleep_until(started + ticks_to_duration(ticks, timescale)).await;
+                }
+                if tx.send(Mp4Segment::media(data, &self.track_info)).is_err() {
+                    return;
+                }
+            }
+
+            if !self.looping {
+                return;
+            }
+            pass += 1;
+            info!("Replay reached the end of the file, looping (pass {})", pass + 1);
+        }
+    }
+}
+
+fn ticks_to_duration(ticks: u64, timescale: u32) -> Duration {
+    Duration::from_nanos((ticks as u128 * 1_000_000_000 / timescale as u128) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fmp4_file;
+    use tokio::sync::mpsc;
+
+    /// Decode time and sequence number of a fragment as sent
+    fn timeline(segment: &Mp4Segment) -> (u64, u32) {
+        let timing = mp4::fragment_timing(&segment.data, &HashMap::new());
+        let sequence = u32::from_be_bytes(segment.data[20..24].try_into().unwrap());
+        (timing[0].decode_time.unwrap(), sequence)
+    }
+
+    #[test]
+    fn reads_timing_from_tfdt_and_trun() {
+        let replay = FileReplay::from_bytes(&fmp4_file(1000, &[(5000, vec![100; 10]), (6000, vec![250; 4])])).unwrap();
+        assert_eq!(replay.fragment_count(), 2);
+        assert_eq!(replay.fragments[1].tracks, vec![(1, 1000, 1000)]);
+        assert_eq!(replay.duration(), Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paces_fragments_by_decode_time() {
+        let replay = FileReplay::from_bytes(&fmp4_file(1000, &[(0, vec![500]), (500, vec![1500]), (2000, vec![500])])).unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let started = Instant::now();
+        tokio::spawn(async move { replay.run(&tx).await });
+
+        assert_eq!(rx.recv().await.unwrap().kind, SegmentType::Init);
+        let mut sent_at = Vec::new();
+        while let Some(segment) = rx.recv().await {
+            assert_eq!(segment.kind, SegmentType::Media);
+            sent_at.push(started.elapsed());
+        }
+        assert_eq!(sent_at, [Duration::ZERO, Duration::from_millis(500), Duration::from_millis(2000)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn looping_keeps_decode_times_increasing() {
+        let replay = FileReplay::from_bytes(&fmp4_file(1000, &[(7000, vec![500]), (7500, vec![500])])).unwrap().looping(true);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move { replay.run(&tx).await });
+
+        rx.recv().await.unwrap();
+        let mut sent = Vec::new();
+        for _ in 0..5 {
+            sent.push(timeline(&rx.recv().await.unwrap()));
+        }
+        assert_eq!(sent, [(0, 1), (500, 2), (1000, 3), (1500, 4), (2000, 5)]);
+    }
+
+    #[test]
+    fn widens_tfdt_past_32_bits() {
+        let file = fmp4_file(1000, &[(0, vec![500])]);
+        let replay = FileReplay::from_bytes(&file).unwrap();
+        let mut data = replay.fragments[0].data.clone();
+        let tracks = mp4::track_info(&replay.init);
+
+        assert!(mp4::set_decode_time(&mut data, 1, 1 << 40));
+        assert_eq!(data.len(), replay.fragments[0].data.len() + 4);
+        assert_eq!(mp4::fragment_timing(&data, &tracks)[0].decode_time, Some(1 << 40));
+
+        // The trun data offset must still point at the mdat payload
+        let moof_size = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+        assert_eq!(&data[moof_size + 4..moof_size + 8], b"mdat");
+        let trun = data.windows(4).position(|w| w == b"trun").unwrap() - 4;
+        let data_offset = u32::from_be_bytes(data[trun + 16..trun + 20].try_into().unwrap()) as usize;
+        assert_eq!(data_offset, moof_size + 8);
+    }
+
+    #[test]
+    fn rejects_decode_times_going_backwards() {
+        let file = fmp4_file(1000, &[(5000, vec![500]), (4000, vec![500])]);
+        assert!(matches!(FileReplay::from_bytes(&file), Err(ReplayError::NonMonotonicTimeline(1))));
+
+        let file = fmp4_file(1000, &[(u64::MAX - 100, vec![500])]);
+        assert!(matches!(FileReplay::from_bytes(&file), Err(ReplayError::NonMonotonicTimeline(1))));
+    }
+
+    #[test]
+    fn rejects_files_without_init() {
+        let file = fmp4_file(1000, &[(0, vec![500])]);
+        let moof = file.windows(4).position(|w| w == b"moof").unwrap() - 4;
+        assert!(matches!(FileReplay::from_bytes(&file[moof..]), Err(ReplayError::NoInitSegment)));
+    }
+}
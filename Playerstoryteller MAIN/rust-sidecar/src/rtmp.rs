@@ -0,0 +1,786 @@
+//! `--rtmp`: push the stream to an RTMP ingest (Twitch, YouTube, nginx-rtmp)
+//! as FLV video, alongside the other outputs.
+//!
+//! Each connection does the handshake, `connect`, `createStream` and
+//! `publish`, then sends `onMetaData`, the AVC sequence header and frames
+//! from the next keyframe on. Nothing is queued while disconnected, so a
+//! reconnect picks up live rather than replaying what was missed.
+
+mod amf;
+mod chunk;
+mod flv;
+mod handshake;
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use log::{debug, error, info, warn};
+use parking_lot::Mutex;
+use thiserror::Error;
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+use tokio_tungstenite::MaybeTlsStream;
+use url::{Position, Url};
+
+use crate::mp4::Mp4Segment;
+use crate::net;
+use crate::policy::{PolicyError, TransportPolicy};
+use crate::queue::{Frame, KeyframeQueue};
+use crate::reconnect::{self, ConnectionError, StopSignal};
+use crate::secret::StreamKey;
+use crate::tls::{TlsConnector, TlsError, TlsOptions};
+
+use self::amf::Amf;
+use self::chunk::{ChunkReader, ChunkWriter, Message};
+use self::flv::{FlvVideo, VideoTag};
+
+/// Longest the handshake, connect and publish may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Chunk size we send with. Larger chunks mean fewer headers for video.
+const CHUNK_SIZE: usize = 4096;
+
+/// Queued video beyond this is dropped up to the next keyframe.
+const QUEUE_LIMIT: usize = 8 * 1024 * 1024;
+
+// Message types
+const SET_CHUNK_SIZE: u8 = 1;
+const ACKNOWLEDGEMENT: u8 = 3;
+const USER_CONTROL: u8 = 4;
+const WINDOW_ACK_SIZE: u8 = 5;
+const SET_PEER_BANDWIDTH: u8 = 6;
+const VIDEO: u8 = 9;
+const DATA: u8 = 18;
+const COMMAND: u8 = 20;
+
+// Chunk streams
+const CONTROL_CHUNK_STREAM: u8 = 2;
+const COMMAND_CHUNK_STREAM: u8 = 3;
+const DATA_CHUNK_STREAM: u8 = 4;
+const VIDEO_CHUNK_STREAM: u8 = 6;
+
+const PING_REQUEST: u16 = 6;
+const PING_RESPONSE: u16 = 7;
+
+type RtmpStream = MaybeTlsStream<TcpStream>;
+
+#[derive(Debug, Error)]
+pub enum RtmpError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Tls(#[from] TlsError),
+    #[error(transparent)]
+    Policy(#[from] PolicyError),
+    #[error("RTMP protocol error: {0}")]
+    Protocol(&'static str),
+    #[error("the server refused {command}: {reason}")]
+    Rejected { command: &'static str, reason: String },
+    #[error("the server did not answer in time")]
+    Timeout,
+}
+
+impl ConnectionError for RtmpError {
+    fn is_fatal(&self) -> bool {
+        matches!(self, RtmpError::Policy(PolicyError::InsecureRemote { .. } | PolicyError::UnsupportedScheme(_)))
+    }
+}
+
+/// `rtmp[s]://host[:port]/app/stream-key`. The last path segment is the
+/// stream key, which is kept out of logs.
+#[derive(Clone)]
+pub struct RtmpUrl {
+    scheme: String,
+    host: String,
+    port: u16,
+    app: String,
+    /// What `connect` is told it connected to: the URL without the key
+    tc_url: String,
+    stream_key: StreamKey,
+}
+
+impl FromStr for RtmpUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|e| format!("invalid RTMP URL: {}", e))?;
+        let default_port = match url.scheme() {
+            "rtmp" => 1935,
+            "rtmps" => 443,
+            other => return Err(format!("unsupported scheme {}, expected rtmp or rtmps", other)),
+        };
+        let host = net::connect_host(&url).ok_or("RTMP URL has no host")?;
+        let (app, key) = url
+            .path()
+            .trim_start_matches('/')
+            .rsplit_once('/')
+            .filter(|(app, key)| !app.is_empty() && !key.is_empty())
+            .ok_or("expected rtmp://host/app/stream-key")?;
+        // Some ingests take parameters after the key
+        let stream_key = match url.query() {
+            Some(query) => format!("{}?{}", key, query),
+            None => key.to_string(),
+        };
+        Ok(Self {
+            scheme: url.scheme().to_string(),
+            host,
+            port: url.port().unwrap_or(default_port),
+            app: app.to_string(),
+            tc_url: format!("{}/{}", &url[..Position::BeforePath], app),
+            stream_key: StreamKey::new(stream_key),
+        })
+    }
+}
+
+impl fmt::Display for RtmpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/<redacted>", self.tc_url)
+    }
+}
+
+impl fmt::Debug for RtmpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RtmpUrl({})", self)
+    }
+}
+
+impl Frame for VideoTag {
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn joinable(&self) -> bool {
+        self.keyframe
+    }
+}
+
+/// Video tags waiting for the connection.
+///
+/// A new sequence header is kept aside and sent before any tag after it, so
+/// dropping the oldest group of pictures never takes it along. Tags still
+/// queued for the previous header are dropped in its favour.
+struct TagQueue {
+    header: Option<VideoTag>,
+    tags: KeyframeQueue<VideoTag>,
+}
+
+impl TagQueue {
+    fn new(limit: usize) -> Self {
+        Self { header: None, tags: KeyframeQueue::new(limit) }
+    }
+
+    fn push(&mut self, tag: VideoTag) {
+        if tag.is_sequence_header() {
+            self.tags.reset();
+            self.header = Some(tag);
+        } else {
+            self.tags.push(tag);
+        }
+    }
+
+    fn pop(&mut self) -> Option<VideoTag> {
+        self.header.take().or_else(|| self.tags.pop())
+    }
+
+    /// Start over for a new connection, which gets the current sequence
+    /// header first and then begins at a keyframe.
+    fn reset(&mut self) {
+        self.header = None;
+        self.tags.reset();
+    }
+
+    fn dropped(&self) -> u64 {
+        self.tags.dropped()
+    }
+}
+
+/// One RTMP destination, with its own connection and queue.
+pub struct RtmpOutput {
+    url: RtmpUrl,
+    policy: TransportPolicy,
+    tls: Option<Arc<TlsConnector>>,
+    video: Mutex<FlvVideo>,
+    queue: Mutex<TagQueue>,
+    queue_notify: Notify,
+    connected: AtomicBool,
+    stop: StopSignal,
+}
+
+impl RtmpOutput {
+    pub fn new(url: RtmpUrl) -> Self {
+        Self {
+            url,
+            policy: TransportPolicy::default(),
+            tls: None,
+            video: Mutex::new(FlvVideo::new()),
+            queue: Mutex::new(TagQueue::new(QUEUE_LIMIT)),
+            queue_notify: Notify::new(),
+            connected: AtomicBool::new(false),
+            stop: StopSignal::default(),
+        }
+    }
+
+    pub fn transport_policy(mut self, policy: TransportPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// TLS settings for `rtmps://`, shared with the WebSocket connections.
+    pub fn tls_connector(mut self, connector: Arc<TlsConnector>) -> Self {
+        self.tls = Some(connector);
+        self
+    }
+
+    /// Take in a segment of the encoder's stream. Never blocks; video is only
+    /// queued while connected.
+    pub fn publish(&self, segment: &Mp4Segment) {
+        let tags = self.video.lock().push(segment);
+        if tags.is_empty() || !self.connected.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut queue = self.queue.lock();
+        let dropped_before = queue.dropped();
+        tags.into_iter().for_each(|tag| queue.push(tag));
+        let dropped = queue.dropped();
+        drop(queue);
+
+        if dropped > dropped_before && (dropped_before == 0 || dropped / 60 != dropped_before / 60) {
+            warn!("RTMP queue overflow for {}: {} frames dropped so far", self.url, dropped);
+        }
+        self.queue_notify.notify_one();
+    }
+
+    /// Send what is queued, unpublish and make `run` return.
+    pub fn finish(&self) {
+        self.stop.stop();
+    }
+
+    /// Connect, publish and reconnect until `finish`. Only a transport policy
+    /// violation stops it early.
+    pub async fn run(&self) -> Result<(), RtmpError> {
+        info!("RTMP destination: {}", self.url);
+        reconnect::supervise(
+            &self.stop,
+            "rtmp",
+            &format!("RTMP {}", self.url),
+            || async { timeout(CONNECT_TIMEOUT, self.connect_once()).await.unwrap_or(Err(RtmpError::Timeout)) },
+            |session| async {
+                info!("RTMP publishing to {}", self.url);
+                self.run_session(session).await
+            },
+        )
+        .await
+    }
+
+    /// Open the connection and get as far as a successful `publish`.
+    async fn connect_once(&self) -> Result<Session, RtmpError> {
+        let url = &self.url;
+        let addrs = match net::resolve(&url.host, url.port).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                error!("DNS lookup for {} failed: {}", url.host, e);
+                Vec::new()
+            }
+        };
+        // The stream key travels in the publish command, so the same rules as ws:// apply
+        self.policy.check(&url.scheme, &url.host, &addrs)?;
+
+        let tcp = net::connect_any(&addrs).await?;
+        tcp.set_nodelay(true)?;
+        let mut stream = if url.scheme == "rtmps" {
+            match &self.tls {
+                Some(connector) => connector.connect(&url.host, tcp).await?,
+                None => TlsConnector::new(&TlsOptions::default())?.connect(&url.host, tcp).await?,
+            }
+        } else {
+            MaybeTlsStream::Plain(tcp)
+        };
+        handshake::client(&mut stream).await?;
+
+        let mut session = Session::start(stream);
+        session.set_chunk_size(CHUNK_SIZE).await?;
+        let connect = Amf::object([
+            ("app", Amf::String(url.app.clone())),
+            ("type", Amf::String("nonprivate".into())),
+            ("flashVer", Amf::String("FMLE/3.0 (compatible; FMSc/1.0)".into())),
+            ("tcUrl", Amf::String(url.tc_url.clone())),
+        ]);
+        session.command("connect", vec![connect], 0).await?;
+        session.wait_for_result("connect").await?;
+
+        let key = Amf::String(url.stream_key.expose().to_string());
+        // Older servers want these before createStream; their answers don't matter
+        session.command("releaseStream", vec![Amf::Null, key.clone()], 0).await?;
+        session.command("FCPublish", vec![Amf::Null, key.clone()], 0).await?;
+        session.command("createStream", vec![Amf::Null], 0).await?;
+        let result = session.wait_for_result("createStream").await?;
+        session.stream_id = result.get(3).and_then(Amf::as_number).ok_or(RtmpError::Protocol("createStream returned no stream id"))? as u32;
+
+        session.command("publish", vec![Amf::Null, key, Amf::String("live".into())], session.stream_id).await?;
+        session.wait_for_publish().await?;
+        Ok(session)
+    }
+
+    /// Send video until the connection fails or `finish` is called.
+    async fn run_session(&self, mut session: Session) -> Result<(), RtmpError> {
+        let (metadata, header) = {
+            let video = self.video.lock();
+            (video.metadata().cloned(), video.sequence_header().cloned())
+        };
+        if let Some(metadata) = metadata {
+            let data = amf::encode(&[Amf::String("@setDataFrame".into()), Amf::String("onMetaData".into()), metadata]);
+            session.send(DATA_CHUNK_STREAM, DATA, 0, data).await?;
+        }
+        if let Some(header) = header {
+            session.send_video(header).await?;
+        }
+        self.queue.lock().reset();
+        self.connected.store(true, Ordering::SeqCst);
+        let result = self.send_queued(&mut session).await;
+        self.connected.store(false, Ordering::SeqCst);
+        session.reader.abort();
+        result
+    }
+
+    async fn send_queued(&self, session: &mut Session) -> Result<(), RtmpError> {
+        loop {
+            if self.stop.is_stopped() {
+                loop {
+                    let next = self.queue.lock().pop();
+                    let Some(tag) = next else { break };
+                    session.send_video(tag).await?;
+                }
+                let key = Amf::String(self.url.stream_key.expose().to_string());
+                session.command("FCUnpublish", vec![Amf::Null, key], 0).await?;
+                session.command("deleteStream", vec![Amf::Null, Amf::Number(session.stream_id as f64)], 0).await?;
+                session.writer.shutdown().await?;
+                info!("RTMP stream to {} ended", self.url);
+                return Ok(());
+            }
+
+            let next = self.queue.lock().pop();
+            match next {
+                Some(tag) => session.send_video(tag).await?,
+                None => tokio::select! {
+                    _ = self.queue_notify.notified() => {}
+                    _ = self.stop.stopped() => {}
+                    received = session.incoming.recv() => {
+                        if let Some(message) = session.handle(received).await? {
+                            session.check_status(&message)?;
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+}
+
+/// Messages read by the reader task, with the byte count so far.
+type Received = Option<Result<(Message, u64), RtmpError>>;
+
+/// One connection. Reading happens on its own task, because a chunk read
+/// can't be interrupted without losing its place.
+struct Session {
+    writer: WriteHalf<RtmpStream>,
+    chunks: ChunkWriter,
+    incoming: mpsc::Receiver<Result<(Message, u64), RtmpError>>,
+    reader: JoinHandle<()>,
+    /// Transaction id of the next command
+    transaction: u32,
+    stream_id: u32,
+    /// Acknowledge every this many bytes, as the server asked
+    ack_window: u64,
+    acknowledged: u64,
+}
+
+impl Session {
+    fn start(stream: RtmpStream) -> Self {
+        let (read_half, writer) = tokio::io::split(stream);
+        let (tx, incoming) = mpsc::channel(16);
+        let reader = tokio::spawn(read_messages(read_half, tx));
+        Self { writer, chunks: ChunkWriter::new(), incoming, reader, transaction: 1, stream_id: 0, ack_window: 0, acknowledged: 0 }
+    }
+
+    async fn send(&mut self, chunk_stream: u8, type_id: u8, timestamp: u32, payload: Vec<u8>) -> Result<(), RtmpError> {
+        let message = Message { type_id, stream_id: self.stream_id, timestamp, payload };
+        self.send_message(chunk_stream, &message).await
+    }
+
+    async fn send_message(&mut self, chunk_stream: u8, message: &Message) -> Result<(), RtmpError> {
+        let mut out = Vec::with_capacity(message.payload.len() + message.payload.len() / 128 + 16);
+        self.chunks.write(&mut out, chunk_stream, message);
+        self.writer.write_all(&out).await?;
+        Ok(())
+    }
+
+    async fn send_control(&mut self, type_id: u8, payload: Vec<u8>) -> Result<(), RtmpError> {
+        self.send_message(CONTROL_CHUNK_STREAM, &Message { type_id, stream_id: 0, timestamp: 0, payload }).await
+    }
+
+    async fn set_chunk_size(&mut self, size: usize) -> Result<(), RtmpError> {
+        self.send_control(SET_CHUNK_SIZE, (size as u32).to_be_bytes().to_vec()).await?;
+        self.chunks.set_chunk_size(size);
+        Ok(())
+    }
+
+    async fn send_video(&mut self, tag: VideoTag) -> Result<(), RtmpError> {
+        self.send(VIDEO_CHUNK_STREAM, VIDEO, tag.timestamp, tag.data).await
+    }
+
+    async fn command(&mut self, name: &str, arguments: Vec<Amf>, stream_id: u32) -> Result<(), RtmpError> {
+        let mut values = vec![Amf::String(name.to_string()), Amf::Number(self.transaction as f64)];
+        values.extend(arguments);
+        self.transaction += 1;
+        let message = Message { type_id: COMMAND, stream_id, timestamp: 0, payload: amf::encode(&values) };
+        self.send_message(COMMAND_CHUNK_STREAM, &message).await
+    }
+
+    /// Deal with protocol control messages; anything else is returned.
+    async fn handle(&mut self, received: Received) -> Result<Option<Message>, RtmpError> {
+        let (message, bytes_read) = match received {
+            Some(result) => result?,
+            None => return Err(RtmpError::Io(io::ErrorKind::UnexpectedEof.into())),
+        };
+        if self.ack_window > 0 && bytes_read - self.acknowledged >= self.ack_window {
+            self.send_control(ACKNOWLEDGEMENT, (bytes_read as u32).to_be_bytes().to_vec()).await?;
+            self.acknowledged = bytes_read;
+        }
+
+        let payload = &message.payload;
+        match message.type_id {
+            // The reader task applies chunk size changes
+            SET_CHUNK_SIZE | ACKNOWLEDGEMENT | SET_PEER_BANDWIDTH => Ok(None),
+            WINDOW_ACK_SIZE => {
+                self.ack_window = payload.get(..4).map_or(0, |b| u32::from_be_bytes(b.try_into().unwrap())) as u64;
+                Ok(None)
+            }
+            USER_CONTROL => {
+                if payload.len() >= 6 && u16::from_be_bytes([payload[0], payload[1]]) == PING_REQUEST {
+                    let mut pong = PING_RESPONSE.to_be_bytes().to_vec();
+                    pong.extend_from_slice(&payload[2..6]);
+                    self.send_control(USER_CONTROL, pong).await?;
+                }
+                Ok(None)
+            }
+            _ => Ok(Some(message)),
+        }
+    }
+
+    /// The next command or data message from the server.
+    async fn next(&mut self) -> Result<Message, RtmpError> {
+        loop {
+            let received = self.incoming.recv().await;
+            if let Some(message) = self.handle(received).await? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Wait for the answer to the last command sent.
+    async fn wait_for_result(&mut self, command: &'static str) -> Result<Vec<Amf>, RtmpError> {
+        let transaction = (self.transaction - 1) as f64;
+        loop {
+            let message = self.next().await?;
+            if message.type_id != COMMAND {
+                continue;
+            }
+            let values = amf::decode(&message.payload)?;
+            if values.get(1).and_then(Amf::as_number) != Some(transaction) {
+                continue;
+            }
+            match values.first().and_then(Amf::as_str) {
+                Some("_result") => return Ok(values),
+                Some("_error") => return Err(RtmpError::Rejected { command, reason: describe(values.get(3)) }),
+                _ => {}
+            }
+        }
+    }
+
+    async fn wait_for_publish(&mut self) -> Result<(), RtmpError> {
+        loop {
+            let message = self.next().await?;
+            if let Some(code) = self.check_status(&message)? {
+                if code == "NetStream.Publish.Start" {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// The code of an `onStatus`, or an error if it reports one.
+    fn check_status(&self, message: &Message) -> Result<Option<String>, RtmpError> {
+        if message.type_id != COMMAND {
+            return Ok(None);
+        }
+        let values = amf::decode(&message.payload)?;
+        if values.first().and_then(Amf::as_str) != Some("onStatus") {
+            return Ok(None);
+        }
+        let info = values.get(3);
+        let code = info.and_then(|i| i.get("code")).and_then(Amf::as_str).unwrap_or_default().to_string();
+        debug!("RTMP onStatus: {}", code);
+        if info.and_then(|i| i.get("level")).and_then(Amf::as_str) == Some("error") {
+            return Err(RtmpError::Rejected { command: "publish", reason: describe(info) });
+        }
+        Ok(Some(code))
+    }
+}
+
+/// The code and description of a status object.
+fn describe(info: Option<&Amf>) -> String {
+    let field = |name| info.and_then(|i| i.get(name)).and_then(Amf::as_str).unwrap_or_default();
+    match (field("code"), field("description")) {
+        ("", "") => "no reason given".to_string(),
+        (code, "") => code.to_string(),
+        (code, description) => format!("{} ({})", code, description),
+    }
+}
+
+async fn read_messages(mut stream: ReadHalf<RtmpStream>, tx: mpsc::Sender<Result<(Message, u64), RtmpError>>) {
+    let mut reader = ChunkReader::new();
+    loop {
+        let result = reader.read(&mut stream).await;
+        if let Ok(message) = &result {
+            if message.type_id == SET_CHUNK_SIZE {
+                // The top bit is reserved
+                let size = message.payload.get(..4).map_or(0, |b| u32::from_be_bytes(b.try_into().unwrap()) & 0x7FFF_FFFF);
+                reader.set_chunk_size(size.max(1) as usize);
+            }
+        }
+        let failed = result.is_err();
+        if tx.send(result.map(|m| (m, reader.bytes_read()))).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::h264_stream;
+    use std::net::SocketAddr;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio::time::sleep;
+
+    /// What the stand-in ingest should say to `publish`
+    #[derive(Clone, Copy)]
+    enum Publish {
+        Start,
+        BadName,
+    }
+
+    /// A stand-in for nginx-rtmp: answers the publishing commands the way it
+    /// does and passes on every message it receives.
+    async fn ingest(publish: Publish) -> (SocketAddr, mpsc::UnboundedReceiver<Message>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, publish, tx.clone()));
+            }
+        });
+        (addr, rx)
+    }
+
+    async fn serve(mut stream: TcpStream, publish: Publish, tx: mpsc::UnboundedSender<Message>) {
+        let mut c0c1 = vec![0; 1 + handshake::PACKET_SIZE];
+        stream.read_exact(&mut c0c1).await.unwrap();
+        let mut reply = vec![handshake::VERSION];
+        reply.extend_from_slice(&[0x5A; handshake::PACKET_SIZE]);
+        reply.extend_from_slice(&c0c1[1..]);
+        stream.write_all(&reply).await.unwrap();
+        let mut c2 = vec![0; handshake::PACKET_SIZE];
+        stream.read_exact(&mut c2).await.unwrap();
+        assert_eq!(c2, [0x5A; handshake::PACKET_SIZE]);
+
+        let mut reader = ChunkReader::new();
+        let writer = ChunkWriter::new();
+        let send = |message: Message| {
+            let mut out = Vec::new();
+            writer.write(&mut out, 3, &message);
+            out
+        };
+        while let Ok(message) = reader.read(&mut stream).await {
+            if message.type_id == SET_CHUNK_SIZE {
+                reader.set_chunk_size(u32::from_be_bytes(message.payload[..4].try_into().unwrap()) as usize);
+            }
+            let mut answer = Vec::new();
+            if message.type_id == COMMAND {
+                let values = amf::decode(&message.payload).unwrap();
+                let transaction = values[1].clone();
+                let command = |values: Vec<Amf>| Message { type_id: COMMAND, stream_id: message.stream_id, timestamp: 0, payload: amf::encode(&values) };
+                let status = |level: &str, code: &str| Amf::object([("level", Amf::String(level.into())), ("code", Amf::String(code.into()))]);
+                match values[0].as_str().unwrap() {
+                    "connect" => {
+                        answer.extend(send(Message { type_id: WINDOW_ACK_SIZE, stream_id: 0, timestamp: 0, payload: 5_000_000u32.to_be_bytes().to_vec() }));
+                        answer.extend(send(Message { type_id: USER_CONTROL, stream_id: 0, timestamp: 0, payload: vec![0, 6, 0, 0, 0, 42] }));
+                        let result = vec![Amf::String("_result".into()), transaction, Amf::Null, status("status", "NetConnection.Connect.Success")];
+                        answer.extend(send(command(result)));
+                    }
+                    "createStream" => answer.extend(send(command(vec![Amf::String("_result".into()), transaction, Amf::Null, Amf::Number(1.0)]))),
+                    "publish" => {
+                        let info = match publish {
+                            Publish::Start => status("status", "NetStream.Publish.Start"),
+                            Publish::BadName => status("error", "NetStream.Publish.BadName"),
+                        };
+                        answer.extend(send(command(vec![Amf::String("onStatus".into()), Amf::Number(0.0), Amf::Null, info])));
+                    }
+                    _ => {}
+                }
+            }
+            stream.write_all(&answer).await.unwrap();
+            if tx.send(message).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn output(addr: SocketAddr) -> Arc<RtmpOutput> {
+        Arc::new(RtmpOutput::new(format!("rtmp://{}/live/test-key", addr).parse().unwrap()))
+    }
+
+    fn command_name(message: &Message) -> Option<String> {
+        (message.type_id == COMMAND).then(|| amf::decode(&message.payload).unwrap()[0].as_str().unwrap().to_string())
+    }
+
+    /// Everything the ingest received, up to the end of the stream
+    async fn until_deleted(received: &mut mpsc::UnboundedReceiver<Message>) -> Vec<Message> {
+        let mut messages = Vec::new();
+        while messages.last().and_then(command_name).as_deref() != Some("deleteStream") {
+            messages.push(timeout(Duration::from_secs(5), received.recv()).await.unwrap().unwrap());
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn publishes_flv_video() {
+        let (addr, mut received) = ingest(Publish::Start).await;
+        let rtmp = output(addr);
+        let run = tokio::spawn({
+            let rtmp = rtmp.clone();
+            async move { rtmp.run().await }
+        });
+        while !rtmp.is_connected() {
+            sleep(Duration::from_millis(5)).await;
+        }
+        for segment in h264_stream(40) {
+            rtmp.publish(&segment);
+        }
+        rtmp.finish();
+        run.await.unwrap().unwrap();
+
+        let messages = until_deleted(&mut received).await;
+        let commands: Vec<String> = messages.iter().filter_map(command_name).collect();
+        assert_eq!(commands, ["connect", "releaseStream", "FCPublish", "createStream", "publish", "FCUnpublish", "deleteStream"]);
+
+        let connect = amf::decode(&messages.iter().find(|m| m.type_id == COMMAND).unwrap().payload).unwrap();
+        assert_eq!(connect[2].get("app").and_then(Amf::as_str), Some("live"));
+        assert_eq!(connect[2].get("tcUrl").and_then(Amf::as_str), Some(format!("rtmp://{}/live", addr).as_str()));
+        let publish = messages.iter().find(|m| command_name(m).as_deref() == Some("publish")).unwrap();
+        assert_eq!(publish.stream_id, 1);
+        assert_eq!(amf::decode(&publish.payload).unwrap()[3], Amf::String("test-key".into()));
+        // The ping was answered
+        assert!(messages.iter().any(|m| m.type_id == USER_CONTROL && m.payload == [0, 7, 0, 0, 0, 42]));
+
+        let video: Vec<&Message> = messages.iter().filter(|m| m.type_id == VIDEO).collect();
+        assert_eq!(video.len(), 41);
+        assert_eq!(video[0].payload[..2], [0x17, 0]);
+        assert_eq!(video[1].payload[..2], [0x17, 1]);
+        assert_eq!((video[2].payload[..2].to_vec(), video[2].timestamp), (vec![0x27, 1], 33));
+        assert!(video.iter().all(|m| m.stream_id == 1));
+    }
+
+    #[tokio::test]
+    async fn sends_metadata_and_the_sequence_header_on_connect() {
+        let (addr, mut received) = ingest(Publish::Start).await;
+        let rtmp = output(addr);
+        // The encoder started before the connection
+        let stream = h264_stream(60);
+        for segment in &stream[..10] {
+            rtmp.publish(segment);
+        }
+        let run = tokio::spawn({
+            let rtmp = rtmp.clone();
+            async move { rtmp.run().await }
+        });
+        while !rtmp.is_connected() {
+            sleep(Duration::from_millis(5)).await;
+        }
+        for segment in &stream[10..] {
+            rtmp.publish(segment);
+        }
+        rtmp.finish();
+        run.await.unwrap().unwrap();
+
+        let messages = until_deleted(&mut received).await;
+        let data = messages.iter().find(|m| m.type_id == DATA).unwrap();
+        let values = amf::decode(&data.payload).unwrap();
+        assert_eq!(values[1], Amf::String("onMetaData".into()));
+        assert_eq!(values[2].get("height"), Some(&Amf::Number(360.0)));
+
+        // The sequence header, then frames from the keyframe at frame 30
+        let video: Vec<&Message> = messages.iter().filter(|m| m.type_id == VIDEO).collect();
+        assert_eq!(video[0].payload[..2], [0x17, 0]);
+        assert_eq!((video[1].payload[..2].to_vec(), video[1].timestamp), (vec![0x17, 1], 1000));
+        assert_eq!(video.len(), 31);
+    }
+
+    #[tokio::test]
+    async fn reports_a_refused_publish() {
+        let (addr, _received) = ingest(Publish::BadName).await;
+        match output(addr).connect_once().await {
+            Err(RtmpError::Rejected { command, reason }) => {
+                assert_eq!((command, reason.as_str()), ("publish", "NetStream.Publish.BadName"));
+            }
+            other => panic!("expected a rejection, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn keeps_the_sequence_header_through_an_overflow() {
+        let video = |id: u8, keyframe: bool| VideoTag { timestamp: 0, data: vec![id, 1, 0, 0], keyframe };
+        let header = |id: u8| VideoTag { timestamp: 0, data: vec![id, 0], keyframe: true };
+        let ids = |queue: &mut TagQueue| std::iter::from_fn(|| queue.pop()).map(|tag| tag.data[0]).collect::<Vec<_>>();
+
+        let mut queue = TagQueue::new(10);
+        queue.push(header(0));
+        [video(1, true), video(2, false), video(3, true)].into_iter().for_each(|tag| queue.push(tag));
+        assert_eq!(ids(&mut queue), [0, 3]);
+        assert_eq!(queue.dropped(), 2);
+
+        // A new header replaces what was queued for the old one, up to its keyframe
+        queue.push(video(4, true));
+        queue.push(header(5));
+        [video(6, false), video(7, true)].into_iter().for_each(|tag| queue.push(tag));
+        assert_eq!(ids(&mut queue), [5, 7]);
+    }
+
+    #[test]
+    fn parses_urls_and_hides_the_key() {
+        let url: RtmpUrl = "rtmp://live.example.com/app/live_123_abc".parse().unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.app.as_str()), ("live.example.com", 1935, "app"));
+        assert_eq!(url.stream_key.expose(), "live_123_abc");
+        assert_eq!(url.to_string(), "rtmp://live.example.com/app/<redacted>");
+
+        let url: RtmpUrl = "rtmps://ingest.example.com/live2/a/key?bandwidthtest=true".parse().unwrap();
+        assert_eq!((url.port, url.app.as_str(), url.tc_url.as_str()), (443, "live2/a", "rtmps://ingest.example.com/live2/a"));
+        assert_eq!(url.stream_key.expose(), "key?bandwidthtest=true");
+        assert!(!format!("{:?}", url).contains("key?"));
+
+        assert!("rtmp://example.com/app".parse::<RtmpUrl>().is_err());
+        assert!("http://example.com/app/key".parse::<RtmpUrl>().is_err());
+    }
+}
@@ -0,0 +1,212 @@
+//! AMF0, the encoding of RTMP commands and FLV script data.
+
+use super::RtmpError;
+
+const NUMBER: u8 = 0x00;
+const BOOLEAN: u8 = 0x01;
+const STRING: u8 = 0x02;
+const OBJECT: u8 = 0x03;
+const NULL: u8 = 0x05;
+const UNDEFINED: u8 = 0x06;
+const ECMA_ARRAY: u8 = 0x08;
+const OBJECT_END: u8 = 0x09;
+const STRICT_ARRAY: u8 = 0x0A;
+const LONG_STRING: u8 = 0x0C;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Amf {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    Object(Vec<(String, Amf)>),
+    Null,
+    Undefined,
+    EcmaArray(Vec<(String, Amf)>),
+    StrictArray(Vec<Amf>),
+}
+
+impl Amf {
+    pub fn object<'a>(properties: impl IntoIterator<Item = (&'a str, Amf)>) -> Self {
+        Amf::Object(properties.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Amf::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Amf::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// A property of an object or ECMA array.
+    pub fn get(&self, name: &str) -> Option<&Amf> {
+        match self {
+            Amf::Object(properties) | Amf::EcmaArray(properties) => properties.iter().find(|(k, _)| k == name).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Amf::Number(n) => {
+                out.push(NUMBER);
+                out.extend_from_slice(&n.to_be_bytes());
+            }
+            Amf::Boolean(b) => out.extend_from_slice(&[BOOLEAN, *b as u8]),
+            Amf::String(s) => match u16::try_from(s.len()) {
+                Ok(_) => {
+                    out.push(STRING);
+                    write_key(out, s);
+                }
+                Err(_) => {
+                    out.push(LONG_STRING);
+                    out.extend_from_slice(&(s.len() as u32).to_be_bytes());
+                    out.extend_from_slice(s.as_bytes());
+                }
+            },
+            Amf::Object(properties) => {
+                out.push(OBJECT);
+                write_properties(out, properties);
+            }
+            Amf::Null => out.push(NULL),
+            Amf::Undefined => out.push(UNDEFINED),
+            Amf::EcmaArray(properties) => {
+                out.push(ECMA_ARRAY);
+                out.extend_from_slice(&(properties.len() as u32).to_be_bytes());
+                write_properties(out, properties);
+            }
+            Amf::StrictArray(values) => {
+                out.push(STRICT_ARRAY);
+                out.extend_from_slice(&(values.len() as u32).to_be_bytes());
+                values.iter().for_each(|v| v.write(out));
+            }
+        }
+    }
+}
+
+/// Values one after the other, as in a command message.
+pub fn encode(values: &[Amf]) -> Vec<u8> {
+    let mut out = Vec::new();
+    values.iter().for_each(|v| v.write(&mut out));
+    out
+}
+
+/// Every value in `data`.
+pub fn decode(data: &[u8]) -> Result<Vec<Amf>, RtmpError> {
+    let mut reader = Reader { data, position: 0 };
+    let mut values = Vec::new();
+    while reader.position < data.len() {
+        values.push(reader.value()?);
+    }
+    Ok(values)
+}
+
+fn write_key(out: &mut Vec<u8>, key: &str) {
+    out.extend_from_slice(&(key.len() as u16).to_be_bytes());
+    out.extend_from_slice(key.as_bytes());
+}
+
+fn write_properties(out: &mut Vec<u8>, properties: &[(String, Amf)]) {
+    for (key, value) in properties {
+        write_key(out, key);
+        value.write(out);
+    }
+    // An empty key, then the end marker
+    out.extend_from_slice(&[0, 0, OBJECT_END]);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, count: usize) -> Result<&[u8], RtmpError> {
+        let bytes = self.data.get(self.position..self.position + count).ok_or(RtmpError::Protocol("truncated AMF0 value"))?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, RtmpError> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, RtmpError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self, length: usize) -> Result<String, RtmpError> {
+        Ok(String::from_utf8_lossy(self.bytes(length)?).into_owned())
+    }
+
+    fn value(&mut self) -> Result<Amf, RtmpError> {
+        let marker = self.bytes(1)?[0];
+        Ok(match marker {
+            NUMBER => Amf::Number(f64::from_be_bytes(self.bytes(8)?.try_into().unwrap())),
+            BOOLEAN => Amf::Boolean(self.bytes(1)?[0] != 0),
+            STRING => {
+                let length = self.u16()? as usize;
+                Amf::String(self.string(length)?)
+            }
+            LONG_STRING => {
+                let length = self.u32()? as usize;
+                Amf::String(self.string(length)?)
+            }
+            OBJECT => Amf::Object(self.properties()?),
+            NULL => Amf::Null,
+            UNDEFINED => Amf::Undefined,
+            ECMA_ARRAY => {
+                // The count is a hint; the end marker is what ends it
+                self.u32()?;
+                Amf::EcmaArray(self.properties()?)
+            }
+            STRICT_ARRAY => {
+                let count = self.u32()?;
+                Amf::StrictArray((0..count).map(|_| self.value()).collect::<Result<_, _>>()?)
+            }
+            _ => return Err(RtmpError::Protocol("unsupported AMF0 type")),
+        })
+    }
+
+    fn properties(&mut self) -> Result<Vec<(String, Amf)>, RtmpError> {
+        let mut properties = Vec::new();
+        loop {
+            let length = self.u16()? as usize;
+            if length == 0 && self.data.get(self.position) == Some(&OBJECT_END) {
+                self.position += 1;
+                return Ok(properties);
+            }
+            let key = self.string(length)?;
+            properties.push((key, self.value()?));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_a_connect_command() {
+        let values = vec![
+            Amf::String("connect".into()),
+            Amf::Number(1.0),
+            Amf::object([("app", Amf::String("live".into())), ("fpad", Amf::Boolean(false))]),
+            Amf::Null,
+            Amf::EcmaArray(vec![("width".into(), Amf::Number(1280.0))]),
+            Amf::StrictArray(vec![Amf::Undefined]),
+        ];
+        let data = encode(&values);
+        assert_eq!(data[..10], [STRING, 0, 7, b'c', b'o', b'n', b'n', b'e', b'c', b't']);
+        let decoded = decode(&data).unwrap();
+        assert_eq!(decoded, values);
+        assert_eq!(decoded[2].get("app").and_then(Amf::as_str), Some("live"));
+        assert!(decode(&data[..data.len() - 1]).is_err());
+    }
+}
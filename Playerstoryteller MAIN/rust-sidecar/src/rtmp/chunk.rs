@@ -0,0 +1,245 @@
+//! The RTMP chunk stream: messages split into chunks of at most the chunk
+//! size, each behind a header that can leave out what the previous chunk on
+//! the same chunk stream already said.
+
+use std::collections::HashMap;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::RtmpError;
+
+/// Chunk size until either side sets another
+pub const DEFAULT_CHUNK_SIZE: usize = 128;
+
+/// Timestamps from here on go in the extended timestamp field
+const EXTENDED_TIMESTAMP: u32 = 0xFF_FFFF;
+
+/// Larger messages are not something a publisher should be sent
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub type_id: u8,
+    /// Message stream: 0 for the connection, else the one from createStream
+    pub stream_id: u32,
+    /// Milliseconds
+    pub timestamp: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Splits messages into chunks. Every message starts with a full (type 0)
+/// header, so no state is kept beyond the chunk size.
+pub struct ChunkWriter {
+    chunk_size: usize,
+}
+
+impl ChunkWriter {
+    pub fn new() -> Self {
+        Self { chunk_size: DEFAULT_CHUNK_SIZE }
+    }
+
+    /// Takes effect for the next message. The peer must be told first, with
+    /// a Set Chunk Size message.
+    pub fn set_chunk_size(&mut self, size: usize) {
+        self.chunk_size = size;
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>, chunk_stream: u8, message: &Message) {
+        debug_assert!((2..64).contains(&chunk_stream), "one byte basic headers only");
+        let extended = message.timestamp >= EXTENDED_TIMESTAMP;
+        out.push(chunk_stream);
+        out.extend_from_slice(&message.timestamp.min(EXTENDED_TIMESTAMP).to_be_bytes()[1..]);
+        out.extend_from_slice(&(message.payload.len() as u32).to_be_bytes()[1..]);
+        out.push(message.type_id);
+        // The only little-endian field in RTMP
+        out.extend_from_slice(&message.stream_id.to_le_bytes());
+
+        for (i, chunk) in message.payload.chunks(self.chunk_size).enumerate() {
+            if i > 0 {
+                // Type 3: everything as before
+                out.push(0xC0 | chunk_stream);
+            }
+            if extended {
+                out.extend_from_slice(&message.timestamp.to_be_bytes());
+            }
+            out.extend_from_slice(chunk);
+        }
+        if message.payload.is_empty() && extended {
+            out.extend_from_slice(&message.timestamp.to_be_bytes());
+        }
+    }
+}
+
+/// Header fields and the partly read message of one chunk stream.
+#[derive(Default)]
+struct ChunkStream {
+    timestamp: u32,
+    delta: u32,
+    length: usize,
+    type_id: u8,
+    stream_id: u32,
+    /// The last header had an extended timestamp, so type 3 chunks carry one too
+    extended: bool,
+    payload: Vec<u8>,
+}
+
+/// Reassembles messages from chunks.
+pub struct ChunkReader {
+    chunk_size: usize,
+    streams: HashMap<u32, ChunkStream>,
+    /// Bytes read so far, for acknowledgements
+    bytes_read: u64,
+}
+
+impl ChunkReader {
+    pub fn new() -> Self {
+        Self { chunk_size: DEFAULT_CHUNK_SIZE, streams: HashMap::new(), bytes_read: 0 }
+    }
+
+    pub fn set_chunk_size(&mut self, size: usize) {
+        self.chunk_size = size;
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// The next complete message. Not cancel safe: a message read halfway is lost.
+    pub async fn read<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> Result<Message, RtmpError> {
+        loop {
+            if let Some(message) = self.read_chunk(reader).await? {
+                return Ok(message);
+            }
+        }
+    }
+
+    async fn read_chunk<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> Result<Option<Message>, RtmpError> {
+        let first = self.u8(reader).await?;
+        let format = first >> 6;
+        let id = match first & 0x3F {
+            0 => 64 + self.u8(reader).await? as u32,
+            1 => 64 + self.u8(reader).await? as u32 + 256 * self.u8(reader).await? as u32,
+            id => id as u32,
+        };
+
+        let mut header = [0; 11];
+        let header_length = [11, 7, 3, 0][format as usize];
+        self.read_exact(reader, &mut header[..header_length]).await?;
+        let time_field = u32::from_be_bytes([0, header[0], header[1], header[2]]);
+
+        let stream = self.streams.entry(id).or_default();
+        if format != 0 && stream.type_id == 0 {
+            return Err(RtmpError::Protocol("compressed header on a new chunk stream"));
+        }
+        if format <= 1 {
+            stream.length = u32::from_be_bytes([0, header[3], header[4], header[5]]) as usize;
+            stream.type_id = header[6];
+            if stream.length > MAX_MESSAGE_SIZE {
+                return Err(RtmpError::Protocol("message too large"));
+            }
+        }
+        if format == 0 {
+            stream.stream_id = u32::from_le_bytes([header[7], header[8], header[9], header[10]]);
+        }
+        if format < 3 {
+            stream.extended = time_field == EXTENDED_TIMESTAMP;
+        }
+        let extended = stream.extended;
+        let starting = stream.payload.is_empty();
+
+        let time = if extended { self.u32(reader).await? } else { time_field };
+        let stream = self.streams.get_mut(&id).expect("inserted above");
+        if starting {
+            match format {
+                0 => stream.timestamp = time,
+                1 | 2 => {
+                    stream.delta = time;
+                    stream.timestamp = stream.timestamp.wrapping_add(time);
+                }
+                // A new message with everything, the delta too, as before
+                _ => stream.timestamp = stream.timestamp.wrapping_add(stream.delta),
+            }
+        }
+
+        let remaining = stream.length - stream.payload.len();
+        let mut chunk = vec![0; remaining.min(self.chunk_size)];
+        self.read_exact(reader, &mut chunk).await?;
+        let stream = self.streams.get_mut(&id).expect("inserted above");
+        stream.payload.extend_from_slice(&chunk);
+        if stream.payload.len() < stream.length {
+            return Ok(None);
+        }
+        Ok(Some(Message {
+            type_id: stream.type_id,
+            stream_id: stream.stream_id,
+            timestamp: stream.timestamp,
+            payload: std::mem::take(&mut stream.payload),
+        }))
+    }
+
+    async fn read_exact<R: AsyncRead + Unpin>(&mut self, reader: &mut R, buf: &mut [u8]) -> Result<(), RtmpError> {
+        reader.read_exact(buf).await?;
+        self.bytes_read += buf.len() as u64;
+        Ok(())
+    }
+
+    async fn u8<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> Result<u8, RtmpError> {
+        let mut byte = [0];
+        self.read_exact(reader, &mut byte).await?;
+        Ok(byte[0])
+    }
+
+    async fn u32<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> Result<u32, RtmpError> {
+        let mut bytes = [0; 4];
+        self.read_exact(reader, &mut bytes).await?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn splits_and_reassembles_messages() {
+        let mut writer = ChunkWriter::new();
+        let video = Message { type_id: 9, stream_id: 1, timestamp: 40, payload: (0..300).map(|i| i as u8).collect() };
+        let late = Message { type_id: 9, stream_id: 1, timestamp: 0x0100_0000, payload: vec![7; 200] };
+        let command = Message { type_id: 20, stream_id: 0, timestamp: 0, payload: vec![1, 2, 3] };
+
+        let mut data = Vec::new();
+        writer.write(&mut data, 6, &video);
+        // 300 bytes in 128 byte chunks: a 12 byte header and two 1 byte ones
+        assert_eq!(data.len(), 12 + 300 + 2);
+        assert_eq!(data[12 + 128], 0xC6);
+        writer.set_chunk_size(100);
+        writer.write(&mut data, 6, &late);
+        writer.write(&mut data, 3, &command);
+
+        let mut reader = ChunkReader::new();
+        let mut input = data.as_slice();
+        assert_eq!(reader.read(&mut input).await.unwrap(), video);
+        reader.set_chunk_size(100);
+        assert_eq!(reader.read(&mut input).await.unwrap(), late);
+        assert_eq!(reader.read(&mut input).await.unwrap(), command);
+        assert_eq!(reader.bytes_read(), data.len() as u64);
+        assert!(matches!(reader.read(&mut input).await, Err(RtmpError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn applies_compressed_headers() {
+        // Type 0, then a type 2 with a delta of 20, then a type 3 that repeats it
+        let mut data = vec![0x04, 0, 0, 10, 0, 0, 1, 9, 1, 0, 0, 0, 0xAA];
+        data.extend_from_slice(&[0x84, 0, 0, 20, 0xBB]);
+        data.extend_from_slice(&[0xC4, 0xCC]);
+
+        let mut reader = ChunkReader::new();
+        let mut input = data.as_slice();
+        for (timestamp, payload) in [(10, 0xAA), (30, 0xBB), (50, 0xCC)] {
+            let message = reader.read(&mut input).await.unwrap();
+            assert_eq!((message.timestamp, message.payload), (timestamp, vec![payload]));
+        }
+        // A compressed header needs something to go on
+        assert!(matches!(ChunkReader::new().read(&mut &[0x45, 0, 0, 1, 0, 0, 1, 9][..]).await, Err(RtmpError::Protocol(_))));
+    }
+}
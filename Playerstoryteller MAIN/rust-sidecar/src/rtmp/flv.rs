@@ -0,0 +1,178 @@
+//! FLV video tags from the fMP4 stream: an AVC sequence header built from
+//! the init segment's `avcC`, then one tag per sample.
+//!
+//! fMP4 samples are already length-prefixed NAL units, which is what FLV
+//! carries too, so sample data is passed through. Timestamps are decode
+//! times in milliseconds, kept rising across init segments so an encoder
+//! restart doesn't send the server back in time.
+
+use std::collections::HashMap;
+
+use log::warn;
+
+use super::amf::Amf;
+use crate::mp4::{avc_config, single_track_samples, sps_dimensions, track_info, Mp4Segment, SegmentType, TrackInfo};
+
+/// FLV codec id of AVC
+const CODEC_AVC: u8 = 7;
+
+const KEY_FRAME: u8 = 1;
+const INTER_FRAME: u8 = 2;
+
+const SEQUENCE_HEADER: u8 = 0;
+const NALU: u8 = 1;
+
+/// The body of an FLV video tag, which is also an RTMP video message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoTag {
+    /// Milliseconds
+    pub timestamp: u32,
+    pub data: Vec<u8>,
+    /// A keyframe or sequence header: somewhere a decoder can start
+    pub keyframe: bool,
+}
+
+impl VideoTag {
+    pub fn is_sequence_header(&self) -> bool {
+        self.data.get(1) == Some(&SEQUENCE_HEADER)
+    }
+}
+
+#[derive(Default)]
+pub struct FlvVideo {
+    tracks: HashMap<u32, TrackInfo>,
+    sequence_header: Option<VideoTag>,
+    metadata: Option<Amf>,
+    /// Milliseconds added to the decode times of the current init segment
+    offset: u64,
+    /// Decode time of the first sample since the init segment, in ticks
+    start: Option<u64>,
+    /// Decode time after the last sample, for fragments without tfdt
+    next_decode_time: u64,
+    /// Timestamp after the last tag, where the next init segment carries on
+    next_timestamp: u64,
+}
+
+impl FlvVideo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The sequence header for the current init segment, which a new
+    /// connection has to be sent before any frame.
+    pub fn sequence_header(&self) -> Option<&VideoTag> {
+        self.sequence_header.as_ref()
+    }
+
+    /// `onMetaData` for the current init segment.
+    pub fn metadata(&self) -> Option<&Amf> {
+        self.metadata.as_ref()
+    }
+
+    /// Tags for `segment`: a new sequence header for an init segment, and a
+    /// tag per sample for media.
+    pub fn push(&mut self, segment: &Mp4Segment) -> Vec<VideoTag> {
+        if segment.kind == SegmentType::Init {
+            self.tracks = track_info(&segment.data);
+            self.offset = self.next_timestamp;
+            self.start = None;
+            self.next_decode_time = 0;
+            self.sequence_header = None;
+            let Some(config) = avc_config(&segment.data) else {
+                warn!("RTMP: the init segment has no avcC, dropping video until the next one");
+                return Vec::new();
+            };
+
+            let mut data = vec![KEY_FRAME << 4 | CODEC_AVC, SEQUENCE_HEADER, 0, 0, 0];
+            config.write(&mut data);
+            let header = VideoTag { timestamp: self.offset as u32, data, keyframe: true };
+            self.sequence_header = Some(header.clone());
+
+            let mut metadata = vec![("videocodecid".to_string(), Amf::Number(CODEC_AVC as f64))];
+            if let Some((width, height)) = sps_dimensions(&segment.data) {
+                metadata.push(("width".into(), Amf::Number(width as f64)));
+                metadata.push(("height".into(), Amf::Number(height as f64)));
+            }
+            if let Some(frame_rate) = config.first_sps().ok().and_then(|sps| sps.frame_rate()) {
+                metadata.push(("framerate".into(), Amf::Number(frame_rate)));
+            }
+            metadata.push(("encoder".into(), Amf::String("ratlab-sidecar".into())));
+            self.metadata = Some(Amf::EcmaArray(metadata));
+            return vec![header];
+        }
+
+        if self.sequence_header.is_none() {
+            return Vec::new();
+        }
+        let Some((track_id, decode_time, samples)) = single_track_samples(&segment.data, &self.tracks) else {
+            warn!("RTMP: dropping a media segment that is not one track fragment");
+            return Vec::new();
+        };
+        let timescale = self.tracks.get(&track_id).map_or(0, |t| t.timescale) as u64;
+        if timescale == 0 {
+            return Vec::new();
+        }
+
+        let mut decode_time = decode_time.unwrap_or(self.next_decode_time);
+        let start = *self.start.get_or_insert(decode_time);
+        let millis = |ticks: u64| ticks * 1000 / timescale;
+        let mut tags = Vec::with_capacity(samples.len());
+        for sample in samples {
+            let timestamp = self.offset + millis(decode_time.saturating_sub(start));
+            // Signed 24 bits
+            let composition_time = (sample.composition_offset as i64 * 1000 / timescale as i64) as i32;
+            let frame_type = if sample.keyframe { KEY_FRAME } else { INTER_FRAME };
+            let mut data = Vec::with_capacity(sample.data.len() + 5);
+            data.extend_from_slice(&[frame_type << 4 | CODEC_AVC, NALU]);
+            data.extend_from_slice(&composition_time.to_be_bytes()[1..]);
+            data.extend_from_slice(&sample.data);
+            tags.push(VideoTag { timestamp: timestamp as u32, data, keyframe: sample.keyframe });
+
+            decode_time += sample.duration as u64;
+            self.next_timestamp = self.offset + millis(decode_time.saturating_sub(start));
+        }
+        self.next_decode_time = decode_time;
+        tags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h264::AvcConfig;
+    use crate::testing::h264_stream;
+
+    #[test]
+    fn builds_the_sequence_header_and_frames() {
+        let stream = h264_stream(40);
+        let mut flv = FlvVideo::new();
+        let tags: Vec<VideoTag> = stream.iter().flat_map(|s| flv.push(s)).collect();
+        assert_eq!(tags.len(), 41);
+
+        let header = &tags[0];
+        assert!(header.is_sequence_header() && header.keyframe);
+        assert_eq!(header.data[..5], [0x17, 0, 0, 0, 0]);
+        assert_eq!(Some(AvcConfig::parse(&header.data[5..]).unwrap()), avc_config(&stream[0].data));
+        assert_eq!(flv.metadata().unwrap().get("width"), Some(&Amf::Number(640.0)));
+
+        // 30 fps: 33 ms apart, a keyframe every 30 frames
+        let frames = &tags[1..];
+        assert_eq!(frames.iter().map(|t| t.timestamp).take(4).collect::<Vec<_>>(), [0, 33, 66, 100]);
+        assert_eq!(frames[0].data[..5], [0x17, 1, 0, 0, 0]);
+        assert_eq!(frames[1].data[..2], [0x27, 1]);
+        assert!(frames[30].keyframe && !frames[29].keyframe);
+        // The sample itself, length prefix and all
+        assert_eq!(frames[2].data[5..], [0, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn keeps_time_going_across_init_segments() {
+        let mut flv = FlvVideo::new();
+        for segment in h264_stream(30) {
+            flv.push(&segment);
+        }
+        let tags: Vec<VideoTag> = h264_stream(2).iter().flat_map(|s| flv.push(s)).collect();
+        assert!(tags[0].is_sequence_header());
+        assert_eq!(tags.iter().map(|t| t.timestamp).collect::<Vec<_>>(), [1000, 1000, 1033]);
+    }
+}
@@ -0,0 +1,31 @@
+//! The simple RTMP handshake: C0/C1 out, S0/S1/S2 in, S1 echoed back as C2.
+//! Ingest servers accept it; only Flash players needed the digest variant.
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::RtmpError;
+
+pub const VERSION: u8 = 3;
+
+/// Size of C1, C2, S1 and S2
+pub const PACKET_SIZE: usize = 1536;
+
+pub async fn client<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<(), RtmpError> {
+    // Time and zero, then random bytes the server echoes in S2
+    let mut c0c1 = vec![0; 1 + PACKET_SIZE];
+    c0c1[0] = VERSION;
+    getrandom::getrandom(&mut c0c1[9..]).expect("OS random source unavailable");
+    stream.write_all(&c0c1).await?;
+
+    let mut s0s1 = vec![0; 1 + PACKET_SIZE];
+    stream.read_exact(&mut s0s1).await?;
+    if s0s1[0] != VERSION {
+        return Err(RtmpError::Protocol("server speaks another RTMP version"));
+    }
+    // C2 echoes S1
+    stream.write_all(&s0s1[1..]).await?;
+
+    let mut s2 = vec![0; PACKET_SIZE];
+    stream.read_exact(&mut s2).await?;
+    Ok(())
+}
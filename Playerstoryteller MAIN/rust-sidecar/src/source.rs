@@ -0,0 +1,346 @@
+//! Where raw video frames come from.
+//!
+//! `FrameSource` decouples the encoder from Windows capture. Window capture is
+//! one implementation. The moving test pattern, Y4M files and PNG sequences
+//! run anywhere, so the encode and stream path can be exercised on Linux.
+
+#[cfg(windows)]
+pub mod capture;
+mod image_sequence;
+mod pattern;
+mod y4m;
+
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+pub use self::image_sequence::PngSequence;
+pub use self::pattern::TestPattern;
+pub use self::y4m::Y4mReader;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 8-bit blue, green, red, alpha: 4 bytes per pixel
+    Bgra,
+    /// Full-resolution Y plane followed by interleaved U/V at quarter resolution
+    Nv12,
+}
+
+impl PixelFormat {
+    /// Bytes in one tightly packed frame
+    pub fn frame_size(self, width: u32, height: u32) -> usize {
+        let (width, height) = (width as usize, height as usize);
+        match self {
+            PixelFormat::Bgra => width * height * 4,
+            PixelFormat::Nv12 => width * height + 2 * width.div_ceil(2) * height.div_ceil(2),
+        }
+    }
+}
+
+/// One uncompressed frame, rows top to bottom without padding.
+#[derive(Clone)]
+pub struct VideoFrame {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    /// Presentation time since the first frame of the source
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Debug for VideoFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VideoFrame")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("format", &self.format)
+            .field("timestamp", &self.timestamp)
+            .finish_non_exhaustive()
+    }
+}
+
+impl VideoFrame {
+    /// For Media Foundation, which takes BGRA
+    #[cfg(windows)]
+    pub fn to_bgra(&self) -> Cow<'_, [u8]> {
+        match self.format {
+            PixelFormat::Bgra => Cow::Borrowed(&self.data),
+            PixelFormat::Nv12 => Cow::Owned(nv12_to_bgra(&self.data, self.width, self.height)),
+        }
+    }
+
+    pub fn to_nv12(&self) -> Cow<'_, [u8]> {
+        match self.format {
+            PixelFormat::Nv12 => Cow::Borrowed(&self.data),
+            PixelFormat::Bgra => Cow::Owned(bgra_to_nv12(&self.data, self.width, self.height)),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SourceError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid Y4M stream: {0}")]
+    InvalidY4m(String),
+    #[error("unsupported Y4M colorspace {0} (only 8-bit 4:2:0 is supported)")]
+    UnsupportedColorspace(String),
+    #[error("cannot decode {path}: {message}")]
+    Image { path: PathBuf, message: String },
+    #[error("no PNG files in {0}")]
+    EmptySequence(PathBuf),
+    #[error("{path} is {width}x{height}, expected {expected_width}x{expected_height}")]
+    SizeMismatch { path: PathBuf, width: u32, height: u32, expected_width: u32, expected_height: u32 },
+    #[cfg(windows)]
+    #[error("capture failed: {0}")]
+    Capture(String),
+    #[cfg(not(windows))]
+    #[error("window capture needs Windows, use --source pattern, y4m:<file> or png:<dir>")]
+    CaptureUnsupported,
+}
+
+/// A producer of timestamped frames. All frames of one source have the same size.
+pub trait FrameSource: Send {
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+
+    /// Nominal frames per second, if the source has one
+    fn frame_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Block until the next frame. `None` once the source is exhausted.
+    fn next_frame(&mut self) -> Result<Option<VideoFrame>, SourceError>;
+}
+
+impl<S: FrameSource + ?Sized> FrameSource for Box<S> {
+    fn width(&self) -> u32 {
+        (**self).width()
+    }
+
+    fn height(&self) -> u32 {
+        (**self).height()
+    }
+
+    fn frame_rate(&self) -> Option<u32> {
+        (**self).frame_rate()
+    }
+
+    fn next_frame(&mut self) -> Result<Option<VideoFrame>, SourceError> {
+        (**self).next_frame()
+    }
+}
+
+/// Holds each frame back until its timestamp, for sources that can produce
+/// frames faster than real time.
+pub struct Paced<S> {
+    inner: S,
+    started: Option<Instant>,
+}
+
+impl<S: FrameSource> Paced<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, started: None }
+    }
+}
+
+impl<S: FrameSource> FrameSource for Paced<S> {
+    fn width(&self) -> u32 {
+        self.inner.width()
+    }
+
+    fn height(&self) -> u32 {
+        self.inner.height()
+    }
+
+    fn frame_rate(&self) -> Option<u32> {
+        self.inner.frame_rate()
+    }
+
+    fn next_frame(&mut self) -> Result<Option<VideoFrame>, SourceError> {
+        let Some(frame) = self.inner.next_frame()? else { return Ok(None) };
+        let started = *self.started.get_or_insert_with(Instant::now);
+        if let Some(wait) = (started + frame.timestamp).checked_duration_since(Instant::now()) {
+            std::thread::sleep(wait);
+        }
+        Ok(Some(frame))
+    }
+}
+
+/// `--source`: what to encode.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceSpec {
+    /// The game window of `--pid` (Windows only)
+    Capture,
+    /// Synthetic moving pattern with a frame counter
+    Pattern { width: u32, height: u32 },
+    /// A YUV4MPEG2 file
+    Y4m(PathBuf),
+    /// A directory of PNG files, played in file name order
+    Png(PathBuf),
+}
+
+impl SourceSpec {
+    /// Open a portable source, paced to real time. Window capture is set up by the caller.
+    pub fn open(&self, frame_rate: u32) -> Result<Option<Box<dyn FrameSource>>, SourceError> {
+        Ok(Some(match self {
+            SourceSpec::Capture => return Ok(None),
+            SourceSpec::Pattern { width, height } => Box::new(Paced::new(TestPattern::new(*width, *height, frame_rate))),
+            SourceSpec::Y4m(path) => Box::new(Paced::new(Y4mReader::open(path)?)),
+            SourceSpec::Png(dir) => Box::new(Paced::new(PngSequence::open(dir, frame_rate)?)),
+        }))
+    }
+}
+
+impl FromStr for SourceSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = match s.split_once(':') {
+            Some((kind, arg)) => (kind, Some(arg)),
+            None => (s, None),
+        };
+        match (kind, arg) {
+            ("capture", None) => Ok(SourceSpec::Capture),
+            ("pattern", None) => Ok(SourceSpec::Pattern { width: 1280, height: 720 }),
+            ("pattern", Some(size)) => {
+                let (width, height) = size
+                    .split_once('x')
+                    .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                    .filter(|&(w, h): &(u32, u32)| w >= 16 && h >= 16 && w % 2 == 0 && h % 2 == 0)
+                    .ok_or_else(|| format!("invalid pattern size {}, expected e.g. 1280x720 (even, at least 16)", size))?;
+                Ok(SourceSpec::Pattern { width, height })
+            }
+            ("y4m", Some(path)) if !path.is_empty() => Ok(SourceSpec::Y4m(path.into())),
+            ("png", Some(dir)) if !dir.is_empty() => Ok(SourceSpec::Png(dir.into())),
+            _ => Err(format!("unknown source {}, expected capture, pattern[:WxH], y4m:<file> or png:<dir>", s)),
+        }
+    }
+}
+
+// BT.601 limited range, the default for H.264 without colour description
+
+fn rgb_to_yuv(r: i32, g: i32, b: i32) -> (u8, u8, u8) {
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    (y.clamp(0, 255) as u8, u.clamp(0, 255) as u8, v.clamp(0, 255) as u8)
+}
+
+#[cfg(any(windows, test))]
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> (u8, u8, u8) {
+    let c = 298 * (y as i32 - 16);
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    let clamp = |x: i32| ((x + 128) >> 8).clamp(0, 255) as u8;
+    (clamp(c + 409 * e), clamp(c - 100 * d - 208 * e), clamp(c + 516 * d))
+}
+
+pub fn bgra_to_nv12(bgra: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let (w, h) = (width as usize, height as usize);
+    let (cw, ch) = (w.div_ceil(2), h.div_ceil(2));
+    let mut out = vec![0u8; PixelFormat::Nv12.frame_size(width, height)];
+    let (luma, chroma) = out.split_at_mut(w * h);
+
+    for (i, px) in bgra.chunks_exact(4).take(w * h).enumerate() {
+        luma[i] = rgb_to_yuv(px[2] as i32, px[1] as i32, px[0] as i32).0;
+    }
+    // Chroma from the average of each 2x2 block
+    for cy in 0..ch {
+        for cx in 0..cw {
+            let (mut r, mut g, mut b, mut n) = (0, 0, 0, 0);
+            for y in (cy * 2)..(cy * 2 + 2).min(h) {
+                for x in (cx * 2)..(cx * 2 + 2).min(w) {
+                    let px = &bgra[(y * w + x) * 4..];
+                    b += px[0] as i32;
+                    g += px[1] as i32;
+                    r += px[2] as i32;
+                    n += 1;
+                }
+            }
+            let (_, u, v) = rgb_to_yuv(r / n, g / n, b / n);
+            chroma[(cy * cw + cx) * 2] = u;
+            chroma[(cy * cw + cx) * 2 + 1] = v;
+        }
+    }
+    out
+}
+
+#[cfg(any(windows, test))]
+pub fn nv12_to_bgra(nv12: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let (w, h) = (width as usize, height as usize);
+    let cw = w.div_ceil(2);
+    let (luma, chroma) = nv12.split_at(w * h);
+    let mut out = vec![0u8; w * h * 4];
+    for y in 0..h {
+        for x in 0..w {
+            let c = ((y / 2) * cw + x / 2) * 2;
+            let (r, g, b) = yuv_to_rgb(luma[y * w + x], chroma[c], chroma[c + 1]);
+            out[(y * w + x) * 4..(y * w + x) * 4 + 4].copy_from_slice(&[b, g, r, 255]);
+        }
+    }
+    out
+}
+
+/// Planar 4:2:0 (I420) to NV12: same luma, chroma planes interleaved.
+pub fn i420_to_nv12(i420: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let luma = width as usize * height as usize;
+    let chroma = width.div_ceil(2) as usize * height.div_ceil(2) as usize;
+    let (u, v) = i420[luma..luma + 2 * chroma].split_at(chroma);
+    let mut out = Vec::with_capacity(luma + 2 * chroma);
+    out.extend_from_slice(&i420[..luma]);
+    for (u, v) in u.iter().zip(v) {
+        out.push(*u);
+        out.push(*v);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_source_specs() {
+        assert_eq!("capture".parse(), Ok(SourceSpec::Capture));
+        assert_eq!("pattern".parse(), Ok(SourceSpec::Pattern { width: 1280, height: 720 }));
+        assert_eq!("pattern:640x360".parse(), Ok(SourceSpec::Pattern { width: 640, height: 360 }));
+        assert_eq!(r"y4m:C:\clips\test.y4m".parse(), Ok(SourceSpec::Y4m(r"C:\clips\test.y4m".into())));
+        assert_eq!("png:frames".parse(), Ok(SourceSpec::Png("frames".into())));
+        assert!("pattern:641x360".parse::<SourceSpec>().is_err());
+        assert!("png:".parse::<SourceSpec>().is_err());
+        assert!("webcam".parse::<SourceSpec>().is_err());
+    }
+
+    #[test]
+    fn color_conversion_round_trips() {
+        // Flat 2x2 blocks so chroma subsampling loses nothing
+        let colors = [[0u8, 0, 255, 255], [0, 255, 0, 255], [255, 0, 0, 255], [128, 128, 128, 255]];
+        let mut bgra = Vec::new();
+        for row in 0..4 {
+            for col in 0..4 {
+                bgra.extend_from_slice(&colors[(row / 2) * 2 + col / 2]);
+            }
+        }
+
+        let nv12 = bgra_to_nv12(&bgra, 4, 4);
+        assert_eq!(nv12.len(), PixelFormat::Nv12.frame_size(4, 4));
+        let back = nv12_to_bgra(&nv12, 4, 4);
+        for (a, b) in bgra.iter().zip(&back) {
+            assert!(a.abs_diff(*b) <= 3, "{:?} -> {:?}", bgra, back);
+        }
+    }
+
+    #[test]
+    fn interleaves_i420_chroma() {
+        let i420 = [1, 2, 3, 4, 10, 20];
+        assert_eq!(i420_to_nv12(&i420, 2, 2), [1, 2, 3, 4, 10, 20]);
+        let i420: Vec<u8> = (0..16).chain([100, 101, 102, 103]).chain([200, 201, 202, 203]).collect();
+        assert_eq!(&i420_to_nv12(&i420, 4, 4)[16..], [100, 200, 101, 201, 102, 202, 103, 203]);
+    }
+}
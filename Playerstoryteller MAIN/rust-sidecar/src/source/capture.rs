@@ -0,0 +1,165 @@
+//! Windows Graphics Capture of the game window, as a `FrameSource`.
+//!
+//! windows-capture calls back on its own thread; frames are copied out and
+//! handed over through a short channel. When the encoder falls behind, new
+//! frames are dropped rather than queued, like the encoder did before.
+
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::info;
+use windows::core::BOOL;
+use windows::Win32::Foundation::{HWND, LPARAM, RECT};
+use windows::Win32::UI::WindowsAndMessaging::{
+    EnumWindows, GetClientRect, GetWindowTextLengthW, GetWindowThreadProcessId, IsWindowVisible,
+};
+use windows_capture::capture::{Context, GraphicsCaptureApiHandler};
+use windows_capture::frame::Frame;
+use windows_capture::graphics_capture_api::InternalCaptureControl;
+use windows_capture::settings::{
+    ColorFormat, CursorCaptureSettings, DirtyRegionSettings, DrawBorderSettings, MinimumUpdateIntervalSettings,
+    SecondaryWindowSettings, Settings,
+};
+use windows_capture::window::Window;
+
+use super::{FrameSource, PixelFormat, SourceError, VideoFrame};
+
+/// Frames waiting for the encoder before new ones are dropped
+const FRAME_QUEUE: usize = 2;
+
+pub struct WindowCapture {
+    frames: Receiver<VideoFrame>,
+    width: u32,
+    height: u32,
+    thread: Option<JoinHandle<Result<(), String>>>,
+}
+
+impl WindowCapture {
+    /// Capture the main window of process `pid`.
+    pub fn start(pid: u32) -> Result<Self, SourceError> {
+        info!("Searching for window with PID: {}", pid);
+        let hwnd = unsafe { find_main_window(pid) };
+        if hwnd.0.is_null() {
+            return Err(SourceError::Capture(format!("game window not found (PID {})", pid)));
+        }
+
+        let mut rect = RECT::default();
+        unsafe { GetClientRect(hwnd, &mut rect) }.map_err(|e| SourceError::Capture(e.to_string()))?;
+        let width = (rect.right - rect.left) as u32;
+        let height = (rect.bottom - rect.top) as u32;
+
+        let window = Window::from_raw_hwnd(hwnd.0);
+        if !window.is_valid() {
+            return Err(SourceError::Capture("invalid window handle".into()));
+        }
+
+        let (sender, frames) = mpsc::sync_channel(FRAME_QUEUE);
+        let settings = Settings::new(
+            window,
+            CursorCaptureSettings::Default,
+            DrawBorderSettings::Default,
+            SecondaryWindowSettings::Default,
+            MinimumUpdateIntervalSettings::Default,
+            DirtyRegionSettings::Default,
+            ColorFormat::Bgra8,
+            sender,
+        );
+        let thread = thread::spawn(move || CaptureHandler::start(settings).map_err(|e| e.to_string()));
+
+        Ok(Self { frames, width, height, thread: Some(thread) })
+    }
+}
+
+impl FrameSource for WindowCapture {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn next_frame(&mut self) -> Result<Option<VideoFrame>, SourceError> {
+        if let Ok(frame) = self.frames.recv() {
+            return Ok(Some(frame));
+        }
+        // The capture thread has gone: the window closed, or capture failed
+        match self.thread.take().map(|t| t.join()) {
+            Some(Ok(Err(e))) => Err(SourceError::Capture(e)),
+            Some(Err(_)) => Err(SourceError::Capture("capture thread panicked".into())),
+            _ => {
+                info!("Capture session ended");
+                Ok(None)
+            }
+        }
+    }
+}
+
+struct CaptureHandler {
+    frames: SyncSender<VideoFrame>,
+    first_timestamp: Option<i64>,
+    dropped: u64,
+}
+
+impl GraphicsCaptureApiHandler for CaptureHandler {
+    type Flags = SyncSender<VideoFrame>;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn new(ctx: Context<Self::Flags>) -> Result<Self, Self::Error> {
+        Ok(Self { frames: ctx.flags, first_timestamp: None, dropped: 0 })
+    }
+
+    fn on_frame_arrived(&mut self, frame: &mut Frame, capture_control: InternalCaptureControl) -> Result<(), Self::Error> {
+        // TimeSpan ticks are 100 ns
+        let ticks = frame.timestamp()?.Duration;
+        let first = *self.first_timestamp.get_or_insert(ticks);
+        let timestamp = Duration::from_nanos((ticks - first).max(0) as u64 * 100);
+
+        let (width, height) = (frame.width(), frame.height());
+        let mut buffer = frame.buffer().map_err(|e| e.to_string())?;
+        let raw = buffer.as_raw_buffer();
+
+        // Rows may be padded to the texture pitch
+        let row = width as usize * 4;
+        let pitch = raw.len() / height.max(1) as usize;
+        let mut data = Vec::with_capacity(row * height as usize);
+        for y in 0..height as usize {
+            data.extend_from_slice(&raw[y * pitch..y * pitch + row]);
+        }
+
+        match self.frames.try_send(VideoFrame { width, height, format: PixelFormat::Bgra, timestamp, data }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                if self.dropped % 60 == 0 {
+                    info!("Frames DROPPED (encoder lag): {}", self.dropped);
+                }
+                self.dropped += 1;
+            }
+            Err(TrySendError::Disconnected(_)) => capture_control.stop(),
+        }
+        Ok(())
+    }
+
+    fn on_closed(&mut self) -> Result<(), Self::Error> {
+        info!("Capture session closed");
+        Ok(())
+    }
+}
+
+unsafe fn find_main_window(pid: u32) -> HWND {
+    unsafe extern "system" fn enum_window_callback(hwnd: HWND, lparam: LPARAM) -> BOOL {
+        let context = &mut *(lparam.0 as *mut FindWindowContext);
+        let mut window_pid = 0;
+        GetWindowThreadProcessId(hwnd, Some(&mut window_pid));
+        if window_pid == context.target_pid && IsWindowVisible(hwnd).as_bool() && GetWindowTextLengthW(hwnd) > 0 {
+            context.found_hwnd = hwnd;
+            return BOOL(0);
+        }
+        BOOL(1)
+    }
+    struct FindWindowContext { target_pid: u32, found_hwnd: HWND }
+    let mut context = FindWindowContext { target_pid: pid, found_hwnd: HWND(std::ptr::null_mut()) };
+    let _ = EnumWindows(Some(enum_window_callback), LPARAM(&mut context as *mut _ as isize));
+    context.found_hwnd
+}
@@ -0,0 +1,84 @@
+//! A directory of PNG files played as video, in file name order, at a fixed
+//! frame rate. Export with e.g. `ffmpeg -i clip.mp4 frames/%05d.png`.
+
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{FrameSource, PixelFormat, SourceError, VideoFrame};
+
+pub struct PngSequence {
+    files: Vec<PathBuf>,
+    width: u32,
+    height: u32,
+    frame_rate: u32,
+    next: usize,
+}
+
+impl PngSequence {
+    pub fn open(dir: &Path, frame_rate: u32) -> Result<Self, SourceError> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png")))
+            .collect();
+        files.sort();
+        let first = files.first().ok_or_else(|| SourceError::EmptySequence(dir.to_path_buf()))?;
+        let (width, height, _) = decode(first)?;
+        Ok(Self { files, width, height, frame_rate: frame_rate.max(1), next: 0 })
+    }
+}
+
+impl FrameSource for PngSequence {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn frame_rate(&self) -> Option<u32> {
+        Some(self.frame_rate)
+    }
+
+    fn next_frame(&mut self) -> Result<Option<VideoFrame>, SourceError> {
+        let Some(path) = self.files.get(self.next) else { return Ok(None) };
+        let (width, height, data) = decode(path)?;
+        if (width, height) != (self.width, self.height) {
+            return Err(SourceError::SizeMismatch {
+                path: path.clone(),
+                width,
+                height,
+                expected_width: self.width,
+                expected_height: self.height,
+            });
+        }
+        let timestamp = Duration::from_secs(self.next as u64) / self.frame_rate;
+        self.next += 1;
+        Ok(Some(VideoFrame { width, height, format: PixelFormat::Bgra, timestamp, data }))
+    }
+}
+
+/// Decode one PNG of any colour type to BGRA.
+fn decode(path: &Path) -> Result<(u32, u32, Vec<u8>), SourceError> {
+    let image_error = |message: String| SourceError::Image { path: path.to_path_buf(), message };
+
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    // Palette and low bit depths to 8-bit RGB(A) or grey, 16-bit down to 8
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(|e| image_error(e.to_string()))?;
+    let mut buffer = vec![0u8; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(|e| image_error(e.to_string()))?;
+    let pixels = &buffer[..info.buffer_size()];
+
+    let mut bgra = Vec::with_capacity(info.width as usize * info.height as usize * 4);
+    match info.color_type {
+        png::ColorType::Rgba => pixels.chunks_exact(4).for_each(|p| bgra.extend_from_slice(&[p[2], p[1], p[0], p[3]])),
+        png::ColorType::Rgb => pixels.chunks_exact(3).for_each(|p| bgra.extend_from_slice(&[p[2], p[1], p[0], 255])),
+        png::ColorType::GrayscaleAlpha => pixels.chunks_exact(2).for_each(|p| bgra.extend_from_slice(&[p[0], p[0], p[0], p[1]])),
+        png::ColorType::Grayscale => pixels.iter().for_each(|&g| bgra.extend_from_slice(&[g, g, g, 255])),
+        png::ColorType::Indexed => return Err(image_error("palette was not expanded".into())),
+    }
+    Ok((info.width, info.height, bgra))
+}
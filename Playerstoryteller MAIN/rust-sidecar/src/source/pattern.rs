@@ -0,0 +1,167 @@
+//! Synthetic source: scrolling colour bars, a bouncing block, and the frame
+//! number burned into the top-left corner so dropped or repeated frames show
+//! up in the player.
+
+use std::time::Duration;
+
+use super::{bgra_to_nv12, FrameSource, PixelFormat, SourceError, VideoFrame};
+
+/// 75% colour bars: white, yellow, cyan, green, magenta, red, blue (BGRA)
+const BARS: [[u8; 4]; 7] = [
+    [191, 191, 191, 255],
+    [0, 191, 191, 255],
+    [191, 191, 0, 255],
+    [0, 191, 0, 255],
+    [191, 0, 191, 255],
+    [0, 0, 191, 255],
+    [191, 0, 0, 255],
+];
+
+/// 3x5 digit glyphs, one bit per pixel, rows top to bottom
+const DIGITS: [u16; 10] = [
+    0b111_101_101_101_111,
+    0b010_110_010_010_111,
+    0b111_001_111_100_111,
+    0b111_001_111_001_111,
+    0b101_101_111_001_001,
+    0b111_100_111_001_111,
+    0b111_100_111_101_111,
+    0b111_001_010_010_010,
+    0b111_101_111_101_111,
+    0b111_101_111_001_111,
+];
+
+/// Digits in the counter, enough for over a day at 60 fps
+const COUNTER_DIGITS: usize = 7;
+
+pub struct TestPattern {
+    width: u32,
+    height: u32,
+    frame_rate: u32,
+    format: PixelFormat,
+    frame: u64,
+}
+
+impl TestPattern {
+    pub fn new(width: u32, height: u32, frame_rate: u32) -> Self {
+        Self { width, height, frame_rate: frame_rate.max(1), format: PixelFormat::Bgra, frame: 0 }
+    }
+
+    #[cfg(test)]
+    pub fn format(mut self, format: PixelFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    fn render(&self) -> Vec<u8> {
+        let (w, h) = (self.width as usize, self.height as usize);
+        let mut bgra = vec![0u8; w * h * 4];
+
+        // Bars scroll left by a few pixels per frame
+        let bar_width = w.div_ceil(BARS.len());
+        let scroll = (self.frame as usize * 4) % w;
+        for x in 0..w {
+            let color = BARS[((x + scroll) % w) / bar_width % BARS.len()];
+            for y in 0..h {
+                bgra[(y * w + x) * 4..(y * w + x) * 4 + 4].copy_from_slice(&color);
+            }
+        }
+
+        // A block bouncing along the bottom edge
+        let block = (h / 6).max(4);
+        let travel = (w - block) as u64;
+        let phase = (self.frame * 8) % (2 * travel.max(1));
+        let left = if phase < travel { phase } else { 2 * travel - phase } as usize;
+        self.fill(&mut bgra, left, h - block, block, block, [255, 255, 255, 255]);
+
+        // Frame counter: black box with white digits
+        let scale = (h / 90).max(2);
+        let margin = scale * 2;
+        self.fill(&mut bgra, 0, 0, margin * 2 + COUNTER_DIGITS * 4 * scale, margin * 2 + 5 * scale, [0, 0, 0, 255]);
+        let digits = format!("{:0width$}", self.frame % 10u64.pow(COUNTER_DIGITS as u32), width = COUNTER_DIGITS);
+        for (i, digit) in digits.bytes().enumerate() {
+            let glyph = DIGITS[(digit - b'0') as usize];
+            for bit in 0..15 {
+                if glyph & (1 << (14 - bit)) != 0 {
+                    let x = margin + i * 4 * scale + (bit % 3) * scale;
+                    let y = margin + (bit / 3) * scale;
+                    self.fill(&mut bgra, x, y, scale, scale, [255, 255, 255, 255]);
+                }
+            }
+        }
+        bgra
+    }
+
+    /// Fill a rectangle, clipped to the frame
+    fn fill(&self, bgra: &mut [u8], left: usize, top: usize, width: usize, height: usize, color: [u8; 4]) {
+        let (w, h) = (self.width as usize, self.height as usize);
+        for y in top..(top + height).min(h) {
+            for x in left..(left + width).min(w) {
+                bgra[(y * w + x) * 4..(y * w + x) * 4 + 4].copy_from_slice(&color);
+            }
+        }
+    }
+}
+
+impl FrameSource for TestPattern {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn frame_rate(&self) -> Option<u32> {
+        Some(self.frame_rate)
+    }
+
+    fn next_frame(&mut self) -> Result<Option<VideoFrame>, SourceError> {
+        let bgra = self.render();
+        let data = match self.format {
+            PixelFormat::Bgra => bgra,
+            PixelFormat::Nv12 => bgra_to_nv12(&bgra, self.width, self.height),
+        };
+        let timestamp = Duration::from_secs(self.frame) / self.frame_rate;
+        self.frame += 1;
+        Ok(Some(VideoFrame { width: self.width, height: self.height, format: self.format, timestamp, data }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The counter area of a BGRA frame, as white/black pixels
+    fn counter(frame: &VideoFrame) -> Vec<bool> {
+        let w = frame.width as usize;
+        (0..30).flat_map(|y| (0..70).map(move |x| frame.data[(y * w + x) * 4] == 255)).collect()
+    }
+
+    #[test]
+    fn frames_are_timestamped_and_sized() {
+        let mut pattern = TestPattern::new(320, 180, 30);
+        for n in 0..3u64 {
+            let frame = pattern.next_frame().unwrap().unwrap();
+            assert_eq!(frame.timestamp, Duration::from_millis(n * 100) / 3);
+            assert_eq!(frame.data.len(), PixelFormat::Bgra.frame_size(320, 180));
+        }
+
+        let mut pattern = TestPattern::new(320, 180, 30).format(PixelFormat::Nv12);
+        let frame = pattern.next_frame().unwrap().unwrap();
+        assert_eq!((frame.format, frame.data.len()), (PixelFormat::Nv12, 320 * 180 * 3 / 2));
+    }
+
+    #[test]
+    fn counter_changes_every_frame() {
+        let mut pattern = TestPattern::new(320, 180, 30);
+        let frames: Vec<_> = (0..11).map(|_| pattern.next_frame().unwrap().unwrap()).collect();
+        for pair in frames.windows(2) {
+            assert_ne!(counter(&pair[0]), counter(&pair[1]));
+        }
+        // Frames 1 and 11 share the last digit but not the tens
+        let mut pattern = TestPattern::new(320, 180, 30);
+        pattern.frame = 11;
+        assert_ne!(counter(&frames[1]), counter(&pattern.next_frame().unwrap().unwrap()));
+    }
+}
@@ -0,0 +1,174 @@
+//! YUV4MPEG2 (`.y4m`) reader, the raw format ffmpeg and most test clip
+//! collections use. Frames come out as NV12.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::time::Duration;
+
+use super::{i420_to_nv12, FrameSource, PixelFormat, SourceError, VideoFrame};
+
+/// Longest header or frame line we accept
+const MAX_LINE: usize = 1024;
+/// Largest width or height we allocate frames for (8K is 7680 × 4320)
+const MAX_DIMENSION: u32 = 8192;
+
+pub struct Y4mReader<R> {
+    reader: R,
+    width: u32,
+    height: u32,
+    /// Frames per second as numerator and denominator
+    rate: (u32, u32),
+    /// Colour planes are absent and get filled with grey
+    mono: bool,
+    frame: u64,
+}
+
+impl Y4mReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self, SourceError> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: BufRead> Y4mReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, SourceError> {
+        let header = read_line(&mut reader)?.ok_or_else(|| SourceError::InvalidY4m("empty file".into()))?;
+        let mut params = header.split(' ');
+        if params.next() != Some("YUV4MPEG2") {
+            return Err(SourceError::InvalidY4m("missing YUV4MPEG2 signature".into()));
+        }
+
+        let (mut width, mut height, mut rate, mut mono) = (0, 0, (25, 1), false);
+        for param in params.filter(|p| !p.is_empty()) {
+            let invalid = || SourceError::InvalidY4m(format!("bad header field {}", param));
+            let (tag, value) = param.split_at_checked(1).ok_or_else(invalid)?;
+            let dimension = || value.parse().ok().filter(|n| (1..=MAX_DIMENSION).contains(n)).ok_or_else(invalid);
+            match tag {
+                "W" => width = dimension()?,
+                "H" => height = dimension()?,
+                "F" => {
+                    rate = value
+                        .split_once(':')
+                        .and_then(|(n, d)| Some((n.parse().ok()?, d.parse().ok()?)))
+                        .filter(|&(n, d)| n > 0 && d > 0)
+                        .ok_or_else(invalid)?
+                }
+                "C" => match value {
+                    "420" | "420jpeg" | "420paldv" | "420mpeg2" => mono = false,
+                    "mono" => mono = true,
+                    other => return Err(SourceError::UnsupportedColorspace(other.to_string())),
+                },
+                "I" if value != "p" && value != "?" => {
+                    return Err(SourceError::InvalidY4m("interlaced video is not supported".into()))
+                }
+                // Aspect ratio, interlacing and extensions don't matter here
+                _ => {}
+            }
+        }
+        if width == 0 || height == 0 {
+            return Err(SourceError::InvalidY4m("missing frame size".into()));
+        }
+
+        Ok(Self { reader, width, height, rate, mono, frame: 0 })
+    }
+}
+
+impl<R: BufRead + Send> FrameSource for Y4mReader<R> {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn frame_rate(&self) -> Option<u32> {
+        Some(self.rate.0.div_ceil(self.rate.1))
+    }
+
+    fn next_frame(&mut self) -> Result<Option<VideoFrame>, SourceError> {
+        let Some(line) = read_line(&mut self.reader)? else { return Ok(None) };
+        if !line.starts_with("FRAME") {
+            return Err(SourceError::InvalidY4m(format!("expected FRAME header at frame {}", self.frame)));
+        }
+
+        let luma = self.width as usize * self.height as usize;
+        let chroma = PixelFormat::Nv12.frame_size(self.width, self.height) - luma;
+        let mut planes = vec![0u8; if self.mono { luma } else { luma + chroma }];
+        self.reader.read_exact(&mut planes).map_err(|_| SourceError::InvalidY4m(format!("frame {} is truncated", self.frame)))?;
+
+        let data = if self.mono {
+            planes.resize(luma + chroma, 128);
+            planes
+        } else {
+            i420_to_nv12(&planes, self.width, self.height)
+        };
+        let (num, den) = self.rate;
+        let timestamp = Duration::from_nanos((self.frame as u128 * den as u128 * 1_000_000_000 / num as u128) as u64);
+        self.frame += 1;
+        Ok(Some(VideoFrame { width: self.width, height: self.height, format: PixelFormat::Nv12, timestamp, data }))
+    }
+}
+
+/// One `\n`-terminated line, or `None` at a clean end of file.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>, SourceError> {
+    let mut line = Vec::new();
+    reader.by_ref().take(MAX_LINE as u64).read_until(b'\n', &mut line)?;
+    match line.pop() {
+        None => Ok(None),
+        Some(b'\n') => String::from_utf8(line).map(Some).map_err(|_| SourceError::InvalidY4m("header is not text".into())),
+        Some(_) => Err(SourceError::InvalidY4m("unterminated header line".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn y4m(header: &str, frames: &[&[u8]]) -> Vec<u8> {
+        let mut data = format!("{}\n", header).into_bytes();
+        for frame in frames {
+            data.extend_from_slice(b"FRAME\n");
+            data.extend_from_slice(frame);
+        }
+        data
+    }
+
+    #[test]
+    fn reads_frames_as_nv12_with_timestamps() {
+        let frame: Vec<u8> = (0..4).chain([10]).chain([20]).collect();
+        let file = y4m("YUV4MPEG2 W2 H2 F30000:1001 Ip A1:1 C420jpeg XYSCSS=420JPEG", &[&frame, &frame]);
+        let mut reader = Y4mReader::new(&file[..]).unwrap();
+        assert_eq!((reader.width(), reader.height(), reader.frame_rate()), (2, 2, Some(30)));
+
+        let first = reader.next_frame().unwrap().unwrap();
+        assert_eq!((first.format, first.timestamp, first.data.as_slice()), (PixelFormat::Nv12, Duration::ZERO, &[0, 1, 2, 3, 10, 20][..]));
+        let second = reader.next_frame().unwrap().unwrap();
+        assert_eq!(second.timestamp, Duration::from_nanos(33_366_666));
+        assert!(reader.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn fills_chroma_for_mono() {
+        let file = y4m("YUV4MPEG2 W2 H2 F25:1 Cmono", &[&[1, 2, 3, 4]]);
+        let frame = Y4mReader::new(&file[..]).unwrap().next_frame().unwrap().unwrap();
+        assert_eq!(frame.data, [1, 2, 3, 4, 128, 128]);
+    }
+
+    #[test]
+    fn rejects_unsupported_streams() {
+        let open = |header: &str| Y4mReader::new(&y4m(header, &[])[..]).err().map(|e| e.to_string());
+        assert!(matches!(Y4mReader::new(&b"RIFF\n"[..]), Err(SourceError::InvalidY4m(_))));
+        assert!(open("YUV4MPEG2 W2 H2 C444").unwrap().contains("444"));
+        assert!(open("YUV4MPEG2 W2 H2 C420p10").is_some());
+        assert!(open("YUV4MPEG2 W2 Ip").is_some());
+        assert!(open("YUV4MPEG2 W2 H2 It").is_some());
+
+        assert!(open("YUV4MPEG2 W0 H2").is_some());
+        assert!(open("YUV4MPEG2 W2 H100000").is_some());
+        assert!(open("YUV4MPEG2 W2 H2 \u{e9}x").is_some());
+
+        let file = y4m("YUV4MPEG2 W2 H2", &[&[1, 2, 3]]);
+        assert!(Y4mReader::new(&file[..]).unwrap().next_frame().is_err());
+    }
+}
@@ -0,0 +1,865 @@
+//! `--srt`: send the stream as MPEG-TS to an SRT listener (a relay, OBS,
+//! ffmpeg or srt-live-transmit), calling out in live mode.
+//!
+//! SRT runs over UDP. The listener reports lost packets and they are sent
+//! again for as long as they can still make its latency window, after which
+//! they are given up on, so loss costs a little latency rather than
+//! stalling the stream like TCP. With a passphrase, payloads are AES
+//! encrypted. As with RTMP, nothing is queued while disconnected and a new
+//! connection starts at a keyframe.
+
+mod crypto;
+mod packet;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio::time::{interval, sleep_until, timeout, timeout_at, Duration, Instant};
+use url::{Position, Url};
+
+use crate::mp4::Mp4Segment;
+use crate::net;
+use crate::queue::{Frame, KeyframeQueue};
+use crate::reconnect::{self, ConnectionError, StopSignal};
+use crate::secret::StreamKey;
+use crate::ts::{TsFrame, TsMuxer, PACKETS_PER_DATAGRAM, PACKET_SIZE};
+
+use self::crypto::{KeySize, StreamCrypto, PASSPHRASE_LENGTH};
+use self::packet::{
+    Control, ControlPacket, DataPacket, Extension, Handshake, HandshakeType, KeyIndex, Packet, SrtOptions, EXT_FLAG_CONFIG,
+    EXT_FLAG_HSREQ, EXT_FLAG_KMREQ, EXT_HSREQ, EXT_HSRSP, EXT_KMREQ, EXT_KMRSP, MESSAGE_MASK, SEQUENCE_MASK, SOCKET_TYPE_DGRAM,
+    SRT_MAGIC, VERSION_5, VERSION_INDUCTION,
+};
+
+/// Longest the handshake may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Handshake packets are sent again this often until answered.
+const HANDSHAKE_RETRY: Duration = Duration::from_millis(250);
+
+/// Latency when the URL doesn't set one, libsrt's default.
+const DEFAULT_LATENCY: Duration = Duration::from_millis(120);
+
+/// Sending rate in bytes a second when the URL doesn't set `maxbw`, libsrt's
+/// 1 Gbit/s.
+const DEFAULT_MAX_BANDWIDTH: u64 = 125_000_000;
+
+/// The connection is given up on after this long without a packet from the listener.
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something is sent at least this often, so the listener knows we're there.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often to expire old packets and check for an idle listener.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Sent packets are kept at least this long for resending, whatever the
+/// latency, like libsrt does.
+const MIN_DROP_DELAY: Duration = Duration::from_secs(1);
+
+/// Queued transport stream beyond this is dropped up to the next keyframe.
+const QUEUE_LIMIT: usize = 8 * 1024 * 1024;
+
+const MTU: u32 = 1500;
+
+/// Packets in flight the listener is told we may have, libsrt's default
+const FLOW_WINDOW: u32 = 8192;
+
+/// The SRT version we speak: 1.5.0
+const SRT_VERSION: u32 = 0x0001_0500;
+
+/// Largest datagram we expect from the listener
+const RECEIVE_BUFFER: usize = 2048;
+
+#[derive(Debug, Error)]
+pub enum SrtError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("SRT protocol error: {0}")]
+    Protocol(&'static str),
+    #[error("the listener refused the connection: {0}")]
+    Rejected(&'static str),
+    #[error("SRT encryption: {0}")]
+    Encryption(&'static str),
+    #[error("the listener did not answer in time")]
+    Timeout,
+    #[error("the listener stopped answering")]
+    PeerIdle,
+    #[error("the listener closed the connection")]
+    Closed,
+}
+
+impl ConnectionError for SrtError {}
+
+/// Checks a passphrase against the lengths libsrt accepts.
+pub fn check_passphrase(passphrase: &str) -> Result<(), String> {
+    if PASSPHRASE_LENGTH.contains(&passphrase.len()) {
+        Ok(())
+    } else {
+        Err(format!("SRT passphrases are {} to {} characters", PASSPHRASE_LENGTH.start(), PASSPHRASE_LENGTH.end()))
+    }
+}
+
+/// `srt://host:port`, with the options srt-live-transmit takes in the query:
+/// `latency` in milliseconds, `maxbw` in bytes a second, `passphrase`,
+/// `pbkeylen` and `streamid`. The passphrase and stream id are kept out of
+/// logs.
+#[derive(Clone)]
+pub struct SrtUrl {
+    host: String,
+    port: u16,
+    /// `srt://host:port`, for logs
+    display: String,
+    latency: Duration,
+    /// Bytes a second of data packets, headers included
+    max_bandwidth: u64,
+    passphrase: Option<StreamKey>,
+    key_size: KeySize,
+    stream_id: Option<StreamKey>,
+}
+
+impl FromStr for SrtUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|e| format!("invalid SRT URL: {}", e))?;
+        if url.scheme() != "srt" {
+            return Err(format!("unsupported scheme {}, expected srt", url.scheme()));
+        }
+        let host = net::connect_host(&url).ok_or("SRT URL has no host")?;
+        let port = url.port().ok_or("SRT URL has no port, expected srt://host:port")?;
+
+        let mut srt = Self {
+            host,
+            port,
+            display: format!("srt://{}", &url[Position::BeforeHost..Position::AfterPort]),
+            latency: DEFAULT_LATENCY,
+            max_bandwidth: DEFAULT_MAX_BANDWIDTH,
+            passphrase: None,
+            key_size: KeySize::Aes128,
+            stream_id: None,
+        };
+        for (name, value) in url.query_pairs() {
+            match name.as_ref() {
+                "latency" => {
+                    let millis: u16 = value.parse().map_err(|_| format!("invalid SRT latency {}, expected milliseconds", value))?;
+                    srt.latency = Duration::from_millis(millis as u64);
+                }
+                "maxbw" => {
+                    srt.max_bandwidth = value
+                        .parse()
+                        .ok()
+                        .filter(|&bytes| bytes > 0)
+                        .ok_or_else(|| format!("invalid SRT maxbw {}, expected bytes a second", value))?;
+                }
+                "passphrase" => {
+                    check_passphrase(&value)?;
+                    srt.passphrase = Some(StreamKey::new(value.into_owned()));
+                }
+                "pbkeylen" => {
+                    srt.key_size = value.parse().ok().and_then(KeySize::from_bytes).ok_or("pbkeylen must be 16, 24 or 32")?;
+                }
+                "streamid" => srt.stream_id = Some(StreamKey::new(value.into_owned())),
+                "mode" if value == "caller" => {}
+                "mode" => return Err("only mode=caller is supported".to_string()),
+                other => return Err(format!("unsupported SRT option {}", other)),
+            }
+        }
+        Ok(srt)
+    }
+}
+
+impl fmt::Display for SrtUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.display)
+    }
+}
+
+impl fmt::Debug for SrtUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SrtUrl({}, latency {:?}, encrypted: {})", self, self.latency, self.passphrase.is_some())
+    }
+}
+
+/// Transport stream waiting for the connection, with when it was published.
+struct QueuedFrame {
+    published: Instant,
+    frame: TsFrame,
+}
+
+impl Frame for QueuedFrame {
+    fn size(&self) -> usize {
+        self.frame.data.len()
+    }
+
+    fn joinable(&self) -> bool {
+        self.frame.keyframe
+    }
+}
+
+/// One SRT destination, with its own connection and queue.
+pub struct SrtOutput {
+    url: SrtUrl,
+    muxer: Mutex<TsMuxer>,
+    queue: Mutex<KeyframeQueue<QueuedFrame>>,
+    queue_notify: Notify,
+    connected: AtomicBool,
+    stop: StopSignal,
+}
+
+impl SrtOutput {
+    pub fn new(url: SrtUrl) -> Self {
+        Self {
+            url,
+            muxer: Mutex::new(TsMuxer::new()),
+            queue: Mutex::new(KeyframeQueue::new(QUEUE_LIMIT)),
+            queue_notify: Notify::new(),
+            connected: AtomicBool::new(false),
+            stop: StopSignal::default(),
+        }
+    }
+
+    /// Passphrase for a URL that doesn't carry its own.
+    pub fn passphrase(mut self, passphrase: StreamKey) -> Self {
+        self.url.passphrase.get_or_insert(passphrase);
+        self
+    }
+
+    /// Take in a segment of the encoder's stream. Never blocks; video is only
+    /// queued while connected.
+    pub fn publish(&self, segment: &Mp4Segment) {
+        let frames = self.muxer.lock().push(segment);
+        if frames.is_empty() || !self.connected.load(Ordering::SeqCst) {
+            return;
+        }
+        let published = Instant::now();
+        let mut queue = self.queue.lock();
+        let dropped_before = queue.dropped();
+        frames.into_iter().for_each(|frame| queue.push(QueuedFrame { published, frame }));
+        let dropped = queue.dropped();
+        drop(queue);
+
+        if dropped > dropped_before && (dropped_before == 0 || dropped / 60 != dropped_before / 60) {
+            warn!("SRT queue overflow for {}: {} frames dropped so far", self.url, dropped);
+        }
+        self.queue_notify.notify_one();
+    }
+
+    /// Send what is queued, close the connection and make `run` return.
+    pub fn finish(&self) {
+        self.stop.stop();
+    }
+
+    /// Connect, send and reconnect until `finish`.
+    pub async fn run(&self) -> Result<(), SrtError> {
+        info!("SRT destination: {:?}", self.url);
+        reconnect::supervise(
+            &self.stop,
+            "srt",
+            &format!("SRT {}", self.url),
+            || async { timeout(CONNECT_TIMEOUT, self.connect_once()).await.unwrap_or(Err(SrtError::Timeout)) },
+            |connection| async {
+                info!("SRT connected to {}", self.url);
+                self.run_session(connection).await
+            },
+        )
+        .await
+    }
+
+    /// Do the caller handshake: induction, then conclusion with our options,
+    /// the wrapped key and the stream id.
+    async fn connect_once(&self) -> Result<Connection, SrtError> {
+        let url = &self.url;
+        let addr = *net::resolve(&url.host, url.port).await?.first().expect("resolve returns at least one address");
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+
+        let started = Instant::now();
+        let socket_id = random_u32() & 0x3FFF_FFFF;
+        let initial_sequence = random_u32() & SEQUENCE_MASK;
+        let crypto = url.passphrase.as_ref().map(|passphrase| StreamCrypto::new(passphrase.expose(), url.key_size));
+
+        let mut handshake = Handshake {
+            version: VERSION_INDUCTION,
+            encryption: 0,
+            extension_field: SOCKET_TYPE_DGRAM,
+            initial_sequence,
+            mtu: MTU,
+            flow_window: FLOW_WINDOW,
+            kind: HandshakeType::Induction,
+            socket_id,
+            cookie: 0,
+            peer_ip: ip_bytes(addr.ip()),
+            extensions: Vec::new(),
+        };
+        let induction = exchange(&socket, started, &handshake).await?;
+        if induction.version < VERSION_5 || induction.extension_field != SRT_MAGIC {
+            return Err(SrtError::Protocol("the listener does not speak the version 5 handshake"));
+        }
+
+        let mut flags = SrtOptions::TSBPD_SEND
+            | SrtOptions::TSBPD_RECEIVE
+            | SrtOptions::TOO_LATE_PACKET_DROP
+            | SrtOptions::PERIODIC_NAK
+            | SrtOptions::RETRANSMIT_FLAG;
+        let latency = url.latency.as_millis().min(u16::MAX as u128) as u16;
+        handshake.version = VERSION_5;
+        handshake.kind = HandshakeType::Conclusion;
+        handshake.cookie = induction.cookie;
+        handshake.extension_field = EXT_FLAG_HSREQ;
+        if crypto.is_some() {
+            flags |= SrtOptions::CRYPT;
+            handshake.encryption = url.key_size.handshake_code();
+            handshake.extension_field |= EXT_FLAG_KMREQ;
+        }
+        let options = SrtOptions { version: SRT_VERSION, flags, receiver_delay: latency, sender_delay: latency };
+        handshake.extensions.push(options.to_extension(EXT_HSREQ));
+        if let Some(crypto) = &crypto {
+            handshake.extensions.push(Extension { kind: EXT_KMREQ, data: crypto.key_material().to_vec() });
+        }
+        if let Some(stream_id) = &url.stream_id {
+            handshake.extension_field |= EXT_FLAG_CONFIG;
+            handshake.extensions.push(Extension::stream_id(stream_id.expose()));
+        }
+        let conclusion = exchange(&socket, started, &handshake).await?;
+
+        if let Some(crypto) = &crypto {
+            match conclusion.extension(EXT_KMRSP) {
+                Some(key_material) if key_material == crypto.key_material() => {}
+                // A single word is the listener's key material state
+                Some([0, 0, 0, 3]) => return Err(SrtError::Encryption("the listener has no passphrase set")),
+                Some([0, 0, 0, 4]) => return Err(SrtError::Encryption("the passphrase does not match the listener's")),
+                _ => return Err(SrtError::Encryption("the listener did not take the key")),
+            }
+        }
+        // The latency is the larger of what the two sides asked for
+        let agreed = match conclusion.extension(EXT_HSRSP) {
+            Some(response) => Duration::from_millis(SrtOptions::parse(response)?.receiver_delay as u64).max(url.latency),
+            None => return Err(SrtError::Protocol("the listener sent no SRT options")),
+        };
+        debug!("SRT {}: socket {:x}, latency {:?}", url, conclusion.socket_id, agreed);
+
+        Ok(Connection {
+            socket,
+            started,
+            peer_socket: conclusion.socket_id,
+            crypto,
+            next_sequence: initial_sequence,
+            next_message: 1,
+            sent: VecDeque::new(),
+            // Packets the receiver would drop as too late by now need not be kept
+            drop_delay: (agreed * 5 / 4).max(MIN_DROP_DELAY),
+            last_sent: started,
+            last_received: Instant::now(),
+            pacer: Pacer::new(url.max_bandwidth),
+            retransmitted: 0,
+        })
+    }
+
+    /// Send video until the connection fails or `finish` is called.
+    async fn run_session(&self, mut connection: Connection) -> Result<(), SrtError> {
+        self.queue.lock().reset();
+        self.connected.store(true, Ordering::SeqCst);
+        let result = self.send_queued(&mut connection).await;
+        self.connected.store(false, Ordering::SeqCst);
+        if connection.retransmitted > 0 {
+            info!("SRT {}: {} packets were sent again", self.url, connection.retransmitted);
+        }
+        result
+    }
+
+    async fn send_queued(&self, connection: &mut Connection) -> Result<(), SrtError> {
+        let mut housekeeping = interval(HOUSEKEEPING_INTERVAL);
+        let mut buffer = vec![0; RECEIVE_BUFFER];
+        loop {
+            if self.stop.is_stopped() {
+                loop {
+                    let next = self.queue.lock().pop();
+                    let Some(queued) = next else { break };
+                    connection.send_frame(queued.published, &queued.frame).await?;
+                }
+                connection.close().await?;
+                info!("SRT stream to {} ended", self.url);
+                return Ok(());
+            }
+
+            let next = self.queue.lock().pop();
+            match next {
+                Some(queued) => connection.send_frame(queued.published, &queued.frame).await?,
+                None => tokio::select! {
+                    _ = self.queue_notify.notified() => {}
+                    _ = self.stop.stopped() => {}
+                    received = connection.socket.recv(&mut buffer) => connection.handle(&buffer[..received?]).await?,
+                    _ = housekeeping.tick() => connection.housekeeping().await?,
+                },
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+}
+
+/// Send a handshake until the listener answers it in kind.
+async fn exchange(socket: &UdpSocket, started: Instant, request: &Handshake) -> Result<Handshake, SrtError> {
+    let packet = Packet::Control(ControlPacket {
+        timestamp: micros_since(started, Instant::now()),
+        destination: 0,
+        control: Control::Handshake(request.clone()),
+    });
+    let packet = packet.to_bytes();
+    let mut buffer = vec![0; RECEIVE_BUFFER];
+    loop {
+        socket.send(&packet).await?;
+        let deadline = Instant::now() + HANDSHAKE_RETRY;
+        while let Ok(received) = timeout_at(deadline, socket.recv(&mut buffer)).await {
+            let Ok(Packet::Control(ControlPacket { control: Control::Handshake(answer), .. })) = Packet::parse(&buffer[..received?]) else {
+                continue;
+            };
+            match answer.kind {
+                HandshakeType::Rejected(reason) => return Err(SrtError::Rejected(reject_reason(reason))),
+                kind if kind == request.kind => return Ok(answer),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// An established connection: sequence numbers, the key, and what was sent
+/// but not yet acknowledged.
+struct Connection {
+    socket: UdpSocket,
+    started: Instant,
+    peer_socket: u32,
+    crypto: Option<StreamCrypto>,
+    next_sequence: u32,
+    next_message: u32,
+    /// Sent data packets, oldest first, with when they were first sent
+    sent: VecDeque<(Instant, DataPacket)>,
+    /// How long sent packets are kept for resending
+    drop_delay: Duration,
+    last_sent: Instant,
+    last_received: Instant,
+    /// Spaces out data packets, first sends and resends alike
+    pacer: Pacer,
+    retransmitted: u64,
+}
+
+impl Connection {
+    /// A frame's transport stream in datagrams of up to seven packets, all
+    /// with the time it was published, which the receiver paces output by.
+    async fn send_frame(&mut self, published: Instant, frame: &TsFrame) -> Result<(), SrtError> {
+        let timestamp = micros_since(self.started, published);
+        for chunk in frame.data.chunks(PACKET_SIZE * PACKETS_PER_DATAGRAM) {
+            let mut payload = chunk.to_vec();
+            if let Some(crypto) = &self.crypto {
+                crypto.apply(self.next_sequence, &mut payload);
+            }
+            let packet = DataPacket {
+                sequence: self.next_sequence,
+                message: self.next_message,
+                key: if self.crypto.is_some() { KeyIndex::Even } else { KeyIndex::None },
+                retransmitted: false,
+                timestamp,
+                destination: self.peer_socket,
+                payload,
+            };
+            self.next_sequence = (self.next_sequence + 1) & SEQUENCE_MASK;
+            // Message numbers start at 1
+            self.next_message = self.next_message % MESSAGE_MASK + 1;
+
+            self.send(&Packet::Data(packet.clone())).await?;
+            self.sent.push_back((Instant::now(), packet));
+            if self.sent.len() > FLOW_WINDOW as usize {
+                self.sent.pop_front();
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, packet: &Packet) -> Result<(), SrtError> {
+        let bytes = packet.to_bytes();
+        if let Packet::Data(_) = packet {
+            sleep_until(self.pacer.reserve(bytes.len())).await;
+        }
+        self.socket.send(&bytes).await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    async fn send_control(&mut self, control: Control) -> Result<(), SrtError> {
+        let packet = ControlPacket { timestamp: micros_since(self.started, Instant::now()), destination: self.peer_socket, control };
+        self.send(&Packet::Control(packet)).await
+    }
+
+    /// Act on a packet from the listener.
+    async fn handle(&mut self, datagram: &[u8]) -> Result<(), SrtError> {
+        self.last_received = Instant::now();
+        let control = match Packet::parse(datagram) {
+            Ok(Packet::Control(packet)) => packet.control,
+            Ok(Packet::Data(_)) => return Ok(()),
+            Err(e) => {
+                debug!("SRT {:x}: ignoring a bad packet: {}", self.peer_socket, e);
+                return Ok(());
+            }
+        };
+        match control {
+            Control::Ack { number, next } => {
+                while self.sent.front().is_some_and(|(_, p)| sequence_before(p.sequence, next)) {
+                    self.sent.pop_front();
+                }
+                // Full ACKs are answered so the listener can measure the round trip
+                if number != 0 {
+                    self.send_control(Control::AckAck(number)).await?;
+                }
+            }
+            Control::Nak(ranges) => {
+                let lost: Vec<DataPacket> = self
+                    .sent
+                    .iter()
+                    .filter(|(_, p)| ranges.iter().any(|&(first, last)| sequence_in(p.sequence, first, last)))
+                    .map(|(_, p)| DataPacket { retransmitted: true, ..p.clone() })
+                    .collect();
+                for packet in lost {
+                    self.retransmitted += 1;
+                    self.send(&Packet::Data(packet)).await?;
+                }
+            }
+            Control::Shutdown => return Err(SrtError::Closed),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Forget packets too late to resend, keep the connection alive and
+    /// notice a listener that went away.
+    async fn housekeeping(&mut self) -> Result<(), SrtError> {
+        let now = Instant::now();
+        if now - self.last_received > PEER_IDLE_TIMEOUT {
+            return Err(SrtError::PeerIdle);
+        }
+        while self.sent.front().is_some_and(|(sent, _)| now - *sent > self.drop_delay) {
+            self.sent.pop_front();
+        }
+        if now - self.last_sent >= KEEPALIVE_INTERVAL {
+            self.send_control(Control::KeepAlive).await?;
+        }
+        Ok(())
+    }
+
+    /// Give the listener a chance to get the last packets, then say goodbye.
+    async fn close(&mut self) -> Result<(), SrtError> {
+        let deadline = Instant::now() + self.drop_delay;
+        let mut buffer = vec![0; RECEIVE_BUFFER];
+        while !self.sent.is_empty() {
+            match timeout_at(deadline, self.socket.recv(&mut buffer)).await {
+                Ok(received) => self.handle(&buffer[..received?]).await?,
+                Err(_) => break,
+            }
+        }
+        self.send_control(Control::Shutdown).await
+    }
+}
+
+/// Spreads packets out so that, over time, they average no more than a
+/// given number of bytes a second, rather than going out in one burst per
+/// frame.
+struct Pacer {
+    bytes_per_second: u64,
+    /// When the next packet may be sent
+    next: Instant,
+}
+
+impl Pacer {
+    fn new(bytes_per_second: u64) -> Self {
+        Self { bytes_per_second, next: Instant::now() }
+    }
+
+    /// When a packet of `size` bytes may go out. Time not used while idle
+    /// is not saved up for later.
+    fn reserve(&mut self, size: usize) -> Instant {
+        let at = self.next.max(Instant::now());
+        self.next = at + Duration::from_nanos((size as u128 * 1_000_000_000 / self.bytes_per_second as u128) as u64);
+        at
+    }
+}
+
+/// `a` comes before `b`, allowing for wrap-around.
+fn sequence_before(a: u32, b: u32) -> bool {
+    a != b && b.wrapping_sub(a) & SEQUENCE_MASK < 0x4000_0000
+}
+
+/// `sequence` is in the inclusive range `first..=last`, allowing for wrap-around.
+fn sequence_in(sequence: u32, first: u32, last: u32) -> bool {
+    sequence.wrapping_sub(first) & SEQUENCE_MASK <= last.wrapping_sub(first) & SEQUENCE_MASK
+}
+
+/// SRT timestamps: microseconds since the connection started, wrapping.
+fn micros_since(started: Instant, at: Instant) -> u32 {
+    at.saturating_duration_since(started).as_micros() as u32
+}
+
+fn random_u32() -> u32 {
+    let mut bytes = [0; 4];
+    getrandom::getrandom(&mut bytes).expect("OS random source unavailable");
+    u32::from_ne_bytes(bytes)
+}
+
+/// An address as the handshake carries it: IPv4 in the first word.
+fn ip_bytes(ip: IpAddr) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    match ip {
+        IpAddr::V4(v4) => bytes[..4].copy_from_slice(&v4.octets()),
+        IpAddr::V6(v6) => bytes = v6.octets(),
+    }
+    bytes
+}
+
+/// What an SRT_REJ_* code, or a listener application's code, means.
+fn reject_reason(reason: u32) -> &'static str {
+    match reason {
+        1 => "system error",
+        2 => "peer error",
+        3 => "out of resources",
+        4 => "bad handshake",
+        5 => "backlog full",
+        6 => "internal error",
+        7 => "socket closing",
+        8 => "SRT version too old",
+        10 => "wrong passphrase",
+        11 => "the encryption settings don't match",
+        12 => "message API mismatch",
+        13 => "congestion control mismatch",
+        14 => "packet filter mismatch",
+        16 => "timed out",
+        // The application codes follow HTTP
+        1401 => "unauthorized",
+        1403 => "forbidden",
+        1404 => "unknown stream id",
+        1409 => "the stream id is in use",
+        1000.. => "refused by the listener's application",
+        _ => "unknown reason",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::h264_stream;
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+    use tokio::time::sleep;
+
+    const LISTENER_SOCKET: u32 = 0x5157;
+    const COOKIE: u32 = 0x00C0_FFEE;
+
+    #[derive(Debug)]
+    enum Event {
+        Connected { options: SrtOptions, stream_id: Option<String> },
+        /// With the payload decrypted
+        Data(DataPacket),
+        AckAck,
+        Closed,
+    }
+
+    /// A stand-in for an SRT listener like srt-live-transmit: answers the
+    /// handshake, decrypts, acknowledges and passes on what it receives. The
+    /// data packets arriving in the places listed in `lose` are reported
+    /// lost instead, once.
+    async fn listener(passphrase: Option<&'static str>, lose: &'static [usize]) -> (SocketAddr, mpsc::UnboundedReceiver<Event>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buffer = vec![0; RECEIVE_BUFFER];
+            let mut crypto = None;
+            let mut caller = 0;
+            let mut arrived = 0;
+            let mut next = 0;
+            let mut early = std::collections::BTreeSet::new();
+            let mut acks = 0;
+            while let Ok((length, peer)) = socket.recv_from(&mut buffer).await {
+                let reply = |destination, control| Packet::Control(ControlPacket { timestamp: 0, destination, control }).to_bytes();
+                let answer = match Packet::parse(&buffer[..length]).unwrap() {
+                    Packet::Control(ControlPacket { control: Control::Handshake(request), .. }) => {
+                        caller = request.socket_id;
+                        let mut answer = Handshake { socket_id: LISTENER_SOCKET, extensions: Vec::new(), ..request.clone() };
+                        if request.kind == HandshakeType::Induction {
+                            answer.version = VERSION_5;
+                            answer.extension_field = SRT_MAGIC;
+                            answer.cookie = COOKIE;
+                        } else {
+                            assert_eq!(request.cookie, COOKIE);
+                            let options = SrtOptions::parse(request.extension(EXT_HSREQ).unwrap()).unwrap();
+                            answer.extensions.push(options.to_extension(EXT_HSRSP));
+                            match (passphrase, request.extension(EXT_KMREQ)) {
+                                (Some(passphrase), Some(key_material)) => match StreamCrypto::from_key_material(passphrase, key_material) {
+                                    Ok(key) => {
+                                        crypto = Some(key);
+                                        answer.extensions.push(Extension { kind: EXT_KMRSP, data: key_material.to_vec() });
+                                    }
+                                    Err(_) => answer.kind = HandshakeType::Rejected(10),
+                                },
+                                (None, None) => {}
+                                _ => answer.kind = HandshakeType::Rejected(11),
+                            }
+                            if answer.kind == HandshakeType::Conclusion {
+                                next = request.initial_sequence;
+                                let stream_id = request.extension(packet::EXT_SID).map(Extension::read_stream_id);
+                                tx.send(Event::Connected { options, stream_id }).unwrap();
+                            }
+                        }
+                        reply(caller, Control::Handshake(answer))
+                    }
+                    Packet::Data(mut data) => {
+                        arrived += 1;
+                        if lose.contains(&arrived) && !data.retransmitted {
+                            reply(caller, Control::Nak(vec![(data.sequence, data.sequence)]))
+                        } else {
+                            if let Some(crypto) = &crypto {
+                                crypto.apply(data.sequence, &mut data.payload);
+                            }
+                            early.insert(data.sequence);
+                            while early.remove(&next) {
+                                next = (next + 1) & SEQUENCE_MASK;
+                            }
+                            let _ = tx.send(Event::Data(data));
+                            acks += 1;
+                            reply(caller, Control::Ack { number: acks, next })
+                        }
+                    }
+                    Packet::Control(ControlPacket { control: Control::AckAck(_), .. }) => {
+                        let _ = tx.send(Event::AckAck);
+                        continue;
+                    }
+                    Packet::Control(ControlPacket { control: Control::Shutdown, .. }) => {
+                        let _ = tx.send(Event::Closed);
+                        return;
+                    }
+                    Packet::Control(_) => continue,
+                };
+                socket.send_to(&answer, peer).await.unwrap();
+            }
+        });
+        (addr, rx)
+    }
+
+    /// Everything the listener saw, up to the shutdown
+    async fn until_closed(events: &mut mpsc::UnboundedReceiver<Event>) -> Vec<Event> {
+        let mut seen = Vec::new();
+        while !matches!(seen.last(), Some(Event::Closed)) {
+            seen.push(timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap());
+        }
+        seen
+    }
+
+    #[tokio::test]
+    async fn streams_encrypted_transport_stream_and_resends_losses() {
+        let (addr, mut events) = listener(Some("correct-horse-battery"), &[3]).await;
+        let url = format!("srt://{}?latency=200&passphrase=correct-horse-battery&streamid=live/test", addr);
+        let srt = Arc::new(SrtOutput::new(url.parse().unwrap()));
+        let run = tokio::spawn({
+            let srt = srt.clone();
+            async move { srt.run().await }
+        });
+        while !srt.is_connected() {
+            sleep(Duration::from_millis(5)).await;
+        }
+        let stream = h264_stream(40);
+        for segment in &stream {
+            srt.publish(segment);
+        }
+        srt.finish();
+        run.await.unwrap().unwrap();
+
+        let events = until_closed(&mut events).await;
+        let Event::Connected { options, stream_id } = &events[0] else { panic!("expected the handshake first, got {:?}", events[0]) };
+        assert_eq!((options.receiver_delay, options.sender_delay), (200, 200));
+        assert_ne!(options.flags & SrtOptions::CRYPT, 0);
+        assert_eq!(stream_id.as_deref(), Some("live/test"));
+        assert!(events.iter().any(|e| matches!(e, Event::AckAck)));
+
+        let data: Vec<&DataPacket> = events.iter().filter_map(|e| if let Event::Data(d) = e { Some(d) } else { None }).collect();
+        assert!(data.iter().all(|p| p.key == KeyIndex::Even && p.payload.len() % PACKET_SIZE == 0 && p.payload.len() <= 1316));
+        assert_eq!(data.iter().filter(|p| p.retransmitted).count(), 1);
+
+        // In sequence order, the decrypted payloads are the transport stream
+        let first = data[0].sequence;
+        let mut ordered = data.clone();
+        ordered.sort_by_key(|p| p.sequence.wrapping_sub(first) & SEQUENCE_MASK);
+        let received: Vec<u8> = ordered.iter().flat_map(|p| p.payload.iter().copied()).collect();
+        let mut muxer = TsMuxer::new();
+        let expected: Vec<u8> = stream.iter().flat_map(|s| muxer.push(s)).flat_map(|f| f.data).collect();
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn reports_a_wrong_passphrase() {
+        let (addr, _events) = listener(Some("another-passphrase"), &[]).await;
+        let srt = SrtOutput::new(format!("srt://{}?passphrase=correct-horse-battery", addr).parse().unwrap());
+        match srt.connect_once().await {
+            Err(SrtError::Rejected(reason)) => assert_eq!(reason, "wrong passphrase"),
+            other => panic!("expected a rejection, got {:?}", other.err()),
+        }
+        // Without one, when the listener wants one
+        let srt = SrtOutput::new(format!("srt://{}", addr).parse().unwrap());
+        assert!(matches!(srt.connect_once().await, Err(SrtError::Rejected(_))));
+    }
+
+    #[test]
+    fn parses_urls_and_hides_secrets() {
+        let url: SrtUrl = "srt://relay.example.com:9000?latency=400&passphrase=0123456789ab&pbkeylen=32&streamid=%23!::r=live/key".parse().unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.latency), ("relay.example.com", 9000, Duration::from_millis(400)));
+        assert_eq!((url.key_size, url.passphrase.as_ref().unwrap().expose()), (KeySize::Aes256, "0123456789ab"));
+        assert_eq!(url.stream_id.as_ref().unwrap().expose(), "#!::r=live/key");
+        assert_eq!(url.to_string(), "srt://relay.example.com:9000");
+        assert!(!format!("{:?}", url).contains("0123456789ab"));
+
+        let url: SrtUrl = "srt://[::1]:9000?mode=caller".parse().unwrap();
+        assert_eq!((url.host.as_str(), url.latency, url.to_string().as_str()), ("::1", DEFAULT_LATENCY, "srt://[::1]:9000"));
+        assert_eq!(url.max_bandwidth, DEFAULT_MAX_BANDWIDTH);
+        let url: SrtUrl = "srt://relay.example.com:9000?maxbw=1250000".parse().unwrap();
+        assert_eq!(url.max_bandwidth, 1_250_000);
+
+        assert!("srt://relay.example.com".parse::<SrtUrl>().is_err());
+        assert!("srt://relay.example.com:9000?passphrase=short".parse::<SrtUrl>().is_err());
+        assert!("srt://relay.example.com:9000?mode=listener".parse::<SrtUrl>().is_err());
+        assert!("srt://relay.example.com:9000?maxbw=0".parse::<SrtUrl>().is_err());
+        assert!("udp://relay.example.com:9000".parse::<SrtUrl>().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paces_packets_by_size() {
+        let mut pacer = Pacer::new(1_000_000);
+        let start = Instant::now();
+        // 1316 bytes at 1 MB/s is 1.316 ms each, one after the other
+        let slots: Vec<Duration> = (0..3).map(|_| pacer.reserve(1316) - start).collect();
+        assert_eq!(slots, [Duration::ZERO, Duration::from_micros(1316), Duration::from_micros(2632)]);
+
+        // After a pause the next packet goes straight out, with no burst saved up
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(pacer.reserve(1316), Instant::now());
+        assert_eq!(pacer.reserve(1316) - Instant::now(), Duration::from_micros(1316));
+    }
+
+    #[test]
+    fn compares_sequence_numbers_across_the_wrap() {
+        assert!(sequence_before(SEQUENCE_MASK, 0) && !sequence_before(0, SEQUENCE_MASK));
+        assert!(sequence_in(1, SEQUENCE_MASK - 1, 2) && !sequence_in(3, SEQUENCE_MASK - 1, 2));
+    }
+}
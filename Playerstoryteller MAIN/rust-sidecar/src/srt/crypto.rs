@@ -0,0 +1,191 @@
+//! SRT encryption: data packet payloads are AES-CTR encrypted with a random
+//! stream key, which travels in the handshake as a key material message,
+//! wrapped (RFC 3394) with a key derived from the passphrase.
+//!
+//! Only the even key is used. libsrt refreshes keys after 2^24 packets,
+//! which the listener doesn't require of a sender.
+
+use std::ops::RangeInclusive;
+
+use aes::{Aes128, Aes192, Aes256};
+use aes_kw::{KekAes128, KekAes192, KekAes256};
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use ctr::Ctr128BE;
+use sha1::Sha1;
+use zeroize::Zeroizing;
+
+#[cfg(test)]
+use super::SrtError;
+
+/// Passphrase lengths libsrt accepts.
+pub const PASSPHRASE_LENGTH: RangeInclusive<usize> = 10..=79;
+
+const SALT_SIZE: usize = 16;
+
+/// PBKDF2 rounds of the passphrase, fixed by libsrt
+const PBKDF2_ROUNDS: u32 = 2048;
+
+/// Added to the key by wrapping it
+const WRAP_OVERHEAD: usize = 8;
+
+// Key material message fields
+const KM_VERSION_TYPE: u8 = 0x12;
+/// "HAI", the PnP vendor id of Haivision
+const KM_SIGNATURE: [u8; 2] = [0x20, 0x29];
+const KM_EVEN_KEY: u8 = 1;
+const KM_CIPHER_AES_CTR: u8 = 2;
+/// Stream encapsulation: SRT (MPEG-TS)
+const KM_SE_SRT: u8 = 2;
+const KM_HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySize {
+    Aes128,
+    Aes192,
+    Aes256,
+}
+
+impl KeySize {
+    /// From a byte count, as `pbkeylen` gives it
+    pub fn from_bytes(bytes: usize) -> Option<Self> {
+        match bytes {
+            16 => Some(Self::Aes128),
+            24 => Some(Self::Aes192),
+            32 => Some(Self::Aes256),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            Self::Aes128 => 16,
+            Self::Aes192 => 24,
+            Self::Aes256 => 32,
+        }
+    }
+
+    /// The handshake's encryption field
+    pub fn handshake_code(self) -> u16 {
+        (self.bytes() / 8) as u16
+    }
+}
+
+/// The stream key of a connection and the key material message that
+/// hands it over.
+pub struct StreamCrypto {
+    salt: [u8; SALT_SIZE],
+    key: Zeroizing<Vec<u8>>,
+    key_material: Vec<u8>,
+}
+
+impl StreamCrypto {
+    /// A fresh salt and stream key, wrapped with `passphrase`.
+    pub fn new(passphrase: &str, key_size: KeySize) -> Self {
+        let mut salt = [0; SALT_SIZE];
+        let mut key = Zeroizing::new(vec![0; key_size.bytes()]);
+        getrandom::getrandom(&mut salt).expect("OS random source unavailable");
+        getrandom::getrandom(&mut key).expect("OS random source unavailable");
+
+        let kek = derive_kek(passphrase, &salt, key_size);
+        let mut key_material = Vec::with_capacity(KM_HEADER_SIZE + SALT_SIZE + key_size.bytes() + WRAP_OVERHEAD);
+        key_material.extend_from_slice(&[KM_VERSION_TYPE, KM_SIGNATURE[0], KM_SIGNATURE[1], KM_EVEN_KEY]);
+        // KEK index 0: the passphrase
+        key_material.extend_from_slice(&[0; 4]);
+        key_material.extend_from_slice(&[KM_CIPHER_AES_CTR, 0, KM_SE_SRT, 0, 0, 0]);
+        key_material.extend_from_slice(&[(SALT_SIZE / 4) as u8, (key_size.bytes() / 4) as u8]);
+        key_material.extend_from_slice(&salt);
+        key_material.extend_from_slice(&wrap(&kek, &key));
+        Self { salt, key, key_material }
+    }
+
+    /// The stream key out of a key material message, as a listener reads it.
+    #[cfg(test)]
+    pub fn from_key_material(passphrase: &str, key_material: &[u8]) -> Result<Self, SrtError> {
+        if key_material.len() < KM_HEADER_SIZE + SALT_SIZE || key_material[0] != KM_VERSION_TYPE {
+            return Err(SrtError::Protocol("not a key material message"));
+        }
+        let key_size = KeySize::from_bytes(key_material[15] as usize * 4).ok_or(SrtError::Protocol("unsupported key size"))?;
+        let salt: [u8; SALT_SIZE] = key_material[KM_HEADER_SIZE..KM_HEADER_SIZE + SALT_SIZE].try_into().unwrap();
+        let wrapped = &key_material[KM_HEADER_SIZE + SALT_SIZE..];
+        if wrapped.len() != key_size.bytes() + WRAP_OVERHEAD {
+            return Err(SrtError::Protocol("key material of the wrong size"));
+        }
+
+        let kek = derive_kek(passphrase, &salt, key_size);
+        let mut key = Zeroizing::new(vec![0; key_size.bytes()]);
+        let unwrapped = match key_size {
+            KeySize::Aes128 => KekAes128::try_from(&kek[..]).and_then(|k| k.unwrap(wrapped, &mut key)),
+            KeySize::Aes192 => KekAes192::try_from(&kek[..]).and_then(|k| k.unwrap(wrapped, &mut key)),
+            KeySize::Aes256 => KekAes256::try_from(&kek[..]).and_then(|k| k.unwrap(wrapped, &mut key)),
+        };
+        // The integrity check of the unwrap is what catches a wrong passphrase
+        unwrapped.map_err(|_| SrtError::Encryption("the passphrase does not match"))?;
+        Ok(Self { salt, key, key_material: key_material.to_vec() })
+    }
+
+    /// The key material message for the KMREQ handshake extension.
+    pub fn key_material(&self) -> &[u8] {
+        &self.key_material
+    }
+
+    /// Encrypt or decrypt the payload of packet `sequence` in place.
+    pub fn apply(&self, sequence: u32, payload: &mut [u8]) {
+        // The first 112 bits of the salt, with the sequence number in bits
+        // 80..112 and the block counter in the last 16
+        let mut iv = [0; 16];
+        iv[..14].copy_from_slice(&self.salt[..14]);
+        for (byte, sequence_byte) in iv[10..14].iter_mut().zip(sequence.to_be_bytes()) {
+            *byte ^= sequence_byte;
+        }
+        match self.key.len() {
+            16 => Ctr128BE::<Aes128>::new_from_slices(&self.key, &iv).expect("key size").apply_keystream(payload),
+            24 => Ctr128BE::<Aes192>::new_from_slices(&self.key, &iv).expect("key size").apply_keystream(payload),
+            _ => Ctr128BE::<Aes256>::new_from_slices(&self.key, &iv).expect("key size").apply_keystream(payload),
+        }
+    }
+}
+
+/// PBKDF2-HMAC-SHA1 of the passphrase over the last 64 bits of the salt.
+fn derive_kek(passphrase: &str, salt: &[u8; SALT_SIZE], key_size: KeySize) -> Zeroizing<Vec<u8>> {
+    let mut kek = Zeroizing::new(vec![0; key_size.bytes()]);
+    pbkdf2::pbkdf2_hmac::<Sha1>(passphrase.as_bytes(), &salt[SALT_SIZE - 8..], PBKDF2_ROUNDS, &mut kek);
+    kek
+}
+
+fn wrap(kek: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut out = vec![0; key.len() + WRAP_OVERHEAD];
+    let wrapped = match kek.len() {
+        16 => KekAes128::try_from(kek).and_then(|k| k.wrap(key, &mut out)),
+        24 => KekAes192::try_from(kek).and_then(|k| k.wrap(key, &mut out)),
+        _ => KekAes256::try_from(kek).and_then(|k| k.wrap(key, &mut out)),
+    };
+    wrapped.expect("AES key sizes are whole semiblocks");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hands_the_key_over_with_the_passphrase() {
+        let sender = StreamCrypto::new("correct-horse-battery", KeySize::Aes256);
+        let km = sender.key_material();
+        assert_eq!(km[..4], [0x12, 0x20, 0x29, 1]);
+        assert_eq!((km[14], km[15]), (4, 8));
+        assert_eq!(km.len(), 16 + 16 + 32 + 8);
+
+        let receiver = StreamCrypto::from_key_material("correct-horse-battery", km).unwrap();
+        let mut payload = vec![0x47; 1316];
+        sender.apply(7, &mut payload);
+        assert_ne!(payload, vec![0x47; 1316]);
+        // The sequence number is part of the counter
+        let mut other = vec![0x47; 1316];
+        sender.apply(8, &mut other);
+        assert_ne!(payload, other);
+        receiver.apply(7, &mut payload);
+        assert_eq!(payload, vec![0x47; 1316]);
+
+        assert!(matches!(StreamCrypto::from_key_material("another-passphrase", km), Err(SrtError::Encryption(_))));
+    }
+}
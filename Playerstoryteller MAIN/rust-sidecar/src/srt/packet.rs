@@ -0,0 +1,446 @@
+//! SRT packets: the 16 byte header shared by data and control packets, the
+//! control types a live sender deals with, and the handshake with its
+//! extensions.
+
+use super::SrtError;
+
+pub const HEADER_SIZE: usize = 16;
+
+/// Handshake versions: callers start with 4 and move to 5 once the
+/// listener shows it speaks it
+pub const VERSION_INDUCTION: u32 = 4;
+pub const VERSION_5: u32 = 5;
+
+/// What a version 5 listener puts in the extension field of its induction answer
+pub const SRT_MAGIC: u16 = 0x4A17;
+
+/// Extension field of the induction request: UDT_DGRAM
+pub const SOCKET_TYPE_DGRAM: u16 = 2;
+
+// Flags of the conclusion request's extension field
+pub const EXT_FLAG_HSREQ: u16 = 0x1;
+pub const EXT_FLAG_KMREQ: u16 = 0x2;
+pub const EXT_FLAG_CONFIG: u16 = 0x4;
+
+// Handshake extension types
+pub const EXT_HSREQ: u16 = 1;
+pub const EXT_HSRSP: u16 = 2;
+pub const EXT_KMREQ: u16 = 3;
+pub const EXT_KMRSP: u16 = 4;
+pub const EXT_SID: u16 = 5;
+
+// Control types
+const HANDSHAKE: u16 = 0;
+const KEEPALIVE: u16 = 1;
+const ACK: u16 = 2;
+const NAK: u16 = 3;
+const SHUTDOWN: u16 = 5;
+const ACKACK: u16 = 6;
+const DROPREQ: u16 = 7;
+
+/// Sequence numbers are 31 bits and wrap.
+pub const SEQUENCE_MASK: u32 = 0x7FFF_FFFF;
+
+/// Message numbers are 26 bits and wrap.
+pub const MESSAGE_MASK: u32 = 0x03FF_FFFF;
+
+/// A data packet that is a whole message by itself
+const POSITION_SOLO: u32 = 0b11 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeType {
+    Induction,
+    Conclusion,
+    /// The listener refused, with an SRT_REJ_* reason
+    Rejected(u32),
+    Other(u32),
+}
+
+impl HandshakeType {
+    fn from_u32(value: u32) -> Self {
+        match value {
+            1 => Self::Induction,
+            0xFFFF_FFFF => Self::Conclusion,
+            1000..=0x7FFF_FFFF => Self::Rejected(value - 1000),
+            other => Self::Other(other),
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            Self::Induction => 1,
+            Self::Conclusion => 0xFFFF_FFFF,
+            Self::Rejected(reason) => 1000 + reason,
+            Self::Other(value) => value,
+        }
+    }
+}
+
+/// The handshake control information.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub version: u32,
+    /// Key size advertised for encryption: 0, or 2, 3 and 4 for AES-128, -192 and -256
+    pub encryption: u16,
+    /// Socket type, the SRT magic or extension flags, depending on the step
+    pub extension_field: u16,
+    pub initial_sequence: u32,
+    pub mtu: u32,
+    pub flow_window: u32,
+    pub kind: HandshakeType,
+    pub socket_id: u32,
+    pub cookie: u32,
+    /// Address of the other side as this side sees it, IPv4 in the first word
+    pub peer_ip: [u8; 16],
+    pub extensions: Vec<Extension>,
+}
+
+impl Handshake {
+    pub fn extension(&self, kind: u16) -> Option<&[u8]> {
+        self.extensions.iter().find(|e| e.kind == kind).map(|e| e.data.as_slice())
+    }
+}
+
+/// A handshake extension block. Its length is counted in 32 bit words.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Extension {
+    pub kind: u16,
+    pub data: Vec<u8>,
+}
+
+impl Extension {
+    /// A stream id extension. The string goes out as 32 bit words that
+    /// libsrt reads in host order, so each group of four bytes is reversed.
+    pub fn stream_id(id: &str) -> Self {
+        let mut data = id.as_bytes().to_vec();
+        data.resize(data.len().div_ceil(4) * 4, 0);
+        data.chunks_mut(4).for_each(|word| word.reverse());
+        Self { kind: EXT_SID, data }
+    }
+
+    /// The string of a stream id extension.
+    #[cfg(test)]
+    pub fn read_stream_id(data: &[u8]) -> String {
+        let mut bytes: Vec<u8> = data.chunks(4).flat_map(|word| word.iter().rev().copied()).collect();
+        while bytes.last() == Some(&0) {
+            bytes.pop();
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+/// The SRT options exchanged in HSREQ and HSRSP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SrtOptions {
+    /// Major, minor and patch in the low three bytes
+    pub version: u32,
+    pub flags: u32,
+    /// Latency in milliseconds the sender asks the receiver to buffer
+    pub receiver_delay: u16,
+    pub sender_delay: u16,
+}
+
+impl SrtOptions {
+    pub const TSBPD_SEND: u32 = 0x01;
+    pub const TSBPD_RECEIVE: u32 = 0x02;
+    pub const CRYPT: u32 = 0x04;
+    pub const TOO_LATE_PACKET_DROP: u32 = 0x08;
+    pub const PERIODIC_NAK: u32 = 0x10;
+    pub const RETRANSMIT_FLAG: u32 = 0x20;
+
+    pub fn to_extension(self, kind: u16) -> Extension {
+        let mut data = Vec::with_capacity(12);
+        data.extend_from_slice(&self.version.to_be_bytes());
+        data.extend_from_slice(&self.flags.to_be_bytes());
+        data.extend_from_slice(&self.receiver_delay.to_be_bytes());
+        data.extend_from_slice(&self.sender_delay.to_be_bytes());
+        Extension { kind, data }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, SrtError> {
+        let word = |i: usize| data.get(i * 4..i * 4 + 4).map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+        let (Some(version), Some(flags), Some(delays)) = (word(0), word(1), word(2)) else {
+            return Err(SrtError::Protocol("short SRT handshake extension"));
+        };
+        Ok(Self { version, flags, receiver_delay: (delays >> 16) as u16, sender_delay: delays as u16 })
+    }
+}
+
+/// Which key a data packet was encrypted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyIndex {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataPacket {
+    pub sequence: u32,
+    pub message: u32,
+    pub key: KeyIndex,
+    pub retransmitted: bool,
+    /// Microseconds since the connection started
+    pub timestamp: u32,
+    pub destination: u32,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    Handshake(Handshake),
+    KeepAlive,
+    /// Everything before `next` has arrived. Light ACKs have number 0 and
+    /// need no ACKACK.
+    Ack { number: u32, next: u32 },
+    /// Lost sequence numbers, as inclusive ranges
+    Nak(Vec<(u32, u32)>),
+    Shutdown,
+    AckAck(u32),
+    /// The sender gave up on these packets of a message
+    DropRequest { message: u32, first: u32, last: u32 },
+    Other(u16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlPacket {
+    pub timestamp: u32,
+    pub destination: u32,
+    pub control: Control,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    Data(DataPacket),
+    Control(ControlPacket),
+}
+
+impl Packet {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Packet::Data(data) => {
+                let key = match data.key {
+                    KeyIndex::None => 0,
+                    KeyIndex::Even => 1,
+                    KeyIndex::Odd => 2,
+                };
+                let word = POSITION_SOLO | key << 27 | (data.retransmitted as u32) << 26 | data.message & MESSAGE_MASK;
+                out.extend_from_slice(&(data.sequence & SEQUENCE_MASK).to_be_bytes());
+                out.extend_from_slice(&word.to_be_bytes());
+                out.extend_from_slice(&data.timestamp.to_be_bytes());
+                out.extend_from_slice(&data.destination.to_be_bytes());
+                out.extend_from_slice(&data.payload);
+            }
+            Packet::Control(control) => {
+                let (kind, info) = match &control.control {
+                    Control::Handshake(_) => (HANDSHAKE, 0),
+                    Control::KeepAlive => (KEEPALIVE, 0),
+                    Control::Ack { number, .. } => (ACK, *number),
+                    Control::Nak(_) => (NAK, 0),
+                    Control::Shutdown => (SHUTDOWN, 0),
+                    Control::AckAck(number) => (ACKACK, *number),
+                    Control::DropRequest { message, .. } => (DROPREQ, *message),
+                    Control::Other(kind) => (*kind, 0),
+                };
+                out.extend_from_slice(&(0x8000_0000 | (kind as u32) << 16).to_be_bytes());
+                out.extend_from_slice(&info.to_be_bytes());
+                out.extend_from_slice(&control.timestamp.to_be_bytes());
+                out.extend_from_slice(&control.destination.to_be_bytes());
+                match &control.control {
+                    Control::Handshake(handshake) => write_handshake(out, handshake),
+                    Control::Ack { next, .. } => out.extend_from_slice(&(next & SEQUENCE_MASK).to_be_bytes()),
+                    Control::Nak(ranges) => {
+                        for &(first, last) in ranges {
+                            if first == last {
+                                out.extend_from_slice(&first.to_be_bytes());
+                            } else {
+                                out.extend_from_slice(&(0x8000_0000 | first).to_be_bytes());
+                                out.extend_from_slice(&last.to_be_bytes());
+                            }
+                        }
+                    }
+                    Control::DropRequest { first, last, .. } => {
+                        out.extend_from_slice(&first.to_be_bytes());
+                        out.extend_from_slice(&last.to_be_bytes());
+                    }
+                    // libsrt pads the rest to one word
+                    _ => out.extend_from_slice(&[0; 4]),
+                }
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write(&mut out);
+        out
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, SrtError> {
+        if data.len() < HEADER_SIZE {
+            return Err(SrtError::Protocol("packet shorter than its header"));
+        }
+        let word = |i: usize| u32::from_be_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+        let (first, second, timestamp, destination) = (word(0), word(1), word(2), word(3));
+        let body = &data[HEADER_SIZE..];
+
+        if first & 0x8000_0000 == 0 {
+            let key = match second >> 27 & 0b11 {
+                0 => KeyIndex::None,
+                1 => KeyIndex::Even,
+                2 => KeyIndex::Odd,
+                _ => return Err(SrtError::Protocol("data packet with both keys")),
+            };
+            return Ok(Packet::Data(DataPacket {
+                sequence: first,
+                message: second & MESSAGE_MASK,
+                key,
+                retransmitted: second & 1 << 26 != 0,
+                timestamp,
+                destination,
+                payload: body.to_vec(),
+            }));
+        }
+
+        let words: Vec<u32> = body.chunks_exact(4).map(|w| u32::from_be_bytes(w.try_into().unwrap())).collect();
+        let control = match (first >> 16 & 0x7FFF) as u16 {
+            HANDSHAKE => Control::Handshake(parse_handshake(body)?),
+            KEEPALIVE => Control::KeepAlive,
+            ACK => Control::Ack { number: second, next: *words.first().ok_or(SrtError::Protocol("empty ACK"))? & SEQUENCE_MASK },
+            NAK => {
+                let mut ranges = Vec::new();
+                let mut words = words.iter();
+                while let Some(&word) = words.next() {
+                    if word & 0x8000_0000 != 0 {
+                        let last = *words.next().ok_or(SrtError::Protocol("NAK range without an end"))?;
+                        ranges.push((word & SEQUENCE_MASK, last & SEQUENCE_MASK));
+                    } else {
+                        ranges.push((word, word));
+                    }
+                }
+                Control::Nak(ranges)
+            }
+            SHUTDOWN => Control::Shutdown,
+            ACKACK => Control::AckAck(second),
+            DROPREQ => match words[..] {
+                [first, last, ..] => Control::DropRequest { message: second & MESSAGE_MASK, first, last },
+                _ => return Err(SrtError::Protocol("short drop request")),
+            },
+            other => Control::Other(other),
+        };
+        Ok(Packet::Control(ControlPacket { timestamp, destination, control }))
+    }
+}
+
+/// Size of the handshake before its extensions
+const HANDSHAKE_SIZE: usize = 48;
+
+fn write_handshake(out: &mut Vec<u8>, handshake: &Handshake) {
+    out.extend_from_slice(&handshake.version.to_be_bytes());
+    out.extend_from_slice(&handshake.encryption.to_be_bytes());
+    out.extend_from_slice(&handshake.extension_field.to_be_bytes());
+    for word in [
+        handshake.initial_sequence,
+        handshake.mtu,
+        handshake.flow_window,
+        handshake.kind.to_u32(),
+        handshake.socket_id,
+        handshake.cookie,
+    ] {
+        out.extend_from_slice(&word.to_be_bytes());
+    }
+    out.extend_from_slice(&handshake.peer_ip);
+    for extension in &handshake.extensions {
+        let words = extension.data.len().div_ceil(4);
+        out.extend_from_slice(&extension.kind.to_be_bytes());
+        out.extend_from_slice(&(words as u16).to_be_bytes());
+        out.extend_from_slice(&extension.data);
+        out.resize(out.len() + words * 4 - extension.data.len(), 0);
+    }
+}
+
+fn parse_handshake(body: &[u8]) -> Result<Handshake, SrtError> {
+    if body.len() < HANDSHAKE_SIZE {
+        return Err(SrtError::Protocol("short handshake"));
+    }
+    let word = |i: usize| u32::from_be_bytes(body[i * 4..i * 4 + 4].try_into().unwrap());
+    let mut extensions = Vec::new();
+    let mut rest = &body[HANDSHAKE_SIZE..];
+    while rest.len() >= 4 {
+        let kind = u16::from_be_bytes([rest[0], rest[1]]);
+        let length = u16::from_be_bytes([rest[2], rest[3]]) as usize * 4;
+        let data = rest.get(4..4 + length).ok_or(SrtError::Protocol("handshake extension runs past the packet"))?;
+        extensions.push(Extension { kind, data: data.to_vec() });
+        rest = &rest[4 + length..];
+    }
+    Ok(Handshake {
+        version: word(0),
+        encryption: (word(1) >> 16) as u16,
+        extension_field: word(1) as u16,
+        initial_sequence: word(2),
+        mtu: word(3),
+        flow_window: word(4),
+        kind: HandshakeType::from_u32(word(5)),
+        socket_id: word(6),
+        cookie: word(7),
+        peer_ip: body[32..48].try_into().unwrap(),
+        extensions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_packets() {
+        let handshake = Handshake {
+            version: VERSION_5,
+            encryption: 2,
+            extension_field: EXT_FLAG_HSREQ | EXT_FLAG_CONFIG,
+            initial_sequence: 12345,
+            mtu: 1500,
+            flow_window: 8192,
+            kind: HandshakeType::Conclusion,
+            socket_id: 0x1111,
+            cookie: 0x2222,
+            peer_ip: [127, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            extensions: vec![
+                SrtOptions { version: 0x010502, flags: SrtOptions::TSBPD_SEND, receiver_delay: 120, sender_delay: 120 }.to_extension(EXT_HSREQ),
+                Extension::stream_id("live/abcde"),
+            ],
+        };
+        let packets = [
+            Packet::Control(ControlPacket { timestamp: 5, destination: 0, control: Control::Handshake(handshake) }),
+            Packet::Control(ControlPacket { timestamp: 6, destination: 9, control: Control::Ack { number: 3, next: 77 } }),
+            Packet::Control(ControlPacket { timestamp: 7, destination: 9, control: Control::Nak(vec![(4, 4), (10, 12)]) }),
+            Packet::Control(ControlPacket { timestamp: 8, destination: 9, control: Control::Shutdown }),
+            Packet::Data(DataPacket {
+                sequence: SEQUENCE_MASK,
+                message: 1,
+                key: KeyIndex::Even,
+                retransmitted: true,
+                timestamp: 1_000_000,
+                destination: 9,
+                payload: vec![0x47; 188],
+            }),
+        ];
+        for packet in packets {
+            assert_eq!(Packet::parse(&packet.to_bytes()).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn lays_out_headers_and_stream_ids() {
+        let nak = Packet::Control(ControlPacket { timestamp: 1, destination: 2, control: Control::Nak(vec![(10, 12)]) });
+        assert_eq!(nak.to_bytes(), [0x80, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0x80, 0, 0, 10, 0, 0, 0, 12]);
+
+        let data = Packet::Data(DataPacket { sequence: 1, message: 2, key: KeyIndex::Odd, retransmitted: false, timestamp: 0, destination: 0, payload: Vec::new() });
+        assert_eq!(data.to_bytes()[4..8], [0xD0, 0, 0, 2]);
+
+        // "abcd" "ef" goes out as "dcba" "\0\0fe"
+        let sid = Extension::stream_id("abcdef");
+        assert_eq!(sid.data, b"dcba\0\0fe");
+        assert_eq!(Extension::read_stream_id(&sid.data), "abcdef");
+    }
+}
@@ -0,0 +1,26 @@
+use serde::Serialize;
+
+/// Severity understood by `SidecarManager.ProcessPendingNotifications` in the mod.
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum StatusLevel {
+    Warning,
+    Error,
+}
+
+#[derive(Serialize)]
+struct StatusMessage<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    message: String,
+    level: StatusLevel,
+}
+
+/// Print a `STATUS:{json}` line on stdout. The mod reads these and shows them in game.
+pub fn emit(kind: &str, level: StatusLevel, message: &str) {
+    // The mod's parser is a regex that stops at the first quote, so keep messages quote-free
+    let msg = StatusMessage { kind, message: message.replace('"', "'"), level };
+    if let Ok(json) = serde_json::to_string(&msg) {
+        println!("STATUS:{}", json);
+    }
+}
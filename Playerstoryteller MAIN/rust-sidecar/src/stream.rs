@@ -7,11 +7,11 @@ use windows_implement::implement;
 use tokio::sync::mpsc::UnboundedSender;
 use log::{debug, info, error};
 use parking_lot::Mutex;
-use crate::mp4::{Mp4Parser, SegmentType};
+use crate::mp4::{Mp4Parser, Mp4Segment, SegmentType};
 
 #[implement(IStream)]
 pub struct WebSocketStream {
-    sender: UnboundedSender<Vec<u8>>,
+    sender: UnboundedSender<Mp4Segment>,
     // state contains the virtual file buffer and current SinkWriter position
     state: Mutex<StreamState>,
     // parser processes completed atoms into segments
@@ -25,7 +25,7 @@ struct StreamState {
 }
 
 impl WebSocketStream {
-    pub fn new(sender: UnboundedSender<Vec<u8>>) -> Self {
+    pub fn new(sender: UnboundedSender<Mp4Segment>) -> Self {
         Self { 
             sender, 
             state: Mutex::new(StreamState {
@@ -79,7 +79,7 @@ impl WebSocketStream {
                         // Media segments logged at debug level (too frequent)
                     },
                 }
-                let _ = self.sender.send(segment.data);
+                let _ = self.sender.send(segment);
             }
 
         }
@@ -0,0 +1,371 @@
+//! Shared test fixtures.
+//!
+//! `TestServer` is an in-process WebSocket server for tests of the streaming
+//! client. It records what each connection presented (headers, query, close
+//! code) and collects every binary message. Faults are scripted per connection
+//! with `push_behavior`: connections beyond the script get `Behavior::Accept`.
+//!
+//! The rest builds media to feed the pipeline with: tagged placeholder
+//! segments, small fMP4 recordings, and H.264 parameter sets and streams.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use parking_lot::Mutex;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message};
+use tokio_tungstenite::accept_hdr_async;
+use url::Url;
+
+use crate::mp4::{Fmp4Muxer, Mp4Segment, Sample, SegmentType, VideoTrack};
+
+/// What the server does with one connection.
+#[derive(Clone, Debug, Default)]
+pub enum Behavior {
+    /// Accept and read until the client goes away
+    #[default]
+    Accept,
+    /// Refuse the upgrade with this HTTP status
+    Reject(StatusCode),
+    /// Close with `code` after receiving `messages` binary messages
+    CloseAfter { messages: usize, code: CloseCode },
+    /// Drop the TCP connection without a close frame after `messages` binary messages
+    DropAfter { messages: usize },
+    /// Wait this long before each read, like a congested link
+    SlowRead(Duration),
+}
+
+/// What one connection presented, and how it ended.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub authorization: Option<String>,
+    pub session_header: Option<String>,
+    pub session_query: Option<String>,
+    pub accepted_at: Instant,
+    /// Close code sent by the client, if it closed cleanly
+    pub client_close: Option<CloseCode>,
+}
+
+#[derive(Debug)]
+pub struct Received {
+    /// Index into `connections()`
+    pub connection: usize,
+    pub data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Shared {
+    behaviors: Mutex<VecDeque<Behavior>>,
+    connections: Mutex<Vec<ConnectionInfo>>,
+    changed: Notify,
+}
+
+pub struct TestServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    messages: mpsc::UnboundedReceiver<Received>,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind test server");
+        let addr = listener.local_addr().unwrap();
+        let shared = Arc::new(Shared::default());
+        let (tx, messages) = mpsc::unbounded_channel();
+
+        let accept_shared = shared.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let behavior = accept_shared.behaviors.lock().pop_front().unwrap_or_default();
+                tokio::spawn(serve(stream, behavior, accept_shared.clone(), tx.clone()));
+            }
+        });
+
+        Self { addr, shared, messages, task }
+    }
+
+    /// `ws://` URL of the server, with `path` appended.
+    pub fn url(&self, path: &str) -> String {
+        format!("ws://{}{}", self.addr, path)
+    }
+
+    /// Script the next connection that has no behavior yet.
+    pub fn push_behavior(&self, behavior: Behavior) {
+        self.shared.behaviors.lock().push_back(behavior);
+    }
+
+    /// Accepted connections so far, in order.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.shared.connections.lock().clone()
+    }
+
+    pub async fn wait_for_connections(&self, count: usize) -> Vec<ConnectionInfo> {
+        self.wait_until(|c| c.len() >= count).await
+    }
+
+    /// Wait until the client has closed connection `index` cleanly.
+    pub async fn wait_for_client_close(&self, index: usize) -> CloseCode {
+        let connections = self.wait_until(|c| c.get(index).is_some_and(|c| c.client_close.is_some())).await;
+        connections[index].client_close.unwrap()
+    }
+
+    async fn wait_until(&self, done: impl Fn(&[ConnectionInfo]) -> bool) -> Vec<ConnectionInfo> {
+        loop {
+            let changed = self.shared.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            {
+                let connections = self.shared.connections.lock();
+                if done(&connections) {
+                    return connections.clone();
+                }
+            }
+            changed.await;
+        }
+    }
+
+    pub async fn recv(&mut self) -> Received {
+        self.messages.recv().await.expect("test server stopped")
+    }
+
+    /// Everything received so far, without waiting.
+    pub fn drain(&mut self) -> Vec<Received> {
+        std::iter::from_fn(|| self.messages.try_recv().ok()).collect()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(stream: TcpStream, behavior: Behavior, shared: Arc<Shared>, tx: mpsc::UnboundedSender<Received>) {
+    let mut info = None;
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        if let Behavior::Reject(status) = behavior {
+            let mut error = ErrorResponse::new(None);
+            *error.status_mut() = status;
+            return Err(error);
+        }
+        let header = |name: &str| request.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let session_query = Url::parse(&format!("ws://test{}", request.uri()))
+            .ok()
+            .and_then(|u| u.query_pairs().find(|(k, _)| k == "session").map(|(_, v)| v.into_owned()));
+        info = Some(ConnectionInfo {
+            authorization: header("authorization"),
+            session_header: header("session-id"),
+            session_query,
+            accepted_at: Instant::now(),
+            client_close: None,
+        });
+        Ok(response)
+    };
+
+    let Ok(mut ws) = accept_hdr_async(stream, callback).await else { return };
+    let index = {
+        let mut connections = shared.connections.lock();
+        connections.push(info.expect("accepted connections are recorded"));
+        connections.len() - 1
+    };
+    shared.changed.notify_waiters();
+
+    let mut received = 0;
+    loop {
+        match behavior {
+            Behavior::CloseAfter { messages, code } if received >= messages => {
+                let _ = ws.close(Some(CloseFrame { code, reason: "test".into() })).await;
+                // Let the client see the close before the socket goes away
+                while let Some(Ok(_)) = ws.next().await {}
+                return;
+            }
+            Behavior::DropAfter { messages } if received >= messages => return,
+            Behavior::SlowRead(delay) => sleep(delay).await,
+            _ => {}
+        }
+
+        match ws.next().await {
+            Some(Ok(Message::Binary(data))) => {
+                received += 1;
+                let _ = tx.send(Received { connection: index, data });
+            }
+            Some(Ok(Message::Close(frame))) => {
+                let code = frame.map(|f| f.code).unwrap_or(CloseCode::Status);
+                shared.connections.lock()[index].client_close = Some(code);
+                shared.changed.notify_waiters();
+                let _ = ws.flush().await;
+            }
+            Some(Ok(_)) => {}
+            Some(Err(_)) | None => return,
+        }
+    }
+}
+
+/// A minimal init segment: only the leading `ftyp` box type matters to the client.
+pub fn init_segment(tag: u8) -> Mp4Segment {
+    segment(SegmentType::Init, b"ftyp", tag, 32)
+}
+
+/// A media segment of `len` bytes, identifiable by `tag`.
+pub fn media_segment(tag: u8, len: usize) -> Mp4Segment {
+    segment(SegmentType::Media, b"moof", tag, len)
+}
+
+fn segment(kind: SegmentType, box_type: &[u8; 4], tag: u8, len: usize) -> Mp4Segment {
+    let mut data = vec![tag; len.max(9)];
+    data[..4].copy_from_slice(&(len.max(9) as u32).to_be_bytes());
+    data[4..8].copy_from_slice(box_type);
+    Mp4Segment { kind, data, fragment: None }
+}
+
+/// The tag a test segment was built with.
+pub fn tag(data: &[u8]) -> u8 {
+    data[8]
+}
+
+/// A small fragmented MP4 recording with one video track: an init segment,
+/// then one fragment per `(decode time, sample durations)`, in `timescale` ticks.
+pub fn fmp4_file(timescale: u32, fragments: &[(u64, Vec<u32>)]) -> Vec<u8> {
+    let full_box = |kind: &[u8; 4], version: u8, flags: u32, fields: &[u8]| {
+        let mut payload = ((version as u32) << 24 | flags).to_be_bytes().to_vec();
+        payload.extend_from_slice(fields);
+        mp4_box(kind, &payload)
+    };
+    let words = |values: &[u32]| values.iter().flat_map(|v| v.to_be_bytes()).collect::<Vec<u8>>();
+
+    let mut file = mp4_box(b"ftyp", b"isom\0\0\x02\0isomiso6");
+    let tkhd = full_box(b"tkhd", 0, 3, &words(&[0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+    let mdhd = full_box(b"mdhd", 0, 0, &words(&[0, 0, timescale, 0, 0x55C4_0000]));
+    let trak = mp4_box(b"trak", &[tkhd, mp4_box(b"mdia", &mdhd)].concat());
+    let trex = full_box(b"trex", 0, 0, &words(&[1, 1, 0, 0, 0]));
+    let mvhd = full_box(b"mvhd", 0, 0, &words(&[0, 0, timescale, 0]));
+    file.extend(mp4_box(b"moov", &[mvhd, trak, mp4_box(b"mvex", &trex)].concat()));
+
+    for (sequence, (decode_time, durations)) in fragments.iter().enumerate() {
+        let mfhd = full_box(b"mfhd", 0, 0, &words(&[sequence as u32 + 1]));
+        // default-base-is-moof, track 1
+        let tfhd = full_box(b"tfhd", 0, 0x020000, &words(&[1]));
+        let tfdt = match u32::try_from(*decode_time) {
+            Ok(time) => full_box(b"tfdt", 0, 0, &time.to_be_bytes()),
+            Err(_) => full_box(b"tfdt", 1, 0, &decode_time.to_be_bytes()),
+        };
+        // data offset, then duration and size per sample; the parser fills in the offset
+        let samples: Vec<u32> = durations.iter().flat_map(|d| [*d, 4]).collect();
+        let trun = full_box(b"trun", 0, 0x000301, &words(&[&[durations.len() as u32, 0][..], &samples].concat()));
+        file.extend(mp4_box(b"moof", &[mfhd, mp4_box(b"traf", &[tfhd, tfdt, trun].concat())].concat()));
+        file.extend(mp4_box(b"mdat", &vec![sequence as u8; 4 * durations.len()]));
+    }
+    file
+}
+
+fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+    data.extend_from_slice(kind);
+    data.extend_from_slice(payload);
+    data
+}
+
+/// Writes H.264 syntax elements, for building parameter sets in tests.
+#[derive(Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    pub fn bits(&mut self, count: u32, value: u32) -> &mut Self {
+        for i in (0..count).rev() {
+            if self.bits.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            *self.bytes.last_mut().unwrap() |= (((value >> i) & 1) as u8) << (7 - self.bits % 8);
+            self.bits += 1;
+        }
+        self
+    }
+
+    pub fn flag(&mut self, value: bool) -> &mut Self {
+        self.bits(1, value as u32)
+    }
+
+    pub fn ue(&mut self, value: u32) -> &mut Self {
+        let code = value as u64 + 1;
+        let length = 64 - code.leading_zeros();
+        self.bits(length - 1, 0).bits(length, code as u32)
+    }
+
+    pub fn se(&mut self, value: i32) -> &mut Self {
+        self.ue(if value > 0 { value as u32 * 2 - 1 } else { value.unsigned_abs() * 2 })
+    }
+
+    /// A NAL unit: `header`, then the RBSP with its stop bit and emulation prevention.
+    pub fn nal(&mut self, header: u8) -> Vec<u8> {
+        self.bits(1, 1);
+        let mut nal = vec![header];
+        let mut zeros = 0;
+        for &byte in &self.bytes {
+            if zeros >= 2 && byte <= 3 {
+                nal.push(3);
+                zeros = 0;
+            }
+            zeros = if byte == 0 { zeros + 1 } else { 0 };
+            nal.push(byte);
+        }
+        nal
+    }
+}
+
+/// A Constrained Baseline level 4.0 SPS for `width` x `height`, cropped from
+/// whole macroblocks like an encoder does.
+pub fn h264_sps(width: u32, height: u32) -> Vec<u8> {
+    let (mbs_wide, mbs_high) = (width.div_ceil(16), height.div_ceil(16));
+    let mut w = BitWriter::default();
+    // profile_idc, constraint_set0 and 1, level_idc, seq_parameter_set_id
+    w.bits(8, 66).bits(8, 0xC0).bits(8, 40).ue(0);
+    // log2_max_frame_num_minus4, pic_order_cnt_type 2, one reference frame, no gaps
+    w.ue(0).ue(2).ue(1).flag(false);
+    w.ue(mbs_wide - 1).ue(mbs_high - 1);
+    // frame_mbs_only_flag, direct_8x8_inference_flag
+    w.flag(true).flag(true);
+    let (crop_right, crop_bottom) = (mbs_wide * 16 - width, mbs_high * 16 - height);
+    w.flag(crop_right + crop_bottom > 0);
+    if crop_right + crop_bottom > 0 {
+        // In chroma samples for 4:2:0
+        w.ue(0).ue(crop_right / 2).ue(0).ue(crop_bottom / 2);
+    }
+    // No VUI
+    w.flag(false);
+    w.nal(0x67)
+}
+
+/// A PPS for `h264_sps`: ids 0, CAVLC.
+pub fn h264_pps() -> Vec<u8> {
+    let mut w = BitWriter::default();
+    // pps id, sps id, entropy_coding_mode_flag, bottom_field_pic_order_in_frame_present_flag, one slice group
+    w.ue(0).ue(0).flag(false).flag(false).ue(0);
+    // num_ref_idx_l0/l1_default_active_minus1, no weighted prediction, QP and deblocking defaults
+    w.ue(0).ue(0).flag(false).bits(2, 0).se(0).se(0).se(0).flag(true).flag(false).flag(false);
+    w.nal(0x68)
+}
+
+/// An init segment, then `count` 640x360 frames at 30 fps with a keyframe
+/// every 30, two frames per fragment, as the encoder packages them.
+pub fn h264_stream(count: usize) -> Vec<Mp4Segment> {
+    let mut muxer = Fmp4Muxer::new(VideoTrack::new(h264_sps(640, 360), h264_pps(), 90_000).unwrap());
+    let frames: Vec<Sample> = (0..count)
+        .map(|i| Sample { data: vec![0, 0, 0, 1, i as u8], duration: 3000, keyframe: i % 30 == 0, composition_offset: 0 })
+        .collect();
+    let mut segments = vec![muxer.init_segment()];
+    segments.extend(frames.chunks(2).filter_map(|chunk| muxer.fragment(chunk)));
+    segments
+}
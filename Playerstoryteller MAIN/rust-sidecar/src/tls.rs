@@ -0,0 +1,361 @@
+//! TLS for `wss://` connections.
+//!
+//! Two backends, picked at build time: `native-tls` (default; SChannel on Windows,
+//! OpenSSL on Linux) and `rustls` (pure Rust, no OpenSSL needed). If both features
+//! are enabled, rustls wins.
+
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::MaybeTlsStream;
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("enable at least one TLS backend: `native-tls` or `rustls`");
+
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// Extra PEM root certificates to trust, on top of the system store
+    pub ca_certs: Vec<PathBuf>,
+    /// PEM certificate chain presented to servers that require mutual TLS
+    pub client_cert: Option<PathBuf>,
+    /// PEM (PKCS#8) private key for `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// SHA-256 fingerprints of acceptable server leaf certificates (hex, colons allowed)
+    pub pins: Vec<String>,
+    /// Skip all certificate validation. Development only.
+    pub insecure_dev: bool,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TlsError {
+    #[error("Could not read {path}: {source}")]
+    Read { path: PathBuf, source: std::io::Error },
+    #[error("No certificates found in {0}")]
+    NoCertificates(PathBuf),
+    #[cfg(feature = "rustls")]
+    #[error("No private key found in {0}")]
+    NoPrivateKey(PathBuf),
+    #[error("--tls-client-cert and --tls-client-key must be given together")]
+    IncompleteClientIdentity,
+    #[error("Invalid certificate pin '{0}' (expected a 64 character hex SHA-256 fingerprint)")]
+    InvalidPin(String),
+    #[error("TLS configuration error: {0}")]
+    Config(String),
+    #[cfg(feature = "rustls")]
+    #[error("Invalid server name '{0}'")]
+    InvalidServerName(String),
+    #[error("TLS handshake failed: {0}")]
+    Handshake(String),
+    #[error("Server certificate {0} does not match any pinned fingerprint")]
+    PinMismatch(String),
+    #[error("Server presented no certificate to check against the pins")]
+    NoPeerCertificate,
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|source| TlsError::Read { path: path.to_path_buf(), source })
+}
+
+fn parse_pin(pin: &str) -> Result<[u8; 32], TlsError> {
+    let cleaned: String = pin.chars().filter(|c| *c != ':').collect();
+    let bytes = hex::decode(&cleaned).map_err(|_| TlsError::InvalidPin(pin.to_string()))?;
+    bytes.try_into().map_err(|_| TlsError::InvalidPin(pin.to_string()))
+}
+
+/// Connector shared by every reconnect attempt. Building it reads all the
+/// certificate files, so configuration mistakes surface once at startup.
+pub struct TlsConnector {
+    inner: backend::Connector,
+    pins: Vec<[u8; 32]>,
+}
+
+impl TlsConnector {
+    pub fn new(options: &TlsOptions) -> Result<Self, TlsError> {
+        if options.client_cert.is_some() != options.client_key.is_some() {
+            return Err(TlsError::IncompleteClientIdentity);
+        }
+        let pins = options.pins.iter().map(|p| parse_pin(p)).collect::<Result<Vec<_>, _>>()?;
+        if options.insecure_dev {
+            warn!("TLS certificate validation is DISABLED (--tls-insecure-dev). Never use this outside development.");
+        }
+
+        Ok(Self { inner: backend::Connector::new(options)?, pins })
+    }
+
+    pub async fn connect<S>(&self, domain: &str, stream: S) -> Result<MaybeTlsStream<S>, TlsError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (stream, peer_cert) = self.inner.connect(domain, stream).await?;
+
+        if !self.pins.is_empty() {
+            let der = peer_cert.ok_or(TlsError::NoPeerCertificate)?;
+            let fingerprint: [u8; 32] = Sha256::digest(&der).into();
+            if !self.pins.contains(&fingerprint) {
+                return Err(TlsError::PinMismatch(hex::encode(fingerprint)));
+            }
+        }
+        Ok(stream)
+    }
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+mod backend {
+    use std::path::Path;
+
+    use super::{read_file, TlsError, TlsOptions};
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio_tungstenite::MaybeTlsStream;
+
+    const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const PEM_END: &str = "-----END CERTIFICATE-----";
+
+    pub struct Connector(tokio_native_tls::TlsConnector);
+
+    impl Connector {
+        pub fn new(options: &TlsOptions) -> Result<Self, TlsError> {
+            let mut builder = native_tls::TlsConnector::builder();
+
+            for path in &options.ca_certs {
+                for cert in load_certs(path)? {
+                    builder.add_root_certificate(cert);
+                }
+            }
+
+            if let (Some(cert_path), Some(key_path)) = (&options.client_cert, &options.client_key) {
+                let identity = native_tls::Identity::from_pkcs8(&read_file(cert_path)?, &read_file(key_path)?)
+                    .map_err(|e| TlsError::Config(format!("client identity: {}", e)))?;
+                builder.identity(identity);
+            }
+
+            if options.insecure_dev {
+                builder.danger_accept_invalid_certs(true);
+                builder.danger_accept_invalid_hostnames(true);
+            }
+
+            let connector = builder.build().map_err(|e| TlsError::Config(e.to_string()))?;
+            Ok(Self(connector.into()))
+        }
+
+        pub async fn connect<S>(&self, domain: &str, stream: S) -> Result<(MaybeTlsStream<S>, Option<Vec<u8>>), TlsError>
+        where
+            S: AsyncRead + AsyncWrite + Unpin,
+        {
+            let tls_stream = self.0.connect(domain, stream).await.map_err(|e| TlsError::Handshake(e.to_string()))?;
+            let peer_cert = match tls_stream.get_ref().peer_certificate() {
+                Ok(Some(cert)) => cert.to_der().ok(),
+                _ => None,
+            };
+            Ok((MaybeTlsStream::NativeTls(tls_stream), peer_cert))
+        }
+    }
+
+    /// Every certificate in a PEM file. `from_pem` only reads the first one,
+    /// so bundles are split up front.
+    pub fn load_certs(path: &Path) -> Result<Vec<native_tls::Certificate>, TlsError> {
+        let pem = read_file(path)?;
+        let pem = String::from_utf8_lossy(&pem);
+        let mut certs = Vec::new();
+        for block in pem.split_inclusive(PEM_END) {
+            let Some(start) = block.find(PEM_BEGIN) else { continue };
+            let cert = native_tls::Certificate::from_pem(&block.as_bytes()[start..])
+                .map_err(|e| TlsError::Config(format!("{}: {}", path.display(), e)))?;
+            certs.push(cert);
+        }
+        if certs.is_empty() {
+            return Err(TlsError::NoCertificates(path.to_path_buf()));
+        }
+        Ok(certs)
+    }
+}
+
+#[cfg(feature = "rustls")]
+mod backend {
+    use std::io::BufReader;
+    use std::sync::Arc;
+
+    use log::warn;
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+    use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+    use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio_tungstenite::MaybeTlsStream;
+
+    use super::{read_file, TlsError, TlsOptions};
+
+    pub struct Connector(tokio_rustls::TlsConnector);
+
+    impl Connector {
+        pub fn new(options: &TlsOptions) -> Result<Self, TlsError> {
+            let builder = ClientConfig::builder();
+
+            let builder = if options.insecure_dev {
+                builder.dangerous().with_custom_certificate_verifier(Arc::new(NoVerification(ring::default_provider())))
+            } else {
+                let mut roots = RootCertStore::empty();
+                match rustls_native_certs::load_native_certs() {
+                    Ok(certs) => {
+                        let (_, ignored) = roots.add_parsable_certificates(certs);
+                        if ignored > 0 {
+                            warn!("Ignored {} unparsable system root certificates", ignored);
+                        }
+                    }
+                    Err(e) => warn!("Could not load system root certificates: {}", e),
+                }
+                for path in &options.ca_certs {
+                    for cert in load_certs(path)? {
+                        roots.add(cert).map_err(|e| TlsError::Config(format!("{}: {}", path.display(), e)))?;
+                    }
+                }
+                builder.with_root_certificates(roots)
+            };
+
+            let config = match (&options.client_cert, &options.client_key) {
+                (Some(cert_path), Some(key_path)) => {
+                    let key_pem = read_file(key_path)?;
+                    let key = rustls_pemfile::private_key(&mut BufReader::new(key_pem.as_slice()))
+                        .map_err(|e| TlsError::Config(format!("{}: {}", key_path.display(), e)))?
+                        .ok_or_else(|| TlsError::NoPrivateKey(key_path.clone()))?;
+                    builder
+                        .with_client_auth_cert(load_certs(cert_path)?, key)
+                        .map_err(|e| TlsError::Config(format!("client identity: {}", e)))?
+                }
+                _ => builder.with_no_client_auth(),
+            };
+
+            Ok(Self(tokio_rustls::TlsConnector::from(Arc::new(config))))
+        }
+
+        pub async fn connect<S>(&self, domain: &str, stream: S) -> Result<(MaybeTlsStream<S>, Option<Vec<u8>>), TlsError>
+        where
+            S: AsyncRead + AsyncWrite + Unpin,
+        {
+            // IPv6 hosts come in bracketed from the URL
+            let name = domain.trim_start_matches('[').trim_end_matches(']').to_string();
+            let server_name = ServerName::try_from(name).map_err(|_| TlsError::InvalidServerName(domain.to_string()))?;
+            let tls_stream = self.0.connect(server_name, stream).await.map_err(|e| TlsError::Handshake(e.to_string()))?;
+            let peer_cert = tls_stream.get_ref().1.peer_certificates().and_then(|c| c.first()).map(|c| c.to_vec());
+            Ok((MaybeTlsStream::Rustls(tls_stream), peer_cert))
+        }
+    }
+
+    pub fn load_certs(path: &std::path::Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+        let pem = read_file(path)?;
+        let certs = rustls_pemfile::certs(&mut BufReader::new(pem.as_slice()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| TlsError::Config(format!("{}: {}", path.display(), e)))?;
+        if certs.is_empty() {
+            return Err(TlsError::NoCertificates(path.to_path_buf()));
+        }
+        Ok(certs)
+    }
+
+    /// `--tls-insecure-dev`: accept any certificate, but still check handshake signatures.
+    #[derive(Debug)]
+    struct NoVerification(CryptoProvider);
+
+    impl ServerCertVerifier for NoVerification {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CA_A: &str = "-----BEGIN CERTIFICATE-----
+MIIBjDCCATOgAwIBAgIUdZXiScudbY/AiIzT9CzzDSdhmxcwCgYIKoZIzj0EAwIw
+GzEZMBcGA1UEAwwQcmF0bGFiLXRlc3QtY2EtYTAgFw0yNjEwMTgxNjAyNDhaGA8y
+MTI2MDkyNDE2MDI0OFowGzEZMBcGA1UEAwwQcmF0bGFiLXRlc3QtY2EtYTBZMBMG
+ByqGSM49AgEGCCqGSM49AwEHA0IABBUYc2+lYZfRUB5vfk1/Jlm99V6tDX4AwZS9
+PF64kBRDPBGV+jJt/IME45DYdeTiJgpZ6gmDa9/0cSd3BT+JpIGjUzBRMB0GA1Ud
+DgQWBBRx6AZP7WQMUuB6lB1RZ3+NUCYl7TAfBgNVHSMEGDAWgBRx6AZP7WQMUuB6
+lB1RZ3+NUCYl7TAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0cAMEQCIFzO
+IKe1Ygmlk/mEkqjLsC290mKxFl/MK2eBjme+swdoAiBl3n4Z/tGI4O1T2PTQoBMN
+3BMNUfXJARFURdoWAoexBw==
+-----END CERTIFICATE-----
+";
+
+    const CA_B: &str = "-----BEGIN CERTIFICATE-----
+MIIBjTCCATOgAwIBAgIUULe327Pb+/Au55etKCMECwYAt9AwCgYIKoZIzj0EAwIw
+GzEZMBcGA1UEAwwQcmF0bGFiLXRlc3QtY2EtYjAgFw0yNjEwMTgxNjAyNDhaGA8y
+MTI2MDkyNDE2MDI0OFowGzEZMBcGA1UEAwwQcmF0bGFiLXRlc3QtY2EtYjBZMBMG
+ByqGSM49AgEGCCqGSM49AwEHA0IABFIY7chnxnGoHHME+SbT4/hAyAT/Snwka6n1
+6WX+PQV6O4Lcp4ladjMpM1GAI9liDhofZkvTMVq3f9ObmaEdRE+jUzBRMB0GA1Ud
+DgQWBBSmIivjzwsa5/EtDimXA4zusTxJ1TAfBgNVHSMEGDAWgBSmIivjzwsa5/Et
+DimXA4zusTxJ1TAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0gAMEUCIQC4
+wPWK+QDnTnuO8SHUqfXAxUOvIJVl6lXGSHAEddY8hQIgYekxiAEmLiKnFIn396Dr
+RONgaFxA5DNzysy309Ep9Tk=
+-----END CERTIFICATE-----
+";
+
+    fn write_temp(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ratlab-tls-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn parses_pins_with_or_without_colons() {
+        let hex = "a1".repeat(32);
+        let colons = vec!["A1"; 32].join(":");
+        assert_eq!(parse_pin(&hex).unwrap(), [0xA1; 32]);
+        assert_eq!(parse_pin(&colons).unwrap(), [0xA1; 32]);
+        for bad in ["", "a1", &"a1".repeat(33), &"zz".repeat(32)] {
+            assert!(matches!(parse_pin(bad), Err(TlsError::InvalidPin(_))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn loads_every_certificate_of_a_ca_bundle() {
+        let bundle = write_temp("bundle.pem", &format!("# Test roots\n{}\n{}", CA_A, CA_B));
+        assert_eq!(backend::load_certs(&bundle).unwrap().len(), 2);
+        let single = write_temp("single.pem", CA_A);
+        assert_eq!(backend::load_certs(&single).unwrap().len(), 1);
+        let empty = write_temp("empty.pem", "no certificates here\n");
+        assert!(matches!(backend::load_certs(&empty), Err(TlsError::NoCertificates(_))));
+        assert!(matches!(backend::load_certs(Path::new("/nonexistent/ca.pem")), Err(TlsError::Read { .. })));
+
+        let options = TlsOptions { ca_certs: vec![bundle.clone()], pins: vec!["00".repeat(32)], ..TlsOptions::default() };
+        assert!(TlsConnector::new(&options).is_ok());
+        let options = TlsOptions { client_cert: Some(single.clone()), ..TlsOptions::default() };
+        assert!(matches!(TlsConnector::new(&options), Err(TlsError::IncompleteClientIdentity)));
+        for path in [bundle, single, empty] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
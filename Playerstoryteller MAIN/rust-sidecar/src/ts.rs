@@ -0,0 +1,481 @@
+//! MPEG transport stream packaging of the video, for SRT and for the
+//! `--ts-file` and `--udp` outputs: LAN spectating and tools that can't
+//! read fragmented MP4.
+//!
+//! One program with one H.264 elementary stream. Every keyframe is preceded
+//! by the PAT and PMT, so a receiver can start there, and each access unit
+//! becomes one PES packet in Annex-B form, with the parameter sets from the
+//! init segment repeated before keyframes. The PCR rides on the video PID at
+//! the start of every PES packet, with PCR-only packets in between at frame
+//! rates below 10 fps.
+
+mod file;
+mod udp;
+
+use std::collections::HashMap;
+
+use log::warn;
+
+use crate::h264::AvcConfig;
+use crate::mp4::{avc_config, single_track_samples, track_info, Mp4Segment, SegmentType, TrackInfo};
+
+pub use self::file::TsFileOutput;
+pub use self::udp::{UdpOutput, UdpUrl};
+
+/// Size of a transport stream packet.
+pub const PACKET_SIZE: usize = 188;
+
+/// Packets per UDP or SRT datagram: 1316 bytes fit any path MTU.
+pub const PACKETS_PER_DATAGRAM: usize = 7;
+
+/// Transport stream clock: PTS, DTS and the PCR base tick at 90 kHz.
+const CLOCK_RATE: u64 = 90_000;
+
+/// PTS and DTS are 33 bits and wrap.
+const TIMESTAMP_MASK: u64 = (1 << 33) - 1;
+
+/// How far decode times run ahead of the PCR: the time a receiver has to
+/// take in a picture before it is due. ffmpeg's default, which players are
+/// used to.
+const DECODE_DELAY: u64 = CLOCK_RATE * 7 / 10;
+
+/// Longest gap between PCRs that receivers are guaranteed to cope with: 100 ms.
+const PCR_INTERVAL: u64 = CLOCK_RATE / 10;
+
+const SYNC_BYTE: u8 = 0x47;
+
+const PAT_PID: u16 = 0;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x100;
+
+const PROGRAM_NUMBER: u16 = 1;
+const TRANSPORT_STREAM_ID: u16 = 1;
+
+const TABLE_PAT: u8 = 0;
+const TABLE_PMT: u8 = 2;
+
+/// stream_type of H.264 in the PMT
+const STREAM_TYPE_H264: u8 = 0x1B;
+
+/// PES stream_id of the first video stream
+const STREAM_ID_VIDEO: u8 = 0xE0;
+
+/// Access unit delimiter for any picture type, which TS receivers look for
+/// at the start of every access unit
+const ACCESS_UNIT_DELIMITER: [u8; 6] = [0, 0, 0, 1, 0x09, 0xF0];
+
+/// The packets of one access unit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TsFrame {
+    /// Whole 188 byte packets; a keyframe's start with the PAT and PMT
+    pub data: Vec<u8>,
+    pub keyframe: bool,
+}
+
+#[derive(Default)]
+pub struct TsMuxer {
+    tracks: HashMap<u32, TrackInfo>,
+    config: Option<AvcConfig>,
+    /// Continuity counters of the PAT, PMT and video PIDs
+    continuity: HashMap<u16, u8>,
+    /// 90 kHz ticks added to the decode times of the current init segment
+    offset: u64,
+    /// Decode time of the first sample since the init segment, in track ticks
+    start: Option<u64>,
+    /// Decode time after the last sample, for fragments without tfdt
+    next_decode_time: u64,
+    /// 90 kHz time after the last sample, where the next init segment carries on
+    next_timestamp: u64,
+    /// The last PCR written
+    last_pcr: Option<u64>,
+}
+
+impl TsMuxer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Transport stream for `segment`: nothing for an init segment, whose
+    /// parameter sets go out with the next keyframe, and a frame per sample
+    /// for media.
+    pub fn push(&mut self, segment: &Mp4Segment) -> Vec<TsFrame> {
+        if segment.kind == SegmentType::Init {
+            self.tracks = track_info(&segment.data);
+            self.config = avc_config(&segment.data);
+            self.offset = self.next_timestamp;
+            self.start = None;
+            self.next_decode_time = 0;
+            if self.config.is_none() {
+                warn!("MPEG-TS: the init segment has no avcC, dropping video until the next one");
+            }
+            return Vec::new();
+        }
+
+        let Some(config) = self.config.clone() else {
+            return Vec::new();
+        };
+        let Some((track_id, decode_time, samples)) = single_track_samples(&segment.data, &self.tracks) else {
+            warn!("MPEG-TS: dropping a media segment that is not one track fragment");
+            return Vec::new();
+        };
+        let timescale = self.tracks.get(&track_id).map_or(0, |t| t.timescale) as u64;
+        if timescale == 0 {
+            return Vec::new();
+        }
+
+        let mut decode_time = decode_time.unwrap_or(self.next_decode_time);
+        let start = *self.start.get_or_insert(decode_time);
+        let clock = |ticks: u64| ticks * CLOCK_RATE / timescale;
+        let mut frames = Vec::with_capacity(samples.len());
+        for sample in samples {
+            let pcr = self.offset + clock(decode_time.saturating_sub(start));
+            let dts = pcr + DECODE_DELAY;
+            let pts = dts.saturating_add_signed(sample.composition_offset as i64 * CLOCK_RATE as i64 / timescale as i64);
+
+            let mut data = Vec::new();
+            if sample.keyframe {
+                self.write_psi(&mut data);
+            }
+            if let Some(last) = self.last_pcr {
+                for between in (last + PCR_INTERVAL..pcr).step_by(PCR_INTERVAL as usize) {
+                    self.write_pcr_packet(&mut data, between);
+                }
+            }
+            self.last_pcr = Some(pcr);
+            let access_unit = annex_b(&config, &sample.data, sample.keyframe);
+            let pes = pes_packet(&access_unit, pts & TIMESTAMP_MASK, dts & TIMESTAMP_MASK);
+            self.write_packets(&mut data, VIDEO_PID, &pes, Some(pcr), sample.keyframe);
+            frames.push(TsFrame { data, keyframe: sample.keyframe });
+
+            decode_time += sample.duration as u64;
+            self.next_timestamp = self.offset + clock(decode_time.saturating_sub(start));
+        }
+        self.next_decode_time = decode_time;
+        frames
+    }
+
+    /// The PAT and PMT, each in a packet of its own.
+    fn write_psi(&mut self, out: &mut Vec<u8>) {
+        let mut pat = Vec::new();
+        pat.extend_from_slice(&PROGRAM_NUMBER.to_be_bytes());
+        pat.extend_from_slice(&(0xE000 | PMT_PID).to_be_bytes());
+        let pat = section(TABLE_PAT, TRANSPORT_STREAM_ID, &pat);
+
+        let mut pmt = Vec::new();
+        pmt.extend_from_slice(&(0xE000 | VIDEO_PID).to_be_bytes());
+        // No program descriptors
+        pmt.extend_from_slice(&0xF000u16.to_be_bytes());
+        pmt.push(STREAM_TYPE_H264);
+        pmt.extend_from_slice(&(0xE000 | VIDEO_PID).to_be_bytes());
+        pmt.extend_from_slice(&0xF000u16.to_be_bytes());
+        let pmt = section(TABLE_PMT, PROGRAM_NUMBER, &pmt);
+
+        for (pid, section) in [(PAT_PID, pat), (PMT_PID, pmt)] {
+            // pointer_field: the section starts right away
+            let mut payload = vec![0];
+            payload.extend_from_slice(&section);
+            // Sections are padded with 0xFF rather than adaptation fields
+            payload.resize(PACKET_SIZE - 4, 0xFF);
+            self.write_packets(out, pid, &payload, None, false);
+        }
+    }
+
+    /// A video packet with only an adaptation field, to carry a PCR.
+    fn write_pcr_packet(&mut self, out: &mut Vec<u8>, pcr: u64) {
+        // Without a payload the packet repeats the last continuity counter
+        let continuity = self.continuity.get(&VIDEO_PID).map_or(0, |next| next.wrapping_sub(1) & 0x0F);
+        out.extend_from_slice(&[SYNC_BYTE, (VIDEO_PID >> 8) as u8 & 0x1F, VIDEO_PID as u8, 0x20 | continuity]);
+        let mut field = vec![0x10];
+        write_pcr(&mut field, pcr);
+        field.resize(PACKET_SIZE - 5, 0xFF);
+        out.push(field.len() as u8);
+        out.extend_from_slice(&field);
+    }
+
+    /// Cut `payload` into packets on `pid`, the first one starting a unit
+    /// and carrying the PCR, in 90 kHz ticks.
+    fn write_packets(&mut self, out: &mut Vec<u8>, pid: u16, payload: &[u8], pcr: Option<u64>, random_access: bool) {
+        let mut rest = payload;
+        let mut first = true;
+        while first || !rest.is_empty() {
+            // Adaptation field after its length byte, if there is one
+            let mut adaptation = None;
+            if first && (pcr.is_some() || random_access) {
+                let mut field = vec![0];
+                if random_access {
+                    field[0] |= 0x40;
+                }
+                if let Some(pcr) = pcr {
+                    field[0] |= 0x10;
+                    write_pcr(&mut field, pcr);
+                }
+                adaptation = Some(field);
+            }
+
+            let header_size = 4 + adaptation.as_ref().map_or(0, |a| a.len() + 1);
+            let room = PACKET_SIZE - header_size;
+            if rest.len() < room {
+                // Stuff the adaptation field so the payload ends the packet
+                let stuffing = room - rest.len();
+                match &mut adaptation {
+                    Some(field) => field.resize(field.len() + stuffing, 0xFF),
+                    // A lone length byte of zero takes exactly one
+                    None if stuffing == 1 => adaptation = Some(Vec::new()),
+                    None => {
+                        let mut field = vec![0];
+                        field.resize(stuffing - 1, 0xFF);
+                        adaptation = Some(field);
+                    }
+                }
+            }
+            let header_size = 4 + adaptation.as_ref().map_or(0, |a| a.len() + 1);
+            let (chunk, remaining) = rest.split_at(rest.len().min(PACKET_SIZE - header_size));
+
+            let continuity = self.continuity.entry(pid).or_default();
+            let start_indicator = if first { 0x40 } else { 0 };
+            let control = if adaptation.is_some() { 0x30 } else { 0x10 };
+            out.extend_from_slice(&[SYNC_BYTE, start_indicator | (pid >> 8) as u8 & 0x1F, pid as u8, control | *continuity]);
+            *continuity = (*continuity + 1) & 0x0F;
+            if let Some(field) = adaptation {
+                out.push(field.len() as u8);
+                out.extend_from_slice(&field);
+            }
+            out.extend_from_slice(chunk);
+
+            rest = remaining;
+            first = false;
+        }
+    }
+}
+
+/// An access unit as Annex-B: a delimiter, the parameter sets before a
+/// keyframe unless it has its own, then the NAL units with start codes.
+fn annex_b(config: &AvcConfig, sample: &[u8], keyframe: bool) -> Vec<u8> {
+    let length_size = config.nal_length_size.clamp(1, 4) as usize;
+    let mut units = Vec::new();
+    let mut rest = sample;
+    while rest.len() >= length_size {
+        let length = rest[..length_size].iter().fold(0usize, |n, &b| n << 8 | b as usize);
+        let Some(unit) = rest.get(length_size..length_size + length) else { break };
+        units.push(unit);
+        rest = &rest[length_size + length..];
+    }
+
+    let mut out = Vec::with_capacity(sample.len() + 64);
+    out.extend_from_slice(&ACCESS_UNIT_DELIMITER);
+    let has_parameter_sets = units.iter().any(|u| u.first().is_some_and(|b| b & 0x1F == 7));
+    if keyframe && !has_parameter_sets {
+        for unit in config.sps.iter().chain(&config.pps) {
+            out.extend_from_slice(&[0, 0, 0, 1]);
+            out.extend_from_slice(unit);
+        }
+    }
+    for unit in units {
+        out.extend_from_slice(&[0, 0, 0, 1]);
+        out.extend_from_slice(unit);
+    }
+    out
+}
+
+/// A video PES packet with PTS, and DTS when it differs.
+fn pes_packet(access_unit: &[u8], pts: u64, dts: u64) -> Vec<u8> {
+    let header_length = if pts == dts { 5 } else { 10 };
+    let mut out = Vec::with_capacity(access_unit.len() + 9 + header_length);
+    out.extend_from_slice(&[0, 0, 1, STREAM_ID_VIDEO]);
+    // Video may leave the length unset when it doesn't fit
+    let length = access_unit.len() + 3 + header_length;
+    out.extend_from_slice(&u16::try_from(length).unwrap_or(0).to_be_bytes());
+    // Marker bits, data_alignment_indicator
+    out.push(0x84);
+    if pts == dts {
+        out.extend_from_slice(&[0x80, header_length as u8]);
+        write_timestamp(&mut out, 0x2, pts);
+    } else {
+        out.extend_from_slice(&[0xC0, header_length as u8]);
+        write_timestamp(&mut out, 0x3, pts);
+        write_timestamp(&mut out, 0x1, dts);
+    }
+    out.extend_from_slice(access_unit);
+    out
+}
+
+/// A PTS or DTS: four prefix bits and 33 bits of time, with marker bits.
+fn write_timestamp(out: &mut Vec<u8>, prefix: u8, time: u64) {
+    out.extend_from_slice(&[
+        prefix << 4 | ((time >> 29) as u8 & 0x0E) | 1,
+        (time >> 22) as u8,
+        ((time >> 14) as u8 & 0xFE) | 1,
+        (time >> 7) as u8,
+        ((time << 1) as u8 & 0xFE) | 1,
+    ]);
+}
+
+/// A PCR with a 33 bit base at 90 kHz and no 27 MHz extension.
+fn write_pcr(out: &mut Vec<u8>, time: u64) {
+    let base = time & TIMESTAMP_MASK;
+    out.extend_from_slice(&[(base >> 25) as u8, (base >> 17) as u8, (base >> 9) as u8, (base >> 1) as u8, (base << 7) as u8 | 0x7E, 0]);
+}
+
+/// A PSI section with the long header, version 0, and its CRC.
+fn section(table_id: u8, id: u16, body: &[u8]) -> Vec<u8> {
+    // Everything after the length field: the header below, the body and the CRC
+    let length = 5 + body.len() + 4;
+    let mut out = vec![table_id];
+    out.extend_from_slice(&(0xB000 | length as u16).to_be_bytes());
+    out.extend_from_slice(&id.to_be_bytes());
+    // Version 0, current; section 0 of 0
+    out.extend_from_slice(&[0xC1, 0, 0]);
+    out.extend_from_slice(body);
+    let crc = crc32(&out);
+    out.extend_from_slice(&crc.to_be_bytes());
+    out
+}
+
+/// CRC-32/MPEG-2: not reflected, no final XOR.
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0xFFFF_FFFF, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u32) << 24, |crc, _| if crc & 0x8000_0000 != 0 { crc << 1 ^ 0x04C1_1DB7 } else { crc << 1 })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::{Fmp4Muxer, Sample, VideoTrack};
+    use crate::testing::{h264_pps, h264_sps, h264_stream};
+
+    struct Packet<'a> {
+        pid: u16,
+        start: bool,
+        continuity: u8,
+        adaptation: &'a [u8],
+        payload: &'a [u8],
+    }
+
+    fn packets(data: &[u8]) -> Vec<Packet<'_>> {
+        assert_eq!(data.len() % PACKET_SIZE, 0);
+        data.chunks(PACKET_SIZE)
+            .map(|p| {
+                assert_eq!(p[0], SYNC_BYTE);
+                let (adaptation, payload) = match p[3] & 0x30 {
+                    0x30 => (&p[5..5 + p[4] as usize], &p[5 + p[4] as usize..]),
+                    0x20 => (&p[5..], &p[5..5]),
+                    0x10 => (&p[4..4], &p[4..]),
+                    other => panic!("adaptation_field_control {:x}", other),
+                };
+                Packet { pid: u16::from_be_bytes([p[1] & 0x1F, p[2]]), start: p[1] & 0x40 != 0, continuity: p[3] & 0x0F, adaptation, payload }
+            })
+            .collect()
+    }
+
+    fn read_timestamp(bytes: &[u8]) -> u64 {
+        (bytes[0] as u64 >> 1 & 0x07) << 30 | (bytes[1] as u64) << 22 | (bytes[2] as u64 >> 1) << 15 | (bytes[3] as u64) << 7 | bytes[4] as u64 >> 1
+    }
+
+    #[test]
+    fn writes_tables_with_valid_crcs() {
+        let stream = h264_stream(2);
+        let mut muxer = TsMuxer::new();
+        assert!(muxer.push(&stream[0]).is_empty());
+        let frames = muxer.push(&stream[1]);
+        assert!(frames[0].keyframe && !frames[1].keyframe);
+
+        let keyframe = packets(&frames[0].data);
+        assert_eq!(keyframe.iter().map(|p| p.pid).collect::<Vec<_>>(), [PAT_PID, PMT_PID, VIDEO_PID]);
+        for psi in &keyframe[..2] {
+            assert!(psi.start && psi.payload[0] == 0);
+            let length = u16::from_be_bytes([psi.payload[2], psi.payload[3]]) as usize & 0x0FFF;
+            let section = &psi.payload[1..4 + length];
+            // The CRC over a section and its CRC comes out as zero
+            assert_eq!(crc32(section), 0);
+        }
+        // The PAT points at the PMT, which lists H.264 on the video PID
+        assert_eq!(keyframe[0].payload[9..13], [0, 1, 0xF0, 0x00]);
+        assert_eq!(keyframe[1].payload[13..16], [STREAM_TYPE_H264, 0xE1, 0x00]);
+        // Only keyframes repeat the tables
+        assert_eq!(packets(&frames[1].data).len(), 1);
+        assert_eq!(crc32(b"123456789"), 0x0376_E6E7);
+    }
+
+    #[test]
+    fn packages_access_units_as_pes() {
+        let stream = h264_stream(40);
+        let mut muxer = TsMuxer::new();
+        let frames: Vec<TsFrame> = stream.iter().flat_map(|s| muxer.push(s)).collect();
+        assert_eq!(frames.len(), 40);
+
+        let first = packets(&frames[0].data);
+        let video = &first[2];
+        // Random access, with a PCR of 0
+        assert_eq!(video.adaptation[0], 0x50);
+        assert_eq!(video.adaptation[1..7], [0, 0, 0, 0, 0x7E, 0]);
+        let pes = video.payload;
+        assert_eq!(pes[..4], [0, 0, 1, STREAM_ID_VIDEO]);
+        assert_eq!(pes[7], 0x80);
+        assert_eq!(read_timestamp(&pes[9..14]), DECODE_DELAY);
+        // Delimiter, SPS and PPS from the init segment, then the sample
+        let config = avc_config(&stream[0].data).unwrap();
+        let mut expected = ACCESS_UNIT_DELIMITER.to_vec();
+        for unit in [&config.sps[0], &config.pps[0], &vec![0]] {
+            expected.extend_from_slice(&[0, 0, 0, 1]);
+            expected.extend_from_slice(unit);
+        }
+        assert_eq!(pes[14..], expected);
+        assert_eq!(u16::from_be_bytes([pes[4], pes[5]]) as usize, pes.len() - 6);
+
+        // 3000 ticks of 90 kHz apart, continuity counting up per PID
+        let later = packets(&frames[30].data);
+        assert_eq!(read_timestamp(&later[2].payload[9..14]), DECODE_DELAY + 30 * 3000);
+        let video_counters: Vec<u8> = frames.iter().flat_map(|f| packets(&f.data)).filter(|p| p.pid == VIDEO_PID).map(|p| p.continuity).collect();
+        assert!(video_counters.windows(2).all(|w| w[1] == (w[0] + 1) & 0x0F));
+        assert_eq!(later[0].continuity, 1);
+    }
+
+    fn read_pcr(adaptation: &[u8]) -> Option<u64> {
+        let base = |a: &[u8]| u64::from_be_bytes([0, 0, 0, a[1], a[2], a[3], a[4], a[5]]) >> 7;
+        (adaptation.first()? & 0x10 != 0).then(|| base(adaptation))
+    }
+
+    #[test]
+    fn keeps_pcrs_within_100_ms_at_low_frame_rates() {
+        // 4 fps: 250 ms between frames
+        let mut fmp4 = Fmp4Muxer::new(VideoTrack::new(h264_sps(640, 360), h264_pps(), 90_000).unwrap());
+        let samples: Vec<Sample> = (0..4)
+            .map(|i| Sample { data: vec![0, 0, 0, 1, i as u8], duration: 22_500, keyframe: i == 0, composition_offset: 0 })
+            .collect();
+        let mut muxer = TsMuxer::new();
+        muxer.push(&fmp4.init_segment());
+        let frames = muxer.push(&fmp4.fragment(&samples).unwrap());
+        assert_eq!(frames.len(), 4);
+
+        let video: Vec<Packet> = frames.iter().flat_map(|f| packets(&f.data)).filter(|p| p.pid == VIDEO_PID).collect();
+        let pcrs: Vec<u64> = video.iter().filter_map(|p| read_pcr(p.adaptation)).collect();
+        assert_eq!(pcrs, [0, 9000, 18_000, 22_500, 31_500, 40_500, 45_000, 54_000, 63_000, 67_500]);
+        // PCR-only packets come before the frame they lead up to, and without
+        // a payload they repeat the continuity counter
+        let second = packets(&frames[1].data);
+        assert!(second[..2].iter().all(|p| p.payload.is_empty() && !p.start) && second[2].start);
+        for pair in video.windows(2) {
+            let expected = if pair[1].payload.is_empty() { pair[0].continuity } else { (pair[0].continuity + 1) & 0x0F };
+            assert_eq!(pair[1].continuity, expected);
+        }
+    }
+
+    #[test]
+    fn splits_large_access_units() {
+        let config = avc_config(&h264_stream(0)[0].data).unwrap();
+        let mut muxer = TsMuxer::new();
+        let mut sample = vec![0, 0, 0x03, 0xE8];
+        sample.extend((0..1000).map(|i| i as u8));
+        let pes = pes_packet(&annex_b(&config, &sample, false), 0, 0);
+        let mut data = Vec::new();
+        muxer.write_packets(&mut data, VIDEO_PID, &pes, Some(0), false);
+
+        let packets = packets(&data);
+        assert!(packets[0].start && packets[1..].iter().all(|p| !p.start));
+        let payload: Vec<u8> = packets.iter().flat_map(|p| p.payload.iter().copied()).collect();
+        assert_eq!(payload, pes);
+        // The last packet is stuffed to size
+        assert!(packets.last().unwrap().adaptation.len() > 1);
+    }
+}
@@ -0,0 +1,84 @@
+//! `--ts-file`: the transport stream written to a `.ts` file.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::mp4::Mp4Segment;
+
+use super::TsMuxer;
+
+pub struct TsFileOutput {
+    path: PathBuf,
+    /// None once a write has failed
+    file: Option<File>,
+    muxer: TsMuxer,
+    /// Nothing is written before the first keyframe, so the file plays from its start
+    started: bool,
+}
+
+impl TsFileOutput {
+    /// Create or truncate `path`.
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let file = File::create(path)?;
+        info!("Writing MPEG-TS to {}", path.display());
+        Ok(Self { path: path.to_path_buf(), file: Some(file), muxer: TsMuxer::new(), started: false })
+    }
+
+    /// Take in a segment of the encoder's stream. Frames are written as they
+    /// come, unbuffered, so players can follow the file while it grows.
+    pub fn publish(&mut self, segment: &Mp4Segment) {
+        for frame in self.muxer.push(segment) {
+            self.started |= frame.keyframe;
+            if !self.started {
+                continue;
+            }
+            let Some(file) = &mut self.file else { return };
+            if let Err(e) = file.write_all(&frame.data) {
+                warn!("Cannot write MPEG-TS to {}, stopping: {}", self.path.display(), e);
+                self.file = None;
+            }
+        }
+    }
+
+    /// The stream has ended: make sure the file is on disk.
+    pub fn finish(&mut self) {
+        if let Some(file) = self.file.take() {
+            if let Err(e) = file.sync_all() {
+                warn!("Cannot write MPEG-TS to {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::h264_stream;
+    use crate::ts::PACKET_SIZE;
+
+    #[test]
+    fn writes_the_transport_stream_from_a_keyframe() {
+        let path = std::env::temp_dir().join(format!("ratlab-ts-{}.ts", std::process::id()));
+        let mut output = TsFileOutput::new(&path).unwrap();
+        let stream = h264_stream(60);
+        // Joining after the first fragment: the file starts at the next keyframe
+        output.publish(&stream[0]);
+        for segment in &stream[2..] {
+            output.publish(segment);
+        }
+        output.finish();
+
+        let mut muxer = TsMuxer::new();
+        muxer.push(&stream[0]);
+        let expected: Vec<u8> = stream[2..].iter().flat_map(|s| muxer.push(s)).skip_while(|f| !f.keyframe).flat_map(|f| f.data).collect();
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written.len() % PACKET_SIZE, 0);
+        assert_eq!(written, expected);
+        // A PAT first
+        assert_eq!(written[..4], [0x47, 0x40, 0x00, 0x10]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,346 @@
+//! `--udp`: the transport stream over plain UDP, to one spectator or to a
+//! multicast group on the LAN, the way `ffmpeg -f mpegts udp://` sends it.
+//!
+//! There is no connection, so nothing is queued or resent: datagrams go out
+//! as frames are packaged, and receivers can join at any keyframe. If the
+//! socket has no room for a frame, the stream picks up again at the next one.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+
+use log::{info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use url::{Position, Url};
+
+use crate::mp4::Mp4Segment;
+use crate::net;
+use crate::status::{self, StatusLevel};
+
+use super::{TsFrame, TsMuxer, PACKETS_PER_DATAGRAM, PACKET_SIZE};
+
+/// Multicast stays on the local network unless asked otherwise.
+const DEFAULT_MULTICAST_TTL: u32 = 1;
+
+/// Socket send buffer, room for a keyframe's burst of datagrams.
+const SEND_BUFFER: usize = 1024 * 1024;
+
+/// `udp://host:port`, unicast or multicast, with ffmpeg's `ttl` and
+/// `localaddr` (the address, and so the interface, to send from) options.
+#[derive(Debug, Clone)]
+pub struct UdpUrl {
+    host: String,
+    port: u16,
+    /// `udp://host:port`, for logs
+    display: String,
+    ttl: Option<u32>,
+    local_address: Option<IpAddr>,
+}
+
+impl FromStr for UdpUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|e| format!("invalid UDP URL: {}", e))?;
+        if url.scheme() != "udp" {
+            return Err(format!("unsupported scheme {}, expected udp", url.scheme()));
+        }
+        let host = net::connect_host(&url).ok_or("UDP URL has no host")?;
+        let port = url.port().ok_or("UDP URL has no port, expected udp://host:port")?;
+
+        let mut udp = Self {
+            host,
+            port,
+            display: format!("udp://{}", &url[Position::BeforeHost..Position::AfterPort]),
+            ttl: None,
+            local_address: None,
+        };
+        for (name, value) in url.query_pairs() {
+            match name.as_ref() {
+                "ttl" => {
+                    let ttl: u8 = value.parse().ok().filter(|&ttl| ttl > 0).ok_or_else(|| format!("invalid UDP ttl {}, expected 1 to 255", value))?;
+                    udp.ttl = Some(ttl as u32);
+                }
+                "localaddr" => {
+                    udp.local_address = Some(value.parse().map_err(|_| format!("invalid UDP localaddr {}, expected an IP address", value))?);
+                }
+                other => return Err(format!("unsupported UDP option {}", other)),
+            }
+        }
+        Ok(udp)
+    }
+}
+
+impl fmt::Display for UdpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.display)
+    }
+}
+
+/// One UDP destination.
+pub struct UdpOutput {
+    url: UdpUrl,
+    socket: UdpSocket,
+    destination: SocketAddr,
+    muxer: TsMuxer,
+    sender: FrameSender,
+}
+
+impl UdpOutput {
+    /// Resolve the destination and open a socket for it, set up for
+    /// multicast if the destination is a group.
+    pub async fn new(url: UdpUrl) -> io::Result<Self> {
+        let destination = net::resolve(&url.host, url.port)
+            .await?
+            .into_iter()
+            .find(|address| url.local_address.is_none_or(|local| local.is_ipv4() == address.is_ipv4()))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no address of {} to send to", url.host)))?;
+
+        let socket = Socket::new(Domain::for_address(destination), Type::DGRAM, Some(Protocol::UDP))?;
+        let local = url.local_address.unwrap_or(match destination {
+            SocketAddr::V4(_) => IpAddr::from([0; 4]),
+            SocketAddr::V6(_) => IpAddr::from([0; 16]),
+        });
+        socket.bind(&SocketAddr::new(local, 0).into())?;
+        let multicast = destination.ip().is_multicast();
+        match (destination.ip(), multicast) {
+            (IpAddr::V4(_), true) => {
+                socket.set_multicast_ttl_v4(url.ttl.unwrap_or(DEFAULT_MULTICAST_TTL))?;
+                // So a player on this machine can join the group too
+                socket.set_multicast_loop_v4(true)?;
+                if let Some(IpAddr::V4(interface)) = url.local_address {
+                    socket.set_multicast_if_v4(&interface)?;
+                }
+            }
+            (IpAddr::V6(_), true) => {
+                socket.set_multicast_hops_v6(url.ttl.unwrap_or(DEFAULT_MULTICAST_TTL))?;
+                socket.set_multicast_loop_v6(true)?;
+                // IPv6 names the interface by index rather than by address
+                if let Some(IpAddr::V6(interface)) = url.local_address {
+                    socket.set_multicast_if_v6(interface_index(interface)?)?;
+                }
+            }
+            (IpAddr::V4(_), false) => {
+                if let Some(ttl) = url.ttl {
+                    socket.set_ttl_v4(ttl)?;
+                }
+            }
+            (IpAddr::V6(_), false) => {
+                if let Some(ttl) = url.ttl {
+                    socket.set_unicast_hops_v6(ttl)?;
+                }
+            }
+        }
+        // Sending happens on the forward task, which must never wait on the network
+        socket.set_nonblocking(true)?;
+        socket.set_send_buffer_size(SEND_BUFFER)?;
+
+        info!("Sending MPEG-TS to {} ({}{})", url, destination, if multicast { ", multicast" } else { "" });
+        Ok(Self { url, socket: socket.into(), destination, muxer: TsMuxer::new(), sender: FrameSender::default() })
+    }
+
+    /// Take in a segment of the encoder's stream and send its frames.
+    pub fn publish(&mut self, segment: &Mp4Segment) {
+        let (socket, destination) = (&self.socket, self.destination);
+        for frame in self.muxer.push(segment) {
+            self.sender.send(&self.url, &frame, |datagram| socket.send_to(datagram, destination));
+        }
+    }
+}
+
+/// Frames out to the socket, seven TS packets to a datagram, from a keyframe.
+///
+/// When the socket has no room for a datagram, the rest of the frame and
+/// the frames up to the next keyframe are dropped: the picture is lost
+/// either way, and what follows it would not decode.
+struct FrameSender {
+    /// Frames are skipped until the next keyframe, at the start and after a full socket
+    waiting_for_keyframe: bool,
+    /// The last send error, reported once until sending works again
+    error: Option<io::ErrorKind>,
+    /// Frames the socket had no room for
+    dropped: u64,
+}
+
+impl Default for FrameSender {
+    fn default() -> Self {
+        Self { waiting_for_keyframe: true, error: None, dropped: 0 }
+    }
+}
+
+impl FrameSender {
+    fn send(&mut self, url: &UdpUrl, frame: &TsFrame, mut send: impl FnMut(&[u8]) -> io::Result<usize>) {
+        if self.waiting_for_keyframe && !frame.keyframe {
+            return;
+        }
+        self.waiting_for_keyframe = false;
+        for datagram in frame.data.chunks(PACKET_SIZE * PACKETS_PER_DATAGRAM) {
+            match send(datagram) {
+                Ok(_) => self.error = None,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.waiting_for_keyframe = true;
+                    self.dropped += 1;
+                    if self.dropped.is_power_of_two() {
+                        warn!("UDP {}: the socket is full, {} frames dropped so far", url, self.dropped);
+                    }
+                    return;
+                }
+                Err(e) => {
+                    if self.error != Some(e.kind()) {
+                        warn!("UDP {}: {}", url, e);
+                        status::emit("udp", StatusLevel::Warning, &format!("{}: {}", url, e));
+                        self.error = Some(e.kind());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Index of the interface that has `address`.
+#[cfg(unix)]
+fn interface_index(address: Ipv6Addr) -> io::Result<u32> {
+    let mut interfaces = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut interfaces) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut index = 0;
+    let mut entry = interfaces;
+    // The list is only read until it is freed below
+    while let Some(interface) = unsafe { entry.as_ref() } {
+        let family = unsafe { interface.ifa_addr.as_ref() }.map(|a| a.sa_family as libc::c_int);
+        if family == Some(libc::AF_INET6) {
+            let socket_address = unsafe { &*(interface.ifa_addr as *const libc::sockaddr_in6) };
+            if Ipv6Addr::from(socket_address.sin6_addr.s6_addr) == address {
+                index = unsafe { libc::if_nametoindex(interface.ifa_name) };
+                break;
+            }
+        }
+        entry = interface.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(interfaces) };
+    match index {
+        0 => Err(io::Error::new(io::ErrorKind::NotFound, format!("no interface has the address {}", address))),
+        index => Ok(index),
+    }
+}
+
+/// Index of the interface that has `address`.
+#[cfg(windows)]
+fn interface_index(address: Ipv6Addr) -> io::Result<u32> {
+    use windows::Win32::Foundation::ERROR_BUFFER_OVERFLOW;
+    use windows::Win32::NetworkManagement::IpHelper::{
+        GetAdaptersAddresses, GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_DNS_SERVER, GAA_FLAG_SKIP_MULTICAST, IP_ADAPTER_ADDRESSES_LH,
+    };
+    use windows::Win32::Networking::WinSock::{AF_INET6, SOCKADDR_IN6};
+
+    let flags = GAA_FLAG_SKIP_ANYCAST | GAA_FLAG_SKIP_MULTICAST | GAA_FLAG_SKIP_DNS_SERVER;
+    let mut size = 16 * 1024;
+    loop {
+        // u64s, to be aligned for the structures written into it
+        let mut buffer = vec![0u64; (size as usize).div_ceil(8)];
+        let adapters = buffer.as_mut_ptr() as *mut IP_ADAPTER_ADDRESSES_LH;
+        let result = unsafe { GetAdaptersAddresses(AF_INET6.0 as u32, flags, None, Some(adapters), &mut size) };
+        if result == ERROR_BUFFER_OVERFLOW.0 {
+            continue;
+        }
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result as i32));
+        }
+
+        let mut adapter = adapters as *const IP_ADAPTER_ADDRESSES_LH;
+        while let Some(current) = unsafe { adapter.as_ref() } {
+            let mut unicast = current.FirstUnicastAddress as *const _;
+            while let Some(entry) = unsafe { unicast.as_ref() } {
+                let socket_address = unsafe { &*(entry.Address.lpSockaddr as *const SOCKADDR_IN6) };
+                if socket_address.sin6_family == AF_INET6 && Ipv6Addr::from(unsafe { socket_address.sin6_addr.u.Byte }) == address {
+                    return Ok(current.Ipv6IfIndex);
+                }
+                unicast = entry.Next;
+            }
+            adapter = current.Next;
+        }
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no interface has the address {}", address)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::h264_stream;
+
+    #[tokio::test]
+    async fn sends_the_transport_stream_in_datagrams() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        let url: UdpUrl = format!("udp://{}", receiver.local_addr().unwrap()).parse().unwrap();
+        let mut output = UdpOutput::new(url).await.unwrap();
+        let stream = h264_stream(4);
+        let mut muxer = TsMuxer::new();
+        let mut expected = Vec::new();
+        for segment in &stream {
+            output.publish(segment);
+            expected.extend(muxer.push(segment).into_iter().flat_map(|f| f.data));
+        }
+
+        let mut received = Vec::new();
+        let mut buf = [0; 2048];
+        while received.len() < expected.len() {
+            let n = receiver.recv(&mut buf).unwrap();
+            assert!(n.is_multiple_of(PACKET_SIZE) && n <= PACKET_SIZE * PACKETS_PER_DATAGRAM);
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn a_full_socket_drops_frames_up_to_the_next_keyframe() {
+        let url: UdpUrl = "udp://127.0.0.1:1234".parse().unwrap();
+        let stream = h264_stream(60);
+        let mut muxer = TsMuxer::new();
+        let frames: Vec<TsFrame> = stream.iter().flat_map(|s| muxer.push(s)).collect();
+        assert!(frames[0].keyframe && frames[30].keyframe);
+
+        let mut sender = FrameSender::default();
+        let mut sent = Vec::new();
+        for (i, frame) in frames.iter().enumerate() {
+            sender.send(&url, frame, |datagram| {
+                // No room for the fourth frame
+                if i == 3 {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                sent.extend_from_slice(datagram);
+                Ok(datagram.len())
+            });
+        }
+
+        let mut expected: Vec<u8> = frames[..3].iter().flat_map(|f| f.data.clone()).collect();
+        expected.extend(frames[30..].iter().flat_map(|f| f.data.clone()));
+        assert_eq!(sent, expected);
+        assert_eq!(sender.dropped, 1);
+    }
+
+    #[test]
+    fn finds_the_interface_of_a_local_address() {
+        // Hosts without IPv6 have no ::1 to find
+        if UdpSocket::bind("[::1]:0").is_ok() {
+            assert!(interface_index(Ipv6Addr::LOCALHOST).is_ok());
+        }
+        assert!(interface_index("2001:db8::1".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn parses_unicast_and_multicast_urls() {
+        let url: UdpUrl = "udp://239.1.2.3:1234?ttl=4&localaddr=192.168.1.20".parse().unwrap();
+        assert_eq!(url.to_string(), "udp://239.1.2.3:1234");
+        assert_eq!((url.ttl, url.local_address), (Some(4), Some("192.168.1.20".parse().unwrap())));
+        let url: UdpUrl = "udp://[ff02::1]:5000".parse().unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("ff02::1", 5000));
+
+        assert!("udp://239.1.2.3".parse::<UdpUrl>().is_err());
+        assert!("udp://239.1.2.3:1234?ttl=0".parse::<UdpUrl>().is_err());
+        assert!("udp://239.1.2.3:1234?pkt_size=1316".parse::<UdpUrl>().is_err());
+        assert!("rtp://239.1.2.3:1234".parse::<UdpUrl>().is_err());
+    }
+}
@@ -1,119 +1,598 @@
-use futures_util::SinkExt; 
-use log::{info, error}; 
+use futures_util::{SinkExt, StreamExt};
+use log::{info, error, warn};
 use tokio::net::TcpStream;
-use tokio_tungstenite::{client_async, tungstenite::{protocol::Message, client::IntoClientRequest}, MaybeTlsStream, WebSocketStream};
-use tokio::time::{sleep, Duration};
+use tokio_tungstenite::{client_async, tungstenite::{protocol::{frame::coding::CloseCode, CloseFrame, Message}, client::IntoClientRequest}, MaybeTlsStream, WebSocketStream};
+use tokio::time::sleep;
 use std::sync::Arc;
-use tokio::sync::{Mutex, Notify};
+use std::sync::atomic::{AtomicBool, Ordering};
+use parking_lot::Mutex;
+use tokio::sync::Notify;
 use url::Url;
-use native_tls::TlsConnector;
-use tokio_native_tls::TlsConnector as TokioTlsConnector;
+use zeroize::Zeroizing;
+use crate::auth::{self, AuthMode};
+use crate::impair::{ImpairedStream, Impairment};
+use crate::mp4::Mp4Segment;
+use crate::net;
+use crate::policy::{PolicyError, TransportPolicy};
+use crate::proxy::{ProxyConfig, ProxyError, ProxySettings};
+use crate::queue::{SendQueue, DEFAULT_QUEUE_LIMIT};
+use crate::reconnect::{StopSignal, RECONNECT_INTERVAL};
+use crate::secret::{self, StreamKey};
+use crate::status::{self, StatusLevel};
+use crate::tls::{TlsConnector, TlsError, TlsOptions};
 
+/// Consecutive failed connects before failing over to the next URL.
+const FAILOVER_AFTER_FAILURES: u32 = 2;
+
+type WsStream = WebSocketStream<MaybeTlsStream<ImpairedStream<TcpStream>>>;
+
+/// Errors that stop `connect_loop` for good. Everything else is retried.
+#[derive(thiserror::Error, Debug)]
+pub enum ConnectError {
+    #[error(transparent)]
+    Policy(#[from] PolicyError),
+    #[error("Invalid server URL: {0}")]
+    InvalidUrl(String),
+}
+
+/// Outcome of a failed connection attempt. Retryable errors are logged where they happen.
+enum AttemptError {
+    /// This URL can never work (bad URL, policy violation)
+    Fatal(ConnectError),
+    Retry,
+}
+
+/// One streaming destination: a primary URL plus optional backups, with its own
+/// connection state and send queue.
 pub struct WebSocketManager {
-    url: String,
-    token: String,
+    urls: Vec<String>,
+    token: StreamKey,
     session_id: String,
-    tx: Arc<Mutex<Option<WebSocketStream<MaybeTlsStream<TcpStream>>>>>,
+    auth_mode: AuthMode,
+    policy: TransportPolicy,
+    tls: Option<Arc<TlsConnector>>,
+    proxy: ProxySettings,
+    impairment: Impairment,
+    queue: Mutex<SendQueue>,
+    queue_notify: Notify,
+    connected: AtomicBool,
     notify: Arc<Notify>,
+    stop: StopSignal,
 }
 
 impl WebSocketManager {
-    pub fn new(url: String, token: String, session_id: String) -> Self {
+    pub fn new(url: String, token: StreamKey, session_id: String) -> Self {
         Self {
-            url,
+            urls: vec![url],
             token,
             session_id,
+            auth_mode: AuthMode::Bearer,
+            policy: TransportPolicy::default(),
+            tls: None,
+            proxy: ProxySettings::default(),
+            impairment: Impairment::default(),
 
-            tx: Arc::new(Mutex::new(None)),
+            queue: Mutex::new(SendQueue::new(DEFAULT_QUEUE_LIMIT)),
+            queue_notify: Notify::new(),
+            connected: AtomicBool::new(false),
             notify: Arc::new(Notify::new()),
+            stop: StopSignal::default(),
+        }
+    }
+
+    /// URLs to fail over to, in order, when the primary is unreachable.
+    pub fn backup_urls(mut self, urls: Vec<String>) -> Self {
+        self.urls.extend(urls);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn queue_limit(mut self, bytes: usize) -> Self {
+        self.queue = Mutex::new(SendQueue::new(bytes));
+        self
+    }
+
+    pub fn auth_mode(mut self, auth_mode: AuthMode) -> Self {
+        self.auth_mode = auth_mode;
+        self
+    }
+
+    pub fn transport_policy(mut self, policy: TransportPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn tls_connector(mut self, connector: Arc<TlsConnector>) -> Self {
+        self.tls = Some(connector);
+        self
+    }
+
+    pub fn proxy(mut self, proxy: ProxySettings) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Simulate a bad network between this manager and the socket.
+    pub fn impairment(mut self, impairment: Impairment) -> Self {
+        self.impairment = impairment;
+        self
+    }
+
+    /// Direct TCP connect, or a tunnel through the configured proxy. Either way the
+    /// ws/wss handshake then runs over the returned stream.
+    async fn open_tcp(&self, proxy: Option<&ProxyConfig>, host: &str, port: u16, addrs: &[std::net::SocketAddr]) -> Result<TcpStream, ProxyError> {
+        match proxy {
+            Some(proxy) => proxy.connect(host, port).await,
+            None => Ok(net::connect_any(addrs).await?),
+        }
+    }
+
+    async fn tls_handshake(&self, host: &str, stream: ImpairedStream<TcpStream>) -> Result<MaybeTlsStream<ImpairedStream<TcpStream>>, TlsError> {
+        match &self.tls {
+            Some(connector) => connector.connect(host, stream).await,
+            None => TlsConnector::new(&TlsOptions::default())?.connect(host, stream).await,
         }
     }
 
-    pub async fn connect_loop(&self) {
-        let reconnect_interval = Duration::from_secs(2);
+    pub async fn connect_loop(&self) -> Result<(), ConnectError> {
+        let mut current = 0;
+        let mut failures = 0;
+        let mut disabled = vec![false; self.urls.len()];
 
         loop {
-            info!("Connecting to streaming server: {}", self.url);
-
-            let uri_str = format!("{}?session={}", self.url, self.session_id);
-            let url_parsed = Url::parse(&uri_str).expect("Invalid URL");
-            
-            let host = url_parsed.host_str().unwrap();
-            let port = url_parsed.port_or_known_default().unwrap();
-            let addr = format!("{}:{}", host, port);
-
-            match TcpStream::connect(&addr).await {
-                Ok(stream) => {
-                    stream.set_nodelay(true).expect("Failed to set TCP_NODELAY");
-
-                    let mut request = uri_str.clone().into_client_request().unwrap();
-                    let headers = request.headers_mut();
-                    headers.insert("Authorization", format!("Bearer {}", self.token).parse().unwrap());
-                    headers.insert("Session-Id", self.session_id.parse().unwrap());
-
-                    let ws_stream_result = if url_parsed.scheme() == "wss" {
-                        // Secure WSS with Nodelay
-                        let cx = TlsConnector::builder().build().unwrap();
-                        let cx = TokioTlsConnector::from(cx);
-                        
-                        match cx.connect(host, stream).await {
-                            Ok(tls_stream) => {
-                                let stream = MaybeTlsStream::NativeTls(tls_stream);
-                                client_async(request, stream).await
-                            },
-                            Err(e) => {
-                                error!("TLS Handshake failed: {}", e);
-                                sleep(reconnect_interval).await;
-                                continue;
-                            }
+            let attempt = tokio::select! {
+                attempt = self.connect_once(&self.urls[current]) => attempt,
+                _ = self.stop.stopped() => return Ok(()),
+            };
+            match attempt {
+                Ok(ws_stream) => {
+                    failures = 0;
+                    if current != 0 {
+                        warn!("Streaming to backup destination {}", secret::redact_url(&self.urls[current]));
+                    }
+                    self.run_session(ws_stream).await;
+                    if self.stop.is_stopped() {
+                        return Ok(());
+                    }
+                    info!("WebSocket disconnected. Reconnecting...");
+                    // Always give the primary another chance after a disconnect
+                    current = disabled.iter().position(|d| !d).unwrap_or(current);
+                }
+                Err(AttemptError::Fatal(e)) => {
+                    disabled[current] = true;
+                    match Self::next_enabled(&disabled, current) {
+                        Some(next) => {
+                            warn!("Skipping {}: {}", secret::redact_url(&self.urls[current]), e);
+                            current = next;
+                            continue;
                         }
-                    } else {
-                        // Plain WS with Nodelay
-                        let stream = MaybeTlsStream::Plain(stream);
-                        client_async(request, stream).await
-                    };
-
-                    match ws_stream_result {
-                        Ok((ws_stream, _)) => {
-                            info!("WebSocket connected! (TCP_NODELAY=true, Scheme: {})", url_parsed.scheme());
-                            let mut lock = self.tx.lock().await;
-                            *lock = Some(ws_stream);
-                            drop(lock);
-                            self.notify.notify_waiters();
-                            self.read_loop().await;
-                            info!("WebSocket disconnected. Reconnecting...");
-                        },
-                        Err(e) => error!("WebSocket handshake error: {}", e),
+                        None => return Err(e),
                     }
-                },
-                Err(e) => error!("TCP Connect error: {}", e),
+                }
+                Err(AttemptError::Retry) => {
+                    failures += 1;
+                    if failures >= FAILOVER_AFTER_FAILURES {
+                        if let Some(next) = Self::next_enabled(&disabled, current).filter(|n| *n != current) {
+                            failures = 0;
+                            current = next;
+                            let msg = format!("Stream server unreachable, failing over to {}", secret::redact_url(&self.urls[current]));
+                            warn!("{}", msg);
+                            status::emit("failover", StatusLevel::Warning, &msg);
+                        }
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = sleep(RECONNECT_INTERVAL) => {}
+                _ = self.stop.stopped() => return Ok(()),
+            }
+        }
+    }
+
+    fn next_enabled(disabled: &[bool], current: usize) -> Option<usize> {
+        (1..=disabled.len()).map(|step| (current + step) % disabled.len()).find(|i| !disabled[*i])
+    }
+
+    /// Open, upgrade and authenticate one connection to `url`.
+    async fn connect_once(&self, url: &str) -> Result<WsStream, AttemptError> {
+        info!("Connecting to streaming server: {}", secret::redact_url(url));
+
+        // Set the pair rather than format it in, so an existing query survives and a session already in it is replaced
+        let invalid = |msg: String| AttemptError::Fatal(ConnectError::InvalidUrl(msg));
+        let mut url_parsed = Url::parse(url).map_err(|e| invalid(e.to_string()))?;
+        net::set_query_pair(&mut url_parsed, "session", &self.session_id);
+
+        let host = net::connect_host(&url_parsed).ok_or_else(|| invalid("missing host".to_string()))?;
+        let host = host.as_str();
+        let port = url_parsed.port_or_known_default().ok_or_else(|| invalid("missing port".to_string()))?;
+
+        // A proxy resolves the host itself, which may be all a proxy-only network can do
+        let proxy = self.proxy.proxy_for(url_parsed.scheme(), host);
+        let addrs: Vec<_> = match proxy {
+            Some(_) => Vec::new(),
+            None => match net::resolve(host, port).await {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    error!("DNS lookup for {} failed: {}", host, e);
+                    Vec::new()
+                }
+            },
+        };
+
+        // Never silently fall back to sending the key in clear text over the internet
+        let checked = match proxy {
+            Some(_) => self.policy.check_proxied(url_parsed.scheme(), host),
+            None => self.policy.check(url_parsed.scheme(), host, &addrs),
+        };
+        if let Err(e) = checked {
+            error!("{}", e);
+            return match e {
+                // A DNS hiccup is worth retrying, it may well resolve to a private address
+                PolicyError::Unresolved { .. } => Err(AttemptError::Retry),
+                _ => {
+                    status::emit("transport_policy", StatusLevel::Error, &e.to_string());
+                    Err(AttemptError::Fatal(e.into()))
+                }
+            };
+        }
+
+        let stream = match self.open_tcp(proxy, host, port, &addrs).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("TCP Connect error: {}", e);
+                return Err(AttemptError::Retry);
+            }
+        };
+        stream.set_nodelay(true).expect("Failed to set TCP_NODELAY");
+        let stream = self.impairment.wrap(stream);
+
+        let mut request = url_parsed.as_str().into_client_request().unwrap();
+        let headers = request.headers_mut();
+        match self.auth_mode {
+            AuthMode::Bearer => {
+                // Mark the header sensitive so it is masked if the request is ever debug-printed
+                let bearer = Zeroizing::new(format!("Bearer {}", self.token.expose()));
+                let mut auth_value: tokio_tungstenite::tungstenite::http::HeaderValue = bearer.parse().unwrap();
+                auth_value.set_sensitive(true);
+                headers.insert("Authorization", auth_value);
+            }
+            // The key itself is only used to sign the server's challenge
+            AuthMode::Hmac => {
+                headers.insert("Authorization", auth::HMAC_SCHEME.parse().unwrap());
             }
+        }
+        headers.insert("Session-Id", self.session_id.parse().unwrap());
+
+        let ws_stream_result = if url_parsed.scheme() == "wss" {
+            // Secure WSS with Nodelay
+            match self.tls_handshake(host, stream).await {
+                Ok(stream) => client_async(request, stream).await,
+                Err(e) => {
+                    error!("TLS Handshake failed: {}", e);
+                    if let TlsError::PinMismatch(_) = e {
+                        status::emit("tls_pin_mismatch", StatusLevel::Error, &e.to_string());
+                    }
+                    return Err(AttemptError::Retry);
+                }
+            }
+        } else {
+            // Plain WS with Nodelay
+            let stream = MaybeTlsStream::Plain(stream);
+            client_async(request, stream).await
+        };
+
+        let mut ws_stream = match ws_stream_result {
+            Ok((ws_stream, _)) => ws_stream,
+            Err(e) => {
+                error!("WebSocket handshake error: {}", e);
+                return Err(AttemptError::Retry);
+            }
+        };
 
-            sleep(reconnect_interval).await;
+        if self.auth_mode == AuthMode::Hmac {
+            if let Err(e) = auth::respond_to_challenge(&mut ws_stream, &self.token, &self.session_id).await {
+                error!("Stream authentication failed: {}", e);
+                let _ = ws_stream.close(None).await;
+                return Err(AttemptError::Retry);
+            }
+            info!("Stream authenticated via HMAC challenge-response");
         }
+
+        info!("WebSocket connected! (TCP_NODELAY=true, Scheme: {})", url_parsed.scheme());
+        Ok(ws_stream)
     }
 
-    async fn read_loop(&self) {
+    /// Drain the send queue into a connected socket until either side goes away.
+    async fn run_session(&self, ws_stream: WsStream) {
+        let (mut sink, mut incoming) = ws_stream.split();
+
+        self.queue.lock().on_connected();
+        self.connected.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+
         loop {
-            sleep(Duration::from_secs(1)).await;
-            let lock = self.tx.lock().await; 
-            if lock.is_none() { break; }
+            if self.stop.is_stopped() {
+                // Flush what is already queued, then say goodbye properly
+                loop {
+                    let next = self.queue.lock().pop();
+                    let Some(data) = next else { break };
+                    if sink.send(Message::Binary(data)).await.is_err() {
+                        break;
+                    }
+                }
+                let frame = CloseFrame { code: CloseCode::Normal, reason: "sidecar shutting down".into() };
+                let _ = sink.send(Message::Close(Some(frame))).await;
+                let _ = sink.close().await;
+                break;
+            }
+
+            let next = self.queue.lock().pop();
+            match next {
+                Some(data) => {
+                    if let Err(e) = sink.send(Message::Binary(data)).await {
+                        error!("WebSocket send error: {}", e);
+                        break;
+                    }
+                }
+                None => tokio::select! {
+                    _ = self.queue_notify.notified() => {}
+                    _ = self.stop.stopped() => {}
+                    msg = incoming.next() => match msg {
+                        Some(Ok(Message::Close(frame))) => {
+                            info!("Server closed the stream: {:?}", frame);
+                            break;
+                        }
+                        // Pings are answered by tungstenite on the next write
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => {
+                            error!("WebSocket read error: {}", e);
+                            break;
+                        }
+                        None => break,
+                    },
+                },
+            }
         }
+
+        self.connected.store(false, Ordering::SeqCst);
     }
 
-    pub async fn send_data(&self, data: Vec<u8>) -> Result<(), String> {
-        let mut lock = self.tx.lock().await;
-        if let Some(stream) = lock.as_mut() {
-            stream.send(Message::Binary(data)).await.map_err(|e| e.to_string())
-        } else {
-            Err("Not connected".to_string())
+    /// Queue a segment for this destination. Never blocks; the oldest media is
+    /// dropped if the destination can't keep up.
+    pub fn enqueue(&self, segment: Mp4Segment) {
+        let mut queue = self.queue.lock();
+        let dropped_before = queue.dropped();
+        queue.push(segment);
+        let dropped = queue.dropped();
+        drop(queue);
+
+        if dropped > dropped_before && (dropped_before == 0 || dropped / 60 != dropped_before / 60) {
+            warn!("Send queue overflow for {}: {} segments dropped so far", secret::redact_url(&self.urls[0]), dropped);
         }
+        self.queue_notify.notify_one();
+    }
+
+    /// Close the connection after flushing the queue and make `connect_loop`
+    /// return `Ok(())`.
+    pub fn shutdown(&self) {
+        self.stop.stop();
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
     }
 
     pub async fn wait_for_connection(&self) {
-        if self.tx.lock().await.is_some() {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        // Register before checking so a connect in between isn't missed
+        notified.as_mut().enable();
+        if self.is_connected() {
             return;
         }
-        self.notify.notified().await;
+        notified.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{init_segment, media_segment, tag, Behavior, TestServer};
+    use tokio::task::JoinHandle;
+    use tokio::time::{timeout, Duration};
+    use tokio_tungstenite::tungstenite::http::StatusCode;
+
+    fn manager(url: String) -> WebSocketManager {
+        WebSocketManager::new(url, StreamKey::new("secret-key".to_string()), "test-session".to_string())
+    }
+
+    fn start(manager: &Arc<WebSocketManager>) -> JoinHandle<Result<(), ConnectError>> {
+        let manager = manager.clone();
+        tokio::spawn(async move { manager.connect_loop().await })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn presents_bearer_key_and_session() {
+        let server = TestServer::start().await;
+        let manager = Arc::new(manager(server.url("/stream")));
+        start(&manager);
+
+        let connections = server.wait_for_connections(1).await;
+        assert_eq!(connections[0].authorization.as_deref(), Some("Bearer secret-key"));
+        assert_eq!(connections[0].session_header.as_deref(), Some("test-session"));
+        assert_eq!(connections[0].session_query.as_deref(), Some("test-session"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hmac_mode_keeps_key_off_the_wire() {
+        let server = TestServer::start().await;
+        let manager = Arc::new(manager(server.url("/stream?region=eu")).auth_mode(AuthMode::Hmac));
+        start(&manager);
+
+        let connections = server.wait_for_connections(1).await;
+        assert_eq!(connections[0].authorization.as_deref(), Some(auth::HMAC_SCHEME));
+        assert_eq!(connections[0].session_query.as_deref(), Some("test-session"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnects_after_interval() {
+        let server = TestServer::start().await;
+        server.push_behavior(Behavior::DropAfter { messages: 0 });
+        server.push_behavior(Behavior::DropAfter { messages: 0 });
+        let manager = Arc::new(manager(server.url("/stream")));
+        start(&manager);
+
+        let connections = server.wait_for_connections(3).await;
+        // The paused clock jumps to the next timer whenever the runtime parks on I/O,
+        // which here is the connect timeout, so only bound the gap from both sides
+        for pair in connections.windows(2) {
+            let gap = pair[1].accepted_at - pair[0].accepted_at;
+            assert!(gap >= RECONNECT_INTERVAL, "reconnected after only {:?}", gap);
+            assert!(gap < RECONNECT_INTERVAL + net::ATTEMPT_TIMEOUT, "reconnected after {:?}", gap);
+        }
     }
-}
\ No newline at end of file
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_init_after_server_close() {
+        let mut server = TestServer::start().await;
+        server.push_behavior(Behavior::CloseAfter { messages: 2, code: CloseCode::Away });
+        server.push_behavior(Behavior::CloseAfter { messages: 2, code: CloseCode::Policy });
+        let manager = Arc::new(manager(server.url("/stream")));
+        manager.enqueue(init_segment(1));
+        manager.enqueue(media_segment(10, 100));
+        start(&manager);
+
+        for expected in [1, 10] {
+            let received = server.recv().await;
+            assert_eq!((received.connection, tag(&received.data)), (0, expected));
+        }
+
+        // Every new connection starts with the cached init, whatever the close code was
+        for (connection, media) in [(1, 11), (2, 12)] {
+            server.wait_for_connections(connection + 1).await;
+            manager.enqueue(media_segment(media, 100));
+            for expected in [1, media] {
+                let received = server.recv().await;
+                assert_eq!((received.connection, tag(&received.data)), (connection, expected));
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn new_init_discards_stale_media() {
+        let mut server = TestServer::start().await;
+        let manager = Arc::new(manager(server.url("/stream")));
+        manager.enqueue(init_segment(1));
+        manager.enqueue(media_segment(10, 100));
+        manager.enqueue(init_segment(2));
+        manager.enqueue(media_segment(20, 100));
+        start(&manager);
+
+        for expected in [2, 20] {
+            assert_eq!(tag(&server.recv().await.data), expected);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queue_overflow_drops_oldest_media() {
+        let mut server = TestServer::start().await;
+        let manager = Arc::new(manager(server.url("/stream")).queue_limit(1000));
+        manager.enqueue(init_segment(1));
+        for i in 0..10 {
+            manager.enqueue(media_segment(10 + i, 300));
+        }
+        start(&manager);
+
+        // Only the newest 900 bytes of media fit, the init is never dropped
+        for expected in [1, 17, 18, 19] {
+            assert_eq!(tag(&server.recv().await.data), expected);
+        }
+        manager.enqueue(media_segment(20, 300));
+        assert_eq!(tag(&server.recv().await.data), 20);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_reader_gets_everything_in_order() {
+        let mut server = TestServer::start().await;
+        server.push_behavior(Behavior::SlowRead(Duration::from_millis(200)));
+        let manager = Arc::new(manager(server.url("/stream")));
+        start(&manager);
+        manager.wait_for_connection().await;
+
+        manager.enqueue(init_segment(1));
+        for i in 0..5 {
+            manager.enqueue(media_segment(10 + i, 100));
+        }
+        for expected in [1, 10, 11, 12, 13, 14] {
+            assert_eq!(tag(&server.recv().await.data), expected);
+        }
+        assert_eq!(server.connections().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_over_to_backup_and_back() {
+        let primary = TestServer::start().await;
+        let mut backup = TestServer::start().await;
+        for _ in 0..FAILOVER_AFTER_FAILURES {
+            primary.push_behavior(Behavior::Reject(StatusCode::SERVICE_UNAVAILABLE));
+        }
+        backup.push_behavior(Behavior::CloseAfter { messages: 1, code: CloseCode::Away });
+        let manager = Arc::new(manager(primary.url("/stream")).backup_urls(vec![backup.url("/stream")]));
+        manager.enqueue(init_segment(1));
+        start(&manager);
+
+        // The backup gets the cached init first
+        let received = backup.recv().await;
+        assert_eq!(tag(&received.data), 1);
+
+        // After the backup drops us, the primary is tried first again
+        primary.wait_for_connections(1).await;
+        assert_eq!(backup.connections().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn impaired_disconnect_triggers_reconnect() {
+        let server = TestServer::start().await;
+        let impairment: Impairment = "disconnect=5s".parse().unwrap();
+        let manager = Arc::new(manager(server.url("/stream")).impairment(impairment));
+        start(&manager);
+
+        // Delivery is covered by the impair tests: with the clock paused, the
+        // pending disconnect timer is the next thing the runtime advances to
+        let connections = server.wait_for_connections(2).await;
+        let gap = connections[1].accepted_at - connections[0].accepted_at;
+        assert!(gap >= Duration::from_secs(5) + RECONNECT_INTERVAL, "reconnected after {:?}", gap);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_flushes_and_closes_normally() {
+        let mut server = TestServer::start().await;
+        let manager = Arc::new(manager(server.url("/stream")));
+        let task = start(&manager);
+        manager.wait_for_connection().await;
+
+        manager.enqueue(init_segment(1));
+        manager.enqueue(media_segment(10, 100));
+        manager.shutdown();
+
+        assert!(timeout(Duration::from_secs(1), task).await.unwrap().unwrap().is_ok());
+        assert_eq!(server.wait_for_client_close(0).await, CloseCode::Normal);
+        let tags: Vec<u8> = server.drain().iter().map(|r| tag(&r.data)).collect();
+        assert_eq!(tags, [1, 10]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_interrupts_reconnect_wait() {
+        let server = TestServer::start().await;
+        server.push_behavior(Behavior::DropAfter { messages: 0 });
+        let manager = Arc::new(manager(server.url("/stream")));
+        let task = start(&manager);
+        server.wait_for_connections(1).await;
+
+        manager.shutdown();
+        let result = timeout(Duration::from_millis(100), task).await.expect("connect_loop should stop right away");
+        assert!(result.unwrap().is_ok());
+        assert_eq!(server.connections().len(), 1);
+    }
+}